@@ -7,8 +7,9 @@ use std::{
 use clap::{Parser, ValueEnum};
 use color_eyre::eyre::{self, WrapErr};
 use llm::{
-    ggml_format, samplers::build_sampler, ElementType, InferenceParameters, InferenceSessionConfig,
-    InvalidTokenBias, LoadProgress, Model, ModelKVMemoryType, ModelParameters, RoPEOverrides,
+    ggml_format, samplers::build_sampler, ElementType, ImportanceMatrix, InferenceParameters,
+    InferenceSessionConfig, InvalidTokenBias, LoadProgress, Model, ModelKVMemoryType,
+    ModelParameters, QuantizationRule, QuantizeParameters, Regex, RoPEOverrides, TensorDumpFormat,
     TokenBias, TokenId, TokenizerSource,
 };
 use rand::SeedableRng;
@@ -48,6 +49,30 @@ pub enum Args {
 
     /// Quantize a GGML model to 4-bit.
     Quantize(Box<Quantize>),
+
+    /// Collect an importance matrix for a model by evaluating calibration text, to guide
+    /// quantization with `quantize --imatrix`.
+    Calibrate(Box<Calibrate>),
+
+    /// Merge LoRA adapters into a GGML model, and save it as a standalone model.
+    LoraMerge(Box<LoraMerge>),
+
+    /// Convert a Hugging Face Transformers checkpoint (safetensors) to a GGML model.
+    Convert(Box<Convert>),
+
+    /// Save a GGML model in another container without changing its tensors, e.g. to make
+    /// an old GGML or GGMF model memory-mappable.
+    ConvertContainer(Box<ConvertContainer>),
+
+    /// Dequantize a GGML model back to f16 or f32.
+    Dequantize(Box<Dequantize>),
+
+    /// Compare two GGML models of the same architecture tensor by tensor.
+    Diff(Box<Diff>),
+
+    /// Check a GGML model for truncation, corrupt tensor headers and NaN or infinite
+    /// values, and optionally check its SHA-256 hash against a manifest.
+    Verify(Box<Verify>),
 }
 
 #[derive(Parser, Debug)]
@@ -112,6 +137,23 @@ pub struct Perplexity {
     pub prompt: Prompt,
 }
 
+#[derive(Parser, Debug)]
+pub struct Calibrate {
+    #[command(flatten)]
+    pub model_load: ModelLoad,
+
+    /// The text file to evaluate. It is split into chunks of the context size.
+    #[arg(long, short = 'f')]
+    pub calibration_file: PathBuf,
+
+    /// The path to save the importance matrix to.
+    #[arg(long, short = 'o')]
+    pub output: PathBuf,
+
+    #[command(flatten)]
+    pub generate: Generate,
+}
+
 #[derive(Parser, Debug)]
 pub struct Info {
     #[command(flatten)]
@@ -124,6 +166,54 @@ pub struct Info {
     /// Show all of the tokens in the tokenizer.
     #[arg(long, short = 'k')]
     pub tokenizer: bool,
+
+    /// Dump the tensors whose whole name matches this regular expression to `--dump-output`,
+    /// dequantized to f32, e.g. to compare them against a reference implementation.
+    ///
+    /// With `--dump-prompt`, the inputs and outputs of the matching weights, named
+    /// `<weight>:input` and `<weight>:output`, are dumped as well.
+    #[arg(long, value_name = "PATTERN", requires = "dump_output")]
+    pub dump: Option<String>,
+
+    /// The format to dump tensors in.
+    #[arg(long, value_enum, default_value_t = DumpFormat::Npy)]
+    pub dump_format: DumpFormat,
+
+    /// Where to dump the tensors to: a directory for `npy`, or a file for `safetensors`.
+    #[arg(long, requires = "dump")]
+    pub dump_output: Option<PathBuf>,
+
+    /// Evaluate this prompt and dump the activations of the weights that match `--dump`,
+    /// with a row for each token of the prompt.
+    #[arg(long, requires = "dump")]
+    pub dump_prompt: Option<String>,
+}
+impl Info {
+    pub fn dump_pattern(&self) -> eyre::Result<Option<Regex>> {
+        self.dump
+            .as_deref()
+            .map(|pattern| {
+                Regex::new(&format!("^(?:{pattern})$"))
+                    .wrap_err_with(|| format!("invalid dump pattern {pattern:?}"))
+            })
+            .transpose()
+    }
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+pub enum DumpFormat {
+    /// A directory with a NumPy `.npy` file for each tensor.
+    Npy,
+    /// A single safetensors file.
+    Safetensors,
+}
+impl From<DumpFormat> for TensorDumpFormat {
+    fn from(value: DumpFormat) -> Self {
+        match value {
+            DumpFormat::Npy => TensorDumpFormat::Npy,
+            DumpFormat::Safetensors => TensorDumpFormat::Safetensors,
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -338,25 +428,8 @@ pub struct Generate {
     pub use_gpu: bool,
 }
 impl Generate {
-    #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
-    pub fn autodetect_num_threads(&self) -> usize {
-        std::process::Command::new("sysctl")
-            .arg("-n")
-            .arg("hw.perflevel0.physicalcpu")
-            .output()
-            .ok()
-            .and_then(|output| String::from_utf8(output.stdout).ok()?.trim().parse().ok())
-            .unwrap_or(num_cpus::get_physical())
-    }
-
-    #[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
-    pub fn autodetect_num_threads(&self) -> usize {
-        num_cpus::get_physical()
-    }
-
     pub fn num_threads(&self) -> usize {
-        self.num_threads
-            .unwrap_or_else(|| self.autodetect_num_threads())
+        self.num_threads.unwrap_or_else(autodetect_num_threads)
     }
 
     pub fn inference_session_config(&self) -> InferenceSessionConfig {
@@ -507,7 +580,9 @@ pub struct ModelLoad {
     #[arg(long)]
     pub no_mmap: bool,
 
-    /// LoRA adapter to use for the model
+    /// LoRA adapter to use for the model.
+    ///
+    /// This can be a GGLA file, or a PEFT adapter directory or `adapter_model.safetensors` file.
     #[arg(long, num_args(0..))]
     pub lora_paths: Option<Vec<PathBuf>>,
 
@@ -529,6 +604,7 @@ impl ModelLoad {
             gpu_layers: self.gpu_layers,
             rope_overrides: self.rope_scaling.to_rope_arguments(),
             n_gqa: None,
+            cancellation: None,
         };
 
         let mut sp = Some(spinoff::Spinner::new(
@@ -648,10 +724,6 @@ pub struct Quantize {
     #[arg()]
     pub source: PathBuf,
 
-    /// The path to save the quantized model to
-    #[arg()]
-    pub destination: PathBuf,
-
     #[command(flatten)]
     pub tokenizer: ModelTokenizer,
 
@@ -664,19 +736,330 @@ pub struct Quantize {
 
     /// The format to convert to
     pub target: QuantizationTarget,
+
+    /// The path to save the quantized model to. Only optional with `--dry-run`.
+    #[arg(required_unless_present = "dry_run")]
+    pub destination: Option<PathBuf>,
+
+    /// Quantize the tensors matching a pattern to another format, in the form
+    /// "PATTERN=FORMAT". The pattern is a regular expression that has to match
+    /// the whole name of a tensor, e.g. "output.weight=q8_0". When several
+    /// rules match a tensor, the first one is used.
+    #[arg(long = "rule", value_parser = parse_quantization_rule)]
+    pub rules: Vec<(String, QuantizationTarget)>,
+
+    /// An importance matrix, collected with the `calibrate` command, to weight
+    /// the quantization error of each weight with. This mostly helps at low
+    /// bitrates.
+    #[arg(long)]
+    pub imatrix: Option<PathBuf>,
+
+    /// Sets the number of threads to quantize with. The quantized model is the
+    /// same for any number of threads.
+    #[arg(long)]
+    pub num_threads: Option<usize>,
+
+    /// Quantize the model without saving it, e.g. to measure the error a
+    /// quantization type introduces. Nothing is written to the destination,
+    /// which can be omitted.
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+
+    /// Print a report of the error introduced by quantizing each tensor,
+    /// and of the change in file size.
+    #[arg(long)]
+    pub report: Option<QuantizeReportFormat>,
+}
+
+impl Quantize {
+    pub fn parameters(&self) -> eyre::Result<QuantizeParameters> {
+        Ok(QuantizeParameters {
+            rules: self
+                .rules
+                .iter()
+                .map(|(pattern, target)| {
+                    QuantizationRule::new(pattern, (*target).into())
+                        .wrap_err_with(|| format!("invalid quantization rule pattern {pattern:?}"))
+                })
+                .collect::<eyre::Result<_>>()?,
+            importance: self
+                .imatrix
+                .as_deref()
+                .map(ImportanceMatrix::load)
+                .transpose()
+                .wrap_err("failed to load importance matrix")?,
+            n_threads: self.num_threads.unwrap_or_else(autodetect_num_threads),
+        })
+    }
+}
+
+/// The number of threads to use when none is given: the number of physical
+/// cores, or of performance cores on Apple Silicon.
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+fn autodetect_num_threads() -> usize {
+    std::process::Command::new("sysctl")
+        .arg("-n")
+        .arg("hw.perflevel0.physicalcpu")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok()?.trim().parse().ok())
+        .unwrap_or(num_cpus::get_physical())
+}
+
+/// The number of threads to use when none is given: the number of physical
+/// cores, or of performance cores on Apple Silicon.
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+fn autodetect_num_threads() -> usize {
+    num_cpus::get_physical()
+}
+
+fn parse_quantization_rule(s: &str) -> Result<(String, QuantizationTarget), String> {
+    let (pattern, target) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected PATTERN=FORMAT, got {s:?}"))?;
+    Ok((
+        pattern.to_string(),
+        QuantizationTarget::from_str(target, true)?,
+    ))
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+pub enum QuantizeReportFormat {
+    /// A human-readable table.
+    Table,
+    /// A JSON object.
+    Json,
+}
+
+#[derive(Parser, Debug)]
+pub struct LoraMerge {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to the base model
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the merged model to
+    #[arg()]
+    pub destination: PathBuf,
+
+    #[command(flatten)]
+    pub tokenizer: ModelTokenizer,
+
+    /// LoRA adapters to merge into the model, applied in order.
+    ///
+    /// These can be GGLA files, or PEFT adapter directories or `adapter_model.safetensors` files.
+    #[arg(long, required = true, num_args(1..))]
+    pub lora_paths: Vec<PathBuf>,
+
+    /// The format to quantize the merged model to.
+    ///
+    /// If not specified, the tensors keep their original format.
+    #[arg(long)]
+    pub target: Option<QuantizationTarget>,
+}
+
+#[derive(Parser, Debug)]
+pub struct Dequantize {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to the model to dequantize
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the dequantized model to
+    #[arg()]
+    pub destination: PathBuf,
+
+    #[command(flatten)]
+    pub tokenizer: ModelTokenizer,
+
+    /// The format to convert to. With f16, one-dimensional tensors are kept as f32.
+    #[arg(long, default_value_t = DequantizationTarget::F16)]
+    pub target: DequantizationTarget,
+}
+
+#[derive(Parser, Debug)]
+pub struct Diff {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to the first model
+    #[arg()]
+    pub a: PathBuf,
+
+    /// The path to the second model
+    #[arg()]
+    pub b: PathBuf,
+
+    /// Show the distance of every tensor, not just of those that differ.
+    #[arg(long, default_value_t = false)]
+    pub all: bool,
+
+    /// The number of differing tokens to show.
+    #[arg(long, default_value_t = 10)]
+    pub max_tokens: usize,
+}
+
+#[derive(Parser, Debug)]
+pub struct Verify {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to the model to verify
+    #[arg()]
+    pub model_path: PathBuf,
+
+    /// A manifest of SHA-256 hashes in the format of `sha256sum`, with an entry
+    /// for the file name of the model.
+    #[arg(long)]
+    pub manifest: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+pub enum DequantizationTarget {
+    /// 32-bit floating point.
+    F32,
+    /// 16-bit floating point.
+    F16,
+}
+impl fmt::Display for DequantizationTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DequantizationTarget::F32 => write!(f, "f32"),
+            DequantizationTarget::F16 => write!(f, "f16"),
+        }
+    }
+}
+impl From<DequantizationTarget> for ElementType {
+    fn from(t: DequantizationTarget) -> Self {
+        match t {
+            DequantizationTarget::F32 => ElementType::F32,
+            DequantizationTarget::F16 => ElementType::F16,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct ConvertContainer {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to the model to convert
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the converted model to
+    #[arg()]
+    pub destination: PathBuf,
+
+    #[command(flatten)]
+    pub tokenizer: ModelTokenizer,
+
+    /// The GGML container type to convert to.
+    ///
+    /// Only GGJT aligns the tensors so that the model can be memory-mapped.
+    /// Note that using GGML requires the original model to have
+    /// an unscored vocabulary, which is not the case for newer models.
+    #[arg(short, long, default_value_t = SaveContainerType::GgjtV3)]
+    pub container_type: SaveContainerType,
+}
+
+#[derive(Parser, Debug)]
+pub struct Convert {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The directory of the checkpoint, containing its `config.json` and `*.safetensors` files
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the converted model to
+    #[arg()]
+    pub destination: PathBuf,
+
+    /// The tokenizer to take the vocabulary from.
+    ///
+    /// If not specified, the `tokenizer.json` in the checkpoint directory is used.
+    #[command(flatten)]
+    pub tokenizer: ModelTokenizer,
+
+    /// The format to convert the tensors to. One-dimensional tensors are always stored as f32.
+    #[arg(long, default_value_t = ConvertTarget::F16)]
+    pub target: ConvertTarget,
+}
+impl Convert {
+    pub fn tokenizer_source(&self) -> eyre::Result<TokenizerSource> {
+        Ok(match self.tokenizer.to_source()? {
+            TokenizerSource::Embedded => {
+                TokenizerSource::HuggingFaceTokenizerFile(self.source.join("tokenizer.json"))
+            }
+            source => source,
+        })
+    }
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+#[clap(rename_all = "snake_case")]
+pub enum ConvertTarget {
+    /// 32-bit floating point.
+    F32,
+    /// 16-bit floating point.
+    F16,
+    /// Quantized 4-bit (type 0).
+    Q4_0,
+    /// Quantized 4-bit (type 1).
+    Q4_1,
+    /// Quantized 5-bit (type 0).
+    Q5_0,
+    /// Quantized 5-bit (type 1).
+    Q5_1,
+    /// Quantized 8-bit (type 0).
+    Q8_0,
+}
+impl fmt::Display for ConvertTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConvertTarget::F32 => write!(f, "f32"),
+            ConvertTarget::F16 => write!(f, "f16"),
+            ConvertTarget::Q4_0 => write!(f, "q4_0"),
+            ConvertTarget::Q4_1 => write!(f, "q4_1"),
+            ConvertTarget::Q5_0 => write!(f, "q5_0"),
+            ConvertTarget::Q5_1 => write!(f, "q5_1"),
+            ConvertTarget::Q8_0 => write!(f, "q8_0"),
+        }
+    }
+}
+impl From<ConvertTarget> for ElementType {
+    fn from(t: ConvertTarget) -> Self {
+        match t {
+            ConvertTarget::F32 => ElementType::F32,
+            ConvertTarget::F16 => ElementType::F16,
+            ConvertTarget::Q4_0 => ElementType::Q4_0,
+            ConvertTarget::Q4_1 => ElementType::Q4_1,
+            ConvertTarget::Q5_0 => ElementType::Q5_0,
+            ConvertTarget::Q5_1 => ElementType::Q5_1,
+            ConvertTarget::Q8_0 => ElementType::Q8_0,
+        }
+    }
 }
 
 #[derive(Parser, Debug, ValueEnum, Clone, Copy)]
 pub enum SaveContainerType {
     /// GGML container.
     Ggml,
-    /// GGJT v3 container.
+    /// GGMF v1 container.
+    GgmfV1,
+    /// GGJT v3 container, which can be memory-mapped.
     GgjtV3,
 }
 impl fmt::Display for SaveContainerType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SaveContainerType::Ggml => write!(f, "ggml"),
+            SaveContainerType::GgmfV1 => write!(f, "ggmf-v1"),
             SaveContainerType::GgjtV3 => write!(f, "ggjt-v3"),
         }
     }
@@ -685,6 +1068,7 @@ impl From<SaveContainerType> for ggml_format::SaveContainerType {
     fn from(value: SaveContainerType) -> Self {
         match value {
             SaveContainerType::Ggml => ggml_format::SaveContainerType::Ggml,
+            SaveContainerType::GgmfV1 => ggml_format::SaveContainerType::GgmfV1,
             SaveContainerType::GgjtV3 => ggml_format::SaveContainerType::GgjtV3,
         }
     }
@@ -703,6 +1087,21 @@ pub enum QuantizationTarget {
     Q5_1,
     /// Quantized 8-bit (type 0).
     Q8_0,
+    /// K-Quantized 2-bit.
+    #[allow(non_camel_case_types)]
+    Q2_K,
+    /// K-Quantized 3-bit.
+    #[allow(non_camel_case_types)]
+    Q3_K,
+    /// K-Quantized 4-bit.
+    #[allow(non_camel_case_types)]
+    Q4_K,
+    /// K-Quantized 5-bit.
+    #[allow(non_camel_case_types)]
+    Q5_K,
+    /// K-Quantized 6-bit.
+    #[allow(non_camel_case_types)]
+    Q6_K,
 }
 impl From<QuantizationTarget> for ElementType {
     fn from(t: QuantizationTarget) -> Self {
@@ -712,6 +1111,11 @@ impl From<QuantizationTarget> for ElementType {
             QuantizationTarget::Q5_0 => ElementType::Q5_0,
             QuantizationTarget::Q5_1 => ElementType::Q5_1,
             QuantizationTarget::Q8_0 => ElementType::Q8_0,
+            QuantizationTarget::Q2_K => ElementType::Q2_K,
+            QuantizationTarget::Q3_K => ElementType::Q3_K,
+            QuantizationTarget::Q4_K => ElementType::Q4_K,
+            QuantizationTarget::Q5_K => ElementType::Q5_K,
+            QuantizationTarget::Q6_K => ElementType::Q6_K,
         }
     }
 }
@@ -44,6 +44,7 @@ pub fn repl(
                 parameters: &parameters,
                 play_back_previous_tokens: false,
                 maximum_token_count: generate.num_predict,
+                cancellation: None,
             },
             &mut Default::default(),
             |r| {
@@ -100,6 +101,7 @@ pub fn chat(args: &Chat) -> eyre::Result<()> {
                 parameters: &parameters,
                 play_back_previous_tokens: false,
                 maximum_token_count: generate.num_predict,
+                cancellation: None,
             },
             &mut Default::default(),
             llm::conversation_inference_callback(&message_prompt_prefix, util::print_token),
@@ -162,10 +164,7 @@ fn create_session(
 }
 
 fn session_ends_with_newline(session: &llm::InferenceSession) -> bool {
-    session
-        .decoded_tokens()
-        .last()
-        .map_or(true, |t| *t == b'\n')
+    matches!(session.decoded_tokens().last(), None | Some(b'\n'))
 }
 
 fn readline_loop(mut body: impl FnMut(String) -> eyre::Result<()>) -> eyre::Result<()> {
@@ -1,4 +1,5 @@
 use std::{
+    cell::RefCell,
     convert::Infallible,
     fs::File,
     io::{BufReader, BufWriter},
@@ -8,9 +9,11 @@ use clap::Parser;
 use cli_args::Args;
 use color_eyre::eyre::{self, Context, ContextCompat};
 use is_terminal::IsTerminal;
+use quantize_report::QuantizeReport;
 
 mod cli_args;
 mod interactive;
+mod quantize_report;
 mod snapshot;
 mod util;
 
@@ -32,6 +35,13 @@ fn main() -> eyre::Result<()> {
         Args::Repl(args) => interactive::repl(&args),
         Args::Chat(args) => interactive::chat(&args),
         Args::Quantize(args) => quantize(&args),
+        Args::Calibrate(args) => calibrate(&args),
+        Args::LoraMerge(args) => lora_merge(&args),
+        Args::Convert(args) => convert(&args),
+        Args::ConvertContainer(args) => convert_container(&args),
+        Args::Dequantize(args) => dequantize(&args),
+        Args::Diff(args) => diff(&args),
+        Args::Verify(args) => verify(&args),
     }
 }
 
@@ -65,6 +75,7 @@ fn infer(args: &cli_args::Infer) -> eyre::Result<()> {
                 parameters: &parameters,
                 play_back_previous_tokens: session_loaded,
                 maximum_token_count: args.generate.num_predict,
+                cancellation: None,
             },
             // OutputRequest
             &mut Default::default(),
@@ -99,7 +110,10 @@ fn infer(args: &cli_args::Infer) -> eyre::Result<()> {
             Err(llm::InferenceError::SamplerFailure(err)) => {
                 log::error!("A sampling-related failure occurred: {}", err);
             }
-            Err(llm::InferenceError::UserCallback(_)) | Err(llm::InferenceError::EndOfText) => {
+            Err(llm::InferenceError::UserCallback(_))
+            | Err(llm::InferenceError::EndOfText)
+            | Err(llm::InferenceError::Cancelled)
+            | Err(llm::InferenceError::UnsupportedOnAccelerator) => {
                 unreachable!("cannot fail")
             }
         }
@@ -127,6 +141,26 @@ fn perplexity(args: &cli_args::Perplexity) -> eyre::Result<()> {
     Ok(())
 }
 
+fn calibrate(args: &cli_args::Calibrate) -> eyre::Result<()> {
+    let text = cli_args::read_prompt_file(&args.calibration_file)?;
+    let inference_session_config = args.generate.inference_session_config();
+    // Activations can only be observed on the CPU.
+    let model = args.model_load.load(false)?;
+    let mut session = model.start_session(inference_session_config);
+
+    let importance = session.calibrate(model.as_ref(), text.as_str(), |chunk, n_chunks| {
+        log::info!("Evaluated chunk {chunk}/{n_chunks}");
+    })?;
+    importance.save(&args.output)?;
+    log::info!(
+        "Saved the importance matrix of {} weights to {:?}",
+        importance.len(),
+        args.output
+    );
+
+    Ok(())
+}
+
 fn info(args: &cli_args::Info) -> eyre::Result<()> {
     struct InfoVisitor<'a>(&'a cli_args::Info);
     impl llm::ModelArchitectureVisitor<eyre::Result<()>> for InfoVisitor<'_> {
@@ -167,6 +201,37 @@ fn info(args: &cli_args::Info) -> eyre::Result<()> {
                 std::str::from_utf8(token).map_or(format!("{:?}", token), |s| s.to_owned())
             }
 
+            let (Some(pattern), Some(output)) = (args.dump_pattern()?, &args.dump_output) else {
+                return Ok(());
+            };
+            let mut tensors =
+                llm::dump_weights::<M>(model_path, &pattern).wrap_err("failed to dump tensors")?;
+            if let Some(prompt) = &args.dump_prompt {
+                let model = llm::load::<M>(
+                    model_path,
+                    args.model_and_tokenizer.to_source()?,
+                    Default::default(),
+                    llm::load_progress_callback_stdout,
+                )
+                .wrap_err("failed to load model")?;
+                let mut session = model.start_session(Default::default());
+                session.capture_activations(pattern)?;
+                session.feed_prompt(&model, prompt.as_str(), &mut Default::default(), |_| {
+                    Ok::<_, Infallible>(llm::InferenceFeedback::Continue)
+                })?;
+                tensors.extend(session.take_activations().unwrap_or_default());
+            }
+
+            if tensors.is_empty() {
+                log::warn!("No tensors match the dump pattern");
+            }
+            let paths = llm::save_dumped_tensors(&tensors, args.dump_format.into(), output)
+                .wrap_err("failed to save dumped tensors")?;
+            log::info!("Dumped {} tensors:", tensors.len());
+            for path in paths {
+                log::info!("- {}", path.display());
+            }
+
             Ok(())
         }
     }
@@ -180,8 +245,13 @@ fn info(args: &cli_args::Info) -> eyre::Result<()> {
 
 fn prompt_tokens(args: &cli_args::PromptTokens) -> eyre::Result<()> {
     let prompt = load_prompt_file_with_prompt(&args.prompt_file, args.prompt.as_deref())?;
-    let model = args.model_load.load(false)?;
-    let toks = match model.tokenizer().tokenize(&prompt, false) {
+    let model_and_tokenizer = &args.model_load.model_and_tokenizer;
+    let (tokenizer, _) = llm::load_tokenizer_dynamic(
+        model_and_tokenizer.architecture.model_architecture,
+        &model_and_tokenizer.model_path,
+        model_and_tokenizer.to_source()?,
+    )?;
+    let toks = match tokenizer.tokenize(&prompt, false) {
         Ok(toks) => toks,
         Err(e) => {
             log::error!("Could not tokenize prompt: {e}");
@@ -208,10 +278,72 @@ fn prompt_tokens(args: &cli_args::PromptTokens) -> eyre::Result<()> {
 }
 
 fn quantize(args: &cli_args::Quantize) -> eyre::Result<()> {
-    use llm::QuantizeProgress;
-
     struct QuantizeVisitor<'a>(&'a cli_args::Quantize);
     impl llm::ModelArchitectureVisitor<eyre::Result<()>> for QuantizeVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let mut source: BufReader<File> = BufReader::new(std::fs::File::open(&args.source)?);
+            let tokenizer: llm::Tokenizer = args.tokenizer.to_source()?.retrieve(&args.source)?;
+
+            let parameters = args.parameters()?;
+            let report = RefCell::new(QuantizeReport::default());
+            let progress_callback = |progress: llm::QuantizeProgress| {
+                report.borrow_mut().record(&progress);
+                log_quantize_progress(progress);
+            };
+
+            let quantized_file_size = if args.dry_run {
+                let mut destination = util::DiscardWriter::default();
+                llm::quantize_with_parameters::<M, _, _>(
+                    &mut source,
+                    &mut destination,
+                    tokenizer,
+                    args.container_type.into(),
+                    args.target.into(),
+                    &parameters,
+                    progress_callback,
+                )
+                .wrap_err("failed to quantize model")?;
+                destination.len()
+            } else {
+                let path = args
+                    .destination
+                    .as_ref()
+                    .wrap_err("a destination is required without --dry-run")?;
+                let mut destination: BufWriter<File> = BufWriter::new(std::fs::File::create(path)?);
+                llm::quantize_with_parameters::<M, _, _>(
+                    &mut source,
+                    &mut destination,
+                    tokenizer,
+                    args.container_type.into(),
+                    args.target.into(),
+                    &parameters,
+                    progress_callback,
+                )
+                .wrap_err("failed to quantize model")?;
+                destination.into_inner()?.metadata()?.len()
+            };
+
+            if let Some(format) = args.report {
+                let original_file_size = std::fs::metadata(&args.source)?.len();
+                report
+                    .into_inner()
+                    .print(format, original_file_size, quantized_file_size)?;
+            }
+            Ok(())
+        }
+    }
+
+    args.architecture
+        .model_architecture
+        .wrap_err("the architecture must be known for quantization")?
+        .visit(&mut QuantizeVisitor(args))
+}
+
+fn lora_merge(args: &cli_args::LoraMerge) -> eyre::Result<()> {
+    struct LoraMergeVisitor<'a>(&'a cli_args::LoraMerge);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for LoraMergeVisitor<'_> {
         fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
             let args = self.0;
 
@@ -220,51 +352,302 @@ fn quantize(args: &cli_args::Quantize) -> eyre::Result<()> {
                 BufWriter::new(std::fs::File::create(&args.destination)?);
             let tokenizer: llm::Tokenizer = args.tokenizer.to_source()?.retrieve(&args.source)?;
 
-            llm::quantize::<M, _, _>(
+            llm::merge_lora::<M, _, _>(
                 &mut source,
                 &mut destination,
                 tokenizer,
-                args.container_type.into(),
+                &args.lora_paths,
+                args.target.map(Into::into),
+                log_quantize_progress,
+            )
+            .wrap_err("failed to merge LoRA adapters")
+        }
+    }
+
+    args.architecture
+        .model_architecture
+        .wrap_err("the architecture must be known for merging LoRA adapters")?
+        .visit(&mut LoraMergeVisitor(args))
+}
+
+fn convert(args: &cli_args::Convert) -> eyre::Result<()> {
+    struct ConvertVisitor<'a>(&'a cli_args::Convert);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for ConvertVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            use llm::ConvertProgress;
+
+            let args = self.0;
+
+            let tokenizer: llm::Tokenizer = args.tokenizer_source()?.retrieve(&args.source)?;
+            let mut destination: BufWriter<File> =
+                BufWriter::new(std::fs::File::create(&args.destination)?);
+
+            llm::convert_hf::<M, _>(
+                &args.source,
+                &mut destination,
+                tokenizer,
                 args.target.into(),
                 |progress| match progress {
-                    QuantizeProgress::HyperparametersLoaded => log::info!("Loaded hyperparameters"),
-                    QuantizeProgress::TensorLoading {
+                    ConvertProgress::HyperparametersLoaded => log::info!("Loaded hyperparameters"),
+                    ConvertProgress::TensorSkipped { name } => {
+                        log::info!("Skipped weight `{name}`")
+                    }
+                    ConvertProgress::TensorConverted {
                         name,
-                        dims,
                         element_type,
-                        n_elements,
-                    } => log::info!(
-                        "Loading tensor `{name}` ({n_elements} ({dims:?}) {element_type} elements)"
-                    ),
-                    QuantizeProgress::TensorQuantizing { name } => log::info!("Quantizing tensor `{name}`"),
-                    QuantizeProgress::TensorQuantized {
-                        name,
-                        original_size,
-                        reduced_size,
-                        history,
-                    } => log::info!(
-                    "Quantized tensor `{name}` from {original_size} to {reduced_size} bytes ({history:?})"
-                ),
-                    QuantizeProgress::TensorSkipped { name, size } => {
-                        log::info!("Skipped tensor `{name}` ({size} bytes)")
+                        size,
+                    } => log::info!("Converted tensor `{name}` to {element_type} ({size} bytes)"),
+                    ConvertProgress::Finished { size } => {
+                        log::info!("Finished conversion ({size} bytes)")
                     }
-                    QuantizeProgress::Finished {
-                        original_size,
-                        reduced_size,
-                        history,
-                    } => log::info!(
-                        "Finished quantization from {original_size} to {reduced_size} bytes ({history:?})"
-                    ),
                 },
             )
-            .wrap_err("failed to quantize model")
+            .wrap_err("failed to convert checkpoint")
         }
     }
 
     args.architecture
         .model_architecture
-        .wrap_err("the architecture must be known for quantization")?
-        .visit(&mut QuantizeVisitor(args))
+        .wrap_err("the architecture must be known for conversion")?
+        .visit(&mut ConvertVisitor(args))
+}
+
+fn dequantize(args: &cli_args::Dequantize) -> eyre::Result<()> {
+    struct DequantizeVisitor<'a>(&'a cli_args::Dequantize);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for DequantizeVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let mut source: BufReader<File> = BufReader::new(std::fs::File::open(&args.source)?);
+            let mut destination: BufWriter<File> =
+                BufWriter::new(std::fs::File::create(&args.destination)?);
+            let tokenizer: llm::Tokenizer = args.tokenizer.to_source()?.retrieve(&args.source)?;
+
+            llm::dequantize::<M, _, _>(
+                &mut source,
+                &mut destination,
+                tokenizer,
+                args.target.into(),
+                log_quantize_progress,
+            )
+            .wrap_err("failed to dequantize model")
+        }
+    }
+
+    args.architecture
+        .model_architecture
+        .wrap_err("the architecture must be known for dequantization")?
+        .visit(&mut DequantizeVisitor(args))
+}
+
+fn convert_container(args: &cli_args::ConvertContainer) -> eyre::Result<()> {
+    struct ConvertContainerVisitor<'a>(&'a cli_args::ConvertContainer);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for ConvertContainerVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let mut source: BufReader<File> = BufReader::new(std::fs::File::open(&args.source)?);
+            let mut destination: BufWriter<File> =
+                BufWriter::new(std::fs::File::create(&args.destination)?);
+            let tokenizer: llm::Tokenizer = args.tokenizer.to_source()?.retrieve(&args.source)?;
+
+            llm::convert_container::<M, _, _>(
+                &mut source,
+                &mut destination,
+                tokenizer,
+                args.container_type.into(),
+                log_quantize_progress,
+            )
+            .wrap_err("failed to convert model container")
+        }
+    }
+
+    args.architecture
+        .model_architecture
+        .wrap_err("the architecture must be known for container conversion")?
+        .visit(&mut ConvertContainerVisitor(args))
+}
+
+fn diff(args: &cli_args::Diff) -> eyre::Result<()> {
+    struct DiffVisitor<'a>(&'a cli_args::Diff);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for DiffVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let diff = llm::diff::<M>(&args.a, &args.b, |progress| {
+                if let llm::DiffProgress::TensorCompared {
+                    name,
+                    current_tensor,
+                    tensor_count,
+                } = progress
+                {
+                    log::debug!("Compared {name} ({}/{tensor_count})", current_tensor + 1);
+                }
+            })
+            .wrap_err("failed to compare models")?;
+
+            let (container_a, container_b) = diff.container_types;
+            if container_a != container_b {
+                log::info!("Container type: {container_a:?} -> {container_b:?}");
+            }
+            if diff.hyperparameters_differ() {
+                log::info!(
+                    "Hyperparameters:\n{:#?}\n->\n{:#?}",
+                    diff.hyperparameters.0,
+                    diff.hyperparameters.1
+                );
+            }
+
+            let (vocabulary_a, vocabulary_b) = diff.vocabulary_sizes;
+            if vocabulary_a != vocabulary_b {
+                log::info!("Vocabulary size: {vocabulary_a} -> {vocabulary_b}");
+            }
+            if !diff.tokens.is_empty() {
+                log::info!("{} tokens differ:", diff.tokens.len());
+                for token in diff.tokens.iter().take(args.max_tokens) {
+                    log::info!("- {}: {:?} -> {:?}", token.id, token.a, token.b);
+                }
+            }
+
+            for name in &diff.missing_tensors {
+                log::info!("Missing tensor: {name}");
+            }
+            for name in &diff.extra_tensors {
+                log::info!("Extra tensor: {name}");
+            }
+            for tensor in &diff.tensors {
+                if !args.all && tensor.is_identical() {
+                    continue;
+                }
+                let (type_a, type_b) = tensor.element_types;
+                let (dims_a, dims_b) = &tensor.dims;
+                let mut line = format!("- {}:", tensor.name);
+                if tensor.type_changed() {
+                    line += &format!(" {type_a:?} -> {type_b:?}");
+                }
+                if tensor.shape_changed() {
+                    line += &format!(" {dims_a:?} -> {dims_b:?}");
+                }
+                if let Some(distance) = tensor.distance {
+                    line += &format!(
+                        " cosine similarity {:.6}, max abs diff {:e}",
+                        distance.cosine_similarity, distance.max_abs_diff
+                    );
+                }
+                log::info!("{line}");
+            }
+
+            if diff.is_identical() {
+                log::info!("The models are identical");
+            }
+
+            Ok(())
+        }
+    }
+
+    args.architecture
+        .model_architecture
+        .wrap_err("the architecture must be known for comparison")?
+        .visit(&mut DiffVisitor(args))
+}
+
+fn verify(args: &cli_args::Verify) -> eyre::Result<()> {
+    struct VerifyVisitor<'a>(&'a cli_args::Verify);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for VerifyVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let report = llm::verify::<M>(&args.model_path, args.manifest.as_deref(), |progress| {
+                match progress {
+                    llm::VerifyProgress::Loaded { tensor_count } => {
+                        log::info!("Loaded {tensor_count} tensor headers")
+                    }
+                    llm::VerifyProgress::TensorVerified {
+                        name,
+                        current_tensor,
+                        tensor_count,
+                    } => log::debug!("Verified {name} ({}/{tensor_count})", current_tensor + 1),
+                    llm::VerifyProgress::HashComputed { sha256 } => {
+                        log::info!("SHA-256: {sha256}")
+                    }
+                }
+            })
+            .wrap_err("failed to verify model")?;
+
+            log::info!(
+                "Checked {} tensors in a {:?} model of {} bytes",
+                report.tensor_count,
+                report.container_type,
+                report.file_size
+            );
+            for issue in &report.issues {
+                log::error!("{issue}");
+            }
+            eyre::ensure!(
+                report.is_ok(),
+                "the model has {} problem(s)",
+                report.issues.len()
+            );
+            log::info!("No problems found");
+
+            Ok(())
+        }
+    }
+
+    args.architecture
+        .model_architecture
+        .wrap_err("the architecture must be known for verification")?
+        .visit(&mut VerifyVisitor(args))
+}
+
+fn log_quantize_progress(progress: llm::QuantizeProgress) {
+    use llm::QuantizeProgress;
+
+    match progress {
+        QuantizeProgress::HyperparametersLoaded => log::info!("Loaded hyperparameters"),
+        QuantizeProgress::TensorLoading {
+            name,
+            dims,
+            element_type,
+            n_elements,
+        } => {
+            log::info!("Loading tensor `{name}` ({n_elements} ({dims:?}) {element_type} elements)")
+        }
+        QuantizeProgress::TensorPatched { name, source } => {
+            log::info!("Patched tensor `{name}` via LoRA from {source:?}")
+        }
+        QuantizeProgress::TensorQuantizing { name } => log::info!("Quantizing tensor `{name}`"),
+        QuantizeProgress::TensorQuantized {
+            name,
+            element_type,
+            original_size,
+            reduced_size,
+            history,
+            error,
+        } => log::info!(
+            "Quantized tensor `{name}` to {element_type} from {original_size} to {reduced_size} bytes ({history:?}, rmse {})",
+            error.rmse
+        ),
+        QuantizeProgress::TensorDequantized {
+            name,
+            element_type,
+            original_size,
+            new_size,
+        } => log::info!(
+            "Converted tensor `{name}` to {element_type} from {original_size} to {new_size} bytes"
+        ),
+        QuantizeProgress::TensorSkipped { name, size } => {
+            log::info!("Skipped tensor `{name}` ({size} bytes)")
+        }
+        QuantizeProgress::Finished {
+            original_size,
+            reduced_size,
+            history,
+            ..
+        } => log::info!(
+            "Finished quantization from {original_size} to {reduced_size} bytes ({history:?})"
+        ),
+    }
 }
 
 fn load_prompt_file_with_prompt(
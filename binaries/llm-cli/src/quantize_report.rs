@@ -0,0 +1,126 @@
+use bytesize::ByteSize;
+use color_eyre::eyre;
+use llm::{QuantizationStats, QuantizeProgress};
+use serde::Serialize;
+
+use crate::cli_args::QuantizeReportFormat;
+
+/// A summary of what quantizing a model cost, built up from its [QuantizeProgress].
+#[derive(Debug, Default, Serialize)]
+pub struct QuantizeReport {
+    tensors: Vec<TensorReport>,
+    error: Option<QuantizationStats>,
+    original_file_size: u64,
+    quantized_file_size: u64,
+    file_size_delta: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct TensorReport {
+    name: String,
+    element_type: String,
+    original_size: usize,
+    quantized_size: usize,
+    #[serde(flatten)]
+    error: QuantizationStats,
+}
+
+impl QuantizeReport {
+    pub fn record(&mut self, progress: &QuantizeProgress) {
+        match progress {
+            QuantizeProgress::TensorQuantized {
+                name,
+                element_type,
+                original_size,
+                reduced_size,
+                error,
+                ..
+            } => self.tensors.push(TensorReport {
+                name: name.to_string(),
+                element_type: element_type.to_string(),
+                original_size: *original_size,
+                quantized_size: *reduced_size,
+                error: *error,
+            }),
+            QuantizeProgress::Finished { error, .. } => self.error = *error,
+            _ => {}
+        }
+    }
+
+    /// Prints the report to stdout, given the sizes of the files before and after quantization.
+    pub fn print(
+        &mut self,
+        format: QuantizeReportFormat,
+        original_file_size: u64,
+        quantized_file_size: u64,
+    ) -> eyre::Result<()> {
+        self.original_file_size = original_file_size;
+        self.quantized_file_size = quantized_file_size;
+        self.file_size_delta =
+            i64::try_from(quantized_file_size)? - i64::try_from(original_file_size)?;
+
+        match format {
+            QuantizeReportFormat::Table => self.print_table(),
+            QuantizeReportFormat::Json => println!("{}", serde_json::to_string_pretty(self)?),
+        }
+        Ok(())
+    }
+
+    fn print_table(&self) {
+        let name_width = self
+            .tensors
+            .iter()
+            .map(|tensor| tensor.name.len())
+            .chain(["tensor".len(), "total".len()])
+            .max()
+            .unwrap_or_default();
+
+        println!(
+            "{:name_width$}  {:>5}  {:>10}  {:>10}  {:>12}  {:>12}  {:>12}",
+            "tensor", "type", "original", "quantized", "rmse", "max abs err", "rel err"
+        );
+        let row = |name: &str,
+                   element_type: &str,
+                   original: String,
+                   quantized: String,
+                   error: &QuantizationStats| {
+            println!(
+                "{name:name_width$}  {element_type:>5}  {original:>10}  {quantized:>10}  {:>12.6e}  {:>12.6e}  {:>12.6e}",
+                error.rmse, error.max_abs_error, error.relative_error
+            )
+        };
+        for tensor in &self.tensors {
+            row(
+                &tensor.name,
+                &tensor.element_type,
+                ByteSize(tensor.original_size as u64).to_string(),
+                ByteSize(tensor.quantized_size as u64).to_string(),
+                &tensor.error,
+            );
+        }
+        if let Some(error) = &self.error {
+            let (original, quantized) = self.tensors.iter().fold((0, 0), |(o, q), tensor| {
+                (o + tensor.original_size, q + tensor.quantized_size)
+            });
+            row(
+                "total",
+                "",
+                ByteSize(original as u64).to_string(),
+                ByteSize(quantized as u64).to_string(),
+                error,
+            );
+        }
+
+        println!(
+            "File size: {} -> {} ({:+} bytes, {:+.1}%)",
+            ByteSize(self.original_file_size),
+            ByteSize(self.quantized_file_size),
+            self.file_size_delta,
+            if self.original_file_size == 0 {
+                0.0
+            } else {
+                self.file_size_delta as f64 / self.original_file_size as f64 * 100.0
+            }
+        );
+    }
+}
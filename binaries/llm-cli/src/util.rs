@@ -1,4 +1,4 @@
-use std::io::Write;
+use std::io::{Seek, SeekFrom, Write};
 
 pub fn process_prompt(raw_prompt: &str, prompt: &str) -> String {
     raw_prompt.replace("{{PROMPT}}", prompt)
@@ -8,3 +8,45 @@ pub fn print_token(t: String) {
     print!("{t}");
     std::io::stdout().flush().unwrap();
 }
+
+/// A writer that discards everything written to it, while keeping track of how many bytes
+/// would have been written. Used to go through the motions of saving a model without
+/// saving it.
+#[derive(Debug, Default)]
+pub struct DiscardWriter {
+    position: u64,
+    len: u64,
+}
+impl DiscardWriter {
+    /// The number of bytes that would have been written.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+}
+impl Write for DiscardWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.position += buf.len() as u64;
+        self.len = self.len.max(self.position);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+impl Seek for DiscardWriter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => i128::from(offset),
+            SeekFrom::End(offset) => i128::from(self.len) + i128::from(offset),
+            SeekFrom::Current(offset) => i128::from(self.position) + i128::from(offset),
+        };
+        self.position = u64::try_from(position).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
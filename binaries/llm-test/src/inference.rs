@@ -75,6 +75,7 @@ fn run_inference(
             },
             play_back_previous_tokens: false,
             maximum_token_count: Some(maximum_token_count),
+            cancellation: None,
         },
         &mut Default::default(),
         |r| match r {
@@ -47,6 +47,15 @@ pub(crate) struct ContextInner {
     // Hopefully, this is resolved by GGML redesigning both its accelerator
     // interface and its scratch buffer solution.
     pub offloaded_tensors: Mutex<HashMap<String, Tensor>>,
+
+    /// The callbacks of the tensors created with [Context::op_observe]. They are boxed so that
+    /// the pointers to them that are stored in the tensors stay valid.
+    #[allow(clippy::vec_box)]
+    pub observers: Mutex<Vec<Box<Box<Observer>>>>,
+
+    /// Whether the data of the tensors can be written to. This is not the case for
+    /// memory-mapped storage, which is mapped read-only.
+    pub writable: bool,
 }
 impl PartialEq for ContextInner {
     fn eq(&self, other: &Self) -> bool {
@@ -55,7 +64,7 @@ impl PartialEq for ContextInner {
 }
 impl Eq for ContextInner {}
 impl ContextInner {
-    pub(crate) fn new(ptr: *mut ggml_sys::ggml_context) -> Arc<Self> {
+    pub(crate) fn new(ptr: *mut ggml_sys::ggml_context, writable: bool) -> Arc<Self> {
         // This context can only be used from one thread at a time - hence why
         // it doesn't implement `Send/Sync` - but higher-level abstractions may
         // choose to layer their own abstractions that implement higher-level
@@ -66,6 +75,8 @@ impl ContextInner {
         Arc::new(Self {
             ptr: NonNull::new(ptr).expect("Should not be null"),
             offloaded_tensors: Default::default(),
+            observers: Default::default(),
+            writable,
         })
     }
 }
@@ -150,9 +161,10 @@ impl Context {
             },
         };
 
+        let writable = !matches!(storage, ContextStorage::Mmap(_));
         let raw = unsafe { sys::ggml_init(init_params) };
         Self {
-            inner: ContextInner::new(raw),
+            inner: ContextInner::new(raw, writable),
             storage: Some(storage),
             can_offload: false,
         }
@@ -261,6 +273,28 @@ impl Context {
         self.new_tensor_raw(raw)
     }
 
+    /// Creates a new 4D tensor.
+    pub fn new_tensor_4d(
+        &self,
+        typ: Type,
+        ne0: usize,
+        ne1: usize,
+        ne2: usize,
+        ne3: usize,
+    ) -> Tensor {
+        let raw = unsafe {
+            sys::ggml_new_tensor_4d(
+                self.as_ptr(),
+                typ.into(),
+                usize_to_i64(ne0),
+                usize_to_i64(ne1),
+                usize_to_i64(ne2),
+                usize_to_i64(ne3),
+            )
+        };
+        self.new_tensor_raw(raw)
+    }
+
     /// Creates a new 1D tensor with the specified value.
     pub fn new_f32(&self, x: f32) -> Tensor {
         let raw = unsafe { sys::ggml_new_f32(self.as_ptr(), x) };
@@ -426,6 +460,30 @@ impl Context {
         self.new_tensor_raw(tensor)
     }
 
+    /// Creates a view of `a` that calls `observer` with the values of `a` once they have been
+    /// computed, along with the size of each of its dimensions.
+    ///
+    /// The values are passed in memory order, with the first dimension varying the fastest.
+    /// `a` must be a [Type::F32] tensor that is computed on the CPU; observing tensors that
+    /// are computed by an accelerator is not supported.
+    pub fn op_observe(
+        &self,
+        a: &Tensor,
+        observer: impl Fn(&[f32], [usize; 4]) + Send + Sync + 'static,
+    ) -> Tensor {
+        let observer: Box<Box<Observer>> = Box::new(Box::new(observer));
+        let tensor = unsafe {
+            let tensor =
+                sys::ggml_map_custom1_inplace_f32(self.as_ptr(), a.ptr.as_ptr(), Some(observe));
+            // `extra` is only used by the accelerators, which do not support custom operations.
+            // The observer is kept alive by the context for as long as the tensor exists.
+            (*tensor).extra = &*observer as *const Box<Observer> as *mut c_void;
+            tensor
+        };
+        self.inner.observers.lock().unwrap().push(observer);
+        self.new_tensor_raw(tensor)
+    }
+
     /// Creates a 1D view over `a`.
     pub fn op_view_1d(&self, a: &Tensor, ne0: usize, offset: usize) -> Tensor {
         #[cfg(debug_assertions)]
@@ -646,6 +704,30 @@ impl Context {
     }
 }
 
+/// A callback passed to [Context::op_observe].
+type Observer = dyn Fn(&[f32], [usize; 4]) + Send + Sync;
+
+/// Gathers the values of `a` and passes them to the [Observer] stored in `dst`.
+unsafe extern "C" fn observe(dst: *mut sys::ggml_tensor, a: *const sys::ggml_tensor) {
+    let observer = &*((*dst).extra as *const Box<Observer>);
+    let a = &*a;
+
+    let ne = a.ne.map(|ne| usize::try_from(ne).unwrap());
+    let mut values = Vec::with_capacity(ne.iter().product());
+    for i3 in 0..ne[3] {
+        for i2 in 0..ne[2] {
+            for i1 in 0..ne[1] {
+                for i0 in 0..ne[0] {
+                    let offset = i0 * a.nb[0] + i1 * a.nb[1] + i2 * a.nb[2] + i3 * a.nb[3];
+                    values.push(*((a.data as *const u8).add(offset) as *const f32));
+                }
+            }
+        }
+    }
+
+    observer(&values, ne);
+}
+
 impl Drop for Context {
     fn drop(&mut self) {
         // SAFETY: The only non-weak copy of ptr is no longer accessible after this drop call.
@@ -0,0 +1,117 @@
+//! Support for the quantized block layouts used before quantization version 2.
+//!
+//! - Version 0 (GGJT v1 and earlier) interleaves the 4-bit values of `Q4_0`, `Q4_1`, `Q5_0`
+//!   and `Q5_1` blocks: element `2i` is in the low nibble of byte `i`, and element `2i + 1`
+//!   in its high nibble. The current layout stores element `i` in the low nibble of byte `i`
+//!   and element `i + 16` in its high nibble.
+//! - Versions 0 and 1 (GGJT v2) store the scales of `Q4_0`, `Q4_1` and `Q8_0` blocks as `f32`
+//!   rather than `f16`.
+//!
+//! All other block layouts are unchanged.
+
+use crate::{sys, ElementType};
+
+/// The number of elements in a `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1` or `Q8_0` block.
+const BLOCK_ELEMENTS: usize = 32;
+
+/// How a legacy block differs from the current layout.
+struct Layout {
+    /// The number of `f32` scales at the start of the block, which are now `f16`.
+    f32_scales: usize,
+    /// The number of bytes after the scales that are unchanged.
+    unchanged: usize,
+    /// The number of bytes of interleaved 4-bit values at the end of the block.
+    interleaved: usize,
+}
+impl Layout {
+    fn for_type(element_type: ElementType, quantization_version: u32) -> Option<Self> {
+        // The bytes taken up by the 4-bit values, and how many of them need reordering.
+        let nibbles = BLOCK_ELEMENTS / 2;
+        let (unchanged, interleaved) = if quantization_version == 0 {
+            (0, nibbles)
+        } else {
+            (nibbles, 0)
+        };
+
+        let layout = match (element_type, quantization_version) {
+            (_, 2..) => return None,
+            (ElementType::Q4_0, _) => Layout {
+                f32_scales: 1,
+                unchanged,
+                interleaved,
+            },
+            (ElementType::Q4_1, _) => Layout {
+                f32_scales: 2,
+                unchanged,
+                interleaved,
+            },
+            (ElementType::Q8_0, _) => Layout {
+                f32_scales: 1,
+                unchanged: BLOCK_ELEMENTS,
+                interleaved: 0,
+            },
+            // `f16` scale and the fifth bits of the values
+            (ElementType::Q5_0, 0) => Layout {
+                f32_scales: 0,
+                unchanged: 2 + 4,
+                interleaved,
+            },
+            // `f16` scale and minimum, and the fifth bits of the values
+            (ElementType::Q5_1, 0) => Layout {
+                f32_scales: 0,
+                unchanged: 2 * 2 + 4,
+                interleaved,
+            },
+            _ => return None,
+        };
+        Some(layout)
+    }
+
+    fn block_size(&self) -> usize {
+        4 * self.f32_scales + self.unchanged + self.interleaved
+    }
+}
+
+/// Returns the size in bytes of a block of `element_type` in the layout used by
+/// `quantization_version`, or `None` if that layout is the current one.
+pub(crate) fn block_size(element_type: ElementType, quantization_version: u32) -> Option<usize> {
+    Layout::for_type(element_type, quantization_version).map(|layout| layout.block_size())
+}
+
+/// Converts `data`, made up of blocks of `element_type` in the layout used by
+/// `quantization_version`, to the current layout.
+///
+/// The quantized values are kept as they are; only `f32` scales lose precision,
+/// as they are rounded to `f16`.
+pub(crate) fn convert(
+    element_type: ElementType,
+    quantization_version: u32,
+    data: &[u8],
+) -> Vec<u8> {
+    let Some(layout) = Layout::for_type(element_type, quantization_version) else {
+        return data.to_vec();
+    };
+    let block_size = layout.block_size();
+
+    let mut output =
+        Vec::with_capacity(data.len() / block_size * (block_size - 2 * layout.f32_scales));
+    for block in data.chunks_exact(block_size) {
+        let (scales, rest) = block.split_at(4 * layout.f32_scales);
+        for scale in scales.chunks_exact(4) {
+            let scale = f32::from_le_bytes(scale.try_into().unwrap());
+            output.extend(unsafe { sys::ggml_fp32_to_fp16(scale) }.to_le_bytes());
+        }
+
+        let (unchanged, values) = rest.split_at(layout.unchanged);
+        output.extend_from_slice(unchanged);
+        output.extend(deinterleave_nibbles(values));
+    }
+    output
+}
+
+/// Converts interleaved 4-bit values to the current order.
+fn deinterleave_nibbles(values: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    let nibble = |i: usize| (values[i / 2] >> (4 * (i % 2))) & 0x0F;
+    let half = values.len();
+    (0..half).map(move |i| nibble(i) | (nibble(i + half) << 4))
+}
@@ -75,14 +75,16 @@ pub struct TensorLoadInfo {
     pub name: String,
     /// The number of dimensions in the tensor.
     pub n_dims: usize,
-    /// The dimensions of the tensor.
-    pub dims: [usize; 2],
+    /// The dimensions of the tensor, padded with 1s after the first `n_dims`.
+    pub dims: [usize; crate::MAX_DIMS],
     /// The number of elements in the tensor.
     pub n_elements: usize,
     /// The type of the elements in the tensor.
     pub element_type: ElementType,
     /// start of tensor - start of file
     pub start_offset: u64,
+    /// The quantization version of the file, which determines the layout of quantized blocks.
+    pub quantization_version: u32,
 }
 impl TensorLoadInfo {
     /// Get the dimensions of the tensor.
@@ -95,6 +97,22 @@ impl TensorLoadInfo {
         data_size(self.element_type, self.dims().iter().product())
     }
 
+    /// Calculate the size of the tensor's values in the file in bytes.
+    ///
+    /// This differs from [Self::calc_size] if the tensor uses a legacy block layout.
+    pub fn calc_file_size(&self) -> usize {
+        match super::legacy::block_size(self.element_type, self.quantization_version) {
+            Some(block_size) => block_size * self.n_elements / crate::blck_size(self.element_type),
+            None => self.calc_size(),
+        }
+    }
+
+    /// Whether the tensor's values are stored in a block layout from an earlier quantization
+    /// version. Such tensors are converted by [Self::read_data], and cannot be memory-mapped.
+    pub fn is_legacy(&self) -> bool {
+        super::legacy::block_size(self.element_type, self.quantization_version).is_some()
+    }
+
     /// Calculates the absolute size in bytes of the tensor's data, given the mmap flag.
     pub fn calc_absolute_size(&self, mmap: bool) -> usize {
         if mmap {
@@ -106,16 +124,55 @@ impl TensorLoadInfo {
 
     /// Reads the tensor's data from the given reader in an owned fashion.
     ///
+    /// Data in a [legacy](Self::is_legacy) block layout is converted to the current layout.
+    ///
     /// The behaviour is undefined if the reader does not correspond to this info.
     ///
     /// Do not use this if loading with `mmap`.
     pub fn read_data<R: BufRead + Seek>(&self, reader: &mut R) -> std::io::Result<Vec<u8>> {
-        let n_bytes = self.n_elements * crate::type_size(self.element_type);
-        let mut data = vec![0; n_bytes];
+        let mut data = vec![0; self.calc_file_size()];
         reader.seek(SeekFrom::Start(self.start_offset))?;
         reader.read_exact(&mut data)?;
+        if self.is_legacy() {
+            data = super::legacy::convert(self.element_type, self.quantization_version, &data);
+        }
         Ok(data)
     }
+
+    /// Reads the tensor's data from the given reader like [Self::read_data], but in chunks of
+    /// whole blocks of at most `max_chunk_size` bytes (and at least one block), which are passed
+    /// to `f` in turn. This avoids holding all of the tensor's data in memory at once.
+    ///
+    /// The behaviour is undefined if the reader does not correspond to this info.
+    pub fn read_data_chunked<R: BufRead + Seek>(
+        &self,
+        reader: &mut R,
+        max_chunk_size: usize,
+        mut f: impl FnMut(&[u8]),
+    ) -> std::io::Result<()> {
+        let block_size = super::legacy::block_size(self.element_type, self.quantization_version)
+            .unwrap_or_else(|| crate::type_size(self.element_type));
+        let chunk_size = (max_chunk_size / block_size).max(1) * block_size;
+
+        let mut remaining = self.calc_file_size();
+        let mut chunk = vec![0; chunk_size.min(remaining)];
+        reader.seek(SeekFrom::Start(self.start_offset))?;
+        while remaining > 0 {
+            let chunk = &mut chunk[..chunk_size.min(remaining)];
+            reader.read_exact(chunk)?;
+            if self.is_legacy() {
+                f(&super::legacy::convert(
+                    self.element_type,
+                    self.quantization_version,
+                    chunk,
+                ));
+            } else {
+                f(chunk);
+            }
+            remaining -= chunk.len();
+        }
+        Ok(())
+    }
 }
 
 /// Returns the size occupied by a tensor's data in bytes given the element type and number of elements.
@@ -139,6 +196,9 @@ pub fn tensor_size(element_type: ElementType, n_elements: usize) -> usize {
 pub struct PartialHyperparameters {
     /// The number of tokens in the model's embedded vocabulary.
     pub n_vocab: usize,
+    /// The quantization version of the model's tensors. Quantized tensors from versions
+    /// before [crate::QNT_VERSION] use a different block layout.
+    pub quantization_version: u32,
 }
 
 /// A handler for loading a GGML model.
@@ -154,6 +214,11 @@ pub trait LoadHandler<E: Error> {
     ) -> Result<PartialHyperparameters, E>;
     /// Called when a new [crate::Tensor] is read for the model.
     fn tensor_buffer(&mut self, info: TensorLoadInfo) -> Result<(), E>;
+    /// Called once the vocabulary has been read. If this returns `false`, loading stops
+    /// there and no tensors are read.
+    fn wants_tensors(&self) -> bool {
+        true
+    }
 }
 
 /// Load a GGML model from a `reader` with the [LoadHandler], which will be called when certain events occur.
@@ -181,6 +246,7 @@ pub fn load<E: Error, R: BufRead + Seek>(
         .read_hyperparameters(reader)
         .map_err(LoadError::ImplementationError)?;
     let n_vocab = hparams.n_vocab;
+    let quantization_version = hparams.quantization_version;
 
     // Load vocabulary
     for i in 0..n_vocab {
@@ -198,11 +264,17 @@ pub fn load<E: Error, R: BufRead + Seek>(
             .map_err(LoadError::ImplementationError)?;
     }
 
+    if !handler.wants_tensors() {
+        return Ok(());
+    }
+
     // Load tensor data
     match container_type {
-        ContainerType::Ggmf(_) | ContainerType::Ggml => load_weights(reader, handler, false),
+        ContainerType::Ggmf(_) | ContainerType::Ggml => {
+            load_weights(reader, handler, false, quantization_version)
+        }
         ContainerType::Ggjt(_version) | ContainerType::Ggla(_version) => {
-            load_weights(reader, handler, true)
+            load_weights(reader, handler, true, quantization_version)
         }
     }
 }
@@ -211,10 +283,14 @@ pub fn load<E: Error, R: BufRead + Seek>(
 ///
 /// `align`
 /// align to 4 bytes before reading tensor weights
+///
+/// `quantization_version`
+/// the quantization version of the tensor weights
 fn load_weights<E: Error, R: BufRead + Seek>(
     reader: &mut R,
     handler: &mut impl LoadHandler<E>,
     align: bool,
+    quantization_version: u32,
 ) -> Result<(), LoadError<E>> {
     while has_data_left(reader)? {
         // load tensor header
@@ -223,7 +299,7 @@ fn load_weights<E: Error, R: BufRead + Seek>(
         let ftype = read_u32(reader)?;
 
         let mut n_elements: usize = 1;
-        let mut dims = [1usize, 1];
+        let mut dims = [1usize; crate::MAX_DIMS];
         let ne_len = dims.len();
         if n_dims > ne_len {
             return Err(LoadError::InvariantBroken(format!("{n_dims} <= {ne_len}")));
@@ -246,10 +322,8 @@ fn load_weights<E: Error, R: BufRead + Seek>(
 
         // sanity check
         match ftype {
-            ElementType::Q4_0 | ElementType::Q4_1 => {
-                if dims[0] % 64 != 0 {
-                    return Err(LoadError::InvariantBroken(format!("{dims:?}[0] % 64 == 0")));
-                }
+            ElementType::Q4_0 | ElementType::Q4_1 if dims[0] % 64 != 0 => {
+                return Err(LoadError::InvariantBroken(format!("{dims:?}[0] % 64 == 0")));
             }
             _ => {}
         }
@@ -269,8 +343,9 @@ fn load_weights<E: Error, R: BufRead + Seek>(
             n_elements,
             element_type: ftype,
             start_offset: offset_aligned,
+            quantization_version,
         };
-        let n_bytes = tensor_info.calc_size();
+        let n_bytes = tensor_info.calc_file_size();
         handler
             .tensor_buffer(tensor_info)
             .map_err(LoadError::ImplementationError)?;
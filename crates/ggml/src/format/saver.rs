@@ -1,4 +1,4 @@
-//! The saver module implements a way to save a model to disk in the GGJT format.
+//! The saver module implements a way to save a model to disk in the GGML, GGMF or GGJT formats.
 //!
 //! To implement a saver for your model, implement [SaveHandler] for your model
 //! and provide data as appropriate, then call [save] with an instance of
@@ -46,8 +46,8 @@ pub trait SaveHandler<E: Error> {
 pub struct TensorSaveInfo {
     /// The number of dimensions in the tensor.
     pub n_dims: usize,
-    /// The dimensions of the tensor.
-    pub dims: [usize; 2],
+    /// The dimensions of the tensor, padded with 1s after the first `n_dims`.
+    pub dims: [usize; crate::MAX_DIMS],
     /// The type of the elements in the tensor.
     pub element_type: ElementType,
     /// The data to save to disk.
@@ -67,13 +67,16 @@ pub struct TensorSaveInfo {
 pub enum SaveContainerType {
     /// The GGML container.
     Ggml,
-    /// The GGJT container.
+    /// The GGMF container, version 1.
+    GgmfV1,
+    /// The GGJT container, version 3.
     GgjtV3,
 }
 impl From<SaveContainerType> for ContainerType {
     fn from(value: SaveContainerType) -> Self {
         match value {
             SaveContainerType::Ggml => ContainerType::Ggml,
+            SaveContainerType::GgmfV1 => ContainerType::Ggmf(1),
             SaveContainerType::GgjtV3 => ContainerType::Ggjt(3),
         }
     }
@@ -81,8 +84,9 @@ impl From<SaveContainerType> for ContainerType {
 
 /// Saves a model to the given writer.
 ///
-/// Only GGML and GGJT version 2 are supported. If using GGML,
-/// the vocabulary *must* have scores of 0.0.
+/// Only GGML, GGMF version 1 and GGJT version 3 are supported. If using GGML,
+/// the vocabulary *must* have scores of 0.0. Only GGJT aligns the tensor data,
+/// which is required for the model to be memory-mapped.
 pub fn save<E: Error, W: Write + Seek>(
     writer: &mut W,
     handler: &mut dyn SaveHandler<E>,
@@ -124,11 +128,13 @@ pub fn save<E: Error, W: Write + Seek>(
             .tensor_data(name)
             .map_err(SaveError::ImplementationError)?;
 
+        if n_dims > dims.len() {
+            let ne_len = dims.len();
+            return Err(SaveError::InvariantBroken(format!("{n_dims} <= {ne_len}")));
+        }
         match element_type {
-            ElementType::Q4_0 | ElementType::Q4_1 => {
-                if dims[0] % 64 != 0 {
-                    return Err(SaveError::InvariantBroken(format!("{dims:?}[0] % 64 == 0")));
-                }
+            ElementType::Q4_0 | ElementType::Q4_1 if dims[0] % 64 != 0 => {
+                return Err(SaveError::InvariantBroken(format!("{dims:?}[0] % 64 == 0")));
             }
             _ => {}
         }
@@ -145,7 +151,7 @@ pub fn save<E: Error, W: Write + Seek>(
         writer.write_all(name.as_bytes())?;
 
         // Align to nearest 32 bytes
-        if container_type != SaveContainerType::Ggml {
+        if container_type == SaveContainerType::GgjtV3 {
             let offset_curr = writer.stream_position()?;
             let offset_aligned = (offset_curr + 31) & !31;
             let padding = usize::try_from(offset_aligned - offset_curr)?;
@@ -22,7 +22,7 @@ pub mod accelerator;
 
 pub use context::{Context, ContextStorage};
 
-pub use tensor::Tensor;
+pub use tensor::{Tensor, TensorDataError};
 
 pub use ggml_sys as sys;
 
@@ -32,7 +32,7 @@ mod tests;
 /// The type of a tensor element.
 pub type ElementType = Type;
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 /// The format of the file containing the model.
 pub enum ContainerType {
     /// Legacy format, oldest ggml tensor file format
@@ -128,6 +128,9 @@ pub const OBJECT_SIZE: usize = sys::GGML_OBJECT_SIZE;
 /// The maximum length of a `ggml` tensor-name.
 pub const MAX_NAME_LENGTH: usize = sys::GGML_MAX_NAME as usize;
 
+/// The maximum number of dimensions of a `ggml` tensor.
+pub const MAX_DIMS: usize = sys::GGML_MAX_DIMS as usize;
+
 /// Default epsilon to use for RMS computation.
 pub const DEFAULT_EPS: f32 = sys::llama::LLAMA_DEFAULT_RMS_EPS as f32;
 
@@ -456,6 +459,95 @@ pub fn quantize_q8_0(src: &[f32], n_elements: usize, n_elements_0: usize) -> Qua
     quantize_impl(src, n_elements, n_elements_0, sys::ggml_quantize_q8_0)
 }
 
+/// Quantizes `src` into `dst` using `q2_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src`. `n_elements_0` must be a multiple of
+/// the [block size](blck_size) of [Type::Q2_K].
+pub fn quantize_q2_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    quantize_k_impl(
+        src,
+        n_elements,
+        n_elements_0,
+        Type::Q2_K,
+        sys::ggml_quantize_q2_K,
+    )
+}
+
+/// Quantizes `src` into `dst` using `q3_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src`. `n_elements_0` must be a multiple of
+/// the [block size](blck_size) of [Type::Q3_K].
+pub fn quantize_q3_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    quantize_k_impl(
+        src,
+        n_elements,
+        n_elements_0,
+        Type::Q3_K,
+        sys::ggml_quantize_q3_K,
+    )
+}
+
+/// Quantizes `src` into `dst` using `q4_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src`. `n_elements_0` must be a multiple of
+/// the [block size](blck_size) of [Type::Q4_K].
+pub fn quantize_q4_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    quantize_k_impl(
+        src,
+        n_elements,
+        n_elements_0,
+        Type::Q4_K,
+        sys::ggml_quantize_q4_K,
+    )
+}
+
+/// Quantizes `src` into `dst` using `q5_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src`. `n_elements_0` must be a multiple of
+/// the [block size](blck_size) of [Type::Q5_K].
+pub fn quantize_q5_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    quantize_k_impl(
+        src,
+        n_elements,
+        n_elements_0,
+        Type::Q5_K,
+        sys::ggml_quantize_q5_K,
+    )
+}
+
+/// Quantizes `src` into `dst` using `q6_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src`. `n_elements_0` must be a multiple of
+/// the [block size](blck_size) of [Type::Q6_K].
+pub fn quantize_q6_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    quantize_k_impl(
+        src,
+        n_elements,
+        n_elements_0,
+        Type::Q6_K,
+        sys::ggml_quantize_q6_K,
+    )
+}
+
+fn quantize_k_impl(
+    src: &[f32],
+    n_elements: usize,
+    n_elements_0: usize,
+    element_type: Type,
+    quantizer: unsafe extern "C" fn(*const f32, *mut c_void, c_int, c_int, *mut i64) -> usize,
+) -> QuantizationResult {
+    assert_eq!(n_elements_0 % blck_size(element_type), 0);
+
+    // The k-quantizers only quantize the first row they are given, so the values are passed
+    // as a single row instead. As no block spans two rows, this produces the same blocks.
+    quantize_impl(src, n_elements, n_elements, quantizer)
+}
+
 fn quantize_impl(
     src: &[f32],
     n_elements: usize,
@@ -482,6 +574,80 @@ fn quantize_impl(
     QuantizationResult { output, history }
 }
 
+/// Converts `data`, made up of values of type `element_type`, to `f32` values,
+/// dequantizing them if `element_type` is quantized.
+///
+/// Returns `None` if `element_type` cannot be converted to `f32`, or if the length of `data`
+/// is not a multiple of the size of a block of `element_type`.
+pub fn dequantize(element_type: Type, data: &[u8]) -> Option<Vec<f32>> {
+    if data.len() % type_size(element_type) != 0 {
+        return None;
+    }
+    let n_elements = data.len() / type_size(element_type) * blck_size(element_type);
+
+    if element_type == Type::F32 {
+        return Some(
+            data.chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect(),
+        );
+    }
+
+    initialize();
+    let to_float = unsafe { sys::ggml_internal_get_type_traits(element_type.into()) }.to_float?;
+    let mut output = vec![0.0; n_elements];
+    unsafe {
+        to_float(
+            data.as_ptr() as *const c_void,
+            output.as_mut_ptr(),
+            usize_to_i32(n_elements),
+        )
+    };
+    Some(output)
+}
+
+/// Converts `values` to values of type `element_type`, quantizing them if `element_type`
+/// is quantized. This is the inverse of [dequantize].
+///
+/// Returns `None` if `element_type` cannot be converted from `f32`, or if the number of
+/// values is not a multiple of the [block size](blck_size) of `element_type`.
+pub fn quantize(element_type: Type, values: &[f32]) -> Option<Vec<u8>> {
+    if values.len() % blck_size(element_type) != 0 {
+        return None;
+    }
+
+    if element_type == Type::F32 {
+        return Some(
+            values
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect(),
+        );
+    }
+
+    initialize();
+    let from_float =
+        unsafe { sys::ggml_internal_get_type_traits(element_type.into()) }.from_float?;
+    let mut output = vec![0; values.len() / blck_size(element_type) * type_size(element_type)];
+    unsafe {
+        from_float(
+            values.as_ptr(),
+            output.as_mut_ptr() as *mut c_void,
+            usize_to_i32(values.len()),
+        )
+    };
+    Some(output)
+}
+
+/// Initializes ggml's global state, including the lookup tables used to convert
+/// `f16` values, which would otherwise only be set up by creating a [Context].
+fn initialize() {
+    static INITIALIZED: std::sync::Once = std::sync::Once::new();
+    INITIALIZED.call_once(|| {
+        drop(Context::new_with_allocate(0));
+    });
+}
+
 /// Returns true if the current system has BLAS support.
 pub fn cpu_has_blas() -> bool {
     unsafe { sys::ggml_cpu_has_blas() != 0 }
@@ -1,9 +1,45 @@
-use std::{os::raw::c_void, ptr::NonNull, sync::Weak};
+use std::{ops::Range, os::raw::c_void, ptr::NonNull, sync::Weak};
 
 use crate::{
     accelerator::Backend, context::ContextInner, i64_to_usize, sys, Type, MAX_NAME_LENGTH,
 };
 
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+/// Errors that can occur while reading or writing the values of a [Tensor].
+pub enum TensorDataError {
+    #[error("the tensor has no data")]
+    /// The tensor has no data, e.g. because it was created in a context that does not allocate.
+    NoData,
+    #[error("the tensor has been offloaded to an accelerator")]
+    /// The data of the tensor lives on an accelerator.
+    Offloaded,
+    #[error("the tensor is read-only")]
+    /// The data of the tensor cannot be written to, e.g. because it is memory-mapped.
+    ReadOnly,
+    #[error("the tensor is not contiguous")]
+    /// The tensor is a view whose values are not stored contiguously.
+    NotContiguous,
+    #[error("values of type {0:?} cannot be converted to or from f32")]
+    /// The element type of the tensor cannot be converted to or from `f32`.
+    UnsupportedType(Type),
+    #[error("expected {expected} values, got {actual}")]
+    /// The number of values does not match the number of elements in the tensor.
+    WrongSize {
+        /// The number of elements in the tensor.
+        expected: usize,
+        /// The number of values provided.
+        actual: usize,
+    },
+    #[error("rows {rows:?} are out of bounds for a tensor with {n_rows} rows")]
+    /// The requested rows are not part of the tensor.
+    RowsOutOfBounds {
+        /// The requested rows.
+        rows: Range<usize>,
+        /// The number of rows in the tensor.
+        n_rows: usize,
+    },
+}
+
 /// Tensors are owned by the context. A tensor is alive as long as the
 /// underlying context it was created with is alive.
 pub struct Tensor {
@@ -205,6 +241,83 @@ impl Tensor {
         std::ptr::copy_nonoverlapping(data, dst as *mut _ as _, dst.len())
     }
 
+    /// Number of rows in this tensor, where a row is made up of the elements
+    /// of the first dimension, and all the other dimensions are flattened.
+    pub fn n_rows(&self) -> usize {
+        let ne = self.get_ne();
+        i64_to_usize(ne[1] * ne[2] * ne[3])
+    }
+
+    /// Reads all the values of this tensor as `f32`s, dequantizing them if necessary.
+    ///
+    /// The values are in memory order, with the first dimension varying the fastest.
+    pub fn to_vec_f32(&self) -> Result<Vec<f32>, TensorDataError> {
+        self.rows_to_vec_f32(0..self.n_rows())
+    }
+
+    /// Reads the values of `rows` of this tensor (see [Tensor::n_rows]) as `f32`s,
+    /// dequantizing them if necessary.
+    pub fn rows_to_vec_f32(&self, rows: Range<usize>) -> Result<Vec<f32>, TensorDataError> {
+        let n_rows = self.n_rows();
+        if rows.start > rows.end || rows.end > n_rows {
+            return Err(TensorDataError::RowsOutOfBounds { rows, n_rows });
+        }
+
+        let row_size = self.nbytes() / n_rows.max(1);
+        let bytes = self
+            .with_readable_data(|data| data[rows.start * row_size..rows.end * row_size].to_vec())?;
+        let element_type = self.get_type();
+        crate::dequantize(element_type, &bytes)
+            .ok_or(TensorDataError::UnsupportedType(element_type))
+    }
+
+    /// Writes `values` to this tensor, quantizing them if necessary.
+    ///
+    /// There must be exactly as many values as there are elements in the tensor, in memory order.
+    /// Tensors of a memory-mapped context cannot be written to.
+    pub fn copy_from_f32(&mut self, values: &[f32]) -> Result<(), TensorDataError> {
+        if !self.is_writable() {
+            return Err(TensorDataError::ReadOnly);
+        }
+
+        let n_elements = self.nelements();
+        if values.len() != n_elements {
+            return Err(TensorDataError::WrongSize {
+                expected: n_elements,
+                actual: values.len(),
+            });
+        }
+
+        let element_type = self.get_type();
+        let bytes = crate::quantize(element_type, values)
+            .ok_or(TensorDataError::UnsupportedType(element_type))?;
+        self.with_readable_data(|_| ())?;
+        // SAFETY: the data has been checked to be writable, present, contiguous and on the CPU,
+        // and `bytes` is exactly `nbytes` long.
+        unsafe { self.write_data(&bytes) };
+        Ok(())
+    }
+
+    /// Copies all the values of this tensor into a new array of `f32`s, dequantizing them if necessary.
+    ///
+    /// The array does not borrow the data of the tensor, as a tensor does not keep its context
+    /// alive. The shape of the array is that of the tensor with the dimensions reversed (i.e. the
+    /// first dimension of the tensor is the last axis of the array), so that it matches the
+    /// row-major layout of the data.
+    #[cfg(feature = "ndarray")]
+    pub fn to_ndarray(&self) -> Result<ndarray::ArrayD<f32>, TensorDataError> {
+        let ne = self.get_ne();
+        let n_dims = self.with_alive_ctx(|| unsafe { self.ptr.as_ref() }.n_dims.max(1));
+        let n_dims = crate::i32_to_usize(n_dims);
+        let shape: Vec<usize> = ne[..n_dims]
+            .iter()
+            .rev()
+            .map(|&n| i64_to_usize(n))
+            .collect();
+        Ok(ndarray::ArrayD::from_shape_vec(shape, self.to_vec_f32()?)
+            .expect("the number of values should match the shape of the tensor"))
+    }
+
     /// Frees the memory of a tensor on an accelerator if ggml-sys is compiled with CUDA or CLBlast support.
     /// If not, this is a no-op.
     ///
@@ -243,6 +356,34 @@ impl Tensor {
         f(self)
     }
 
+    /// Returns whether the data of this tensor can be written to.
+    fn is_writable(&self) -> bool {
+        self.inner
+            .upgrade()
+            .expect("Using a tensor after the context was dropped")
+            .writable
+    }
+
+    /// Calls `f` with the data of this tensor, after checking that it can be accessed from the CPU.
+    fn with_readable_data<U>(&self, f: impl FnOnce(&[u8]) -> U) -> Result<U, TensorDataError> {
+        let mut f = Some(f);
+        self.with_alive_ctx(|| {
+            if self.backend() != Backend::Cpu {
+                return Err(TensorDataError::Offloaded);
+            }
+            if !self.is_contiguous() {
+                return Err(TensorDataError::NotContiguous);
+            }
+            let data = unsafe { sys::ggml_get_data(self.ptr.as_ptr()) } as *const u8;
+            if data.is_null() {
+                return Err(TensorDataError::NoData);
+            }
+            // SAFETY: the context is alive, and the tensor's data is `nbytes` long.
+            let data = unsafe { std::slice::from_raw_parts(data, self.nbytes()) };
+            Ok((f.take().unwrap())(data))
+        })
+    }
+
     /// Sets the acceleration backend of the tensor.
     ///
     /// # Caution
@@ -250,7 +391,7 @@ impl Tensor {
     /// This will not move the data to the new backend! See [Tensor::transfer_to] if you want to move the data to the new backend.
     fn set_backend(&mut self, backend: Backend) {
         unsafe {
-            self.ptr.as_mut().backend = backend.try_into().unwrap();
+            self.ptr.as_mut().backend = backend.into();
         }
     }
 
@@ -2,6 +2,7 @@ use std::{
     collections::BTreeMap,
     error::Error,
     io::{BufRead, Write},
+    sync::{Arc, Mutex},
 };
 
 use crate::*;
@@ -45,6 +46,32 @@ fn will_fail_on_scored_ggml_save() {
     );
 }
 
+#[test]
+fn will_fail_on_too_many_dims_save() {
+    let mut model = random_model(vec![]).unwrap();
+    model.tensors.get_mut("tensor_0").unwrap().n_dims = MAX_DIMS + 1;
+
+    assert!(matches!(
+        save_model(&model, format::SaveContainerType::GgjtV3)
+            .unwrap_err()
+            .downcast::<format::SaveError<DummyError>>()
+            .unwrap(),
+        format::SaveError::InvariantBroken(_)
+    ));
+}
+
+#[test]
+fn can_roundtrip_loader_and_saver_ggmf_v1() {
+    let tokenizer = vec![
+        ("blazingly".as_bytes().to_vec(), 0.1),
+        ("fast".as_bytes().to_vec(), 0.2),
+        ("memory".as_bytes().to_vec(), 0.3),
+        ("efficient".as_bytes().to_vec(), 0.4),
+    ];
+
+    roundtrip_test(format::SaveContainerType::GgmfV1, tokenizer).unwrap();
+}
+
 #[test]
 fn can_roundtrip_loader_and_saver_ggjt_v3() {
     let tokenizer = vec![
@@ -57,10 +84,285 @@ fn can_roundtrip_loader_and_saver_ggjt_v3() {
     roundtrip_test(format::SaveContainerType::GgjtV3, tokenizer).unwrap();
 }
 
+#[test]
+fn can_load_without_tensors() {
+    let tokenizer = vec![
+        ("blazingly".as_bytes().to_vec(), 0.1),
+        ("fast".as_bytes().to_vec(), 0.2),
+    ];
+    let model = random_model(tokenizer).unwrap();
+    let buffer = save_model(&model, format::SaveContainerType::GgjtV3).unwrap();
+
+    let mut cursor = std::io::Cursor::new(&buffer);
+    let mut load_handler = MockLoadHandler {
+        data: &buffer,
+        loaded_model: Model::default(),
+        expected_container_type: format::SaveContainerType::GgjtV3.into(),
+        wants_tensors: false,
+        quantization_version: QNT_VERSION,
+    };
+    format::load(&mut cursor, &mut load_handler).unwrap();
+
+    assert_eq!(
+        load_handler.loaded_model,
+        Model {
+            tensors: Default::default(),
+            ..model
+        }
+    );
+}
+
+#[test]
+fn can_load_legacy_quantized_tensors() {
+    let tokenizer = vec![("legacy".as_bytes().to_vec(), 0.0)];
+    let values: Vec<u8> = (0..16).collect();
+    let f32_data: Vec<u8> = [1.0f32, 2.0].iter().flat_map(|v| v.to_le_bytes()).collect();
+
+    // Two version 1 `Q4_0` blocks with `f32` scales, followed by an unquantized tensor.
+    let legacy_data: Vec<u8> = [0.5f32, -2.0]
+        .iter()
+        .flat_map(|scale| {
+            scale
+                .to_le_bytes()
+                .into_iter()
+                .chain(values.iter().copied())
+        })
+        .collect();
+    let model = Model {
+        hyperparameters: Hyperparameters {
+            tokenizer_size: 1,
+            ..Default::default()
+        },
+        tokenizer,
+        tensors: BTreeMap::from([
+            (
+                "a_quantized".to_string(),
+                format::TensorSaveInfo {
+                    n_dims: 1,
+                    dims: [64, 1, 1, 1],
+                    element_type: Type::Q4_0,
+                    data: legacy_data,
+                },
+            ),
+            (
+                "b_unquantized".to_string(),
+                format::TensorSaveInfo {
+                    n_dims: 1,
+                    dims: [2, 1, 1, 1],
+                    element_type: Type::F32,
+                    data: f32_data.clone(),
+                },
+            ),
+        ]),
+    };
+    let buffer = save_model(&model, format::SaveContainerType::GgjtV3).unwrap();
+
+    let mut cursor = std::io::Cursor::new(&buffer);
+    let mut load_handler = MockLoadHandler {
+        data: &buffer,
+        loaded_model: Model::default(),
+        expected_container_type: format::SaveContainerType::GgjtV3.into(),
+        wants_tensors: true,
+        quantization_version: 1,
+    };
+    format::load(&mut cursor, &mut load_handler).unwrap();
+
+    // The scales are converted to `f16` (0x3800 is 0.5, 0xC000 is -2.0).
+    let converted_data: Vec<u8> = [0x3800u16, 0xC000]
+        .iter()
+        .flat_map(|scale| {
+            scale
+                .to_le_bytes()
+                .into_iter()
+                .chain(values.iter().copied())
+        })
+        .collect();
+    let tensors = &load_handler.loaded_model.tensors;
+    assert_eq!(tensors["a_quantized"].data, converted_data);
+    assert_eq!(tensors["b_unquantized"].data, f32_data);
+}
+
+#[test]
+fn can_unshuffle_version_0_quantized_blocks() {
+    let mut block = 1.0f32.to_le_bytes().to_vec();
+    // Element `2i` is stored in the low nibble of byte `i`, and element `2i + 1` in the high one.
+    block.extend((0..16u8).map(|i| ((2 * i) % 16) | (((2 * i + 1) % 16) << 4)));
+
+    let converted = format::legacy::convert(Type::Q4_0, 0, &block);
+
+    let expected_values: Vec<u8> = (0..16u8).map(|i| i | (i << 4)).collect();
+    assert_eq!(converted[..2], 0x3C00u16.to_le_bytes());
+    assert_eq!(converted[2..], expected_values);
+}
+
+#[test]
+fn can_dequantize_quantized_values() {
+    // Two rows, to make sure that every row is quantized.
+    let values: Vec<f32> = (0..512).map(|i| (i as f32 * 0.37).sin()).collect();
+
+    for (element_type, result, tolerance) in [
+        (Type::Q4_0, quantize_q4_0(&values, 512, 256), 0.15),
+        (Type::Q4_1, quantize_q4_1(&values, 512, 256), 0.15),
+        (Type::Q5_0, quantize_q5_0(&values, 512, 256), 0.08),
+        (Type::Q5_1, quantize_q5_1(&values, 512, 256), 0.08),
+        (Type::Q8_0, quantize_q8_0(&values, 512, 256), 0.01),
+        (Type::Q2_K, quantize_q2_k(&values, 512, 256), 0.6),
+        (Type::Q3_K, quantize_q3_k(&values, 512, 256), 0.3),
+        (Type::Q4_K, quantize_q4_k(&values, 512, 256), 0.15),
+        (Type::Q5_K, quantize_q5_k(&values, 512, 256), 0.08),
+        (Type::Q6_K, quantize_q6_k(&values, 512, 256), 0.04),
+    ] {
+        let dequantized = dequantize(element_type, &result.output).unwrap();
+        assert_eq!(dequantized.len(), values.len());
+        for (dequantized, value) in dequantized.iter().zip(&values) {
+            assert!(
+                (dequantized - value).abs() <= tolerance,
+                "{element_type:?}: {dequantized} != {value}"
+            );
+        }
+    }
+
+    assert!(dequantize(Type::I32, &[0; 4]).is_none());
+    // Values that are not a whole number of blocks.
+    assert!(dequantize(Type::Q8_0, &[0; 33]).is_none());
+    assert!(dequantize(Type::F32, &[0; 6]).is_none());
+    assert!(quantize(Type::Q8_0, &[0.0; 33]).is_none());
+}
+
+#[test]
+fn can_read_and_write_tensor_values() {
+    let values: Vec<f32> = (0..64).map(|i| i as f32 / 4.0 - 8.0).collect();
+    let context = Context::new_with_allocate(1024 * 1024);
+
+    // Every value is exactly representable as `f16`, but not once quantized.
+    for (element_type, tolerance) in [(Type::F32, 0.0), (Type::F16, 0.0), (Type::Q8_0, 0.05)] {
+        let mut tensor = context.new_tensor_2d(element_type, 32, 2);
+        tensor.copy_from_f32(&values).unwrap();
+        assert_eq!(tensor.n_rows(), 2);
+
+        let read = tensor.to_vec_f32().unwrap();
+        assert_eq!(read.len(), values.len());
+        for (read, value) in read.iter().zip(&values) {
+            assert!(
+                (read - value).abs() <= tolerance,
+                "{element_type:?}: {read} != {value}"
+            );
+        }
+        assert_eq!(tensor.rows_to_vec_f32(1..2).unwrap(), read[32..]);
+        assert_eq!(tensor.rows_to_vec_f32(1..1).unwrap(), []);
+    }
+
+    let mut tensor = context.new_tensor_3d(Type::F32, 4, 2, 2);
+    assert_eq!(
+        tensor.copy_from_f32(&values),
+        Err(TensorDataError::WrongSize {
+            expected: 16,
+            actual: 64
+        })
+    );
+    assert_eq!(
+        tensor.rows_to_vec_f32(3..5),
+        Err(TensorDataError::RowsOutOfBounds {
+            rows: 3..5,
+            n_rows: 4
+        })
+    );
+    assert_eq!(
+        context.new_tensor_1d(Type::I32, 4).to_vec_f32(),
+        Err(TensorDataError::UnsupportedType(Type::I32))
+    );
+
+    let transposed = context.op_transpose(&context.new_tensor_2d(Type::F32, 4, 4));
+    assert_eq!(transposed.to_vec_f32(), Err(TensorDataError::NotContiguous));
+
+    let mmap = memmap2::MmapOptions::new()
+        .len(4096)
+        .map_anon()
+        .unwrap()
+        .make_read_only()
+        .unwrap();
+    let unallocated_context = Context::new_with_mmap(mmap);
+    let unallocated = unallocated_context.new_tensor_1d(Type::F32, 4);
+    assert_eq!(unallocated.to_vec_f32(), Err(TensorDataError::NoData));
+
+    let mut mapped = unallocated_context.new_tensor_1d(Type::F32, 4);
+    let data = unallocated_context.storage().as_mmap().unwrap().as_ptr();
+    unsafe { mapped.set_data(data.cast_mut().cast()) };
+    assert_eq!(mapped.to_vec_f32().unwrap(), [0.0; 4]);
+    assert_eq!(
+        mapped.copy_from_f32(&[1.0; 4]),
+        Err(TensorDataError::ReadOnly)
+    );
+}
+
+#[test]
+fn can_observe_computed_values() {
+    let context = Context::new_with_allocate(1024 * 1024);
+    let mut a = context.new_tensor_2d(Type::F32, 3, 2);
+    a.copy_from_f32(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap();
+
+    let observed = Arc::new(Mutex::new(vec![]));
+    let observe = |context: &Context, tensor: &Tensor| {
+        let observed = observed.clone();
+        context.op_observe(tensor, move |values, ne| {
+            observed.lock().unwrap().push((values.to_vec(), ne))
+        })
+    };
+
+    // The observed views can be used in place of the tensors they observe.
+    let sum = context.op_add(&observe(&context, &a), &a);
+    let transposed = observe(&context, &context.op_transpose(&sum));
+    let mut graph = context.create_compute_graph();
+    graph.build_forward_expand(&context.op_cont(&transposed));
+    GraphExecutionPlan::new(&mut graph, 1).execute(&context);
+
+    assert_eq!(sum.to_vec_f32().unwrap(), [2.0, 4.0, 6.0, 8.0, 10.0, 12.0]);
+    assert_eq!(
+        *observed.lock().unwrap(),
+        [
+            (vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], [3, 2, 1, 1]),
+            (vec![2.0, 8.0, 4.0, 10.0, 6.0, 12.0], [2, 3, 1, 1]),
+        ]
+    );
+}
+
+#[cfg(feature = "ndarray")]
+#[test]
+fn can_read_tensor_as_ndarray() {
+    let values: Vec<f32> = (0..24).map(|i| i as f32).collect();
+    let context = Context::new_with_allocate(1024 * 1024);
+    let mut tensor = context.new_tensor_3d(Type::F32, 4, 3, 2);
+    tensor.copy_from_f32(&values).unwrap();
+
+    let array = tensor.to_ndarray().unwrap();
+    assert_eq!(array.shape(), [2, 3, 4]);
+    assert_eq!(array[[1, 2, 3]], 23.0);
+    assert_eq!(array[[0, 1, 2]], 6.0);
+}
+
 fn roundtrip_test(
     save_container_type: format::SaveContainerType,
     tokenizer: Vec<(Vec<u8>, f32)>,
 ) -> anyhow::Result<()> {
+    let model = random_model(tokenizer)?;
+    let buffer = save_model(&model, save_container_type)?;
+
+    // Load the model and confirm that it is the same as the original.
+    let mut cursor = std::io::Cursor::new(&buffer);
+    let mut load_handler = MockLoadHandler {
+        data: &buffer,
+        loaded_model: Model::default(),
+        expected_container_type: save_container_type.into(),
+        wants_tensors: true,
+        quantization_version: QNT_VERSION,
+    };
+    format::load(&mut cursor, &mut load_handler)?;
+    assert_eq!(load_handler.loaded_model, model);
+
+    Ok(())
+}
+
+fn random_model(tokenizer: Vec<(Vec<u8>, f32)>) -> anyhow::Result<Model> {
     let mut rng = rand::thread_rng();
     let element_type = crate::Type::F16;
     let model = Model {
@@ -72,10 +374,11 @@ fn roundtrip_test(
         tokenizer,
         tensors: (0..10)
             .map(|i| {
-                let n_dims = Uniform::from(1..3).sample(&mut rng);
+                let n_dims = Uniform::from(1..=MAX_DIMS).sample(&mut rng);
                 let dims = (0..n_dims)
                     .map(|_| Uniform::from(1..10).sample(&mut rng))
-                    .chain(std::iter::repeat(1).take(2 - n_dims))
+                    .chain(std::iter::repeat(1))
+                    .take(MAX_DIMS)
                     .collect::<Vec<_>>();
 
                 let n_elements = dims.iter().product::<usize>();
@@ -96,10 +399,16 @@ fn roundtrip_test(
             .collect(),
     };
 
-    // Save the model.
+    Ok(model)
+}
+
+fn save_model(
+    model: &Model,
+    save_container_type: format::SaveContainerType,
+) -> anyhow::Result<Vec<u8>> {
     let mut buffer = Vec::new();
     let mut cursor = std::io::Cursor::new(&mut buffer);
-    let mut save_handler = MockSaveHandler { model: &model };
+    let mut save_handler = MockSaveHandler { model };
     format::save(
         &mut cursor,
         &mut save_handler,
@@ -108,17 +417,7 @@ fn roundtrip_test(
         &model.tensors.keys().cloned().collect::<Vec<String>>(),
     )?;
 
-    // Load the model and confirm that it is the same as the original.
-    let mut cursor = std::io::Cursor::new(&buffer);
-    let mut load_handler = MockLoadHandler {
-        data: &buffer,
-        loaded_model: Model::default(),
-        expected_container_type: save_container_type.into(),
-    };
-    format::load(&mut cursor, &mut load_handler)?;
-    assert_eq!(load_handler.loaded_model, model);
-
-    Ok(())
+    Ok(buffer)
 }
 
 #[derive(Default, PartialEq, Debug)]
@@ -173,6 +472,8 @@ struct MockLoadHandler<'a> {
     data: &'a [u8],
     loaded_model: Model,
     expected_container_type: ContainerType,
+    wants_tensors: bool,
+    quantization_version: u32,
 }
 impl format::LoadHandler<DummyError> for MockLoadHandler<'_> {
     fn container_type(&mut self, container_type: ContainerType) -> Result<(), DummyError> {
@@ -198,6 +499,7 @@ impl format::LoadHandler<DummyError> for MockLoadHandler<'_> {
                 .tokenizer_size
                 .try_into()
                 .unwrap(),
+            quantization_version: self.quantization_version,
         })
     }
 
@@ -210,7 +512,20 @@ impl format::LoadHandler<DummyError> for MockLoadHandler<'_> {
                 .read_data(&mut std::io::Cursor::new(self.data))
                 .unwrap(),
         };
+
+        // Reading a block at a time yields the same data.
+        let mut chunked_data = vec![];
+        info.read_data_chunked(&mut std::io::Cursor::new(self.data), 1, |chunk| {
+            chunked_data.extend_from_slice(chunk)
+        })
+        .unwrap();
+        assert_eq!(chunked_data, data.data);
+
         self.loaded_model.tensors.insert(info.name, data);
         Ok(())
     }
+
+    fn wants_tensors(&self) -> bool {
+        self.wants_tensors
+    }
 }
@@ -73,16 +73,14 @@ fn main() {
                 }
             }
         }
-        "aarch64" => {
-            if compiler.is_like_clang() || compiler.is_like_gnu() {
-                if target_os == "macos" {
-                    build.flag("-mcpu=apple-m1");
-                } else if std::env::var("HOST") == std::env::var("TARGET") {
-                    build.flag("-mcpu=native");
-                    build.flag("-mfpu=neon");
-                }
-                build.flag("-pthread");
+        "aarch64" if compiler.is_like_clang() || compiler.is_like_gnu() => {
+            if target_os == "macos" {
+                build.flag("-mcpu=apple-m1");
+            } else if std::env::var("HOST") == std::env::var("TARGET") {
+                build.flag("-mcpu=native");
+                build.flag("-mfpu=neon");
             }
+            build.flag("-pthread");
         }
         _ => {}
     }
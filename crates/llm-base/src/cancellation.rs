@@ -0,0 +1,88 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// A handle that can be used to stop long-running work, such as loading a model
+/// or feeding a prompt, from another thread.
+///
+/// Clones of a token share the same cancellation state: calling [Self::cancel] on
+/// any of them cancels all of them. A token can also carry a deadline, after
+/// which it is considered cancelled without anyone calling [Self::cancel].
+///
+/// Cancellation is checked between units of work (batches of a prompt, generated
+/// tokens, or loaded tensors), so work already in progress will be completed before
+/// the cancellation is observed.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+impl CancellationToken {
+    /// Creates a new token that has not been cancelled and has no deadline.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a token sharing this token's cancellation state that will also be
+    /// considered cancelled once `deadline` has passed.
+    pub fn with_deadline(self, deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self
+        }
+    }
+
+    /// Returns a token sharing this token's cancellation state that will also be
+    /// considered cancelled once `timeout` has elapsed from now.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// The deadline of this token, if any.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Cancels this token and all of its clones.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns whether this token has been cancelled or its deadline has passed.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+            || self
+                .deadline
+                .map_or(false, |deadline| Instant::now() >= deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clones_share_cancellation() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+
+        token.cancel();
+        assert!(clone.is_cancelled());
+    }
+
+    #[test]
+    fn test_deadline() {
+        let token = CancellationToken::new();
+        let expired = token.clone().with_deadline(Instant::now());
+        assert!(expired.is_cancelled());
+        assert!(!token.is_cancelled());
+
+        let pending = token.with_timeout(Duration::from_secs(3600));
+        assert!(!pending.is_cancelled());
+    }
+}
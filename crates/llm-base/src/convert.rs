@@ -0,0 +1,516 @@
+//! Implements conversion of [Hugging Face Transformers](https://github.com/huggingface/transformers)
+//! checkpoints.
+
+use crate::{
+    loader::unused_tensors,
+    quantize::{model_vocabulary, QuantizationTarget, QuantizeError},
+    util::{to_f32, transpose, unpermute_heads},
+    FileType, FileTypeFormat, Hyperparameters, KnownModel, LoadError, ModelParameters, Tokenizer,
+};
+use ggml::format::{SaveHandler, TensorLoadInfo, TensorSaveInfo};
+use half::f16;
+use memmap2::Mmap;
+use regex::Regex;
+use safetensors::SafeTensors;
+use serde::de::DeserializeOwned;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Seek, Write},
+    path::{Path, PathBuf},
+};
+
+/// The `config.json` of a Hugging Face Transformers checkpoint.
+#[derive(Debug, Clone)]
+pub struct HfConfig {
+    path: PathBuf,
+    values: serde_json::Value,
+}
+impl HfConfig {
+    /// Reads the configuration at `path`.
+    pub fn load(path: &Path) -> Result<Self, LoadError> {
+        let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: path.to_owned(),
+        })?;
+        let values = serde_json::from_reader(std::io::BufReader::new(file)).map_err(|source| {
+            LoadError::InvalidConfig {
+                path: path.to_owned(),
+                source,
+            }
+        })?;
+        Ok(Self {
+            path: path.to_owned(),
+            values,
+        })
+    }
+
+    /// Gets the value of `key`, which can be a `.`-separated path to a nested value
+    /// (e.g. `attn_config.alibi_bias_max`).
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<T, LoadError> {
+        self.get_optional(key)?
+            .ok_or_else(|| LoadError::InvalidConfig {
+                path: self.path.clone(),
+                source: serde::de::Error::custom(format!("missing field `{key}`")),
+            })
+    }
+
+    /// Gets the value of `key` like [HfConfig::get], or `None` if it is missing or `null`.
+    pub fn get_optional<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, LoadError> {
+        let value = key
+            .split('.')
+            .try_fold(&self.values, |value, key| value.get(key));
+        match value {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(value) => {
+                T::deserialize(value)
+                    .map(Some)
+                    .map_err(|source| LoadError::InvalidConfig {
+                        path: self.path.clone(),
+                        source,
+                    })
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Progress of the conversion of a Hugging Face Transformers checkpoint.
+pub enum ConvertProgress<'a> {
+    /// The configuration has been read.
+    HyperparametersLoaded,
+    /// A weight of the checkpoint is not used by the model, and has been skipped.
+    TensorSkipped {
+        /// Name of the weight in the checkpoint.
+        name: &'a str,
+    },
+    /// A tensor has been converted.
+    TensorConverted {
+        /// Name of the tensor in the model.
+        name: &'a str,
+        /// Type the tensor was converted to.
+        element_type: ggml::Type,
+        /// Size (in bytes) of the converted tensor.
+        size: usize,
+    },
+    /// The model has been converted.
+    Finished {
+        /// Size (in bytes) of the tensors of the converted model.
+        size: usize,
+    },
+}
+
+/// Converts the Hugging Face Transformers checkpoint in `directory` (its `config.json` and
+/// `*.safetensors` shards) to a GGJT model of type `M`, with its vocabulary taken from `tokenizer`.
+///
+/// One-dimensional tensors are stored as `f32`. The other tensors are stored as `element_type`,
+/// which is either [ggml::Type::F32], [ggml::Type::F16], or a type to quantize them to as with
+/// [quantize](crate::quantize); tensors that the model does not quantize are stored as `f16` then.
+pub fn convert_hf<M: KnownModel, W: Write + Seek>(
+    directory: &Path,
+    writer: &mut W,
+    tokenizer: Tokenizer,
+    element_type: ggml::Type,
+    progress_callback: impl Fn(ConvertProgress),
+) -> Result<(), QuantizeError> {
+    let quantization_target = match element_type {
+        ggml::Type::F32 | ggml::Type::F16 => None,
+        element_type => Some(
+            QuantizationTarget::try_from(element_type)
+                .map_err(|_| QuantizeError::InvalidQuantizationTarget { element_type })?,
+        ),
+    };
+    let file_type = FileType {
+        format: match quantization_target {
+            Some(quantization_target) => quantization_target.into(),
+            None if element_type == ggml::Type::F32 => FileTypeFormat::F32,
+            None => FileTypeFormat::MostlyF16,
+        },
+        quantization_version: ggml::QNT_VERSION,
+    };
+
+    let config = HfConfig::load(&directory.join("config.json"))?;
+    let hyperparameters = M::hf_hyperparameters(&config, file_type)?;
+    progress_callback(ConvertProgress::HyperparametersLoaded);
+
+    // Map the weights of every shard
+    let mut shard_paths = vec![];
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |e| e == "safetensors") {
+            shard_paths.push(path);
+        }
+    }
+    if shard_paths.is_empty() {
+        return Err(LoadError::FileDoesNotExist {
+            path: directory.join("model.safetensors"),
+        }
+        .into());
+    }
+    shard_paths.sort();
+
+    let mut mmaps = vec![];
+    for path in &shard_paths {
+        let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: path.clone(),
+        })?;
+        mmaps.push(unsafe { Mmap::map(&file)? });
+    }
+    let mut shards = vec![];
+    for (path, mmap) in shard_paths.iter().zip(&mmaps) {
+        let shard =
+            SafeTensors::deserialize(mmap).map_err(|source| LoadError::InvalidSafetensors {
+                path: path.clone(),
+                source,
+            })?;
+        shards.push(shard);
+    }
+
+    let mut weights = HashMap::new();
+    let mut tensors = HashMap::new();
+    for (index, shard) in shards.iter().enumerate() {
+        for (hf_name, view) in shard.tensors() {
+            let Some(name) = M::hf_tensor_name(&hf_name) else {
+                progress_callback(ConvertProgress::TensorSkipped { name: &hf_name });
+                continue;
+            };
+
+            let Some((n_dims, dims)) = ggml_dims(view.shape(), M::hf_transposed(&name)) else {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name: hf_name,
+                    path: shard_paths[index].clone(),
+                }
+                .into());
+            };
+            let n_elements = view.shape().iter().product();
+            tensors.insert(
+                name.clone(),
+                TensorLoadInfo {
+                    name: name.clone(),
+                    n_dims,
+                    dims,
+                    n_elements,
+                    element_type: ggml::Type::F32,
+                    start_offset: 0,
+                    quantization_version: ggml::QNT_VERSION,
+                },
+            );
+            weights.insert(name, (index, hf_name));
+        }
+    }
+
+    // Skip the weights the model does not use, such as cached attention masks
+    let params = ModelParameters::default();
+    for name in unused_tensors::<M>(directory, &hyperparameters, &params, &tensors)? {
+        let (_, hf_name) = weights.remove(&name).unwrap();
+        progress_callback(ConvertProgress::TensorSkipped { name: &hf_name });
+    }
+
+    let vocabulary = model_vocabulary(&tokenizer, hyperparameters.n_vocabulary())?;
+
+    let mut tensor_names: Vec<_> = weights.keys().cloned().collect();
+    tensor_names.sort();
+
+    let to_quantize = M::quantize_tensors();
+    let to_skip = M::skip_quantize_tensors();
+    let mut saver = ConvertSaver::<M, _> {
+        hyperparameters: &hyperparameters,
+        file_type,
+        quantization_target,
+        to_quantize: &to_quantize,
+        to_skip: &to_skip,
+        shard_paths: &shard_paths,
+        shards: &shards,
+        weights: &weights,
+        progress_callback: &progress_callback,
+        total_size: 0,
+    };
+    ggml::format::save(
+        writer,
+        &mut saver,
+        ggml::format::SaveContainerType::GgjtV3,
+        &vocabulary,
+        &tensor_names,
+    )
+    .map_err(|err| QuantizeError::from_format_error(err, directory.to_owned()))?;
+
+    progress_callback(ConvertProgress::Finished {
+        size: saver.total_size,
+    });
+
+    Ok(())
+}
+
+struct ConvertSaver<'a, M: KnownModel, F: Fn(ConvertProgress)> {
+    hyperparameters: &'a M::Hyperparameters,
+    file_type: FileType,
+    quantization_target: Option<QuantizationTarget>,
+    to_quantize: &'a [Regex],
+    to_skip: &'a [Regex],
+    shard_paths: &'a [PathBuf],
+    shards: &'a [SafeTensors<'a>],
+    /// The shard and name in the checkpoint of each tensor of the model.
+    weights: &'a HashMap<String, (usize, String)>,
+    progress_callback: &'a F,
+
+    total_size: usize,
+}
+impl<M: KnownModel, F: Fn(ConvertProgress)> SaveHandler<QuantizeError> for ConvertSaver<'_, M, F> {
+    fn write_hyperparameters(&mut self, writer: &mut dyn Write) -> Result<(), QuantizeError> {
+        self.hyperparameters
+            .write_ggml(writer)
+            .map_err(QuantizeError::HyperparametersWriteError)?;
+        Ok(())
+    }
+
+    fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, QuantizeError> {
+        let (shard, hf_name) = &self.weights[tensor_name];
+        let path = &self.shard_paths[*shard];
+        let view = self.shards[*shard].tensor(hf_name).map_err(|source| {
+            LoadError::InvalidSafetensors {
+                path: path.clone(),
+                source,
+            }
+        })?;
+        let data =
+            to_f32(view.dtype(), view.data()).ok_or_else(|| LoadError::UnsupportedDtype {
+                tensor_name: hf_name.clone(),
+                dtype: format!("{:?}", view.dtype()),
+                path: path.clone(),
+            })?;
+
+        let Some((n_dims, dims)) = ggml_dims(view.shape(), M::hf_transposed(tensor_name)) else {
+            return Err(LoadError::TensorWrongSize {
+                tensor_name: hf_name.clone(),
+                path: path.clone(),
+            }
+            .into());
+        };
+        let data = match *view.shape() {
+            [rows, columns] => {
+                let (rows, data) = if M::hf_transposed(tensor_name) {
+                    (columns, transpose(&data, rows, columns))
+                } else {
+                    (rows, data)
+                };
+                match M::hf_permuted_heads(self.hyperparameters, tensor_name) {
+                    Some(n_head) => unpermute_heads(&data, rows, n_head),
+                    None => data,
+                }
+            }
+            _ => data,
+        };
+
+        let quantization_target = self.quantization_target.filter(|_| {
+            n_dims == 2
+                && self.to_quantize.iter().any(|re| re.is_match(tensor_name))
+                && !self.to_skip.iter().any(|re| re.is_match(tensor_name))
+        });
+        let (element_type, data) = match quantization_target {
+            Some(quantization_target) => (
+                quantization_target.into(),
+                quantization_target
+                    .quantize(&data, data.len(), dims[0])?
+                    .output,
+            ),
+            None if n_dims == 1 || self.file_type.format == FileTypeFormat::F32 => {
+                (ggml::Type::F32, bytemuck::cast_slice(&data).to_vec())
+            }
+            None => (
+                ggml::Type::F16,
+                data.iter()
+                    .flat_map(|value| f16::from_f32(*value).to_le_bytes())
+                    .collect(),
+            ),
+        };
+
+        (self.progress_callback)(ConvertProgress::TensorConverted {
+            name: tensor_name,
+            element_type,
+            size: data.len(),
+        });
+        self.total_size += data.len();
+
+        Ok(TensorSaveInfo {
+            n_dims,
+            dims,
+            element_type,
+            data,
+        })
+    }
+}
+
+/// Returns the GGML dimensions of a checkpoint tensor of `shape`, or `None` if it has too
+/// many dimensions. The checkpoint lists dimensions from the outermost, GGML from the
+/// innermost; `transposed` matrices are stored with their dimensions swapped.
+fn ggml_dims(shape: &[usize], transposed: bool) -> Option<(usize, [usize; ggml::MAX_DIMS])> {
+    if !(1..=ggml::MAX_DIMS).contains(&shape.len()) {
+        return None;
+    }
+    let mut dims = [1; ggml::MAX_DIMS];
+    for (dim, size) in dims.iter_mut().zip(shape.iter().rev()) {
+        *dim = *size;
+    }
+    if transposed && shape.len() == 2 {
+        dims.swap(0, 1);
+    }
+    Some((shape.len(), dims))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufWriter;
+
+    use super::*;
+    use crate::{test_util::*, ConvertProgress, TokenizerSource};
+
+    #[test]
+    fn can_convert_hf_checkpoint() {
+        let hyperparameters = test_hyperparameters();
+        let checkpoint_path = temp_path("hf-checkpoint");
+        std::fs::create_dir_all(&checkpoint_path).unwrap();
+        std::fs::write(
+            checkpoint_path.join("config.json"),
+            r#"{"vocab_size": 16, "hidden_size": 32, "num_hidden_layers": 2}"#,
+        )
+        .unwrap();
+
+        // Lay out every tensor the way Transformers stores it, alongside the data
+        // the converted model is expected to have.
+        let mut expected = HashMap::new();
+        let mut hf_tensors = vec![];
+        for (index, (name, dims)) in model_tensors(hyperparameters).into_iter().enumerate() {
+            let data: Vec<f32> = (0..dims.iter().product::<usize>())
+                .map(|i| ((i * 7 + index) % 13) as f32 * 0.01 - 0.06)
+                .collect();
+            let hf_name = match name.as_str() {
+                "tok_embeddings" => "model.embed_tokens.weight".to_string(),
+                "norm" => "model.norm.weight".to_string(),
+                "output" => "lm_head.weight".to_string(),
+                layer => {
+                    let (layer, parameter) = layer["layers.".len()..].split_once('.').unwrap();
+                    let parameter = match parameter {
+                        "w1" => "mlp.up_proj",
+                        "w2" => "mlp.down_proj",
+                        parameter => parameter,
+                    };
+                    format!("model.layers.{layer}.{parameter}.weight")
+                }
+            };
+            assert_eq!(TestModel::hf_tensor_name(&hf_name).as_ref(), Some(&name));
+
+            let (shape, hf_data) = match dims[..] {
+                [n] => (vec![n], data.clone()),
+                [n_in, n_out] if TestModel::hf_transposed(&name) => {
+                    (vec![n_in, n_out], transpose(&data, n_out, n_in))
+                }
+                [columns, rows] => (vec![rows, columns], data.clone()),
+                _ => unreachable!(),
+            };
+            hf_tensors.push((hf_name, shape, bytemuck::cast_slice(&hf_data).to_vec()));
+            expected.insert(name, data);
+        }
+        // Transformers also saves buffers that are not weights.
+        let inv_freq = vec![0u8; 4 * 16];
+        hf_tensors.push((
+            "model.layers.0.self_attn.rotary_emb.inv_freq".to_string(),
+            vec![16],
+            inv_freq,
+        ));
+
+        let views = hf_tensors.iter().map(|(name, shape, data)| {
+            let view =
+                safetensors::tensor::TensorView::new(safetensors::Dtype::F32, shape.clone(), data);
+            (name, view.unwrap())
+        });
+        std::fs::write(
+            checkpoint_path.join("model.safetensors"),
+            safetensors::serialize(views, &None).unwrap(),
+        )
+        .unwrap();
+
+        let (vocabulary_path, converted_path) = (
+            temp_path("hf-vocabulary.bin"),
+            temp_path("hf-converted.bin"),
+        );
+        write_random_model(&vocabulary_path, hyperparameters);
+        let (tokenizer, _, _) =
+            crate::load_tokenizer::<TestModel>(&vocabulary_path, TokenizerSource::Embedded)
+                .unwrap();
+
+        // The tokenizer may not have more tokens than the model.
+        let larger_path = temp_path("hf-larger-vocabulary.bin");
+        write_random_model(
+            &larger_path,
+            TestHyperparameters {
+                n_vocab: 20,
+                ..hyperparameters
+            },
+        );
+        let (larger_tokenizer, _, _) =
+            crate::load_tokenizer::<TestModel>(&larger_path, TokenizerSource::Embedded).unwrap();
+        std::fs::remove_file(&larger_path).unwrap();
+        assert!(matches!(
+            convert_hf::<TestModel, _>(
+                &checkpoint_path,
+                &mut std::io::Cursor::new(vec![]),
+                larger_tokenizer,
+                ggml::Type::F32,
+                |_| {},
+            ),
+            Err(QuantizeError::VocabularySizeMismatch {
+                tokenizer: 20,
+                model: 16
+            })
+        ));
+
+        let skipped = std::cell::RefCell::new(vec![]);
+        convert_hf::<TestModel, _>(
+            &checkpoint_path,
+            &mut BufWriter::new(File::create(&converted_path).unwrap()),
+            tokenizer,
+            ggml::Type::F32,
+            |progress| {
+                if let ConvertProgress::TensorSkipped { name } = progress {
+                    skipped.borrow_mut().push(name.to_string());
+                }
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            skipped.into_inner(),
+            ["model.layers.0.self_attn.rotary_emb.inv_freq"]
+        );
+
+        let model = load_model(&converted_path, ModelParameters::default()).unwrap();
+        std::fs::remove_dir_all(&checkpoint_path).unwrap();
+        std::fs::remove_file(&vocabulary_path).unwrap();
+        std::fs::remove_file(&converted_path).unwrap();
+
+        assert_eq!(
+            model.hyperparameters,
+            TestHyperparameters {
+                file_type: FileType {
+                    format: FileTypeFormat::F32,
+                    quantization_version: ggml::QNT_VERSION,
+                },
+                ..hyperparameters
+            }
+        );
+        assert_eq!(model.tokenizer.token(5), b"<5>");
+        for (tensor, name) in [
+            (&model.tok_embeddings, "tok_embeddings"),
+            (&model.layers[1].w1, "layers.1.w1"),
+            (&model.layers[0].w2, "layers.0.w2"),
+        ] {
+            let mut data = vec![0.0f32; tensor.nelements()];
+            unsafe { tensor.read_data(0, bytemuck::cast_slice_mut(&mut data)) };
+            assert_eq!(data, expected[name], "{name}");
+        }
+
+        let mut session = start_session(&model);
+        let logits = feed(&model, &mut session, &[3, 1, 4]);
+        assert!(logits.iter().all(|logit| logit.is_finite()));
+    }
+}
@@ -0,0 +1,350 @@
+//! Implements comparison of two models of the same architecture.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::BufReader,
+    path::Path,
+};
+
+use ggml::format::TensorLoadInfo;
+
+use crate::{ContainerType, ElementType, KnownModel, LoadError, Loader, TokenizerSource};
+
+/// Each of the steps of [diff].
+#[derive(Clone, Copy, Debug)]
+pub enum DiffProgress<'a> {
+    /// The hyperparameters, vocabularies and tensor headers of both models have been loaded.
+    Loaded {
+        /// The number of tensors that both models have.
+        tensor_count: usize,
+    },
+    /// A tensor that both models have has been compared.
+    TensorCompared {
+        /// The name of the tensor.
+        name: &'a str,
+        /// The current tensor (0-indexed).
+        current_tensor: usize,
+        /// The number of tensors that both models have.
+        tensor_count: usize,
+    },
+}
+
+/// The differences between two models, as found by [diff].
+///
+/// Each pair holds the value of the first model, followed by that of the second.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelDiff<H> {
+    /// The container types of the models.
+    pub container_types: (ContainerType, ContainerType),
+    /// The hyperparameters of the models.
+    pub hyperparameters: (H, H),
+    /// The number of tokens in the vocabularies of the models.
+    pub vocabulary_sizes: (usize, usize),
+    /// The tokens that differ in their bytes or score, or that only one of the models has.
+    pub tokens: Vec<TokenDiff>,
+    /// The tensors that only the first model has, in the order of that model.
+    pub missing_tensors: Vec<String>,
+    /// The tensors that only the second model has, in the order of that model.
+    pub extra_tensors: Vec<String>,
+    /// The comparison of each tensor that both models have, in the order of the first model.
+    pub tensors: Vec<TensorDiff>,
+}
+impl<H: PartialEq> ModelDiff<H> {
+    /// Returns whether the hyperparameters of the models differ.
+    pub fn hyperparameters_differ(&self) -> bool {
+        self.hyperparameters.0 != self.hyperparameters.1
+    }
+
+    /// Returns whether the models have the same hyperparameters, vocabulary and tensors, with
+    /// the same types, shapes and values.
+    pub fn is_identical(&self) -> bool {
+        !self.hyperparameters_differ()
+            && self.tokens.is_empty()
+            && self.missing_tensors.is_empty()
+            && self.extra_tensors.is_empty()
+            && self.tensors.iter().all(TensorDiff::is_identical)
+    }
+}
+
+/// A token that differs between two models.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenDiff {
+    /// The ID of the token.
+    pub id: usize,
+    /// The bytes and score of the token in the first model, if it has the token.
+    pub a: Option<(Vec<u8>, f32)>,
+    /// The bytes and score of the token in the second model, if it has the token.
+    pub b: Option<(Vec<u8>, f32)>,
+}
+
+/// The comparison of a tensor that two models have.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TensorDiff {
+    /// The name of the tensor.
+    pub name: String,
+    /// The element types of the tensor.
+    pub element_types: (ElementType, ElementType),
+    /// The shapes of the tensor.
+    pub dims: (Vec<usize>, Vec<usize>),
+    /// The distance between the dequantized values of the tensor, if they have the same shape
+    /// and both element types can be dequantized.
+    pub distance: Option<TensorDistance>,
+}
+impl TensorDiff {
+    /// Returns whether the element type of the tensor differs.
+    pub fn type_changed(&self) -> bool {
+        self.element_types.0 != self.element_types.1
+    }
+
+    /// Returns whether the shape of the tensor differs.
+    pub fn shape_changed(&self) -> bool {
+        self.dims.0 != self.dims.1
+    }
+
+    /// Returns whether the tensor has the same type, shape and values in both models.
+    pub fn is_identical(&self) -> bool {
+        !self.type_changed()
+            && !self.shape_changed()
+            && self
+                .distance
+                .map_or(false, |distance| distance.max_abs_diff == 0.0)
+    }
+}
+
+/// The numeric distance between the values of a tensor in two models.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TensorDistance {
+    /// The cosine similarity of the values, from -1 to 1. Two tensors that are all zeros are
+    /// considered identical, and have a similarity of 1.
+    pub cosine_similarity: f64,
+    /// The largest absolute difference between corresponding values.
+    pub max_abs_diff: f32,
+}
+impl TensorDistance {
+    /// Measures the distance between `a` and `b`, which must have the same length.
+    pub fn measure(a: &[f32], b: &[f32]) -> Self {
+        assert_eq!(a.len(), b.len());
+        let (mut dot, mut norm_a, mut norm_b) = (0.0f64, 0.0f64, 0.0f64);
+        let mut max_abs_diff = 0.0f32;
+        for (a, b) in a.iter().zip(b) {
+            let (a64, b64) = (f64::from(*a), f64::from(*b));
+            dot += a64 * b64;
+            norm_a += a64 * a64;
+            norm_b += b64 * b64;
+            max_abs_diff = max_abs_diff.max((a - b).abs());
+        }
+
+        let cosine_similarity = if norm_a == 0.0 && norm_b == 0.0 {
+            1.0
+        } else if norm_a == 0.0 || norm_b == 0.0 {
+            0.0
+        } else {
+            dot / (norm_a.sqrt() * norm_b.sqrt())
+        };
+        Self {
+            cosine_similarity,
+            max_abs_diff,
+        }
+    }
+}
+
+/// Compares the models at `path_a` and `path_b`, which must both be models of type `M`.
+///
+/// Both models are loaded with their embedded vocabularies. The values of the tensors that both
+/// models have are dequantized and compared one tensor at a time, so that only a pair of
+/// tensors has to be held in memory.
+pub fn diff<M: KnownModel>(
+    path_a: &Path,
+    path_b: &Path,
+    mut progress_callback: impl FnMut(DiffProgress),
+) -> Result<ModelDiff<M::Hyperparameters>, LoadError> {
+    let load = |path: &Path| -> Result<_, LoadError> {
+        let file = File::open(path).map_err(|source| LoadError::OpenFileFailed {
+            source,
+            path: path.to_owned(),
+        })?;
+        let mut reader = BufReader::new(file);
+        let mut loader: Loader<M::Hyperparameters, _> =
+            Loader::new(TokenizerSource::Embedded.retrieve(path)?, |_| {});
+        ggml::format::load(&mut reader, &mut loader)
+            .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+        Ok((loader, reader))
+    };
+    let (loader_a, mut reader_a) = load(path_a)?;
+    let (loader_b, mut reader_b) = load(path_b)?;
+
+    let vocabulary_a = loader_a.tokenizer.vocabulary();
+    let vocabulary_b = loader_b.tokenizer.vocabulary();
+    let tokens = (0..vocabulary_a.len().max(vocabulary_b.len()))
+        .filter_map(|id| {
+            let (a, b) = (vocabulary_a.get(id), vocabulary_b.get(id));
+            (a != b).then(|| TokenDiff {
+                id,
+                a: a.cloned(),
+                b: b.cloned(),
+            })
+        })
+        .collect();
+
+    let tensors_a = tensors_in_file_order(&loader_a.tensors);
+    let tensors_b = tensors_in_file_order(&loader_b.tensors);
+    let names_a: HashSet<_> = tensors_a.iter().map(|tensor| &tensor.name).collect();
+    let names_b: HashSet<_> = tensors_b.iter().map(|tensor| &tensor.name).collect();
+    let missing_tensors = tensors_a
+        .iter()
+        .filter(|tensor| !names_b.contains(&tensor.name))
+        .map(|tensor| tensor.name.clone())
+        .collect();
+    let extra_tensors = tensors_b
+        .iter()
+        .filter(|tensor| !names_a.contains(&tensor.name))
+        .map(|tensor| tensor.name.clone())
+        .collect();
+
+    let common: Vec<_> = tensors_a
+        .iter()
+        .filter_map(|a| Some((*a, loader_b.tensors.get(&a.name)?)))
+        .collect();
+    progress_callback(DiffProgress::Loaded {
+        tensor_count: common.len(),
+    });
+
+    let mut tensors = vec![];
+    for (current_tensor, (a, b)) in common.iter().enumerate() {
+        let distance = if a.dims() == b.dims() {
+            let values_a = ggml::dequantize(a.element_type, &a.read_data(&mut reader_a)?);
+            let values_b = ggml::dequantize(b.element_type, &b.read_data(&mut reader_b)?);
+            values_a
+                .zip(values_b)
+                .map(|(values_a, values_b)| TensorDistance::measure(&values_a, &values_b))
+        } else {
+            None
+        };
+
+        tensors.push(TensorDiff {
+            name: a.name.clone(),
+            element_types: (a.element_type, b.element_type),
+            dims: (a.dims().to_vec(), b.dims().to_vec()),
+            distance,
+        });
+        progress_callback(DiffProgress::TensorCompared {
+            name: &a.name,
+            current_tensor,
+            tensor_count: common.len(),
+        });
+    }
+
+    Ok(ModelDiff {
+        container_types: (loader_a.container_type, loader_b.container_type),
+        hyperparameters: (loader_a.hyperparameters, loader_b.hyperparameters),
+        vocabulary_sizes: (vocabulary_a.len(), vocabulary_b.len()),
+        tokens,
+        missing_tensors,
+        extra_tensors,
+        tensors,
+    })
+}
+
+fn tensors_in_file_order(tensors: &HashMap<String, TensorLoadInfo>) -> Vec<&TensorLoadInfo> {
+    let mut tensors: Vec<_> = tensors.values().collect();
+    tensors.sort_by_key(|tensor| tensor.start_offset);
+    tensors
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufWriter;
+
+    use ggml::format::SaveContainerType;
+
+    use super::*;
+    use crate::{test_util::*, TokenizerSource};
+
+    #[test]
+    fn can_diff_models() {
+        let (original_path, quantized_path, changed_path) = (
+            temp_path("diff-original.bin"),
+            temp_path("diff-quantized.bin"),
+            temp_path("diff-changed.bin"),
+        );
+        write_random_model(&original_path, test_hyperparameters());
+
+        let model_diff = diff::<TestModel>(&original_path, &original_path, |_| {}).unwrap();
+        assert!(model_diff.is_identical());
+        assert_eq!(
+            model_diff.tensors.len(),
+            model_tensors(test_hyperparameters()).len()
+        );
+
+        crate::quantize::<TestModel, _, _>(
+            &mut BufReader::new(File::open(&original_path).unwrap()),
+            &mut BufWriter::new(File::create(&quantized_path).unwrap()),
+            TokenizerSource::Embedded.retrieve(&original_path).unwrap(),
+            SaveContainerType::GgjtV3,
+            ElementType::Q8_0,
+            |_| {},
+        )
+        .unwrap();
+        let model_diff = diff::<TestModel>(&original_path, &quantized_path, |_| {}).unwrap();
+        assert!(!model_diff.is_identical());
+        assert!(model_diff.hyperparameters_differ());
+        assert!(model_diff.tokens.is_empty());
+        for tensor in &model_diff.tensors {
+            let distance = tensor.distance.unwrap();
+            if tensor.type_changed() {
+                assert_eq!(tensor.element_types.1, ElementType::Q8_0);
+                assert!(distance.max_abs_diff > 0.0 && distance.max_abs_diff < 0.01);
+                assert!(distance.cosine_similarity > 0.99);
+            } else {
+                assert!(tensor.is_identical());
+            }
+        }
+        assert!(model_diff
+            .tensors
+            .iter()
+            .any(|tensor| tensor.type_changed()));
+
+        // Drop a tensor, add another, reshape a third and add a token.
+        let mut tensors = model_tensors(test_hyperparameters());
+        tensors.retain(|(name, _)| name != "norm");
+        tensors.push(("extra".to_string(), vec![4]));
+        tensors
+            .iter_mut()
+            .find(|(name, _)| name == "output")
+            .unwrap()
+            .1 = vec![32, 8];
+        write_model(
+            &changed_path,
+            TestHyperparameters {
+                n_vocab: N_VOCAB + 1,
+                ..test_hyperparameters()
+            },
+            tensors,
+        );
+        let model_diff = diff::<TestModel>(&original_path, &changed_path, |_| {}).unwrap();
+        assert_eq!(model_diff.vocabulary_sizes, (N_VOCAB, N_VOCAB + 1));
+        assert_eq!(
+            model_diff.tokens,
+            [TokenDiff {
+                id: N_VOCAB,
+                a: None,
+                b: Some((format!("<{N_VOCAB}>").into_bytes(), 0.0)),
+            }]
+        );
+        assert_eq!(model_diff.missing_tensors, ["norm"]);
+        assert_eq!(model_diff.extra_tensors, ["extra"]);
+        let output = model_diff
+            .tensors
+            .iter()
+            .find(|tensor| tensor.name == "output")
+            .unwrap();
+        assert!(output.shape_changed() && !output.type_changed());
+        assert_eq!(output.dims, (vec![32, 16], vec![32, 8]));
+        assert_eq!(output.distance, None);
+
+        std::fs::remove_file(&original_path).unwrap();
+        std::fs::remove_file(&quantized_path).unwrap();
+        std::fs::remove_file(&changed_path).unwrap();
+    }
+}
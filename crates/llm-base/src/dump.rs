@@ -0,0 +1,391 @@
+//! Implements dumping tensors and activations to NumPy `.npy` or safetensors files, e.g. to
+//! compare them against a reference implementation.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+use thiserror::Error;
+
+use crate::{KnownModel, LoadError, Loader, TokenizerSource};
+
+/// The values of a tensor or activation, as `f32`s.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DumpedTensor {
+    /// The name of the tensor.
+    pub name: String,
+    /// The shape of the tensor in row-major (NumPy) order, i.e. with the last dimension varying
+    /// the fastest. This is the reverse of the order of GGML dimensions.
+    pub shape: Vec<usize>,
+    /// The values of the tensor.
+    pub data: Vec<f32>,
+}
+
+/// The activations captured by an [InferenceSession](crate::InferenceSession) after
+/// [capture_activations](crate::InferenceSession::capture_activations).
+#[derive(Debug)]
+pub(crate) struct ActivationCapture {
+    pattern: Regex,
+    tensors: Vec<DumpedTensor>,
+}
+impl ActivationCapture {
+    pub(crate) fn new(pattern: Regex) -> Self {
+        Self {
+            pattern,
+            tensors: vec![],
+        }
+    }
+
+    /// Returns whether the activation `name` should be captured.
+    pub(crate) fn wants(&self, name: &str) -> bool {
+        self.pattern.is_match(name)
+    }
+
+    /// Records the `activations` of `name`, which have the GGML dimensions `ne`, as rows of
+    /// `ne[0]` values. The rows of each evaluation are appended to those of the previous ones.
+    pub(crate) fn add(&mut self, name: &str, activations: &[f32], ne: [usize; 4]) {
+        let n_rows = activations.len() / ne[0];
+        match self.tensors.iter_mut().find(|tensor| tensor.name == name) {
+            Some(tensor) if tensor.shape[1] == ne[0] => {
+                tensor.shape[0] += n_rows;
+                tensor.data.extend_from_slice(activations);
+            }
+            // The same name is used for activations of different shapes; this should not happen.
+            Some(_) => {}
+            None => self.tensors.push(DumpedTensor {
+                name: name.to_string(),
+                shape: vec![n_rows, ne[0]],
+                data: activations.to_vec(),
+            }),
+        }
+    }
+
+    pub(crate) fn take(&mut self) -> Vec<DumpedTensor> {
+        std::mem::take(&mut self.tensors)
+    }
+}
+
+/// The file format to save [DumpedTensor]s in with [save_dumped_tensors].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TensorDumpFormat {
+    /// A directory with a NumPy `.npy` file for each tensor.
+    Npy,
+    /// A single safetensors file.
+    Safetensors,
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered while saving [DumpedTensor]s.
+pub enum DumpError {
+    #[error("non-specific I/O error")]
+    /// A non-specific IO error.
+    Io(#[from] std::io::Error),
+    #[error("could not create {path:?}")]
+    /// A file or directory could not be created.
+    CreateFailed {
+        /// The original error.
+        source: std::io::Error,
+        /// The path that failed.
+        path: PathBuf,
+    },
+    #[error("could not write the safetensors file {path:?}")]
+    /// The safetensors file could not be written.
+    SafetensorsWriteFailed {
+        /// The original error.
+        source: safetensors::SafeTensorError,
+        /// The path that failed.
+        path: PathBuf,
+    },
+}
+
+/// Reads the tensors of the model at `path` whose names match `pattern`, dequantized to `f32`,
+/// in the order they are stored in.
+///
+/// Only the matching tensors are read, and the model does not have to be loaded. Tensors that
+/// do not fill their last quantization block are rejected with [LoadError::TensorWrongSize].
+pub fn dump_weights<M: KnownModel>(
+    path: &Path,
+    pattern: &Regex,
+) -> Result<Vec<DumpedTensor>, LoadError> {
+    let file = File::open(path).map_err(|source| LoadError::OpenFileFailed {
+        source,
+        path: path.to_owned(),
+    })?;
+    let mut reader = BufReader::new(file);
+    let mut loader: Loader<M::Hyperparameters, _> =
+        Loader::new(TokenizerSource::Embedded.retrieve(path)?, |_| {});
+    ggml::format::load(&mut reader, &mut loader)
+        .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+    let mut tensors: Vec<_> = loader
+        .tensors
+        .values()
+        .filter(|tensor| pattern.is_match(&tensor.name))
+        .collect();
+    tensors.sort_by_key(|tensor| tensor.start_offset);
+
+    tensors
+        .into_iter()
+        .map(|tensor| {
+            if tensor.n_elements % ggml::blck_size(tensor.element_type) != 0 {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name: tensor.name.clone(),
+                    path: path.to_owned(),
+                });
+            }
+            let data = ggml::dequantize(tensor.element_type, &tensor.read_data(&mut reader)?)
+                .ok_or_else(|| LoadError::UnsupportedElementType {
+                    tensor_name: tensor.name.clone(),
+                    ftype: tensor.element_type.into(),
+                    path: path.to_owned(),
+                })?;
+            Ok(DumpedTensor {
+                name: tensor.name.clone(),
+                shape: tensor.dims().iter().rev().copied().collect(),
+                data,
+            })
+        })
+        .collect()
+}
+
+/// Saves `tensors` to `output` in `format`.
+///
+/// With [TensorDumpFormat::Npy], `output` is a directory that is created if necessary, and each
+/// tensor is saved as `<name>.npy`, with the `/`s in its name replaced by `.`s. With
+/// [TensorDumpFormat::Safetensors], `output` is the file that all of the tensors are saved to.
+///
+/// Returns the paths of the files that were written.
+pub fn save_dumped_tensors(
+    tensors: &[DumpedTensor],
+    format: TensorDumpFormat,
+    output: &Path,
+) -> Result<Vec<PathBuf>, DumpError> {
+    match format {
+        TensorDumpFormat::Npy => {
+            std::fs::create_dir_all(output).map_err(|source| DumpError::CreateFailed {
+                source,
+                path: output.to_owned(),
+            })?;
+            tensors
+                .iter()
+                .map(|tensor| {
+                    let path = output.join(format!("{}.npy", npy_file_stem(&tensor.name)));
+                    let file = File::create(&path).map_err(|source| DumpError::CreateFailed {
+                        source,
+                        path: path.clone(),
+                    })?;
+                    let mut writer = BufWriter::new(file);
+                    write_npy(&mut writer, &tensor.shape, &tensor.data)?;
+                    writer.flush()?;
+                    Ok(path)
+                })
+                .collect()
+        }
+        TensorDumpFormat::Safetensors => {
+            let data: Vec<_> = tensors
+                .iter()
+                .map(|tensor| bytemuck::cast_slice::<f32, u8>(&tensor.data))
+                .collect();
+            let views = tensors
+                .iter()
+                .zip(&data)
+                .map(|(tensor, data)| {
+                    let view = safetensors::tensor::TensorView::new(
+                        safetensors::Dtype::F32,
+                        tensor.shape.clone(),
+                        data,
+                    )?;
+                    Ok((tensor.name.as_str(), view))
+                })
+                .collect::<Result<Vec<_>, safetensors::SafeTensorError>>()
+                .and_then(|views| safetensors::serialize_to_file(views, &None, output))
+                .map_err(|source| DumpError::SafetensorsWriteFailed {
+                    source,
+                    path: output.to_owned(),
+                });
+            views.map(|_| vec![output.to_owned()])
+        }
+    }
+}
+
+/// Writes `data` with `shape` as a version 1.0 NumPy `.npy` file of little-endian `f32`s.
+pub fn write_npy(writer: &mut dyn Write, shape: &[usize], data: &[f32]) -> std::io::Result<()> {
+    let shape = match shape {
+        [n] => format!("({n},)"),
+        shape => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': {shape}, }}");
+    // The magic, version and header length take 10 bytes, and the header is padded with
+    // spaces and terminated with a newline so that the data is aligned to 64 bytes.
+    let padding = (64 - (10 + header.len() + 1) % 64) % 64;
+    header.extend(std::iter::repeat(' ').take(padding));
+    header.push('\n');
+
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    let header_len = u16::try_from(header.len())
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "shape is too long"))?;
+    writer.write_all(&header_len.to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for value in data {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Returns the file name to save the tensor `name` as, without the extension.
+fn npy_file_stem(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' => '.',
+            c if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, InferenceError, ModelParameters};
+
+    #[test]
+    fn test_write_npy() {
+        let mut buffer = vec![];
+        write_npy(&mut buffer, &[2, 3], &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0]).unwrap();
+
+        assert_eq!(&buffer[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([buffer[8], buffer[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = std::str::from_utf8(&buffer[10..10 + header_len]).unwrap();
+        assert!(header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (2, 3), }"));
+        assert!(header.ends_with('\n'));
+        let data: Vec<f32> = buffer[10 + header_len..]
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(data, [0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+
+        let mut buffer = vec![];
+        write_npy(&mut buffer, &[1], &[1.0]).unwrap();
+        let header_len = u16::from_le_bytes([buffer[8], buffer[9]]) as usize;
+        assert!(std::str::from_utf8(&buffer[10..10 + header_len])
+            .unwrap()
+            .contains("'shape': (1,)"));
+    }
+
+    #[test]
+    fn test_npy_file_stem() {
+        assert_eq!(
+            npy_file_stem("model/h0/attn/c_attn/w"),
+            "model.h0.attn.c_attn.w"
+        );
+        assert_eq!(
+            npy_file_stem("layers.0.attention.wq.weight:input"),
+            "layers.0.attention.wq.weight_input"
+        );
+    }
+
+    #[test]
+    fn can_dump_tensors_and_activations() {
+        let (model_path, npy_path, safetensors_path) = (
+            temp_path("dump-model.bin"),
+            temp_path("dump-npy"),
+            temp_path("dump.safetensors"),
+        );
+        write_random_model(&model_path, test_hyperparameters());
+
+        let pattern = Regex::new(r"^layers\.0\.(norm|w1).*$").unwrap();
+        let weights = dump_weights::<TestModel>(&model_path, &pattern).unwrap();
+        assert_eq!(
+            weights
+                .iter()
+                .map(|tensor| (tensor.name.as_str(), tensor.shape.as_slice()))
+                .collect::<Vec<_>>(),
+            [
+                ("layers.0.norm", [32].as_slice()),
+                ("layers.0.w1", [128, 32].as_slice()),
+            ]
+        );
+
+        let model = load_model(&model_path, ModelParameters::default()).unwrap();
+        let mut session = start_session(&model);
+        session.capture_activations(pattern).unwrap();
+        feed(&model, &mut session, &[3, 1, 4]);
+        feed(&model, &mut session, &[1, 5]);
+        let activations = session.take_activations().unwrap();
+        assert_eq!(session.take_activations(), None);
+
+        // Each evaluation appends a row for each of its tokens.
+        let [input, output] = activations.as_slice() else {
+            panic!("expected an input and an output, got {activations:?}");
+        };
+        assert_eq!(input.name, "layers.0.w1:input");
+        assert_eq!(input.shape, [5, 32]);
+        assert_eq!(output.name, "layers.0.w1:output");
+        assert_eq!(output.shape, [5, 128]);
+        let weight = &weights[1].data;
+        for (input_row, output_row) in input.data.chunks(32).zip(output.data.chunks(128)) {
+            for (expected, weight_row) in output_row.iter().zip(weight.chunks(32)) {
+                let actual: f32 = input_row.iter().zip(weight_row).map(|(a, b)| a * b).sum();
+                assert!((actual - expected).abs() < 1e-4);
+            }
+        }
+
+        let tensors: Vec<_> = weights.into_iter().chain(activations).collect();
+        let paths = save_dumped_tensors(&tensors, TensorDumpFormat::Npy, &npy_path).unwrap();
+        assert_eq!(
+            paths
+                .iter()
+                .map(|path| path.file_name().unwrap().to_str().unwrap())
+                .collect::<Vec<_>>(),
+            [
+                "layers.0.norm.npy",
+                "layers.0.w1.npy",
+                "layers.0.w1_input.npy",
+                "layers.0.w1_output.npy",
+            ]
+        );
+        let norm = std::fs::read(&paths[0]).unwrap();
+        assert_eq!(&norm[..6], b"\x93NUMPY");
+        assert_eq!(
+            bytemuck::cast_slice::<f32, u8>(&tensors[0].data),
+            &norm[norm.len() - 32 * 4..]
+        );
+
+        save_dumped_tensors(&tensors, TensorDumpFormat::Safetensors, &safetensors_path).unwrap();
+        let buffer = std::fs::read(&safetensors_path).unwrap();
+        let safetensors = safetensors::SafeTensors::deserialize(&buffer).unwrap();
+        for tensor in &tensors {
+            let view = safetensors.tensor(&tensor.name).unwrap();
+            assert_eq!(view.dtype(), safetensors::Dtype::F32);
+            assert_eq!(view.shape(), tensor.shape);
+            assert_eq!(view.data(), bytemuck::cast_slice::<f32, u8>(&tensor.data));
+        }
+
+        std::fs::remove_file(&model_path).unwrap();
+        std::fs::remove_dir_all(&npy_path).unwrap();
+        std::fs::remove_file(&safetensors_path).unwrap();
+    }
+
+    #[test]
+    fn cannot_capture_activations_in_offloading_session() {
+        let model = load_random_model("dump-offloading", ModelParameters::default()).unwrap();
+        let mut session = start_offloading_session(&model);
+
+        assert!(matches!(
+            session.capture_activations(Regex::new(".*").unwrap()),
+            Err(InferenceError::UnsupportedOnAccelerator)
+        ));
+        assert_eq!(session.take_activations(), None);
+    }
+}
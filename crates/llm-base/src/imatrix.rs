@@ -0,0 +1,367 @@
+//! Implements importance matrices, which record how strongly each input of the model's
+//! weights is activated, so that quantization can prioritise the inputs that matter.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use ggml::util::{read_bytes, read_bytes_with_len, read_f32, read_u32, write_f32, write_u32};
+use thiserror::Error;
+
+/// The magic number at the start of an importance matrix file (`imtx`).
+const MAGIC: u32 = 0x696d_7478;
+/// The version of the importance matrix file format.
+const VERSION: u32 = 1;
+
+/// The mean squared activation of each input of the weights of a model, collected by
+/// evaluating calibration text with [InferenceSession::calibrate](crate::InferenceSession::calibrate).
+///
+/// Quantizing with an importance matrix (see [QuantizeParameters::importance](crate::QuantizeParameters::importance))
+/// weights the error of each value by the importance of the input it is multiplied with,
+/// which preserves the model's quality better at low bitrates.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportanceMatrix {
+    entries: BTreeMap<String, ImportanceEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ImportanceEntry {
+    /// The sum of the squared activations of each input.
+    sum_squares: Vec<f64>,
+    /// The number of activations that have been summed up for each input.
+    n_activations: u64,
+}
+
+impl ImportanceMatrix {
+    /// Returns the names of the weights with recorded activations.
+    pub fn tensor_names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    /// Returns the number of weights with recorded activations.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether no activations have been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the mean squared activation of each input of the weight `tensor_name`, i.e.
+    /// one value for each element of a row of the weight.
+    pub fn importance(&self, tensor_name: &str) -> Option<Vec<f32>> {
+        let entry = self.entries.get(tensor_name)?;
+        let n_activations = entry.n_activations.max(1) as f64;
+        Some(
+            entry
+                .sum_squares
+                .iter()
+                .map(|sum| (sum / n_activations) as f32)
+                .collect(),
+        )
+    }
+
+    /// Records the `activations` of the inputs of the weight `tensor_name`, which are made up
+    /// of rows of `row_length` values.
+    pub(crate) fn add(&mut self, tensor_name: &str, activations: &[f32], row_length: usize) {
+        let entry = self
+            .entries
+            .entry(tensor_name.to_string())
+            .or_insert_with(|| ImportanceEntry {
+                sum_squares: vec![0.0; row_length],
+                n_activations: 0,
+            });
+        if entry.sum_squares.len() != row_length {
+            // The same name is used for weights of different shapes; this should not happen.
+            return;
+        }
+
+        for row in activations.chunks_exact(row_length) {
+            for (sum, value) in entry.sum_squares.iter_mut().zip(row) {
+                *sum += f64::from(*value) * f64::from(*value);
+            }
+            entry.n_activations += 1;
+        }
+    }
+
+    /// Loads an importance matrix saved with [ImportanceMatrix::save].
+    pub fn load(path: &Path) -> Result<Self, ImportanceMatrixError> {
+        let file = File::open(path).map_err(|source| ImportanceMatrixError::OpenFileFailed {
+            source,
+            path: path.to_owned(),
+        })?;
+        Self::read(&mut BufReader::new(file))
+    }
+
+    /// Saves this importance matrix to `path`.
+    pub fn save(&self, path: &Path) -> Result<(), ImportanceMatrixError> {
+        let file =
+            File::create(path).map_err(|source| ImportanceMatrixError::CreateFileFailed {
+                source,
+                path: path.to_owned(),
+            })?;
+        let mut writer = BufWriter::new(file);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Reads an importance matrix written with [ImportanceMatrix::write].
+    pub fn read(reader: &mut dyn BufRead) -> Result<Self, ImportanceMatrixError> {
+        let magic = read_u32(reader)?;
+        if magic != MAGIC {
+            return Err(ImportanceMatrixError::InvalidMagic(magic));
+        }
+        let version = read_u32(reader)?;
+        if version != VERSION {
+            return Err(ImportanceMatrixError::UnsupportedVersion(version));
+        }
+
+        let n_entries = read_u32(reader)?;
+        let mut entries = BTreeMap::new();
+        for _ in 0..n_entries {
+            let name_len = read_u32(reader)?.try_into()?;
+            let name = String::from_utf8(read_bytes_with_len(reader, name_len)?)?;
+            let n_activations = u64::from_le_bytes(read_bytes::<8>(reader)?);
+            let n_values = read_u32(reader)?;
+            let sum_squares = (0..n_values)
+                .map(|_| Ok(f64::from(read_f32(reader)?) * n_activations as f64))
+                .collect::<Result<_, std::io::Error>>()?;
+            entries.insert(
+                name,
+                ImportanceEntry {
+                    sum_squares,
+                    n_activations,
+                },
+            );
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Writes this importance matrix.
+    ///
+    /// The format starts with the magic number `imtx` and a version, followed by the number
+    /// of weights. For each weight, its name, the number of activations that were recorded,
+    /// and the mean squared activation of each input are written. All numbers are
+    /// little-endian, and all lengths are `u32`s.
+    pub fn write(&self, writer: &mut dyn Write) -> Result<(), ImportanceMatrixError> {
+        write_u32(writer, MAGIC)?;
+        write_u32(writer, VERSION)?;
+        write_u32(writer, self.entries.len().try_into()?)?;
+        for (name, entry) in &self.entries {
+            write_u32(writer, name.len().try_into()?)?;
+            writer.write_all(name.as_bytes())?;
+            writer.write_all(&entry.n_activations.to_le_bytes())?;
+            write_u32(writer, entry.sum_squares.len().try_into()?)?;
+            for value in self.importance(name).unwrap_or_default() {
+                write_f32(writer, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered while reading or writing an [ImportanceMatrix].
+pub enum ImportanceMatrixError {
+    #[error("non-specific I/O error")]
+    /// A non-specific IO error.
+    Io(#[from] std::io::Error),
+    #[error("could not open file {path:?}")]
+    /// A file failed to open.
+    OpenFileFailed {
+        /// The original error.
+        source: std::io::Error,
+        /// The path that failed.
+        path: PathBuf,
+    },
+    #[error("could not create file {path:?}")]
+    /// A file failed to create.
+    CreateFileFailed {
+        /// The original error.
+        source: std::io::Error,
+        /// The path that failed.
+        path: PathBuf,
+    },
+    #[error("invalid magic number {0:#x}, which is not that of an importance matrix")]
+    /// The file is not an importance matrix.
+    InvalidMagic(u32),
+    #[error("unsupported importance matrix version {0}")]
+    /// The file was written with an unsupported version of the format.
+    UnsupportedVersion(u32),
+    #[error("could not convert bytes to a UTF-8 string")]
+    /// The name of a weight was not valid UTF-8.
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+    #[error("invalid integer conversion")]
+    /// One of the integers encountered could not be converted to a more appropriate type.
+    InvalidIntegerConversion(#[from] std::num::TryFromIntError),
+}
+
+#[cfg(test)]
+mod tests {
+    use ggml::format::SaveContainerType;
+
+    use super::*;
+    use crate::{
+        quantize_with_parameters, test_util::*, InferenceError, ModelParameters, QuantizeError,
+        QuantizeParameters, TokenId, TokenizerSource,
+    };
+
+    #[test]
+    fn test_roundtrip() {
+        let mut matrix = ImportanceMatrix::default();
+        matrix.add("a", &[1.0, -2.0, 3.0, 4.0], 2);
+        matrix.add("a", &[0.0, 2.0], 2);
+        matrix.add("b", &[0.5, 0.5, 0.5], 3);
+
+        assert_eq!(matrix.len(), 2);
+        assert_eq!(matrix.tensor_names().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(matrix.importance("a").unwrap(), [10.0 / 3.0, 24.0 / 3.0]);
+        assert_eq!(matrix.importance("b").unwrap(), [0.25; 3]);
+        assert_eq!(matrix.importance("c"), None);
+
+        let mut buffer = vec![];
+        matrix.write(&mut buffer).unwrap();
+        let read = ImportanceMatrix::read(&mut buffer.as_slice()).unwrap();
+        assert_eq!(read.tensor_names().collect::<Vec<_>>(), ["a", "b"]);
+        for name in ["a", "b"] {
+            for (read, original) in read
+                .importance(name)
+                .unwrap()
+                .iter()
+                .zip(matrix.importance(name).unwrap())
+            {
+                assert!((read - original).abs() < 1e-6);
+            }
+        }
+
+        assert!(matches!(
+            ImportanceMatrix::read(&mut [0u8; 8].as_slice()),
+            Err(ImportanceMatrixError::InvalidMagic(0))
+        ));
+    }
+
+    #[test]
+    fn can_quantize_with_importance_matrix() {
+        let path = |name: &str| temp_path(&format!("importance-{name}.bin"));
+        let original_path = path("original");
+        write_random_model(&original_path, test_hyperparameters());
+        let load = |path: &Path| load_model(path, ModelParameters::default()).unwrap();
+        let original = load(&original_path);
+
+        let tokens: Vec<TokenId> = (0..40).map(|i| (i * 7 % N_VOCAB) as TokenId).collect();
+        let mut chunks = vec![];
+        let importance = start_session(&original)
+            .calibrate(&original, tokens.as_slice(), |chunk, n_chunks| {
+                chunks.push((chunk, n_chunks))
+            })
+            .unwrap();
+        assert_eq!(chunks, [(1, 2), (2, 2)]);
+        assert_eq!(importance.importance("layers.0.w1").unwrap().len(), 32);
+        assert_eq!(importance.importance("layers.1.w2").unwrap().len(), 128);
+        assert!(importance.importance("layers.0.norm").is_none());
+
+        let importance_path = path("imatrix");
+        importance.save(&importance_path).unwrap();
+        let importance = ImportanceMatrix::load(&importance_path).unwrap();
+
+        let quantize = |name: &str, importance: Option<ImportanceMatrix>| {
+            let quantized_path = path(name);
+            quantize_with_parameters::<TestModel, _, _>(
+                &mut BufReader::new(File::open(&original_path).unwrap()),
+                &mut BufWriter::new(File::create(&quantized_path).unwrap()),
+                TokenizerSource::Embedded.retrieve(&original_path).unwrap(),
+                SaveContainerType::GgjtV3,
+                ggml::Type::Q5_1,
+                &QuantizeParameters {
+                    importance,
+                    ..Default::default()
+                },
+                |_| {},
+            )
+            .unwrap();
+            let model = load(&quantized_path);
+            std::fs::remove_file(&quantized_path).unwrap();
+            model
+        };
+        let unweighted = quantize("unweighted", None);
+        let weighted = quantize("weighted", Some(importance.clone()));
+
+        // Weighting by importance never increases the weighted error of a tensor.
+        let weighted_error = |model: &TestModel| {
+            let weights = importance.importance("layers.0.w1").unwrap();
+            let original = original.layers[0].w1.to_vec_f32().unwrap();
+            let quantized = model.layers[0].w1.to_vec_f32().unwrap();
+            original
+                .iter()
+                .zip(&quantized)
+                .enumerate()
+                .map(|(i, (original, quantized))| {
+                    weights[i % weights.len()] * (original - quantized).powi(2)
+                })
+                .sum::<f32>()
+        };
+        assert_eq!(weighted.layers[0].w1.get_type(), ggml::Type::Q5_1);
+        assert!(weighted_error(&weighted) <= weighted_error(&unweighted));
+
+        // The importance matrix must match the shapes of the model.
+        let wider_path = path("wider");
+        write_random_model(
+            &wider_path,
+            TestHyperparameters {
+                n_embd: 64,
+                ..test_hyperparameters()
+            },
+        );
+        let wider = load(&wider_path);
+        std::fs::remove_file(&wider_path).unwrap();
+        let mismatched = start_session(&wider)
+            .calibrate(&wider, tokens.as_slice(), |_, _| {})
+            .unwrap();
+        let result = quantize_with_parameters::<TestModel, _, _>(
+            &mut BufReader::new(File::open(&original_path).unwrap()),
+            &mut std::io::Cursor::new(vec![]),
+            TokenizerSource::Embedded.retrieve(&original_path).unwrap(),
+            SaveContainerType::GgjtV3,
+            ggml::Type::Q5_1,
+            &QuantizeParameters {
+                importance: Some(mismatched),
+                ..Default::default()
+            },
+            |_| {},
+        );
+        assert!(matches!(
+            result,
+            Err(QuantizeError::ImportanceSizeMismatch {
+                expected: 32,
+                actual: 64,
+                ..
+            })
+        ));
+
+        std::fs::remove_file(&original_path).unwrap();
+        std::fs::remove_file(&importance_path).unwrap();
+    }
+
+    #[test]
+    fn cannot_calibrate_accelerated_session() {
+        let model =
+            load_random_model("importance-accelerated", ModelParameters::default()).unwrap();
+        let mut session = start_offloading_session(&model);
+
+        assert!(matches!(
+            session.collect_importance_matrix(),
+            Err(InferenceError::UnsupportedOnAccelerator)
+        ));
+        assert!(matches!(
+            session.calibrate(&model, [1, 2, 3].as_slice(), |_, _| {}),
+            Err(InferenceError::UnsupportedOnAccelerator)
+        ));
+        assert!(session.take_importance_matrix().is_none());
+    }
+}
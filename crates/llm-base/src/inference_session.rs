@@ -1,6 +1,10 @@
 use ggml::{Buffer, ComputationGraph, Context, GraphExecutionPlan, Tensor};
 use serde::Serialize;
-use std::{cell::RefCell, fmt::Display, sync::Arc};
+use std::{
+    cell::RefCell,
+    fmt::Display,
+    sync::{Arc, Mutex},
+};
 use thiserror::Error;
 use tracing::{instrument, log};
 
@@ -8,15 +12,17 @@ use tracing::{instrument, log};
 use ggml::accelerator::metal::MetalContext;
 
 use crate::{
-    mulf, util, InferenceParameters, Model, ModelContext, ModelParameters, OutputRequest, Prompt,
-    TokenId, TokenUtf8Buffer, TokenizationError,
+    dump::ActivationCapture, lora::SessionLoraAdapter, mulf, util, CancellationToken, DumpedTensor,
+    ImportanceMatrix, InferenceParameters, LoraAdapter, LoraAdapterId, LoraError, Model,
+    ModelContext, ModelParameters, OutputRequest, Prompt, Regex, TokenId, TokenUtf8Buffer,
+    TokenizationError,
 };
 
 // The size of a scratch buffer used for inference. This is used for temporary
 // storage of intermediate results during inference.
 //
 // The specific value was copied from `llama.cpp`.
-const SCRATCH_SIZE: usize = 512 * 1024 * 1024;
+pub(crate) const SCRATCH_SIZE: usize = 512 * 1024 * 1024;
 
 type ScratchBuffers = [ggml::Buffer; 2];
 
@@ -91,21 +97,91 @@ pub struct InferenceSession {
     n_embd: usize,
 
     scratch: ScratchBuffers,
+
+    // LoRA adapters applied by this session only.
+    lora_adapters: Vec<(LoraAdapterId, SessionLoraAdapter)>,
+    next_lora_id: usize,
+
+    // Whether any layer is evaluated by an accelerator, whose intermediate values cannot be
+    // observed from the CPU.
+    accelerated: bool,
+
+    // The importance matrix being collected by this session, if any.
+    importance: Option<Arc<Mutex<ImportanceMatrix>>>,
+    // The activations being captured by this session, if any.
+    activations: Option<Arc<Mutex<ActivationCapture>>>,
 }
 
+/// Used by models to build the computation graph in [InferenceSession::compute].
 pub struct BuildContext<'session> {
     //FIXME: Borrowing issue, dont know how to fix it
+    /// The context the graph is built in.
     pub ctx0: RefCell<&'session mut Context>,
+    /// The input tokens.
     pub embd: &'session Tensor,
+    /// The key memory of the session.
     pub memory_k: &'session Tensor,
+    /// The value memory of the session.
     pub memory_v: &'session Tensor,
+    /// The scratch buffers of the session.
     pub scratch: &'session ScratchBuffers,
+    lora_adapters: &'session [(LoraAdapterId, SessionLoraAdapter)],
+    importance: Option<&'session Arc<Mutex<ImportanceMatrix>>>,
+    activations: Option<&'session Arc<Mutex<ActivationCapture>>>,
 }
 
 impl<'session> BuildContext<'session> {
+    /// Get the scratch buffer with index `idx`.
     pub fn get_scratch(&self, idx: usize) -> Option<&Buffer> {
         Some(&self.scratch[idx])
     }
+
+    /// Multiplies the model weight `weight` by `input`, adding the low-rank path of every
+    /// LoRA adapter attached to the session that patches `weight`.
+    ///
+    /// If the session is collecting an importance matrix, the activations in `input` are
+    /// recorded for `weight`. If the session is capturing activations, `input` and the result
+    /// are captured as `<weight>:input` and `<weight>:output`.
+    pub fn op_mul_mat_weight(&self, ctx0: &Context, weight: &Tensor, input: &Tensor) -> Tensor {
+        let tensor_name = weight.name();
+        let input = match self.importance {
+            Some(importance) => {
+                let importance = importance.clone();
+                let tensor_name = tensor_name.clone();
+                ctx0.op_observe(input, move |activations, ne| {
+                    importance
+                        .lock()
+                        .unwrap()
+                        .add(&tensor_name, activations, ne[0])
+                })
+            }
+            None => input.share(),
+        };
+        let input = &self.capture_activation(ctx0, input, format!("{tensor_name}:input"));
+
+        let output = ctx0.op_mul_mat(weight, input);
+        let output = self
+            .lora_adapters
+            .iter()
+            .fold(output, |output, (_, adapter)| {
+                adapter.apply(ctx0, weight, input, output)
+            });
+        self.capture_activation(ctx0, output, format!("{tensor_name}:output"))
+    }
+
+    /// Captures the values of `tensor` as the activation `name`, if the session is capturing
+    /// activations that match `name`.
+    fn capture_activation(&self, ctx0: &Context, tensor: Tensor, name: String) -> Tensor {
+        match self.activations {
+            Some(activations) if activations.lock().unwrap().wants(&name) => {
+                let activations = activations.clone();
+                ctx0.op_observe(&tensor, move |values, ne| {
+                    activations.lock().unwrap().add(&name, values, ne)
+                })
+            }
+            _ => tensor,
+        }
+    }
 }
 
 unsafe impl Send for InferenceSession {}
@@ -124,24 +200,9 @@ impl InferenceSession {
             ..
         } = *params;
 
-        let context_byte_size = {
-            let mut size = 0;
-            size += mulf!(
-                context_size,
-                n_layer,
-                n_embd,
-                ggml::type_sizef(config.memory_k_type.into())
-            ); // memory_k
-            size += mulf!(
-                context_size,
-                n_layer,
-                n_embd,
-                ggml::type_sizef(config.memory_v_type.into())
-            ); // memory_v
-            size += (5 + 10 * n_layer) * 256; // object overhead
-
-            size
-        };
+        let context_byte_size = kv_memory_size(&config, context_size, n_layer, n_embd);
+        let accelerated = (0..n_layer).any(|il| params.should_offload(il))
+            || (use_gpu && cfg!(feature = "metal"));
 
         if use_gpu {
             ggml::accelerator::initialize(0);
@@ -161,23 +222,7 @@ impl InferenceSession {
 
         let scratch = scratch_buffers();
 
-        // Allocate buffer for storing intermediate values during evaluation (ctx0 backing)
-        // For the first run, we need to guess a maximum buffer size so we can measure
-        // the actual memory consumption of the temporary ggml context.
-        //
-        // These numbers are from `llama.cpp`, and could potentially be more efficient.
-        let buf_size = {
-            let buf_size_mb = if n_layer >= 80 {
-                1536
-            } else if n_layer >= 60 {
-                1280
-            } else {
-                1024
-            };
-            buf_size_mb * 1024 * 1024 + ggml::graph_overhead()
-        };
-
-        let eval = Buffer::new(buf_size);
+        let eval = Buffer::new(eval_buffer_size(n_layer));
         let ctx0 = ggml::Context::new_with_buffer(eval);
 
         // Set up Metal support
@@ -213,19 +258,113 @@ impl InferenceSession {
             ctx0,
             n_embd,
             scratch,
+            lora_adapters: vec![],
+            next_lora_id: 0,
+            accelerated,
+            importance: None,
+            activations: None,
         }
     }
 
+    /// Apply a LoRA `adapter`, scaled by [LoraAdapter::scaling], to the evaluations of this
+    /// session only.
+    ///
+    /// Rather than patching the model's weights, the adapter is evaluated as an extra
+    /// low-rank path next to each weight it patches, so other sessions using the same model
+    /// are unaffected. Adapters attached this way are not part of snapshots, and sessions
+    /// using Metal acceleration are rejected with [LoraError::UnsupportedOnMetal].
+    pub fn attach_lora(&mut self, adapter: &LoraAdapter) -> Result<LoraAdapterId, LoraError> {
+        #[cfg(feature = "metal")]
+        if self.metal_context.is_some() {
+            return Err(LoraError::UnsupportedOnMetal);
+        }
+
+        let session_adapter = SessionLoraAdapter::new(adapter)?;
+        let id = LoraAdapterId(self.next_lora_id);
+        self.next_lora_id += 1;
+        self.lora_adapters.push((id, session_adapter));
+        Ok(id)
+    }
+
+    /// Stop applying a LoRA adapter attached with [InferenceSession::attach_lora].
+    pub fn detach_lora(&mut self, id: LoraAdapterId) -> Result<(), LoraError> {
+        let index = self
+            .lora_adapters
+            .iter()
+            .position(|(attached_id, _)| *attached_id == id)
+            .ok_or(LoraError::NotAttached(id))?;
+        self.lora_adapters.remove(index);
+        Ok(())
+    }
+
+    /// Start recording the activations of the inputs of the model's weights in every
+    /// evaluation of this session, to build an [ImportanceMatrix].
+    ///
+    /// The matrix can be retrieved with [InferenceSession::take_importance_matrix]. Collecting
+    /// an importance matrix is not supported for layers that are evaluated by an accelerator,
+    /// so [InferenceError::UnsupportedOnAccelerator] is returned if the session offloads any.
+    pub fn collect_importance_matrix(&mut self) -> Result<(), InferenceError> {
+        self.check_not_accelerated()?;
+        self.importance.get_or_insert_with(Default::default);
+        Ok(())
+    }
+
+    /// Returns [InferenceError::UnsupportedOnAccelerator] if any layer of this session is
+    /// evaluated by an accelerator.
+    fn check_not_accelerated(&self) -> Result<(), InferenceError> {
+        if self.accelerated {
+            return Err(InferenceError::UnsupportedOnAccelerator);
+        }
+        Ok(())
+    }
+
+    /// Stop collecting an importance matrix, and return what has been collected so far, if
+    /// [InferenceSession::collect_importance_matrix] was called.
+    pub fn take_importance_matrix(&mut self) -> Option<ImportanceMatrix> {
+        self.importance
+            .take()
+            .map(|importance| std::mem::take(&mut *importance.lock().unwrap()))
+    }
+
+    /// Start capturing the inputs and outputs of the model's weights whose names match
+    /// `pattern` in every evaluation of this session, e.g. to compare them against a reference
+    /// implementation.
+    ///
+    /// The input and output of each weight are named `<weight>:input` and `<weight>:output`,
+    /// and are captured as matrices with a row for each evaluated token. The activations can
+    /// be retrieved with [InferenceSession::take_activations]. Capturing activations is not
+    /// supported for layers that are evaluated by an accelerator, so
+    /// [InferenceError::UnsupportedOnAccelerator] is returned if the session offloads any.
+    pub fn capture_activations(&mut self, pattern: Regex) -> Result<(), InferenceError> {
+        self.check_not_accelerated()?;
+        self.activations = Some(Arc::new(Mutex::new(ActivationCapture::new(pattern))));
+        Ok(())
+    }
+
+    /// Stop capturing activations, and return what has been captured so far in the order
+    /// the activations were first computed, if [InferenceSession::capture_activations] was
+    /// called.
+    pub fn take_activations(&mut self) -> Option<Vec<DumpedTensor>> {
+        self.activations
+            .take()
+            .map(|activations| activations.lock().unwrap().take())
+    }
+
     /// Compute a model (possibly building a graph in the provided closure when called for the first time and/or when parameters have)
     pub fn compute<F>(
         &mut self,
-        #[allow(unused_variables)] model_context: ModelContext,
+        model_context: ModelContext,
         input_tokens: &[TokenId],
         builder: F,
     ) -> GraphOutputs
     where
         F: FnOnce(BuildContext) -> (ComputationGraph, GraphOutputs),
     {
+        // Keep LoRA adapters from being attached to or detached from the model while it is
+        // being evaluated.
+        let lora = model_context.lora.clone();
+        let _lora = lora.read().unwrap();
+
         // Build a graph
         self.ctx0.recreate();
         let ctx0 = &mut self.ctx0;
@@ -239,6 +378,9 @@ impl InferenceSession {
             memory_k: &self.memory_k,
             memory_v: &self.memory_v,
             scratch: &mut self.scratch,
+            lora_adapters: &self.lora_adapters,
+            importance: self.importance.as_ref(),
+            activations: self.activations.as_ref(),
         };
         let (mut built_gf, built_result) = builder(bc);
 
@@ -246,7 +388,10 @@ impl InferenceSession {
         #[cfg(feature = "metal")]
         {
             if let Some(ref mut metal_context) = self.metal_context {
-                metal_context.add_context(model_context.0);
+                metal_context.add_context(model_context.context);
+                if let Some(owned_context) = model_context.owned_context {
+                    metal_context.add_context(owned_context);
+                }
             }
         }
 
@@ -301,6 +446,38 @@ impl InferenceSession {
         model: &dyn Model,
         prompt: P,
         output_request: &mut OutputRequest,
+        callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
+    ) -> Result<(), InferenceError> {
+        self.feed_prompt_impl(model, prompt, output_request, None, callback)
+    }
+
+    /// Feed a prompt to the model for this session, like [InferenceSession::feed_prompt].
+    ///
+    /// `cancellation` is checked before each batch is evaluated; if it has been cancelled,
+    /// [InferenceError::Cancelled] is returned and the session is left holding the batches
+    /// that were already evaluated.
+    #[instrument(skip_all)]
+    pub fn feed_prompt_with_cancellation<
+        'a,
+        E: std::error::Error + Send + Sync + 'static,
+        P: Into<Prompt<'a>>,
+    >(
+        &mut self,
+        model: &dyn Model,
+        prompt: P,
+        output_request: &mut OutputRequest,
+        cancellation: &CancellationToken,
+        callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
+    ) -> Result<(), InferenceError> {
+        self.feed_prompt_impl(model, prompt, output_request, Some(cancellation), callback)
+    }
+
+    fn feed_prompt_impl<'a, E: std::error::Error + Send + Sync + 'static, P: Into<Prompt<'a>>>(
+        &mut self,
+        model: &dyn Model,
+        prompt: P,
+        output_request: &mut OutputRequest,
+        cancellation: Option<&CancellationToken>,
         mut callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
     ) -> Result<(), InferenceError> {
         let beginning_of_sentence = self.n_past == 0;
@@ -313,6 +490,7 @@ impl InferenceSession {
         }
 
         'outer: for batch in prompt_tokens.chunks(self.config.n_batch) {
+            check_cancellation(cancellation)?;
             model.evaluate(self, batch, output_request);
             for &tk in batch {
                 let should_call_callback = Some(tk) != model.bot_token_id();
@@ -430,6 +608,10 @@ impl InferenceSession {
     /// generated (specified by [InferenceRequest::maximum_token_count]).
     ///
     /// This is a wrapper around [Self::feed_prompt] and [Self::infer_next_token].
+    ///
+    /// If [InferenceRequest::cancellation] is set, it is checked before each batch of the
+    /// prompt and before each generated token; once it has been cancelled,
+    /// [InferenceError::Cancelled] is returned. The session remains usable afterwards.
     #[instrument(skip_all)]
     pub fn infer<E: std::error::Error + Send + Sync + 'static>(
         &mut self,
@@ -468,10 +650,11 @@ impl InferenceSession {
         // Feed the initial prompt through the transformer, to update its
         // context window with new data, if necessary.
         if !request.prompt.is_empty() {
-            self.feed_prompt(
+            self.feed_prompt_impl(
                 model,
                 request.prompt,
                 output_request,
+                request.cancellation,
                 feed_prompt_callback(&mut callback),
             )?;
         }
@@ -485,6 +668,7 @@ impl InferenceSession {
         let mut tokens_processed = 0;
         let mut token_utf8_buf = TokenUtf8Buffer::new();
         while tokens_processed < maximum_token_count {
+            check_cancellation(request.cancellation)?;
             let token = match self.infer_next_token(model, parameters, &mut Default::default(), rng)
             {
                 Ok(token) => token,
@@ -588,6 +772,43 @@ impl InferenceSession {
         Ok(())
     }
 
+    /// Collects an [ImportanceMatrix] by evaluating calibration text, with the callback
+    /// being called after each chunk is evaluated with the number of chunks processed and
+    /// the total number of chunks.
+    ///
+    /// The text is split into chunks of the model's context size, each of which is evaluated
+    /// from the start of the context. This will alter the state of the LM, like
+    /// [Self::perplexity], and any importance matrix that was already being collected is
+    /// included in the result. As with [Self::collect_importance_matrix], sessions that
+    /// evaluate layers on an accelerator are rejected.
+    pub fn calibrate<'a, P: Into<Prompt<'a>>>(
+        &mut self,
+        model: &dyn Model,
+        prompt: P,
+        mut calibration_callback: impl FnMut(usize, usize),
+    ) -> Result<ImportanceMatrix, InferenceError> {
+        self.collect_importance_matrix()?;
+        let tokens = prompt.into().to_tokens(model.tokenizer(), true)?;
+        let chunks: Vec<_> = tokens.chunks(model.context_size()).collect();
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut chunk = chunk.to_vec();
+            // Start every chunk as if it were the start of the text.
+            if let Some(bot_token_id) = model.bot_token_id() {
+                chunk[0] = bot_token_id;
+            }
+
+            self.n_past = 0;
+            for batch in chunk.chunks(self.config.n_batch) {
+                model.evaluate(self, batch, &mut OutputRequest::default());
+            }
+
+            calibration_callback(i + 1, chunks.len());
+        }
+
+        Ok(self.take_importance_matrix().unwrap_or_default())
+    }
+
     /// Obtains a serializable snapshot of the current inference status. This
     /// can be used to cache the state of the model and store them into a file.
     ///
@@ -664,6 +885,13 @@ impl Drop for InferenceSession {
     }
 }
 
+fn check_cancellation(cancellation: Option<&CancellationToken>) -> Result<(), InferenceError> {
+    match cancellation {
+        Some(token) if token.is_cancelled() => Err(InferenceError::Cancelled),
+        _ => Ok(()),
+    }
+}
+
 fn get_newly_decoded_portion_huggingface(
     model: &dyn Model,
     tokens: Vec<u32>,
@@ -700,6 +928,16 @@ pub enum InferenceError {
     /// Sampling returned an error.
     #[error("token sampling failed")]
     SamplerFailure(crate::samplers::SamplingError),
+    /// Inference was cancelled through a [CancellationToken].
+    ///
+    /// The session remains usable: everything that was evaluated before the
+    /// cancellation was observed is kept.
+    #[error("inference was cancelled")]
+    Cancelled,
+    /// The operation observes intermediate values of the evaluation, which is not supported
+    /// when layers are evaluated by an accelerator.
+    #[error("the operation is not supported when layers are evaluated by an accelerator")]
+    UnsupportedOnAccelerator,
 }
 
 #[derive(Error, Debug)]
@@ -855,6 +1093,9 @@ pub struct InferenceRequest<'a> {
     pub play_back_previous_tokens: bool,
     /// The maximum number of tokens to generate.
     pub maximum_token_count: Option<usize>,
+    /// A token that can be used to stop inference from another thread, or once
+    /// its deadline has passed.
+    pub cancellation: Option<&'a CancellationToken>,
 }
 
 /// Statistics about the inference process.
@@ -992,6 +1233,47 @@ pub fn conversation_inference_callback<'a, E: std::error::Error + Send + Sync +
     }
 }
 
+/// The size of the session context holding the memory K/V tensors.
+pub(crate) fn kv_memory_size(
+    config: &InferenceSessionConfig,
+    context_size: usize,
+    n_layer: usize,
+    n_embd: usize,
+) -> usize {
+    let mut size = 0;
+    size += mulf!(
+        context_size,
+        n_layer,
+        n_embd,
+        ggml::type_sizef(config.memory_k_type.into())
+    ); // memory_k
+    size += mulf!(
+        context_size,
+        n_layer,
+        n_embd,
+        ggml::type_sizef(config.memory_v_type.into())
+    ); // memory_v
+    size += (5 + 10 * n_layer) * 256; // object overhead
+
+    size
+}
+
+/// The size of the buffer used to store intermediate values during evaluation (ctx0 backing).
+pub(crate) fn eval_buffer_size(n_layer: usize) -> usize {
+    // For the first run, we need to guess a maximum buffer size so we can measure
+    // the actual memory consumption of the temporary ggml context.
+    //
+    // These numbers are from `llama.cpp`, and could potentially be more efficient.
+    let buf_size_mb = if n_layer >= 80 {
+        1536
+    } else if n_layer >= 60 {
+        1280
+    } else {
+        1024
+    };
+    buf_size_mb * 1024 * 1024 + ggml::graph_overhead()
+}
+
 /// Create the memory K/V tensors for the inference-session.
 fn kv_memory(
     context: &Context,
@@ -1019,3 +1301,92 @@ fn kv_memory(
 
     (memory_k, memory_v)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn can_rewind() {
+        let model = load_random_model("rewind", ModelParameters::default()).unwrap();
+
+        let mut session = start_session(&model);
+        let original_logits = feed(&model, &mut session, &[3, 1, 4, 1, 5]);
+        session.rewind(&model, 3).unwrap();
+        let redone_logits = feed(&model, &mut session, &[4, 1, 5]);
+
+        assert_eq!(original_logits, redone_logits);
+        assert_eq!(session.tokens(), &[3, 1, 4, 1, 5]);
+    }
+
+    #[test]
+    fn cancelled_feed_keeps_session_usable() {
+        let model = load_random_model("cancelled-feed", ModelParameters::default()).unwrap();
+        let mut session = start_session(&model);
+        feed(&model, &mut session, &[3, 1]);
+
+        let cancellation = CancellationToken::new().with_deadline(std::time::Instant::now());
+        let result = session.feed_prompt_with_cancellation(
+            &model,
+            [4, 1, 5].as_slice(),
+            &mut Default::default(),
+            &cancellation,
+            |_| Ok::<_, Infallible>(InferenceFeedback::Continue),
+        );
+        assert!(matches!(result, Err(InferenceError::Cancelled)));
+        assert_eq!(session.tokens(), &[3, 1]);
+
+        let resumed_logits = feed(&model, &mut session, &[4, 1, 5]);
+        let mut fresh_session = start_session(&model);
+        let expected_logits = feed(&model, &mut fresh_session, &[3, 1, 4, 1, 5]);
+        assert_eq!(resumed_logits, expected_logits);
+    }
+
+    #[test]
+    fn can_apply_lora_per_session() {
+        let lora_path = temp_path("session-lora-adapter.bin");
+        write_lora(
+            &lora_path,
+            &model_tensors(test_hyperparameters()),
+            &["layers.0.w1", "layers.1.w2"],
+        );
+
+        let model = load_random_model("session-lora", ModelParameters::default()).unwrap();
+        let patched_model = load_random_model(
+            "session-lora-patched",
+            ModelParameters {
+                lora_adapters: Some(vec![lora_path.clone()]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let adapter = LoraAdapter::load(&lora_path).unwrap();
+        std::fs::remove_file(&lora_path).unwrap();
+
+        let tokens = [3, 1, 4];
+        let original_logits = feed(&model, &mut start_session(&model), &tokens);
+        let patched_logits = feed(&patched_model, &mut start_session(&patched_model), &tokens);
+
+        let mut session = start_session(&model);
+        session.attach_lora(&adapter).unwrap();
+        let session_logits = feed(&model, &mut session, &tokens);
+        for (session_logit, patched_logit) in session_logits.iter().zip(&patched_logits) {
+            assert!((session_logit - patched_logit).abs() < 1e-4);
+        }
+        assert_ne!(session_logits, original_logits);
+
+        // Other sessions on the same model are unaffected.
+        assert_eq!(
+            feed(&model, &mut start_session(&model), &tokens),
+            original_logits
+        );
+
+        let mut session = start_session(&model);
+        let id = session.attach_lora(&adapter).unwrap();
+        session.detach_lora(id).unwrap();
+        assert_eq!(feed(&model, &mut session, &tokens), original_logits);
+    }
+}
@@ -7,42 +7,66 @@
 //! As a user, you probably want to use the [llm](https://crates.io/crates/llm) crate instead.
 #![deny(missing_docs)]
 
+mod cancellation;
+mod convert;
+mod diff;
+mod dump;
+mod imatrix;
 mod inference_session;
 mod loader;
 mod lora;
 mod quantize;
 mod tokenizer;
+mod verify;
 
 pub mod model;
 pub mod samplers;
 pub mod util;
 
+#[cfg(any(test, feature = "test-util"))]
+#[doc(hidden)]
+pub mod test_util;
+
 use std::sync::{Arc, Mutex};
 
 pub use ggml;
 pub use ggml::Type as ElementType;
 
+pub use cancellation::CancellationToken;
+pub use convert::{convert_hf, ConvertProgress, HfConfig};
+pub use diff::{diff, DiffProgress, ModelDiff, TensorDiff, TensorDistance, TokenDiff};
+pub use dump::{
+    dump_weights, save_dumped_tensors, write_npy, DumpError, DumpedTensor, TensorDumpFormat,
+};
+pub use imatrix::{ImportanceMatrix, ImportanceMatrixError};
 pub use inference_session::{
-    conversation_inference_callback, feed_prompt_callback, GraphOutputs, InferenceError,
-    InferenceFeedback, InferenceRequest, InferenceResponse, InferenceSession,
+    conversation_inference_callback, feed_prompt_callback, BuildContext, GraphOutputs,
+    InferenceError, InferenceFeedback, InferenceRequest, InferenceResponse, InferenceSession,
     InferenceSessionConfig, InferenceSnapshot, InferenceSnapshotRef, InferenceStats,
     ModelKVMemoryType, RewindError, SnapshotError,
 };
 pub use llm_samplers::prelude::{Sampler, SamplerChain};
 pub use loader::{
-    load, load_progress_callback_stdout, ContainerType, FileType, FileTypeFormat, FormatMagic,
-    LoadError, LoadProgress, Loader, TensorLoader,
+    estimate_memory, load, load_progress_callback_stdout, load_tokenizer, ContainerType, FileType,
+    FileTypeFormat, FormatMagic, LoadError, LoadProgress, Loader, MemoryEstimate, TensorLoader,
+    TensorShapeMismatch,
 };
-pub use lora::{LoraAdapter, LoraParameters};
+pub use lora::{LoraAdapter, LoraAdapterId, LoraError, LoraParameters};
 pub use memmap2::Mmap;
-pub use model::{Hyperparameters, KnownModel, Model, ModelContext, ModelParameters, OutputRequest};
-pub use quantize::{quantize, QuantizeError, QuantizeProgress};
+pub use model::{
+    Hyperparameters, KnownModel, Model, ModelContext, ModelInfo, ModelParameters, OutputRequest,
+};
+pub use quantize::{
+    convert_container, dequantize, merge_lora, quantize, quantize_with_parameters,
+    QuantizationRule, QuantizationStats, QuantizeError, QuantizeParameters, QuantizeProgress,
+};
 pub use regex::Regex;
 pub use tokenizer::{
     InvalidTokenBias, Prompt, TokenBias, TokenId, TokenizationError, Tokenizer, TokenizerLoadError,
     TokenizerSource,
 };
 pub use util::TokenUtf8Buffer;
+pub use verify::{verify, VerifyError, VerifyIssue, VerifyProgress, VerifyReport};
 
 #[derive(Clone, Debug)]
 /// The parameters for text generation.
@@ -1,16 +1,17 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     error::Error,
     fmt::{Debug, Display, Formatter},
     fs::File,
     io::{BufRead, BufReader, Read, Seek, SeekFrom},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, RwLock},
 };
 
 use crate::{
-    util, Hyperparameters, KnownModel, LoraAdapter, LoraParameters, ModelContext, ModelParameters,
-    TokenId, Tokenizer, TokenizerLoadError, TokenizerSource,
+    lora::LoraState, util, CancellationToken, Hyperparameters, InferenceSessionConfig, KnownModel,
+    LoraAdapter, ModelContext, ModelParameters, TokenId, Tokenizer, TokenizerLoadError,
+    TokenizerSource,
 };
 pub use ggml::{format::FormatMagic, ContainerType};
 use ggml::{
@@ -209,6 +210,17 @@ pub enum LoadProgress {
     },
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A tensor whose shape in the file differs from the shape the architecture expects.
+pub struct TensorShapeMismatch {
+    /// The name of the tensor.
+    pub tensor_name: String,
+    /// The dimensions the architecture expects.
+    pub expected: Vec<usize>,
+    /// The dimensions of the tensor in the file.
+    pub actual: Vec<usize>,
+}
+
 #[derive(Error, Debug)]
 /// Errors encountered during the loading process.
 pub enum LoadError {
@@ -347,6 +359,86 @@ pub enum LoadError {
         /// The path that failed.
         path: PathBuf,
     },
+    /// Loading was cancelled through [ModelParameters::cancellation].
+    #[error("loading was cancelled")]
+    Cancelled,
+    /// The tensors in the file do not match the tensors expected by the model architecture.
+    ///
+    /// This usually means that the file contains a model of a different architecture.
+    #[error(
+        "the tensors in {path:?} do not match the {expected} architecture ({} missing, {} unexpected, {} with the wrong shape)",
+        missing_tensors.len(),
+        unexpected_tensors.len(),
+        mismatched_tensors.len()
+    )]
+    ArchitectureMismatch {
+        /// The path that failed.
+        path: PathBuf,
+        /// The name of the architecture that was expected.
+        expected: String,
+        /// The tensors required by the architecture that are not in the file.
+        missing_tensors: Vec<String>,
+        /// The tensors in the file that are not used by the architecture.
+        unexpected_tensors: Vec<String>,
+        /// The tensors in the file whose shape differs from the one the architecture expects.
+        mismatched_tensors: Vec<TensorShapeMismatch>,
+    },
+    /// The quantized tensors in the file use a quantization version that is not supported.
+    #[error("unsupported quantization version {quantization_version} in {path:?}")]
+    UnsupportedQuantizationVersion {
+        /// The path that failed.
+        path: PathBuf,
+        /// The quantization version that was encountered.
+        quantization_version: u32,
+    },
+    /// A safetensors file could not be read.
+    #[error("could not read the safetensors file {path:?}")]
+    InvalidSafetensors {
+        /// The path that failed.
+        path: PathBuf,
+        /// The original error.
+        source: safetensors::SafeTensorError,
+    },
+    /// A JSON configuration file could not be parsed.
+    #[error("could not parse the configuration file {path:?}")]
+    InvalidConfig {
+        /// The path that failed.
+        path: PathBuf,
+        /// The original error.
+        source: serde_json::Error,
+    },
+    /// The model architecture cannot be converted from Hugging Face Transformers checkpoints
+    /// (see [KnownModel::hf_hyperparameters]).
+    #[error("the model architecture does not support converting Hugging Face checkpoints")]
+    HfConversionUnsupported,
+    /// The hyperparameters of the model do not record a value that is required, such as
+    /// the number of layers (see [Hyperparameters::n_layer]).
+    #[error("the hyperparameters in {path:?} do not record `{name}`")]
+    MissingHyperparameter {
+        /// The path that failed.
+        path: PathBuf,
+        /// The name of the missing value.
+        name: &'static str,
+    },
+    /// The hyperparameters of the model have a value that the architecture cannot be built with,
+    /// such as a model without attention heads.
+    #[error("invalid hyperparameter `{name}`: {reason}")]
+    InvalidHyperparameter {
+        /// The name of the invalid value.
+        name: &'static str,
+        /// Why the value is invalid.
+        reason: String,
+    },
+    /// A tensor in a safetensors file has a data type that cannot be converted.
+    #[error("unsupported data type {dtype} for tensor `{tensor_name}` in {path:?}")]
+    UnsupportedDtype {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The data type of the tensor.
+        dtype: String,
+        /// The path that failed.
+        path: PathBuf,
+    },
 }
 impl From<util::FindAllModelFilesError> for LoadError {
     fn from(value: util::FindAllModelFilesError) -> Self {
@@ -396,8 +488,10 @@ impl LoadError {
 
 /// Used by models to fetch tensors from a loader.
 pub trait TensorLoader<E: std::error::Error> {
-    /// Gets a tensor from the loader.
-    fn load(&mut self, name: &str) -> Result<ggml::Tensor, E>;
+    /// Gets a tensor from the loader, checking that it has the dimensions `ne`.
+    fn load(&mut self, name: &str, ne: &[usize]) -> Result<ggml::Tensor, E>;
+    /// Gets a tensor from the loader if it is present, checking that it has the dimensions `ne`.
+    fn load_optional(&mut self, name: &str, ne: &[usize]) -> Result<Option<ggml::Tensor>, E>;
     /// Finish loading the model, returning the context.
     fn finish(self) -> ModelContext;
 }
@@ -405,17 +499,12 @@ pub trait TensorLoader<E: std::error::Error> {
 /// Load a GGML model from the `path` and configure it per the `params`. The status
 /// of the loading process will be reported through `load_progress_callback`.
 ///
-/// Note that the model must be a single-part model, and the model in `path`
-/// *must* match the architecture of `M`.
-///
-/// # Panics
-///
-/// - If the model does not match the architecture of `M`. This is not checked
-///   before execution, so this function will panic if the model does not match
-///   the architecture.
-///
-///   This is a limitation of the GGML format, which does not
-///   store any information about the architecture.
+/// Note that the model must be a single-part model. As the GGML format does not store
+/// any information about the architecture, the names and shapes of the tensors in `path`
+/// are checked against the tensors that `M` expects before any of them are loaded. If they do not match,
+/// [LoadError::ArchitectureMismatch] is returned. Models with quantized tensors that use
+/// an unsupported quantization version are rejected with
+/// [LoadError::UnsupportedQuantizationVersion].
 pub fn load<M: KnownModel>(
     path: &Path,
     tokenizer_source: TokenizerSource,
@@ -440,12 +529,16 @@ pub fn load<M: KnownModel>(
     let mut reader = BufReader::new(&file);
     log::trace!("Read model file from {:?}", path);
 
+    let cancellation = params.cancellation.clone();
+    check_cancellation(cancellation.as_ref())?;
+
     let tokenizer = tokenizer_source.retrieve(path)?;
     let mut loader = Loader::new(tokenizer, load_progress_callback);
 
     ggml::format::load(&mut reader, &mut loader)
         .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
     log::trace!("Loaded GGML model from reader");
+    check_cancellation(cancellation.as_ref())?;
 
     let Loader {
         hyperparameters,
@@ -453,84 +546,73 @@ pub fn load<M: KnownModel>(
         tensors,
         mut load_progress_callback,
         container_type,
+        quantization_version,
         ..
     } = loader;
 
-    let quantization_version = (&hyperparameters as &M::Hyperparameters)
-        .file_type()
-        .map(|ft| ft.quantization_version)
-        .unwrap_or_default();
-    let quantization_version = if quantization_version == 0 {
-        // HACK: I think llama.cpp does not actually write the quantization version correctly,
-        // so we need to guess it from the container type.
-        if container_type == ggml::ContainerType::Ggjt(2) {
-            1
-        } else if container_type == ggml::ContainerType::Ggjt(3) {
-            2
-        } else {
-            quantization_version
-        }
-    } else {
-        quantization_version
-    };
     log::trace!(
         "Determined quantization version of model as {:?}",
         quantization_version
     );
 
-    // TODO: this is temporary while we figure out how to handle this
-    if tensors.values().any(|t| t.element_type.is_quantized()) {
-        assert_eq!(quantization_version, 2, "quantization version must be 2");
+    if quantization_version > ggml::QNT_VERSION
+        && tensors.values().any(|t| t.element_type.is_quantized())
+    {
+        return Err(LoadError::UnsupportedQuantizationVersion {
+            path: path.to_owned(),
+            quantization_version,
+        });
     }
 
-    let use_mmap =
-        params.prefer_mmap && container_type.support_mmap() && params.lora_adapters.is_none();
-
-    let ctx_size = tensors
-        .values()
-        .map(|ti| ti.calc_absolute_size(use_mmap))
-        .sum::<usize>();
-    log::trace!("Context size: {:?}", ctx_size);
+    validate_tensors::<M>(path, &hyperparameters, &params, &tensors)?;
 
     let mut lora_adapters: Option<Vec<LoraAdapter>> = None;
     if let Some(lora_paths) = &params.lora_adapters {
         let adapters: Result<Vec<_>, _> = lora_paths
             .iter()
-            .map(|lora_path| {
-                // Read the LoRA file
-                let lora_file = File::open(lora_path).map_err(|e| LoadError::OpenFileFailed {
-                    source: e,
-                    path: lora_path.to_owned(),
-                })?;
-                let mut lora_reader = BufReader::new(&lora_file);
-                // TODO: Consider updating the progress callback to report the progress of the LoRA file.
-                // Most LoRAs are small enough that this is not necessary, but it would be nice to have.
-                let mut lora_loader: Loader<LoraParameters, _> =
-                    Loader::new(Tokenizer::empty_embedded(), |_| {});
-                ggml::format::load(&mut lora_reader, &mut lora_loader)
-                    .map_err(|err| LoadError::from_format_error(err, lora_path.to_owned()))?;
-
-                // Collect the names of the tensors that should be patched
-                let tensors_to_patch = lora_loader
-                    .tensors
-                    .keys()
-                    .filter_map(|k| Some(k.rsplit_once('.')?.0.to_owned()))
-                    .collect();
-
-                log::trace!("Loaded LoRA weights");
-                // Return the LoRA patches
-                Ok::<_, LoadError>(LoraAdapter {
-                    scaling: lora_loader.hyperparameters.calculate_scaling(),
-                    tensors: lora_loader.tensors,
-                    tensors_to_patch,
-                    file: lora_file,
-                    path: lora_path.to_owned(),
-                })
-            })
+            .map(|lora_path| LoraAdapter::load_for_model::<M>(lora_path, &hyperparameters))
             .collect();
         lora_adapters = Some(adapters?);
     }
 
+    let use_mmap = should_use_mmap(&params, container_type);
+
+    // Tensors that are converted or patched while loading cannot be memory-mapped,
+    // so they are loaded into a separate context that owns its memory.
+    let owned_tensors: HashSet<String> = if use_mmap {
+        tensors
+            .values()
+            .filter(|ti| {
+                ti.is_legacy()
+                    || lora_adapters
+                        .iter()
+                        .flatten()
+                        .any(|adapter| adapter.tensors_to_patch.contains(&ti.name))
+            })
+            .map(|ti| ti.name.clone())
+            .collect()
+    } else {
+        HashSet::new()
+    };
+    let owned_ctx_size = tensors
+        .values()
+        .filter(|ti| owned_tensors.contains(&ti.name))
+        .map(|ti| ti.calc_absolute_size(false))
+        .sum::<usize>();
+    let ctx_size = owned_ctx_size
+        + tensors
+            .values()
+            .filter(|ti| !owned_tensors.contains(&ti.name))
+            .map(|ti| ti.calc_absolute_size(use_mmap))
+            .sum::<usize>();
+    log::trace!("Context size: {:?}", ctx_size);
+    if !owned_tensors.is_empty() {
+        log::trace!(
+            "Loading {} tensors ({owned_ctx_size} bytes) into owned memory",
+            owned_tensors.len()
+        );
+    }
+
     (load_progress_callback)(LoadProgress::ContextSize { bytes: ctx_size });
     let (context, file_size) = if use_mmap {
         let file = File::open(path)?;
@@ -542,6 +624,8 @@ pub fn load<M: KnownModel>(
     } else {
         (Context::new_with_allocate(ctx_size), file.metadata()?.len())
     };
+    let owned_context =
+        (!owned_tensors.is_empty()).then(|| Context::new_with_allocate(owned_ctx_size));
 
     let tensors_len = tensors.len();
     let tl = MmapCompatibleLoader {
@@ -549,9 +633,14 @@ pub fn load<M: KnownModel>(
         file,
         tensors,
         context,
+        owned_context,
+        owned_tensors,
         lora_adapters,
         load_progress_callback: &mut load_progress_callback,
         loaded_tensors: Default::default(),
+        cancellation,
+        container_type,
+        metal: params.use_gpu && cfg!(feature = "metal"),
     };
 
     let model = KnownModel::new(hyperparameters, params, tokenizer, tl)?;
@@ -566,10 +655,155 @@ pub fn load<M: KnownModel>(
     Ok(model)
 }
 
+/// Load only the tokenizer, hyperparameters and container type of the GGML model at
+/// `path`, without loading any of its tensors.
+///
+/// This reads the container header, the hyperparameters and the embedded vocabulary,
+/// and is much cheaper than [load] when only the tokenizer is needed (e.g. to count
+/// the tokens in a prompt). If `tokenizer_source` does not refer to the embedded
+/// vocabulary, the vocabulary is read from that source instead.
+///
+/// Unlike [load], this does not check the tensors against the architecture of `M`,
+/// so the model in `path` *must* match it.
+pub fn load_tokenizer<M: KnownModel>(
+    path: &Path,
+    tokenizer_source: TokenizerSource,
+) -> Result<(Tokenizer, M::Hyperparameters, ContainerType), LoadError> {
+    if !path.exists() {
+        return Err(LoadError::FileDoesNotExist {
+            path: path.to_owned(),
+        });
+    }
+
+    let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+        source: e,
+        path: path.to_owned(),
+    })?;
+    let mut reader = BufReader::new(&file);
+
+    let tokenizer = tokenizer_source.retrieve(path)?;
+    let mut loader: Loader<M::Hyperparameters, _> = Loader::new(tokenizer, |_| {});
+    loader.wants_tensors = false;
+
+    ggml::format::load(&mut reader, &mut loader)
+        .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+    log::trace!("Loaded tokenizer from {:?}", path);
+
+    Ok((
+        loader.tokenizer,
+        loader.hyperparameters,
+        loader.container_type,
+    ))
+}
+
+/// An estimate of the memory required to load a model and run an [InferenceSession](crate::InferenceSession)
+/// with it, as returned by [estimate_memory].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    /// The size of the model's weights, including the ggml tensor headers.
+    pub weights: usize,
+    /// Whether the weights will be memory-mapped from the model file rather than
+    /// allocated. Memory-mapped weights are backed by the operating system's page cache.
+    pub weights_mmapped: bool,
+    /// The size of the session's key/value memory.
+    pub kv_memory: usize,
+    /// The size of the session's scratch buffers.
+    pub scratch: usize,
+    /// The size of the buffer used for intermediate values during evaluation.
+    pub eval_buffer: usize,
+}
+impl MemoryEstimate {
+    /// The total number of bytes required.
+    pub fn total(&self) -> usize {
+        self.weights + self.kv_memory + self.scratch + self.eval_buffer
+    }
+}
+
+/// Estimate the memory required to load the GGML model at `path` with `params` and to
+/// run an inference session configured with `session_config`, without loading the model.
+///
+/// Only the container header, the hyperparameters and the tensor headers are read;
+/// the tensor data is skipped.
+///
+/// Unlike [load], this does not check the tensors against the architecture of `M`,
+/// so the model in `path` *must* match it.
+pub fn estimate_memory<M: KnownModel>(
+    path: &Path,
+    params: &ModelParameters,
+    session_config: &InferenceSessionConfig,
+) -> Result<MemoryEstimate, LoadError> {
+    if !path.exists() {
+        return Err(LoadError::FileDoesNotExist {
+            path: path.to_owned(),
+        });
+    }
+
+    let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+        source: e,
+        path: path.to_owned(),
+    })?;
+    let mut reader = BufReader::new(&file);
+
+    // The vocabulary is read from the file, but it is not needed for the estimate.
+    let mut loader: Loader<M::Hyperparameters, _> =
+        Loader::new(Tokenizer::empty_embedded(), |_| {});
+    ggml::format::load(&mut reader, &mut loader)
+        .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+    let info = M::info_from_hyperparameters(&loader.hyperparameters, loader.container_type);
+    let missing = |name| LoadError::MissingHyperparameter {
+        path: path.to_owned(),
+        name,
+    };
+    let n_layer = info.n_layer.ok_or_else(|| missing("n_layer"))?;
+    let n_embd = info.n_embd.ok_or_else(|| missing("n_embd"))?;
+    let weights = loader
+        .tensors
+        .values()
+        .map(|ti| ti.calc_absolute_size(false))
+        .sum::<usize>();
+
+    Ok(MemoryEstimate {
+        weights,
+        weights_mmapped: should_use_mmap(params, loader.container_type),
+        kv_memory: crate::inference_session::kv_memory_size(
+            session_config,
+            params.context_size,
+            n_layer,
+            n_embd,
+        ),
+        scratch: 2 * crate::inference_session::SCRATCH_SIZE,
+        eval_buffer: crate::inference_session::eval_buffer_size(n_layer),
+    })
+}
+
+fn should_use_mmap(params: &ModelParameters, container_type: ContainerType) -> bool {
+    params.prefer_mmap && container_type.support_mmap()
+}
+
+/// Determines the quantization version of a model's tensors.
+fn quantization_version(file_type: Option<FileType>, container_type: ContainerType) -> u32 {
+    let Some(file_type) = file_type else {
+        return ggml::QNT_VERSION;
+    };
+    if file_type.quantization_version != 0 {
+        return file_type.quantization_version;
+    }
+
+    // HACK: I think llama.cpp does not actually write the quantization version correctly,
+    // so we need to guess it from the container type.
+    match container_type {
+        ContainerType::Ggjt(2) => 1,
+        ContainerType::Ggjt(3) => 2,
+        _ => 0,
+    }
+}
+
 /// A GGML format loader for LLMs.
 pub struct Loader<Hp: Hyperparameters, F: FnMut(LoadProgress)> {
     // Input
     load_progress_callback: F,
+    pub(crate) wants_tensors: bool,
 
     // Input/Output
     /// The tokenizer of the model.
@@ -580,6 +814,8 @@ pub struct Loader<Hp: Hyperparameters, F: FnMut(LoadProgress)> {
     pub container_type: ContainerType,
     /// The hyperparameters of the model.
     pub hyperparameters: Hp,
+    /// The quantization version of the model's tensors.
+    pub quantization_version: u32,
     /// The tensors of the model.
     pub tensors: HashMap<String, TensorLoadInfo>,
 }
@@ -588,9 +824,11 @@ impl<Hp: Hyperparameters, F: FnMut(LoadProgress)> Loader<Hp, F> {
     pub fn new(tokenizer: Tokenizer, load_progress_callback: F) -> Self {
         Self {
             load_progress_callback,
+            wants_tensors: true,
 
             container_type: ContainerType::Ggml,
             hyperparameters: Hp::default(),
+            quantization_version: ggml::QNT_VERSION,
             tokenizer,
             tensors: HashMap::default(),
         }
@@ -623,8 +861,11 @@ impl<Hp: Hyperparameters, F: FnMut(LoadProgress)> ggml::format::LoadHandler<Load
     ) -> Result<PartialHyperparameters, LoadError> {
         // NOTE: Field order matters! Data is laid out in the file exactly in this order.
         let hyperparameters = Hp::read_ggml(reader)?;
+        self.quantization_version =
+            quantization_version(hyperparameters.file_type(), self.container_type);
         let partial = PartialHyperparameters {
             n_vocab: hyperparameters.n_vocabulary(),
+            quantization_version: self.quantization_version,
         };
         self.hyperparameters = hyperparameters;
         (self.load_progress_callback)(LoadProgress::HyperparametersLoaded);
@@ -636,6 +877,10 @@ impl<Hp: Hyperparameters, F: FnMut(LoadProgress)> ggml::format::LoadHandler<Load
         self.tensors.insert(info.name.clone(), info);
         Ok(())
     }
+
+    fn wants_tensors(&self) -> bool {
+        self.wants_tensors
+    }
 }
 
 struct MmapCompatibleLoader<'a> {
@@ -643,18 +888,37 @@ struct MmapCompatibleLoader<'a> {
     file: File,
     tensors: HashMap<String, TensorLoadInfo>,
     context: Context,
+    /// Holds the tensors in `owned_tensors` when `context` is memory-mapped.
+    owned_context: Option<Context>,
+    owned_tensors: HashSet<String>,
     lora_adapters: Option<Vec<LoraAdapter>>,
     load_progress_callback: &'a mut dyn FnMut(LoadProgress),
     loaded_tensors: HashMap<String, ggml::Tensor>,
+    cancellation: Option<CancellationToken>,
+    container_type: ContainerType,
+    /// Whether the model is evaluated with Metal, which cannot see patched weights.
+    metal: bool,
 }
 impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
-    fn load(&mut self, name: &str) -> Result<ggml::Tensor, LoadError> {
+    fn load(&mut self, name: &str, ne: &[usize]) -> Result<ggml::Tensor, LoadError> {
+        check_cancellation(self.cancellation.as_ref())?;
+
         let info = self.tensors.get(name).ok_or(LoadError::UnknownTensor {
             tensor_name: String::from(name),
             path: Default::default(),
         })?;
+        if info.dims() != ne {
+            return Err(LoadError::TensorWrongSize {
+                tensor_name: String::from(name),
+                path: self.path.clone(),
+            });
+        }
 
-        let mut main_context = FileContext::new(&self.context, &mut self.file, &self.path);
+        let context = match &self.owned_context {
+            Some(owned_context) if self.owned_tensors.contains(name) => owned_context,
+            _ => &self.context,
+        };
+        let mut main_context = FileContext::new(context, &mut self.file, &self.path);
 
         let mut tensor = main_context.get_tensor(info)?;
 
@@ -677,11 +941,36 @@ impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
         Ok(tensor)
     }
 
-    fn finish(self) -> ModelContext {
+    fn load_optional(
+        &mut self,
+        name: &str,
+        ne: &[usize],
+    ) -> Result<Option<ggml::Tensor>, LoadError> {
+        if !self.tensors.contains_key(name) {
+            return Ok(None);
+        }
+        self.load(name, ne).map(Some)
+    }
+
+    fn finish(mut self) -> ModelContext {
+        let lora_tensors = self
+            .loaded_tensors
+            .into_iter()
+            .map(|(name, tensor)| {
+                let info = self.tensors.remove(&name).unwrap();
+                (name, (info, tensor))
+            })
+            .collect();
+
         // We can ignore this warning as it's OK to share this particular
         // context around, being that it is immutable.
         #[allow(clippy::arc_with_non_send_sync)]
-        ModelContext(Arc::new(self.context))
+        ModelContext {
+            context: Arc::new(self.context),
+            owned_context: self.owned_context.map(Arc::new),
+            container_type: self.container_type,
+            lora: Arc::new(RwLock::new(LoraState::new(lora_tensors, self.metal))),
+        }
     }
 }
 
@@ -720,21 +1009,40 @@ impl<'a> FileContext<'a> {
             3 => self
                 .context
                 .new_tensor_3d(info.element_type, ne[0], ne[1], ne[2]),
+            4 => self
+                .context
+                .new_tensor_4d(info.element_type, ne[0], ne[1], ne[2], ne[3]),
             _ => {
                 return Err(LoadError::InvariantBroken {
                     path: Some(self.path.to_owned()),
                     invariant: format!(
-                        "the tensor {name} should have between 1 and 3 dimensions, not {dims}"
+                        "the tensor {name} should have between 1 and {} dimensions, not {dims}",
+                        ggml::MAX_DIMS
                     ),
                 })
             }
         };
 
         match self.context.storage().as_mmap() {
+            Some(_) if info.is_legacy() => {
+                return Err(LoadError::InvariantBroken {
+                    path: Some(self.path.to_owned()),
+                    invariant: format!(
+                        "the tensor {name} uses a legacy block layout and cannot be memory-mapped"
+                    ),
+                });
+            }
             Some(mmap) => unsafe {
                 let ptr = mmap.as_ptr().offset(info.start_offset as isize);
                 tensor.set_data(ptr as *mut std::ffi::c_void);
             },
+            None if info.is_legacy() => {
+                let data = info.read_data(&mut BufReader::new(&mut *self.file))?;
+                let buf: &mut [u8] = unsafe {
+                    std::slice::from_raw_parts_mut(tensor.data() as *mut u8, tensor.nbytes())
+                };
+                buf.copy_from_slice(&data);
+            }
             None => {
                 let buf: &mut [u8] = unsafe {
                     std::slice::from_raw_parts_mut(tensor.data() as *mut u8, tensor.nbytes())
@@ -744,14 +1052,175 @@ impl<'a> FileContext<'a> {
             }
         }
 
-        // The tensor name is truncated to its maximum length.
-        let tensor_name = if name.len() >= MAX_NAME_LENGTH {
-            &name[name.len() - MAX_NAME_LENGTH..]
-        } else {
-            name
-        };
+        Ok(tensor.set_name(truncated_tensor_name(name)))
+    }
+}
+
+/// The tensor name is truncated to its maximum length.
+pub(crate) fn truncated_tensor_name(name: &str) -> &str {
+    if name.len() >= MAX_NAME_LENGTH {
+        &name[name.len() - MAX_NAME_LENGTH..]
+    } else {
+        name
+    }
+}
+
+/// Checks that the tensors requested by `M` match the tensors in the file, without loading
+/// any tensor data.
+fn validate_tensors<M: KnownModel>(
+    path: &Path,
+    hyperparameters: &M::Hyperparameters,
+    params: &ModelParameters,
+    tensors: &HashMap<String, TensorLoadInfo>,
+) -> Result<(), LoadError> {
+    let unused_tensors = unused_tensors::<M>(path, hyperparameters, params, tensors)?;
+    if !unused_tensors.is_empty() {
+        log::warn!(
+            "The following tensors in {path:?} are not used by the model: {unused_tensors:?}"
+        );
+    }
+    Ok(())
+}
 
-        Ok(tensor.set_name(tensor_name))
+/// Returns the sorted names of the `tensors` that `M` does not use, or an error if `M`
+/// requires tensors that are not among them or that have a different shape, without
+/// loading any tensor data.
+///
+/// The model is constructed once against a placeholder tensor, recording every tensor it
+/// requests, so that all of the mismatches are reported at once.
+pub(crate) fn unused_tensors<M: KnownModel>(
+    path: &Path,
+    hyperparameters: &M::Hyperparameters,
+    params: &ModelParameters,
+    tensors: &HashMap<String, TensorLoadInfo>,
+) -> Result<Vec<String>, LoadError> {
+    let params = ModelParameters {
+        use_gpu: false,
+        lora_adapters: None,
+        cancellation: None,
+        ..params.clone()
+    };
+
+    let mut validation = TensorValidation::default();
+    M::new(
+        hyperparameters.clone(),
+        params,
+        Tokenizer::empty_embedded(),
+        ValidationLoader::new(tensors, &mut validation),
+    )?;
+
+    let TensorValidation {
+        requested_tensors,
+        mut missing_tensors,
+        mut mismatched_tensors,
+    } = validation;
+
+    let mut unexpected_tensors: Vec<_> = tensors
+        .keys()
+        .filter(|name| !requested_tensors.contains(*name))
+        .cloned()
+        .collect();
+    unexpected_tensors.sort();
+
+    if missing_tensors.is_empty() && mismatched_tensors.is_empty() {
+        return Ok(unexpected_tensors);
+    }
+
+    missing_tensors.sort();
+    mismatched_tensors.sort_by(|a, b| a.tensor_name.cmp(&b.tensor_name));
+    Err(LoadError::ArchitectureMismatch {
+        path: path.to_owned(),
+        expected: std::any::type_name::<M>().to_owned(),
+        missing_tensors,
+        unexpected_tensors,
+        mismatched_tensors,
+    })
+}
+
+/// The tensors recorded by a [ValidationLoader].
+#[derive(Default)]
+struct TensorValidation {
+    requested_tensors: HashSet<String>,
+    missing_tensors: Vec<String>,
+    mismatched_tensors: Vec<TensorShapeMismatch>,
+}
+
+/// A [TensorLoader] that compares every requested tensor against the tensors in the file,
+/// handing out the same one-element placeholder for each of them.
+struct ValidationLoader<'a> {
+    tensors: &'a HashMap<String, TensorLoadInfo>,
+    validation: &'a mut TensorValidation,
+    context: Context,
+    placeholder: ggml::Tensor,
+}
+impl<'a> ValidationLoader<'a> {
+    fn new(
+        tensors: &'a HashMap<String, TensorLoadInfo>,
+        validation: &'a mut TensorValidation,
+    ) -> Self {
+        // The tensor and object headers, the data and alignment padding of the placeholder.
+        let context =
+            Context::new_with_allocate(ggml::Tensor::C_TYPE_SIZE + ggml::OBJECT_SIZE + 32);
+        let placeholder = context.new_tensor_1d(ggml::Type::F32, 1);
+        Self {
+            tensors,
+            validation,
+            context,
+            placeholder,
+        }
+    }
+}
+impl TensorLoader<LoadError> for ValidationLoader<'_> {
+    fn load(&mut self, name: &str, ne: &[usize]) -> Result<ggml::Tensor, LoadError> {
+        if !self.validation.requested_tensors.insert(name.to_owned()) {
+            return Ok(self.placeholder.share());
+        }
+
+        match self.tensors.get(name) {
+            Some(info) if info.dims() != ne => {
+                self.validation
+                    .mismatched_tensors
+                    .push(TensorShapeMismatch {
+                        tensor_name: name.to_owned(),
+                        expected: ne.to_vec(),
+                        actual: info.dims().to_vec(),
+                    })
+            }
+            Some(_) => {}
+            None => self.validation.missing_tensors.push(name.to_owned()),
+        }
+
+        Ok(self.placeholder.share())
+    }
+
+    fn load_optional(
+        &mut self,
+        name: &str,
+        ne: &[usize],
+    ) -> Result<Option<ggml::Tensor>, LoadError> {
+        if !self.tensors.contains_key(name) {
+            return Ok(None);
+        }
+        self.load(name, ne).map(Some)
+    }
+
+    fn finish(self) -> ModelContext {
+        // We can ignore this warning as it's OK to share this particular
+        // context around, being that it is immutable.
+        #[allow(clippy::arc_with_non_send_sync)]
+        ModelContext {
+            context: Arc::new(self.context),
+            owned_context: None,
+            container_type: ContainerType::Ggml,
+            lora: Default::default(),
+        }
+    }
+}
+
+fn check_cancellation(cancellation: Option<&CancellationToken>) -> Result<(), LoadError> {
+    match cancellation {
+        Some(token) if token.is_cancelled() => Err(LoadError::Cancelled),
+        _ => Ok(()),
     }
 }
 
@@ -793,3 +1262,138 @@ pub fn load_progress_callback_stdout(progress: LoadProgress) {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn cancelled_load_fails() {
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+        let result = load_random_model(
+            "cancelled-load",
+            ModelParameters {
+                cancellation: Some(cancellation),
+                ..Default::default()
+            },
+        );
+
+        assert!(matches!(result, Err(LoadError::Cancelled)));
+    }
+
+    #[test]
+    fn can_load_tokenizer_only() {
+        let path = temp_path("tokenizer-only.bin");
+        write_random_model(&path, test_hyperparameters());
+        let loaded = load_tokenizer::<TestModel>(&path, TokenizerSource::Embedded);
+        std::fs::remove_file(&path).unwrap();
+        let (tokenizer, hyperparameters, container_type) = loaded.unwrap();
+
+        assert_eq!(hyperparameters, test_hyperparameters());
+        assert_eq!(container_type, ContainerType::Ggjt(3));
+        assert_eq!(tokenizer.len(), N_VOCAB);
+        assert_eq!(tokenizer.token(3), b"<3>");
+    }
+
+    #[test]
+    fn can_estimate_memory() {
+        let path = temp_path("estimate-memory.bin");
+        write_random_model(&path, test_hyperparameters());
+        let params = ModelParameters {
+            prefer_mmap: false,
+            context_size: 32,
+            ..Default::default()
+        };
+        let session_config = InferenceSessionConfig::default();
+
+        let estimate = estimate_memory::<TestModel>(&path, &params, &session_config);
+        let mut context_size = None;
+        let model = load::<TestModel>(&path, TokenizerSource::Embedded, params, |p| {
+            if let LoadProgress::ContextSize { bytes } = p {
+                context_size = Some(bytes);
+            }
+        });
+        std::fs::remove_file(&path).unwrap();
+        let estimate = estimate.unwrap();
+        model.unwrap();
+
+        assert_eq!(Some(estimate.weights), context_size);
+        assert!(!estimate.weights_mmapped);
+        // Two f16 tensors of n_layer * n_ctx * n_embd elements, plus object overhead.
+        assert_eq!(estimate.kv_memory, 2 * 2 * 2 * 32 * 32 + (5 + 10 * 2) * 256);
+        assert_eq!(
+            estimate.total(),
+            estimate.weights + estimate.kv_memory + estimate.scratch + estimate.eval_buffer
+        );
+    }
+
+    #[test]
+    fn mismatched_tensors_fail_to_load() {
+        let path = temp_path("mismatched-tensors.bin");
+        let mut tensors = model_tensors(test_hyperparameters());
+        tensors.retain(|(name, _)| name != "norm" && name != "layers.1.w2");
+        tensors.push(("transformer.word_embeddings.weight".to_string(), vec![32]));
+        write_model(&path, test_hyperparameters(), tensors);
+        let result = load_model(&path, ModelParameters::default());
+        std::fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(LoadError::ArchitectureMismatch {
+                missing_tensors,
+                unexpected_tensors,
+                ..
+            }) => {
+                assert_eq!(missing_tensors, ["layers.1.w2", "norm"]);
+                assert_eq!(unexpected_tensors, ["transformer.word_embeddings.weight"]);
+            }
+            Err(err) => panic!("unexpected error: {err}"),
+            Ok(_) => panic!("the model should not have loaded"),
+        }
+    }
+
+    #[test]
+    fn wrong_shaped_tensors_fail_to_load() {
+        let path = temp_path("wrong-shaped-tensors.bin");
+        let mut tensors = model_tensors(test_hyperparameters());
+        for (name, dims) in &mut tensors {
+            if name == "norm" || name == "layers.0.w1" {
+                dims.reverse();
+                dims.push(2);
+            }
+        }
+        write_model(&path, test_hyperparameters(), tensors);
+        let result = load_model(&path, ModelParameters::default());
+        std::fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(LoadError::ArchitectureMismatch {
+                missing_tensors,
+                unexpected_tensors,
+                mismatched_tensors,
+                ..
+            }) => {
+                assert!(missing_tensors.is_empty());
+                assert!(unexpected_tensors.is_empty());
+                assert_eq!(
+                    mismatched_tensors,
+                    [
+                        TensorShapeMismatch {
+                            tensor_name: "layers.0.w1".to_string(),
+                            expected: vec![32, 128],
+                            actual: vec![128, 32, 2],
+                        },
+                        TensorShapeMismatch {
+                            tensor_name: "norm".to_string(),
+                            expected: vec![32],
+                            actual: vec![32, 2],
+                        },
+                    ]
+                );
+            }
+            Err(err) => panic!("unexpected error: {err}"),
+            Ok(_) => panic!("the model should not have loaded"),
+        }
+    }
+}
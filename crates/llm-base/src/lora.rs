@@ -1,14 +1,21 @@
 use crate::{
-    loader::FileContext, model::HyperparametersWriteError, util, FileType, Hyperparameters,
-    LoadError,
+    loader::truncated_tensor_name,
+    model::HyperparametersWriteError,
+    util::{self, to_f32, transpose, unpermute_heads},
+    FileType, Hyperparameters, KnownModel, LoadError, Loader, Tokenizer,
 };
 
-use ggml::{format::TensorLoadInfo, GraphExecutionPlan};
+use ggml::{accelerator::Backend, format::TensorLoadInfo, GraphExecutionPlan, MAX_NAME_LENGTH};
+use memmap2::Mmap;
+use safetensors::SafeTensors;
 use std::{
     collections::{HashMap, HashSet},
     fs::File,
-    path::PathBuf,
+    io::BufReader,
+    path::{Path, PathBuf},
 };
+use thiserror::Error;
+use tracing::log;
 
 #[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
 /// Parameters for a [LoRA](https://arxiv.org/abs/2106.09685) adapter.
@@ -64,9 +71,187 @@ pub struct LoraAdapter {
     pub file: File,
     /// Path to the LoRA file.
     pub path: PathBuf,
+    /// The data of tensors that had to be converted while loading, which is used
+    /// instead of reading them from [LoraAdapter::file].
+    converted: HashMap<String, Vec<u8>>,
 }
 
 impl LoraAdapter {
+    /// Load the GGLA LoRA adapter at `path`.
+    ///
+    /// Only the names and locations of its tensors are read; the tensors themselves
+    /// are read when they are used to patch a model.
+    ///
+    /// Adapters trained with PEFT can be loaded with [LoraAdapter::load_peft].
+    pub fn load(path: &Path) -> Result<Self, LoadError> {
+        // Read the LoRA file
+        let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: path.to_owned(),
+        })?;
+        let mut reader = BufReader::new(&file);
+        // TODO: Consider updating the progress callback to report the progress of the LoRA file.
+        // Most LoRAs are small enough that this is not necessary, but it would be nice to have.
+        let mut loader: Loader<LoraParameters, _> =
+            Loader::new(Tokenizer::empty_embedded(), |_| {});
+        ggml::format::load(&mut reader, &mut loader)
+            .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+        // Collect the names of the tensors that should be patched
+        let tensors_to_patch = loader
+            .tensors
+            .keys()
+            .filter_map(|k| Some(k.rsplit_once('.')?.0.to_owned()))
+            .collect();
+
+        log::trace!("Loaded LoRA weights");
+        // Return the LoRA patches
+        Ok(LoraAdapter {
+            scaling: loader.hyperparameters.calculate_scaling(),
+            tensors: loader.tensors,
+            tensors_to_patch,
+            file,
+            path: path.to_owned(),
+            converted: HashMap::new(),
+        })
+    }
+
+    /// Load a LoRA adapter trained with [PEFT](https://github.com/huggingface/peft) for a
+    /// model of type `M` with the given `hyperparameters`.
+    ///
+    /// `path` is either the directory containing `adapter_model.safetensors` and
+    /// `adapter_config.json`, or the path to the former. The adapted weights are mapped onto
+    /// the tensors of the model with [KnownModel::hf_tensor_name], and the adapter's tensors
+    /// are converted to the layout of GGLA adapters as they are loaded.
+    pub fn load_peft<M: KnownModel>(
+        path: &Path,
+        hyperparameters: &M::Hyperparameters,
+    ) -> Result<Self, LoadError> {
+        let (weights_path, config_path) = if path.is_dir() {
+            (
+                path.join("adapter_model.safetensors"),
+                path.join("adapter_config.json"),
+            )
+        } else {
+            let parent = path.parent().ok_or_else(|| LoadError::NoParentPath {
+                path: path.to_owned(),
+            })?;
+            (path.to_owned(), parent.join("adapter_config.json"))
+        };
+
+        #[derive(serde::Deserialize)]
+        struct PeftConfig {
+            r: usize,
+            lora_alpha: f32,
+        }
+        let config = std::fs::read(&config_path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: config_path.clone(),
+        })?;
+        let config: PeftConfig =
+            serde_json::from_slice(&config).map_err(|source| LoadError::InvalidConfig {
+                path: config_path,
+                source,
+            })?;
+
+        let file = File::open(&weights_path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: weights_path.clone(),
+        })?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let safetensors =
+            SafeTensors::deserialize(&mmap).map_err(|source| LoadError::InvalidSafetensors {
+                path: weights_path.clone(),
+                source,
+            })?;
+
+        let mut tensors = HashMap::new();
+        let mut tensors_to_patch = HashSet::new();
+        let mut converted = HashMap::new();
+        for (hf_name, view) in safetensors.tensors() {
+            let unknown_tensor = || LoadError::UnknownTensor {
+                tensor_name: hf_name.clone(),
+                path: weights_path.clone(),
+            };
+
+            // e.g. `base_model.model.model.layers.0.self_attn.q_proj.lora_A.weight`
+            let (module, matrix) = hf_name
+                .strip_prefix("base_model.model.")
+                .and_then(|name| name.strip_suffix(".weight"))
+                .and_then(|name| name.rsplit_once('.'))
+                .ok_or_else(unknown_tensor)?;
+            let name = M::hf_tensor_name(&format!("{module}.weight")).ok_or_else(unknown_tensor)?;
+
+            let &[rows, columns] = view.shape() else {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name: hf_name,
+                    path: weights_path,
+                });
+            };
+            let data =
+                to_f32(view.dtype(), view.data()).ok_or_else(|| LoadError::UnsupportedDtype {
+                    tensor_name: hf_name.clone(),
+                    dtype: format!("{:?}", view.dtype()),
+                    path: weights_path.clone(),
+                })?;
+
+            // GGLA adapters store `A` as `n_in` rows of `rank` values and `B` as `n_out`
+            // rows of `rank` values, while PEFT stores `A` as `rank` rows of `n_in` values.
+            let (suffix, data, n) = match matrix {
+                "lora_A" => ("loraA", transpose(&data, rows, columns), columns),
+                "lora_B" => match M::hf_permuted_heads(hyperparameters, &name) {
+                    Some(n_head) => ("loraB", unpermute_heads(&data, rows, n_head), rows),
+                    None => ("loraB", data, rows),
+                },
+                _ => return Err(unknown_tensor()),
+            };
+            let rank = data.len() / n;
+
+            let tensor_name = format!("{name}.{suffix}");
+            tensors.insert(
+                tensor_name.clone(),
+                TensorLoadInfo {
+                    name: tensor_name.clone(),
+                    n_dims: 2,
+                    dims: [rank, n, 1, 1],
+                    n_elements: data.len(),
+                    element_type: ggml::Type::F32,
+                    start_offset: 0,
+                    quantization_version: ggml::QNT_VERSION,
+                },
+            );
+            converted.insert(tensor_name, bytemuck::cast_slice(&data).to_vec());
+            tensors_to_patch.insert(name);
+        }
+
+        log::trace!("Loaded PEFT LoRA weights");
+        Ok(LoraAdapter {
+            scaling: config.lora_alpha / config.r as f32,
+            tensors,
+            tensors_to_patch,
+            file,
+            path: path.to_owned(),
+            converted,
+        })
+    }
+
+    /// Load the adapter at `path` for a model of type `M`, with [LoraAdapter::load_peft] if it
+    /// is a PEFT adapter and [LoraAdapter::load] otherwise.
+    pub(crate) fn load_for_model<M: KnownModel>(
+        path: &Path,
+        hyperparameters: &M::Hyperparameters,
+    ) -> Result<Self, LoadError> {
+        let is_peft = path.is_dir()
+            || path
+                .extension()
+                .map_or(false, |extension| extension == "safetensors");
+        if is_peft {
+            Self::load_peft::<M>(path, hyperparameters)
+        } else {
+            Self::load(path)
+        }
+    }
+
     /// Patch a tensor via LoRA
     pub fn patch(
         &mut self,
@@ -100,17 +285,20 @@ impl LoraAdapter {
             patch_context_size += scaled_size + ba_size;
         }
 
+        // 3c. The output of the patch has the same dimensions and element type as the original tensor,
+        // and the compute graph is allocated in the context as well.
+        patch_context_size += info.calc_absolute_size(false) + ggml::graph_overhead();
+
         // 4. Add 5% as ggml overhead (I dont know why this is needed but the calculation is always a few 100-1000 bytes off)
         patch_context_size = patch_context_size + (patch_context_size / 20);
 
         // Create a temporary context for the patching operations
         // TODO: test if GPU can be enabled (make it configurable)
         let patch_context = ggml::Context::new_with_allocate(patch_context_size);
-        let mut patch_file = FileContext::new(&patch_context, &mut self.file, &self.path);
 
         // Load the A and B tensors
-        let a = patch_file.get_tensor(&a_info)?;
-        let b = patch_file.get_tensor(&b_info)?;
+        let a = self.load_tensor(&patch_context, &a_info)?;
+        let b = self.load_tensor(&patch_context, &b_info)?;
 
         //Build a ggml context and apply the patch
 
@@ -151,3 +339,498 @@ impl LoraAdapter {
             })
     }
 }
+
+/// Identifies a LoRA adapter that was attached to a model at runtime.
+///
+/// Returned by [Model::attach_lora](crate::Model::attach_lora).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LoraAdapterId(pub(crate) usize);
+
+#[derive(Error, Debug)]
+/// Errors encountered while attaching or detaching a LoRA adapter at runtime.
+pub enum LoraError {
+    #[error("no LoRA adapter with id {0:?} is attached")]
+    /// The adapter is not attached to the model.
+    NotAttached(LoraAdapterId),
+    #[error("the LoRA adapter {path:?} patches `{tensor_name}`, which is not in the model")]
+    /// The adapter patches a tensor that the model does not have.
+    UnknownTensor {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The path of the adapter.
+        path: PathBuf,
+    },
+    #[error(
+        "the tensor `{tensor_name}` has been offloaded to an accelerator and cannot be patched"
+    )]
+    /// The adapter patches a tensor that is not in main memory.
+    TensorOffloaded {
+        /// The name of the tensor.
+        tensor_name: String,
+    },
+    #[error("failed to patch the model")]
+    /// Reading or applying the adapter failed.
+    PatchFailed(#[from] LoadError),
+    #[error("LoRA adapters cannot be attached at runtime when evaluating with Metal")]
+    /// Metal evaluates the weights in the buffers it was given when the model was loaded,
+    /// so it would not see the patched weights, and does not support the low-rank path of
+    /// adapters attached to a session.
+    UnsupportedOnMetal,
+    #[error("the model does not support attaching LoRA adapters at runtime")]
+    /// The model does not expose its [ModelContext](crate::ModelContext) (see
+    /// [KnownModel::model_context](crate::KnownModel::model_context)).
+    Unsupported,
+}
+
+/// The weights of a model that LoRA adapters can be attached to at runtime, and the
+/// adapters that are currently attached.
+#[derive(Default)]
+pub(crate) struct LoraState {
+    tensors: HashMap<String, (TensorLoadInfo, ggml::Tensor)>,
+    attached: Vec<(LoraAdapterId, LoraAdapter)>,
+    patched: HashMap<String, PatchedTensor>,
+    next_id: usize,
+    metal: bool,
+}
+
+/// A tensor whose data has been redirected to a buffer that holds its patched weights.
+///
+/// The original data is never written to, so that it can be restored exactly; this also
+/// allows memory-mapped tensors to be patched.
+struct PatchedTensor {
+    original: *mut std::ffi::c_void,
+    // `u64` keeps the buffer aligned for every element type.
+    buffer: Vec<u64>,
+}
+
+impl LoraState {
+    /// Tracks the adapters attached to the `tensors` of a model, which is evaluated with
+    /// Metal if `metal` is set.
+    pub(crate) fn new(
+        tensors: HashMap<String, (TensorLoadInfo, ggml::Tensor)>,
+        metal: bool,
+    ) -> Self {
+        Self {
+            tensors,
+            metal,
+            ..Default::default()
+        }
+    }
+
+    /// Patches the model's tensors with `adapter` and adds it to the attached adapters.
+    pub(crate) fn attach(&mut self, mut adapter: LoraAdapter) -> Result<LoraAdapterId, LoraError> {
+        if self.metal {
+            return Err(LoraError::UnsupportedOnMetal);
+        }
+        let mut names: Vec<_> = adapter.tensors_to_patch.iter().cloned().collect();
+        names.sort();
+        for name in &names {
+            match self.tensors.get(name) {
+                None => {
+                    return Err(LoraError::UnknownTensor {
+                        tensor_name: name.clone(),
+                        path: adapter.path.clone(),
+                    })
+                }
+                Some((_, tensor)) if tensor.backend() != Backend::Cpu => {
+                    return Err(LoraError::TensorOffloaded {
+                        tensor_name: name.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+
+        for (i, name) in names.iter().enumerate() {
+            let (info, tensor) = self.tensors.get_mut(name).unwrap();
+            if !self.patched.contains_key(name) {
+                let patched = unsafe { PatchedTensor::redirect(tensor) };
+                self.patched.insert(name.clone(), patched);
+            }
+
+            if let Err(err) = adapter.patch(info, tensor) {
+                // Undo the tensors that have been patched so far.
+                self.restore(&names[..=i])?;
+                return Err(err.into());
+            }
+        }
+
+        let id = LoraAdapterId(self.next_id);
+        self.next_id += 1;
+        self.attached.push((id, adapter));
+        Ok(id)
+    }
+
+    /// Removes the adapter with `id` and restores the tensors it patched.
+    pub(crate) fn detach(&mut self, id: LoraAdapterId) -> Result<LoraAdapter, LoraError> {
+        let index = self
+            .attached
+            .iter()
+            .position(|(attached_id, _)| *attached_id == id)
+            .ok_or(LoraError::NotAttached(id))?;
+        let (_, adapter) = self.attached.remove(index);
+
+        let names: Vec<_> = adapter.tensors_to_patch.iter().cloned().collect();
+        self.restore(&names)?;
+        Ok(adapter)
+    }
+
+    /// Resets the tensors in `names` to their original weights, and re-applies the adapters
+    /// that are still attached to them.
+    fn restore(&mut self, names: &[String]) -> Result<(), LoraError> {
+        for name in names {
+            let Some(patched) = self.patched.get_mut(name) else {
+                continue;
+            };
+            let (info, tensor) = self.tensors.get_mut(name).unwrap();
+
+            let remaining: Vec<_> = self
+                .attached
+                .iter_mut()
+                .map(|(_, adapter)| adapter)
+                .filter(|adapter| adapter.tensors_to_patch.contains(name))
+                .collect();
+            if remaining.is_empty() {
+                unsafe { tensor.set_data(patched.original) };
+                self.patched.remove(name);
+                continue;
+            }
+
+            unsafe { patched.reset(tensor) };
+            for adapter in remaining {
+                adapter.patch(info, tensor)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PatchedTensor {
+    /// Copies the data of `tensor` into a new buffer, and points `tensor` at it.
+    ///
+    /// # Safety
+    ///
+    /// `tensor` must be in main memory.
+    unsafe fn redirect(tensor: &mut ggml::Tensor) -> Self {
+        let nbytes = tensor.nbytes();
+        let mut patched = Self {
+            original: tensor.data(),
+            buffer: vec![0; nbytes / 8 + 1],
+        };
+        patched.reset(tensor);
+        tensor.set_data(patched.buffer.as_mut_ptr().cast());
+        patched
+    }
+
+    /// Copies the original data of `tensor` into the buffer.
+    ///
+    /// # Safety
+    ///
+    /// `tensor` must be the tensor this was created for.
+    unsafe fn reset(&mut self, tensor: &ggml::Tensor) {
+        std::ptr::copy_nonoverlapping(
+            self.original as *const u8,
+            self.buffer.as_mut_ptr().cast::<u8>(),
+            tensor.nbytes(),
+        );
+    }
+}
+
+/// A LoRA adapter that is applied by a single [InferenceSession](crate::InferenceSession)
+/// as an extra low-rank path next to the weights it patches, rather than by patching them.
+pub(crate) struct SessionLoraAdapter {
+    // Must be kept alive for the tensors
+    _context: ggml::Context,
+    scaling: f32,
+    /// For each patched weight, keyed by its name in the model's context, the transposed
+    /// `A` tensor and the `B` tensor as `f32`.
+    tensors: HashMap<String, (ggml::Tensor, ggml::Tensor)>,
+}
+impl SessionLoraAdapter {
+    pub(crate) fn new(adapter: &LoraAdapter) -> Result<Self, LoraError> {
+        let mut names: Vec<_> = adapter.tensors_to_patch.iter().cloned().collect();
+        names.sort();
+
+        let mut pairs = vec![];
+        for name in names {
+            let a_info = adapter.get_info(&format!("{name}.loraA"))?;
+            let b_info = adapter.get_info(&format!("{name}.loraB"))?;
+            let (a_dims, b_dims) = (a_info.dims(), b_info.dims());
+            if a_dims.len() != 2 || b_dims.len() != 2 || a_dims[0] != b_dims[0] {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name: name,
+                    path: adapter.path.clone(),
+                }
+                .into());
+            }
+
+            let a = adapter.read_f32(&a_info)?;
+            let b = adapter.read_f32(&b_info)?;
+            pairs.push((name, a_info.dims[0], a_info.dims[1], a, b));
+        }
+
+        let context_size = pairs
+            .iter()
+            .map(|(_, _, _, a, b)| {
+                // Leave room for alignment padding.
+                ggml::format::tensor_size(ggml::Type::F32, a.len())
+                    + ggml::format::tensor_size(ggml::Type::F32, b.len())
+                    + 64
+            })
+            .sum::<usize>()
+            + 64;
+        let context = ggml::Context::new_with_allocate(context_size);
+
+        let mut tensors = HashMap::new();
+        for (name, rank, n_in, a, b) in pairs {
+            let n_out = b.len() / rank;
+
+            // `A` has `n_in` rows of `rank` values. The low-rank path needs its transpose, so
+            // that the input can be multiplied by it with `op_mul_mat`.
+            let a_transposed = transpose(&a, n_in, rank);
+            let mut a_tensor = context.new_tensor_2d(ggml::Type::F32, n_in, rank);
+            let mut b_tensor = context.new_tensor_2d(ggml::Type::F32, rank, n_out);
+            unsafe {
+                a_tensor.write_data(bytemuck::cast_slice(&a_transposed));
+                b_tensor.write_data(bytemuck::cast_slice(&b));
+            }
+
+            tensors.insert(context_tensor_name(&name), (a_tensor, b_tensor));
+        }
+
+        Ok(Self {
+            _context: context,
+            scaling: adapter.scaling,
+            tensors,
+        })
+    }
+
+    /// Adds the low-rank path of this adapter for `weight` to `output`, the result of
+    /// multiplying `weight` by `input`.
+    pub(crate) fn apply(
+        &self,
+        ctx0: &ggml::Context,
+        weight: &ggml::Tensor,
+        input: &ggml::Tensor,
+        output: ggml::Tensor,
+    ) -> ggml::Tensor {
+        let Some((a, b)) = self.tensors.get(&weight.name()) else {
+            return output;
+        };
+
+        let low_rank = ctx0.op_mul_mat(b, &ctx0.op_mul_mat(a, input));
+        let low_rank = ctx0.op_scale(&low_rank, &ctx0.new_f32(self.scaling));
+        ctx0.op_add(&output, &low_rank)
+    }
+}
+
+impl LoraAdapter {
+    /// Reads the data of a tensor of the adapter.
+    fn read_data(&self, info: &TensorLoadInfo) -> Result<Vec<u8>, LoadError> {
+        match self.converted.get(&info.name) {
+            Some(data) => Ok(data.clone()),
+            None => Ok(info.read_data(&mut BufReader::new(&self.file))?),
+        }
+    }
+
+    /// Creates a tensor of the adapter in `context`.
+    fn load_tensor(
+        &self,
+        context: &ggml::Context,
+        info: &TensorLoadInfo,
+    ) -> Result<ggml::Tensor, LoadError> {
+        let &[ne0, ne1] = info.dims() else {
+            return Err(LoadError::TensorWrongSize {
+                tensor_name: info.name.clone(),
+                path: self.path.clone(),
+            });
+        };
+        let mut tensor = context.new_tensor_2d(info.element_type, ne0, ne1);
+        let data = self.read_data(info)?;
+        unsafe { tensor.write_data(&data) };
+        Ok(tensor.set_name(truncated_tensor_name(&info.name)))
+    }
+
+    /// Reads a tensor of the adapter as `f32` values.
+    fn read_f32(&self, info: &TensorLoadInfo) -> Result<Vec<f32>, LoadError> {
+        let data = self.read_data(info)?;
+        match info.element_type {
+            ggml::Type::F32 => Ok(data
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect()),
+            ggml::Type::F16 => Ok(data
+                .chunks_exact(2)
+                .map(|chunk| half::f16::from_le_bytes(chunk.try_into().unwrap()).to_f32())
+                .collect()),
+            element_type => Err(LoadError::UnsupportedElementType {
+                tensor_name: info.name.clone(),
+                ftype: element_type.into(),
+                path: self.path.clone(),
+            }),
+        }
+    }
+}
+
+/// The name a tensor loaded as `name` has in its context, which is limited to
+/// one less than [MAX_NAME_LENGTH] bytes.
+fn context_tensor_name(name: &str) -> String {
+    let name = truncated_tensor_name(name);
+    name[..name.len().min(MAX_NAME_LENGTH - 1)].to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, LoadProgress, ModelParameters, TokenizerSource};
+
+    #[test]
+    fn can_apply_lora_while_memory_mapped() {
+        let lora_path = temp_path("lora.bin");
+        write_lora(
+            &lora_path,
+            &model_tensors(test_hyperparameters()),
+            &["layers.0.w1", "layers.1.w2"],
+        );
+
+        let load = |name: &str, prefer_mmap: bool, apply_lora: bool| {
+            let path = temp_path(&format!("{name}.bin"));
+            write_random_model(&path, test_hyperparameters());
+            let mut context_size = 0;
+            let params = ModelParameters {
+                prefer_mmap,
+                context_size: 32,
+                lora_adapters: apply_lora.then(|| vec![lora_path.clone()]),
+                ..Default::default()
+            };
+            let model = crate::load::<TestModel>(&path, TokenizerSource::Embedded, params, |p| {
+                if let LoadProgress::ContextSize { bytes } = p {
+                    context_size = bytes;
+                }
+            });
+            std::fs::remove_file(&path).unwrap();
+            let model = model.unwrap();
+
+            let mut session = start_session(&model);
+            (feed(&model, &mut session, &[3, 1, 4]), context_size)
+        };
+        let (mapped_logits, mapped_size) = load("lora-mmap", true, true);
+        let (owned_logits, owned_size) = load("lora-owned", false, true);
+        let (unpatched_logits, _) = load("lora-unpatched", true, false);
+        std::fs::remove_file(&lora_path).unwrap();
+
+        assert_eq!(mapped_logits, owned_logits);
+        assert_ne!(mapped_logits, unpatched_logits);
+        // Only the patched tensors are loaded into owned memory.
+        assert!(mapped_size < owned_size / 2);
+    }
+
+    #[test]
+    fn cannot_attach_lora_when_evaluating_with_metal() {
+        let lora_path = temp_path("metal-lora-adapter.bin");
+        write_lora(
+            &lora_path,
+            &model_tensors(test_hyperparameters()),
+            &["layers.0.w1"],
+        );
+        let adapter = LoraAdapter::load(&lora_path).unwrap();
+        std::fs::remove_file(&lora_path).unwrap();
+
+        assert!(matches!(
+            LoraState::new(HashMap::new(), true).attach(adapter),
+            Err(LoraError::UnsupportedOnMetal)
+        ));
+    }
+
+    #[test]
+    fn can_load_peft_lora() {
+        let lora_path = temp_path("peft-lora-adapter.bin");
+        let tensor_names = ["layers.0.w1", "layers.1.w2"];
+        write_lora(
+            &lora_path,
+            &model_tensors(test_hyperparameters()),
+            &tensor_names,
+        );
+
+        // Write the same adapter in the layout PEFT uses.
+        let peft_path = temp_path("peft-lora");
+        std::fs::create_dir_all(&peft_path).unwrap();
+        std::fs::write(
+            peft_path.join("adapter_config.json"),
+            r#"{"r": 4, "lora_alpha": 8.0, "target_modules": ["up_proj", "down_proj"]}"#,
+        )
+        .unwrap();
+        let adapter = LoraAdapter::load(&lora_path).unwrap();
+        let mut peft_tensors = vec![];
+        for name in tensor_names {
+            let hf_name = match name {
+                "layers.0.w1" => "model.layers.0.mlp.up_proj",
+                _ => "model.layers.1.mlp.down_proj",
+            };
+            let read = |suffix: &str| {
+                let info = &adapter.tensors[&format!("{name}.{suffix}")];
+                let data = info.read_data(&mut BufReader::new(&adapter.file)).unwrap();
+                (info.dims, data)
+            };
+
+            // `A` is stored transposed.
+            let ([rank, n_in, ..], a) = read("loraA");
+            let a: Vec<f32> = a
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect();
+            let a_transposed = transpose(&a, n_in, rank);
+            peft_tensors.push((
+                format!("base_model.model.{hf_name}.lora_A.weight"),
+                vec![rank, n_in],
+                bytemuck::cast_slice(&a_transposed).to_vec(),
+            ));
+
+            let ([rank, n_out, ..], b) = read("loraB");
+            peft_tensors.push((
+                format!("base_model.model.{hf_name}.lora_B.weight"),
+                vec![n_out, rank],
+                b,
+            ));
+        }
+        let views = peft_tensors.iter().map(|(name, shape, data)| {
+            let view =
+                safetensors::tensor::TensorView::new(safetensors::Dtype::F32, shape.clone(), data);
+            (name, view.unwrap())
+        });
+        std::fs::write(
+            peft_path.join("adapter_model.safetensors"),
+            safetensors::serialize(views, &None).unwrap(),
+        )
+        .unwrap();
+
+        let logits = |name: &str, lora_path: &Path| {
+            let model = load_random_model(
+                name,
+                ModelParameters {
+                    lora_adapters: Some(vec![lora_path.to_owned()]),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let mut session = start_session(&model);
+            feed(&model, &mut session, &[3, 1, 4])
+        };
+        let ggla_logits = logits("peft-ggla", &lora_path);
+        let peft_logits = logits("peft-safetensors", &peft_path);
+        assert_eq!(ggla_logits, peft_logits);
+
+        // PEFT allows fractional scaling factors.
+        std::fs::write(
+            peft_path.join("adapter_config.json"),
+            r#"{"r": 4, "lora_alpha": 6.5}"#,
+        )
+        .unwrap();
+        let adapter =
+            LoraAdapter::load_peft::<TestModel>(&peft_path, &test_hyperparameters()).unwrap();
+        std::fs::remove_file(&lora_path).unwrap();
+        std::fs::remove_dir_all(&peft_path).unwrap();
+
+        assert_eq!(adapter.scaling, 6.5 / 4.0);
+    }
+}
@@ -10,12 +10,10 @@ pub fn read_last_token(
     n: usize,
 ) {
     assert_eq!(session.last_logits.len(), n_vocab);
-    unsafe {
-        input_layer.read_data(
-            n_vocab * (n - 1) * std::mem::size_of::<f32>(),
-            bytemuck::cast_slice_mut(&mut session.last_logits),
-        )
-    };
+    assert_eq!(input_layer.nelements(), n_vocab * n);
+    session.last_logits = input_layer
+        .rows_to_vec_f32(n - 1..n)
+        .expect("the logits should be readable");
 }
 
 /// Extract logits from [OutputRequest] evaluation
@@ -26,14 +24,10 @@ pub fn extract_logits(
     n: usize,
 ) {
     if let Some(all_logits) = &mut output_request.all_logits {
-        all_logits.resize(n_vocab * n, 0.0);
-        // SAFETY: Tensor data can be read (properly aligned, initialized,
-        // data will not be mutated or otherwise aliased during the copy),
-        // and we're not reading past the end of the tensor data.
         assert_eq!(input_layer.nelements(), n_vocab * n);
-        unsafe {
-            input_layer.read_data(0, bytemuck::cast_slice_mut(all_logits));
-        }
+        *all_logits = input_layer
+            .to_vec_f32()
+            .expect("the logits should be readable");
     }
 }
 
@@ -46,14 +40,10 @@ pub fn extract_embeddings(
 ) {
     // Extract embeddings
     if let Some(embeddings) = &mut output_request.embeddings {
-        embeddings.resize(n_embd, 0.0);
-        // Create a new vector to hold all embeddings
-        let mut all_embeddings = vec![0.0; n_embd * n];
-        // SAFETY: Same rationale as for the "Extract logits" section applies.
+        // Only keep the embeddings of the last token
         assert_eq!(embeddings_tensor.nelements(), n_embd * n);
-        unsafe {
-            embeddings_tensor.read_data(0, bytemuck::cast_slice_mut(&mut all_embeddings));
-        }
-        embeddings.copy_from_slice(&all_embeddings[n_embd * (n - 1)..]);
+        *embeddings = embeddings_tensor
+            .rows_to_vec_f32(n - 1..n)
+            .expect("the embeddings should be readable");
     }
 }
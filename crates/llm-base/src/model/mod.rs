@@ -5,7 +5,7 @@ use std::{
     fmt::Debug,
     io::{BufRead, Write},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, RwLock},
 };
 
 use ggml::accelerator::Backend;
@@ -13,8 +13,9 @@ use regex::Regex;
 use thiserror::Error;
 
 use crate::{
-    loader::TensorLoader, tokenizer::TokenId, FileType, InferenceSession, InferenceSessionConfig,
-    LoadError, LoadProgress, Tokenizer, TokenizerSource,
+    loader::TensorLoader, lora::LoraState, tokenizer::TokenId, CancellationToken, ContainerType,
+    FileType, HfConfig, InferenceSession, InferenceSessionConfig, LoadError, LoadProgress,
+    LoraAdapter, LoraAdapterId, LoraError, Tokenizer, TokenizerSource,
 };
 
 /// Common functions for model evaluation
@@ -69,9 +70,55 @@ pub trait KnownModel: Send + Sync {
     /// Get the hyperparameters for this model.
     fn hyperparameters(&self) -> &Self::Hyperparameters;
 
+    /// Get information about this model, such as its dimensions and file type.
+    ///
+    /// By default, this is [KnownModel::info_from_hyperparameters] for the model's
+    /// hyperparameters and the container type of its [ModelContext].
+    fn info(&self) -> ModelInfo
+    where
+        Self: Sized,
+    {
+        Self::info_from_hyperparameters(
+            self.hyperparameters(),
+            self.model_context()
+                .map(ModelContext::container_type)
+                .unwrap_or(ContainerType::Ggml),
+        )
+    }
+
+    /// Get information about a model with the given `hyperparameters` that was stored
+    /// in a `container_type` file, without having to load it.
+    ///
+    /// By default, this is built from the methods of [Hyperparameters].
+    fn info_from_hyperparameters(
+        hyperparameters: &Self::Hyperparameters,
+        container_type: ContainerType,
+    ) -> ModelInfo
+    where
+        Self: Sized,
+    {
+        ModelInfo {
+            n_embd: hyperparameters.n_embd(),
+            n_layer: hyperparameters.n_layer(),
+            n_head: hyperparameters.n_head(),
+            n_vocab: hyperparameters.n_vocabulary(),
+            file_type: hyperparameters.file_type(),
+            container_type,
+        }
+    }
+
     /// Get the tokenizer for this model.
     fn tokenizer(&self) -> &Tokenizer;
 
+    /// Get the context that holds the weights of this model, as returned by
+    /// [TensorLoader::finish].
+    ///
+    /// Models that do not return their context cannot have LoRA adapters attached at
+    /// runtime, which is the default.
+    fn model_context(&self) -> Option<&ModelContext> {
+        None
+    }
+
     /// Get the context size (configured with [ModelParameters::context_size]) used by
     /// this model.
     fn context_size(&self) -> usize;
@@ -82,12 +129,79 @@ pub trait KnownModel: Send + Sync {
     /// Get the end of text/end of string token ID. This value is defined by model implementers.
     fn eot_token_id(&self) -> TokenId;
 
+    /// Patch the weights of this model with a LoRA `adapter`, scaled by [LoraAdapter::scaling].
+    ///
+    /// The adapter can be removed again with [KnownModel::detach_lora]. Weights that have been
+    /// offloaded to an accelerator cannot be patched, and models evaluated with Metal are
+    /// rejected with [LoraError::UnsupportedOnMetal]. Evaluations that are in progress finish
+    /// before the weights are patched.
+    fn attach_lora(&self, adapter: LoraAdapter) -> Result<LoraAdapterId, LoraError> {
+        let context = self.model_context().ok_or(LoraError::Unsupported)?;
+        context.lora.write().unwrap().attach(adapter)
+    }
+
+    /// Remove a LoRA adapter attached with [KnownModel::attach_lora], restoring the weights
+    /// it patched to their exact values before it was attached (apart from the patches of
+    /// any other attached adapters). The adapter is returned so that it can be attached again.
+    fn detach_lora(&self, id: LoraAdapterId) -> Result<LoraAdapter, LoraError> {
+        let context = self.model_context().ok_or(LoraError::Unsupported)?;
+        context.lora.write().unwrap().detach(id)
+    }
+
     /// Get the list of regexes to use to determine if a tensor in this model should be quantized.
     fn quantize_tensors() -> Vec<Regex>;
 
     /// Get the list of regexes to use to determine if a tensor in this model should not be quantized.
     fn skip_quantize_tensors() -> Vec<Regex>;
 
+    /// Maps the name of a weight in the [Hugging Face Transformers](https://github.com/huggingface/transformers)
+    /// implementation of this architecture onto the name of the corresponding tensor in this model.
+    ///
+    /// Returns `None` if the weight has no counterpart in this model, which is the default.
+    fn hf_tensor_name(name: &str) -> Option<String>
+    where
+        Self: Sized,
+    {
+        let _ = name;
+        None
+    }
+
+    /// Returns the number of attention heads if the rows of the tensor `name` are ordered
+    /// differently in the Hugging Face Transformers implementation of this architecture,
+    /// which splits the rotary dimensions of each head into halves instead of interleaving them.
+    fn hf_permuted_heads(hyperparameters: &Self::Hyperparameters, name: &str) -> Option<usize>
+    where
+        Self: Sized,
+    {
+        let _ = (hyperparameters, name);
+        None
+    }
+
+    /// Returns whether the tensor `name` is stored transposed in the Hugging Face Transformers
+    /// implementation of this architecture, as is the case for weights of `Conv1D` modules.
+    fn hf_transposed(name: &str) -> bool
+    where
+        Self: Sized,
+    {
+        let _ = name;
+        false
+    }
+
+    /// Creates the hyperparameters of this model from the `config.json` of a Hugging Face
+    /// Transformers checkpoint, to be saved with `file_type`.
+    ///
+    /// By default, this fails with [LoadError::HfConversionUnsupported].
+    fn hf_hyperparameters(
+        config: &HfConfig,
+        file_type: FileType,
+    ) -> Result<Self::Hyperparameters, LoadError>
+    where
+        Self: Sized,
+    {
+        let _ = (config, file_type);
+        Err(LoadError::HfConversionUnsupported)
+    }
+
     /// Returns whether the model supports deleting tokens.
     fn supports_rewind(&self) -> bool {
         // Assume we can't delete unless otherwise specified
@@ -115,6 +229,9 @@ pub trait Model: Send + Sync {
     /// Get the tokenizer for this model.
     fn tokenizer(&self) -> &Tokenizer;
 
+    /// Get information about this model, such as its dimensions and file type.
+    fn info(&self) -> ModelInfo;
+
     /// Get the context size (configured with [ModelParameters::context_size]) used by
     /// this model.
     fn context_size(&self) -> usize;
@@ -127,6 +244,16 @@ pub trait Model: Send + Sync {
 
     /// Returns whether the model supports deleting tokens.
     fn supports_rewind(&self) -> bool;
+
+    /// Patch the weights of this model with a LoRA `adapter`, scaled by [LoraAdapter::scaling].
+    ///
+    /// The adapter can be removed again with [Model::detach_lora].
+    fn attach_lora(&self, adapter: LoraAdapter) -> Result<LoraAdapterId, LoraError>;
+
+    /// Remove a LoRA adapter attached with [Model::attach_lora], restoring the weights
+    /// it patched to their exact values before it was attached (apart from the patches of
+    /// any other attached adapters). The adapter is returned so that it can be attached again.
+    fn detach_lora(&self, id: LoraAdapterId) -> Result<LoraAdapter, LoraError>;
 }
 impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
@@ -146,6 +273,10 @@ impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
         KnownModel::tokenizer(self)
     }
 
+    fn info(&self) -> ModelInfo {
+        KnownModel::info(self)
+    }
+
     fn context_size(&self) -> usize {
         KnownModel::context_size(self)
     }
@@ -161,11 +292,19 @@ impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
     fn supports_rewind(&self) -> bool {
         KnownModel::supports_rewind(self)
     }
+
+    fn attach_lora(&self, adapter: LoraAdapter) -> Result<LoraAdapterId, LoraError> {
+        KnownModel::attach_lora(self, adapter)
+    }
+
+    fn detach_lora(&self, id: LoraAdapterId) -> Result<LoraAdapter, LoraError> {
+        KnownModel::detach_lora(self, id)
+    }
 }
 
 /// Implemented by model hyperparameters for interacting with hyperparameters
 /// without knowing what they are, as well as writing/reading them as required.
-pub trait Hyperparameters: Sized + Default + Debug + PartialEq + Eq {
+pub trait Hyperparameters: Sized + Clone + Default + Debug + PartialEq + Eq {
     /// Read the parameters in GGML format from a reader.
     fn read_ggml(reader: &mut dyn BufRead) -> Result<Self, LoadError>;
 
@@ -175,6 +314,21 @@ pub trait Hyperparameters: Sized + Default + Debug + PartialEq + Eq {
     /// Get the number of tokens in the embedded vocabulary, if any.
     fn n_vocabulary(&self) -> usize;
 
+    /// Get the size of the model's embedding layer, if the hyperparameters record it.
+    fn n_embd(&self) -> Option<usize> {
+        None
+    }
+
+    /// Get the number of layers in the model, if the hyperparameters record it.
+    fn n_layer(&self) -> Option<usize> {
+        None
+    }
+
+    /// Get the number of attention heads in the model, if the hyperparameters record it.
+    fn n_head(&self) -> Option<usize> {
+        None
+    }
+
     /// Get the filetype of the model.
     fn file_type(&self) -> Option<FileType>;
 
@@ -203,6 +357,11 @@ pub struct ModelParameters {
     /// consumes more resources, but produces more consistent and coherent responses.
     pub context_size: usize,
     /// The [LoRA](https://arxiv.org/abs/2106.09685) adapters to use when loading the model. If `None`, no adapters will be used.
+    ///
+    /// Each path is either a GGLA file, or a [PEFT](https://github.com/huggingface/peft) adapter
+    /// directory or `adapter_model.safetensors` file (see [LoraAdapter::load_peft]).
+    ///
+    /// The tensors patched by the adapters are loaded into memory, while the rest of the model can still be memory-mapped.
     pub lora_adapters: Option<Vec<PathBuf>>,
     /// Whether to use GPU acceleration when available
     pub use_gpu: bool,
@@ -212,6 +371,9 @@ pub struct ModelParameters {
     pub rope_overrides: Option<ggml::RoPEOverrides>,
     /// Enables gouped-query attention for Llama-2 70B model
     pub n_gqa: Option<usize>,
+    /// If set, loading will stop with [LoadError::Cancelled] once this token is cancelled
+    /// or its deadline has passed.
+    pub cancellation: Option<CancellationToken>,
 }
 
 impl Default for ModelParameters {
@@ -224,6 +386,7 @@ impl Default for ModelParameters {
             gpu_layers: None,
             rope_overrides: None,
             n_gqa: None,
+            cancellation: None,
         }
     }
 }
@@ -271,6 +434,100 @@ pub struct OutputRequest {
 /// modified across threads.
 #[derive(Clone)]
 #[allow(clippy::arc_with_non_send_sync)]
-pub struct ModelContext(pub(crate) Arc<ggml::Context>);
+pub struct ModelContext {
+    #[allow(dead_code)]
+    pub(crate) context: Arc<ggml::Context>,
+    /// Holds the tensors that could not be memory-mapped, such as those patched by a LoRA
+    /// adapter, when `context` is memory-mapped.
+    #[allow(dead_code)]
+    pub(crate) owned_context: Option<Arc<ggml::Context>>,
+    pub(crate) container_type: ContainerType,
+    /// The LoRA adapters attached at runtime.
+    pub(crate) lora: Arc<RwLock<LoraState>>,
+}
 unsafe impl Send for ModelContext {}
 unsafe impl Sync for ModelContext {}
+impl ModelContext {
+    /// The container type of the file the model was loaded from.
+    pub fn container_type(&self) -> ContainerType {
+        self.container_type
+    }
+}
+
+/// Information about a model that is available without knowing its concrete type.
+///
+/// Obtained through [Model::info] or [KnownModel::info].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModelInfo {
+    /// Size of the model's embedding layer, if the model's hyperparameters record it.
+    pub n_embd: Option<usize>,
+    /// Number of layers in the model, if the model's hyperparameters record it.
+    pub n_layer: Option<usize>,
+    /// Number of attention heads, if the model's hyperparameters record it.
+    pub n_head: Option<usize>,
+    /// Size of the model's vocabulary.
+    pub n_vocab: usize,
+    /// The file type of the model, if the model's hyperparameters record one.
+    pub file_type: Option<FileType>,
+    /// The container type of the file the model was loaded from.
+    pub container_type: ContainerType,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn can_get_model_info() {
+        let model: Box<dyn Model> =
+            Box::new(load_random_model("info", ModelParameters::default()).unwrap());
+
+        assert_eq!(
+            model.info(),
+            ModelInfo {
+                n_embd: Some(32),
+                n_layer: Some(2),
+                // The test model does not record its number of heads.
+                n_head: None,
+                n_vocab: N_VOCAB,
+                file_type: Some(test_hyperparameters().file_type),
+                container_type: ContainerType::Ggjt(3),
+            }
+        );
+    }
+
+    #[test]
+    fn can_attach_and_detach_lora() {
+        let lora_path = temp_path("runtime-lora-adapter.bin");
+        write_lora(
+            &lora_path,
+            &model_tensors(test_hyperparameters()),
+            &["layers.0.w1", "layers.1.w2"],
+        );
+        let logits = |model: &TestModel| feed(model, &mut start_session(model), &[3, 1, 4]);
+
+        let model = load_random_model("unpatched", ModelParameters::default()).unwrap();
+        let patched_model = load_random_model(
+            "load-time-lora",
+            ModelParameters {
+                lora_adapters: Some(vec![lora_path.clone()]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let adapter = LoraAdapter::load(&lora_path).unwrap();
+        std::fs::remove_file(&lora_path).unwrap();
+
+        let original_logits = logits(&model);
+        let id = KnownModel::attach_lora(&model, adapter).unwrap();
+        assert_eq!(logits(&model), logits(&patched_model));
+
+        KnownModel::detach_lora(&model, id).unwrap();
+        assert_eq!(logits(&model), original_logits);
+        assert!(matches!(
+            KnownModel::detach_lora(&model, id),
+            Err(LoraError::NotAttached(_))
+        ));
+    }
+}
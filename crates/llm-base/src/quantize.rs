@@ -1,16 +1,18 @@
 //! Implements quantization of weights.
 
 use crate::{
-    loader::FileTypeFormat, model::HyperparametersWriteError, Hyperparameters, KnownModel,
-    LoadError, LoadProgress, Loader, Tokenizer,
+    loader::{truncated_tensor_name, FileTypeFormat},
+    model::HyperparametersWriteError,
+    tokenizer::{Token, TokenScore},
+    Hyperparameters, ImportanceMatrix, KnownModel, LoadError, LoadProgress, Loader, LoraAdapter,
+    Tokenizer,
 };
 use ggml::format::{SaveError, SaveHandler, TensorLoadInfo, TensorSaveInfo};
-use half::f16;
 use regex::Regex;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     io::{BufRead, Seek, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
 };
 use thiserror::Error;
@@ -25,13 +27,20 @@ pub enum QuantizeProgress<'a> {
     TensorLoading {
         /// Name of the tensor.
         name: &'a str,
-        /// Size of the tensor.
-        dims: [usize; 2],
+        /// Size of the tensor, padded with 1s after its dimensions.
+        dims: [usize; ggml::MAX_DIMS],
         /// Type of the tensor.
         element_type: ggml::Type,
         /// Number of elements in the tensor.
         n_elements: usize,
     },
+    /// A tensor has been patched with a LoRA adapter.
+    TensorPatched {
+        /// Name of the tensor.
+        name: &'a str,
+        /// LoRA file the patch was applied from.
+        source: &'a Path,
+    },
     /// A tensor is being quantized.
     TensorQuantizing {
         /// Name of the tensor.
@@ -41,12 +50,27 @@ pub enum QuantizeProgress<'a> {
     TensorQuantized {
         /// Name of the tensor.
         name: &'a str,
+        /// The type the tensor was quantized to.
+        element_type: ggml::Type,
         /// The original size of the tensor.
         original_size: usize,
         /// The reduced size of the tensor.
         reduced_size: usize,
         /// The history of the quantization.
         history: Vec<f32>,
+        /// The error introduced by quantizing the tensor.
+        error: QuantizationStats,
+    },
+    /// A tensor has been converted to a floating-point type, dequantizing it if it was quantized.
+    TensorDequantized {
+        /// Name of the tensor.
+        name: &'a str,
+        /// The type the tensor was converted to.
+        element_type: ggml::Type,
+        /// The original size of the tensor.
+        original_size: usize,
+        /// The new size of the tensor.
+        new_size: usize,
     },
     /// A tensor has been skipped.
     TensorSkipped {
@@ -63,9 +87,82 @@ pub enum QuantizeProgress<'a> {
         reduced_size: usize,
         /// The history of the quantization.
         history: Vec<f32>,
+        /// The error introduced by quantization across all quantized tensors, if any were
+        /// quantized.
+        error: Option<QuantizationStats>,
     },
 }
 
+/// Measures of the error introduced by quantizing a set of values, computed by comparing
+/// the original values against their dequantized counterparts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize)]
+pub struct QuantizationStats {
+    /// The root-mean-square error.
+    pub rmse: f32,
+    /// The largest absolute error of any single value.
+    pub max_abs_error: f32,
+    /// The norm of the error relative to the norm of the original values.
+    pub relative_error: f32,
+}
+impl QuantizationStats {
+    /// Measures the error between `original` and `quantized`, which must have the same length.
+    pub fn measure(original: &[f32], quantized: &[f32]) -> Self {
+        let mut accumulator = QuantizationStatsAccumulator::default();
+        accumulator.add(original, quantized);
+        accumulator.stats()
+    }
+}
+
+/// Accumulates the error of several sets of quantized values, so that it can be reported
+/// as if they were one.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct QuantizationStatsAccumulator {
+    n_elements: usize,
+    sum_squared_error: f64,
+    sum_squared: f64,
+    max_abs_error: f32,
+}
+impl QuantizationStatsAccumulator {
+    pub(crate) fn add(&mut self, original: &[f32], quantized: &[f32]) {
+        assert_eq!(original.len(), quantized.len());
+
+        self.n_elements += original.len();
+        for (original, quantized) in original.iter().zip(quantized) {
+            let error = (original - quantized).abs();
+            self.sum_squared_error += f64::from(error) * f64::from(error);
+            self.sum_squared += f64::from(*original) * f64::from(*original);
+            self.max_abs_error = self.max_abs_error.max(error);
+        }
+    }
+
+    pub(crate) fn add_all(&mut self, other: &Self) {
+        self.n_elements += other.n_elements;
+        self.sum_squared_error += other.sum_squared_error;
+        self.sum_squared += other.sum_squared;
+        self.max_abs_error = self.max_abs_error.max(other.max_abs_error);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.n_elements == 0
+    }
+
+    pub(crate) fn stats(&self) -> QuantizationStats {
+        if self.is_empty() {
+            return QuantizationStats::default();
+        }
+
+        QuantizationStats {
+            rmse: (self.sum_squared_error / self.n_elements as f64).sqrt() as f32,
+            max_abs_error: self.max_abs_error,
+            relative_error: if self.sum_squared == 0.0 {
+                0.0
+            } else {
+                (self.sum_squared_error / self.sum_squared).sqrt() as f32
+            },
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 /// Errors encountered during the quantization process.
 pub enum QuantizeError {
@@ -105,12 +202,33 @@ pub enum QuantizeError {
         /// The quantization target.
         element_type: ggml::Type,
     },
+    /// A tensor could not be quantized to a type, as its rows are not made up of whole blocks
+    /// of that type.
+    #[error("rows of {row_length} values cannot be quantized to {element_type:?}, which has blocks of {block_size} values")]
+    UnalignedRowLength {
+        /// The quantization target.
+        element_type: ggml::Type,
+        /// The number of values in each row of the tensor.
+        row_length: usize,
+        /// The number of values in each block of the quantization target.
+        block_size: usize,
+    },
     /// The quantization process encountered an unsupported element type.
     #[error("unsupported element type {element_type:?}")]
     UnsupportedElementType {
         /// The element type.
         element_type: ggml::Type,
     },
+    /// The importance matrix does not have one value for each element of the rows of a tensor.
+    #[error("the importance matrix has {actual} values for {tensor_name}, which has rows of {expected} values")]
+    ImportanceSizeMismatch {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The number of values in each row of the tensor.
+        expected: usize,
+        /// The number of values in the importance matrix.
+        actual: usize,
+    },
     /// An error was encountered while writing the hyperparameters.
     #[error("an error was encountered while writing the hyperparameters")]
     HyperparametersWriteError(#[source] HyperparametersWriteError),
@@ -118,6 +236,16 @@ pub enum QuantizeError {
     /// support vocabulary scoring, despite the model having a scored vocabulary.
     #[error("container type does not support vocabulary scoring")]
     VocabularyScoringNotSupported,
+    /// The tokenizer has more tokens than the vocabulary of the model.
+    #[error(
+        "the tokenizer has {tokenizer} tokens, but the vocabulary of the model only has {model}"
+    )]
+    VocabularySizeMismatch {
+        /// The number of tokens in the tokenizer.
+        tokenizer: usize,
+        /// The size of the vocabulary of the model.
+        model: usize,
+    },
 }
 impl QuantizeError {
     pub(crate) fn from_format_error(value: SaveError<QuantizeError>, path: PathBuf) -> Self {
@@ -136,6 +264,12 @@ impl QuantizeError {
 }
 
 /// Quantizes a model.
+///
+/// Tensors that are already quantized to another type are dequantized before being quantized
+/// to `quantization_type`.
+///
+/// The vocabulary of `tokenizer` is saved in the quantized model, even if it is a Hugging Face
+/// tokenizer, so that the model can be loaded with [TokenizerSource::Embedded](crate::TokenizerSource::Embedded).
 pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
     reader: &mut R,
     writer: &mut W,
@@ -144,13 +278,207 @@ pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
     quantization_type: ggml::Type,
     progress_callback: impl Fn(QuantizeProgress),
 ) -> Result<(), QuantizeError> {
-    // Sanity check
-    let quantization_target = QuantizationTarget::try_from(quantization_type).map_err(|_| {
-        QuantizeError::InvalidQuantizationTarget {
-            element_type: quantization_type,
+    quantize_with_parameters::<M, _, _>(
+        reader,
+        writer,
+        tokenizer,
+        save_container_type,
+        quantization_type,
+        &QuantizeParameters::default(),
+        progress_callback,
+    )
+}
+
+/// Quantizes a model as with [quantize], using the additional `parameters`.
+///
+/// Each tensor that the model quantizes is quantized to the type of the first of the
+/// [rules](QuantizeParameters::rules) that matches its name, or to `quantization_type` if none
+/// of them do. The file type of the model is that of the type most of the quantized weights
+/// end up as; if some of them use another k-quantization type, the "medium" variant of the
+/// file type is used, e.g. [FileTypeFormat::MostlyQ4_K_M] rather than
+/// [FileTypeFormat::MostlyQ4_K_S].
+///
+/// If an [importance matrix](QuantizeParameters::importance) is provided, the blocks of the
+/// tensors it has activations for are quantized to minimise the error weighted by the
+/// importance of each value.
+///
+/// The tensors are read, quantized and written one at a time, so only one tensor has to be
+/// held in memory; the rows of each tensor are quantized on [QuantizeParameters::n_threads]
+/// threads.
+pub fn quantize_with_parameters<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+    tokenizer: Tokenizer,
+    save_container_type: ggml::format::SaveContainerType,
+    quantization_type: ggml::Type,
+    parameters: &QuantizeParameters,
+    progress_callback: impl Fn(QuantizeProgress),
+) -> Result<(), QuantizeError> {
+    save_model::<M, _, _>(
+        reader,
+        writer,
+        tokenizer,
+        save_container_type,
+        &[],
+        SaveTarget::quantize(quantization_type, parameters)?,
+        progress_callback,
+    )
+}
+
+/// Additional parameters for [quantize_with_parameters].
+#[derive(Debug, Clone)]
+pub struct QuantizeParameters {
+    /// Overrides the type that the tensors with matching names are quantized to.
+    pub rules: Vec<QuantizationRule>,
+    /// The importance matrix to weight the quantization error of each value with, collected
+    /// with [InferenceSession::calibrate](crate::InferenceSession::calibrate).
+    pub importance: Option<ImportanceMatrix>,
+    /// The number of threads to quantize the rows of each tensor with.
+    ///
+    /// The rows are quantized independently of each other, so the quantized model is the
+    /// same for any number of threads. Defaults to the number of threads that
+    /// [InferenceSessionConfig](crate::InferenceSessionConfig) evaluates with by default.
+    pub n_threads: usize,
+}
+impl Default for QuantizeParameters {
+    fn default() -> Self {
+        Self {
+            rules: vec![],
+            importance: None,
+            n_threads: crate::InferenceSessionConfig::default().n_threads,
         }
-    })?;
+    }
+}
+
+/// Overrides the type that [quantize_with_parameters] quantizes the tensors with matching
+/// names to.
+#[derive(Debug, Clone)]
+pub struct QuantizationRule {
+    /// The pattern to match the names of tensors against. [QuantizationRule::new] creates a
+    /// pattern that has to match the whole name.
+    pub pattern: Regex,
+    /// The type to quantize the matching tensors to.
+    pub element_type: ggml::Type,
+}
+impl QuantizationRule {
+    /// Creates a rule that quantizes the tensors whose whole name matches `pattern` to
+    /// `element_type`.
+    pub fn new(pattern: &str, element_type: ggml::Type) -> Result<Self, regex::Error> {
+        Ok(Self {
+            pattern: Regex::new(&format!("^(?:{pattern})$"))?,
+            element_type,
+        })
+    }
+}
+
+/// Dequantizes a model, and saves the result as a GGJT model whose tensors are stored as
+/// `element_type`, which must be either [ggml::Type::F32] or [ggml::Type::F16].
+///
+/// With [ggml::Type::F16], one-dimensional tensors are stored as `f32`, and tensors that
+/// are already stored as `f32` are left as they are.
+pub fn dequantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+    tokenizer: Tokenizer,
+    element_type: ggml::Type,
+    progress_callback: impl Fn(QuantizeProgress),
+) -> Result<(), QuantizeError> {
+    if !matches!(element_type, ggml::Type::F32 | ggml::Type::F16) {
+        return Err(QuantizeError::InvalidQuantizationTarget { element_type });
+    }
+
+    save_model::<M, _, _>(
+        reader,
+        writer,
+        tokenizer,
+        ggml::format::SaveContainerType::GgjtV3,
+        &[],
+        SaveTarget::Dequantize(element_type),
+        progress_callback,
+    )
+}
 
+/// Merges the LoRA adapters at `lora_adapters` into a model, and saves the result as a
+/// standalone GGJT model.
+///
+/// The adapters are loaded and applied in order, in the same way as
+/// [ModelParameters::lora_adapters](crate::ModelParameters::lora_adapters).
+/// If `quantization_type` is provided, the merged tensors are quantized to it afterwards,
+/// as with [quantize]; otherwise, they are saved with their original element type.
+pub fn merge_lora<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+    tokenizer: Tokenizer,
+    lora_adapters: &[PathBuf],
+    quantization_type: Option<ggml::Type>,
+    progress_callback: impl Fn(QuantizeProgress),
+) -> Result<(), QuantizeError> {
+    save_model::<M, _, _>(
+        reader,
+        writer,
+        tokenizer,
+        ggml::format::SaveContainerType::GgjtV3,
+        lora_adapters,
+        match quantization_type {
+            Some(quantization_type) => {
+                SaveTarget::quantize(quantization_type, &QuantizeParameters::default())?
+            }
+            None => SaveTarget::Original,
+        },
+        progress_callback,
+    )
+}
+
+/// Saves a model in another container, such as a GGML or GGMF model as GGJT so that it can be
+/// memory-mapped, without changing the element types of its tensors.
+///
+/// Tensors in a legacy quantization layout are converted to the current layout. The tensors
+/// are written in the same order as in the original model, and are aligned if the container
+/// supports it.
+pub fn convert_container<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+    tokenizer: Tokenizer,
+    save_container_type: ggml::format::SaveContainerType,
+    progress_callback: impl Fn(QuantizeProgress),
+) -> Result<(), QuantizeError> {
+    save_model::<M, _, _>(
+        reader,
+        writer,
+        tokenizer,
+        save_container_type,
+        &[],
+        SaveTarget::Original,
+        progress_callback,
+    )
+}
+
+/// Returns the vocabulary of the `tokenizer`, padded with empty tokens to the `n_vocabulary`
+/// tokens of the model, which may include unused padding rows.
+pub(crate) fn model_vocabulary(
+    tokenizer: &Tokenizer,
+    n_vocabulary: usize,
+) -> Result<Vec<(Token, TokenScore)>, QuantizeError> {
+    let mut vocabulary = tokenizer.vocabulary();
+    if vocabulary.len() > n_vocabulary {
+        return Err(QuantizeError::VocabularySizeMismatch {
+            tokenizer: vocabulary.len(),
+            model: n_vocabulary,
+        });
+    }
+    vocabulary.resize(n_vocabulary, (vec![], 0.0));
+    Ok(vocabulary)
+}
+
+fn save_model<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+    tokenizer: Tokenizer,
+    save_container_type: ggml::format::SaveContainerType,
+    lora_adapters: &[PathBuf],
+    target: SaveTarget,
+    progress_callback: impl Fn(QuantizeProgress),
+) -> Result<(), QuantizeError> {
     // Load the model
     let progress_callback = Arc::new(progress_callback);
 
@@ -173,26 +501,63 @@ pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
         ..
     } = loader;
 
+    let mut lora_adapters = lora_adapters
+        .iter()
+        .map(|path| LoraAdapter::load_for_model::<M>(path, &hyperparameters))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Every tensor an adapter patches must be part of the model
+    for lora_adapter in &lora_adapters {
+        if let Some(tensor_name) = lora_adapter
+            .tensors_to_patch
+            .iter()
+            .find(|name| !tensors.contains_key(*name))
+        {
+            return Err(LoadError::UnknownTensor {
+                tensor_name: tensor_name.to_owned(),
+                path: lora_adapter.path.to_owned(),
+            }
+            .into());
+        }
+    }
+
+    let to_quantize = M::quantize_tensors();
+    let to_skip = M::skip_quantize_tensors();
+
     if let Some(ft) = hyperparameters.file_type_mut() {
         ft.quantization_version = ggml::QNT_VERSION;
-        ft.format = quantization_target
-            .try_into()
-            .expect("format has no corresponding ftype");
+        match &target {
+            SaveTarget::Original => {}
+            SaveTarget::Quantize { default, .. } => {
+                let mut n_elements = BTreeMap::new();
+                for tensor in tensors.values() {
+                    if let Some(quantization_target) =
+                        target.quantization_target(tensor, &to_quantize, &to_skip)
+                    {
+                        *n_elements.entry(quantization_target).or_default() += tensor.n_elements;
+                    }
+                }
+                ft.format = QuantizationTarget::file_type_format(*default, &n_elements);
+            }
+            SaveTarget::Dequantize(ggml::Type::F32) => ft.format = FileTypeFormat::F32,
+            SaveTarget::Dequantize(_) => ft.format = FileTypeFormat::MostlyF16,
+        }
     }
 
-    let tokenizer = match tokenizer {
-        Tokenizer::Embedded(v) => v.iter().collect::<Vec<_>>(),
-        Tokenizer::HuggingFace(_) => vec![],
-    };
+    // Save the vocabulary of Hugging Face tokenizers too, so that the model is self-contained
+    let vocabulary = model_vocabulary(&tokenizer, hyperparameters.n_vocabulary())?;
+
+    // Keep the tensors in the order of the original model
+    let mut tensor_names: Vec<_> = tensors.keys().cloned().collect();
+    tensor_names.sort_by_key(|name| tensors[name].start_offset);
 
-    let to_quantize = M::quantize_tensors();
-    let to_skip = M::skip_quantize_tensors();
     let mut saver = QuantizeSaver::new(
-        quantization_target,
+        target,
         &hyperparameters,
         &tensors,
         &to_quantize,
         &to_skip,
+        &mut lora_adapters,
         reader,
         |p| progress_callback(p),
     );
@@ -200,8 +565,8 @@ pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
         writer,
         &mut saver,
         save_container_type,
-        &tokenizer,
-        &tensors.keys().cloned().collect::<Vec<_>>(),
+        &vocabulary,
+        &tensor_names,
     )
     .map_err(|err| QuantizeError::from_format_error(err, PathBuf::default()))?;
 
@@ -210,23 +575,320 @@ pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
     progress_callback(QuantizeProgress::Finished {
         original_size: saver.total_size_original,
         reduced_size: saver.total_size_new,
-        history: saver
-            .history_all
-            .iter()
-            .map(|hist| *hist as f32 / sum_all as f32)
-            .collect(),
+        history: if sum_all == 0 {
+            vec![]
+        } else {
+            saver
+                .history_all
+                .iter()
+                .map(|hist| *hist as f32 / sum_all as f32)
+                .collect()
+        },
+        error: (!saver.error_all.is_empty()).then(|| saver.error_all.stats()),
     });
 
     Ok(())
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-enum QuantizationTarget {
+/// The element types to save the tensors of a model as.
+#[derive(Debug, Clone)]
+enum SaveTarget {
+    /// Keep the original element types.
+    Original,
+    /// Quantize the tensors that the model quantizes, to the target of the first rule
+    /// matching their name, or to `default`, weighting the error with `importance`, with
+    /// `n_threads` threads.
+    Quantize {
+        default: QuantizationTarget,
+        rules: Vec<(Regex, QuantizationTarget)>,
+        importance: Option<ImportanceMatrix>,
+        n_threads: usize,
+    },
+    /// Convert the tensors to `f32` or `f16`.
+    Dequantize(ggml::Type),
+}
+impl SaveTarget {
+    fn quantize(
+        quantization_type: ggml::Type,
+        parameters: &QuantizeParameters,
+    ) -> Result<Self, QuantizeError> {
+        let target = |element_type: ggml::Type| {
+            QuantizationTarget::try_from(element_type)
+                .map_err(|_| QuantizeError::InvalidQuantizationTarget { element_type })
+        };
+        Ok(SaveTarget::Quantize {
+            default: target(quantization_type)?,
+            rules: parameters
+                .rules
+                .iter()
+                .map(|rule| Ok((rule.pattern.clone(), target(rule.element_type)?)))
+                .collect::<Result<_, QuantizeError>>()?,
+            importance: parameters.importance.clone(),
+            n_threads: parameters.n_threads.max(1),
+        })
+    }
+
+    /// Returns the target to quantize `tensor` to, if it should be quantized.
+    ///
+    /// Only 2D tensors that match `to_quantize` and do not match `to_skip` are quantized.
+    fn quantization_target(
+        &self,
+        tensor: &TensorLoadInfo,
+        to_quantize: &[Regex],
+        to_skip: &[Regex],
+    ) -> Option<QuantizationTarget> {
+        let SaveTarget::Quantize { default, rules, .. } = self else {
+            return None;
+        };
+        if tensor.n_dims != 2
+            || !to_quantize.iter().any(|re| re.is_match(&tensor.name))
+            || to_skip.iter().any(|re| re.is_match(&tensor.name))
+        {
+            return None;
+        }
+
+        Some(
+            rules
+                .iter()
+                .find(|(pattern, _)| pattern.is_match(&tensor.name))
+                .map_or(*default, |(_, target)| *target),
+        )
+    }
+
+    /// Returns the importance of each element of the rows of `tensor`, if there is an
+    /// importance matrix with activations for it.
+    fn importance(&self, tensor: &TensorLoadInfo) -> Result<Option<Vec<f32>>, QuantizeError> {
+        let SaveTarget::Quantize {
+            importance: Some(importance),
+            ..
+        } = self
+        else {
+            return Ok(None);
+        };
+        // The activations are recorded under the names of the tensors in the graph.
+        let Some(values) = importance.importance(truncated_tensor_name(&tensor.name)) else {
+            return Ok(None);
+        };
+
+        if values.len() != tensor.dims[0] {
+            return Err(QuantizeError::ImportanceSizeMismatch {
+                tensor_name: tensor.name.clone(),
+                expected: tensor.dims[0],
+                actual: values.len(),
+            });
+        }
+        Ok(Some(values))
+    }
+}
+
+/// The factors of the largest magnitude of each block that the values of the block are
+/// clamped to when searching for the quantization with the lowest weighted error.
+const CLAMP_FACTORS: [f32; 9] = [1.0, 0.95, 0.9, 0.85, 0.8, 0.75, 0.7, 0.65, 0.6];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[allow(non_camel_case_types)]
+pub(crate) enum QuantizationTarget {
     Q4_0,
     Q4_1,
     Q5_0,
     Q5_1,
     Q8_0,
+    Q2_K,
+    Q3_K,
+    Q4_K,
+    Q5_K,
+    Q6_K,
+}
+impl QuantizationTarget {
+    /// Quantizes the `n_elements` values of `data`, which is made up of rows of `row_length` values.
+    ///
+    /// Fails if `row_length` is not a multiple of the block size of the target.
+    pub(crate) fn quantize(
+        self,
+        data: &[f32],
+        n_elements: usize,
+        row_length: usize,
+    ) -> Result<ggml::QuantizationResult, QuantizeError> {
+        let block_size = ggml::blck_size(self.into());
+        if row_length % block_size != 0 {
+            return Err(QuantizeError::UnalignedRowLength {
+                element_type: self.into(),
+                row_length,
+                block_size,
+            });
+        }
+
+        Ok(match self {
+            QuantizationTarget::Q4_0 => ggml::quantize_q4_0(data, n_elements, row_length),
+            QuantizationTarget::Q4_1 => ggml::quantize_q4_1(data, n_elements, row_length),
+            QuantizationTarget::Q5_0 => ggml::quantize_q5_0(data, n_elements, row_length),
+            QuantizationTarget::Q5_1 => ggml::quantize_q5_1(data, n_elements, row_length),
+            QuantizationTarget::Q8_0 => ggml::quantize_q8_0(data, n_elements, row_length),
+            QuantizationTarget::Q2_K => ggml::quantize_q2_k(data, n_elements, row_length),
+            QuantizationTarget::Q3_K => ggml::quantize_q3_k(data, n_elements, row_length),
+            QuantizationTarget::Q4_K => ggml::quantize_q4_k(data, n_elements, row_length),
+            QuantizationTarget::Q5_K => ggml::quantize_q5_k(data, n_elements, row_length),
+            QuantizationTarget::Q6_K => ggml::quantize_q6_k(data, n_elements, row_length),
+        })
+    }
+
+    /// Quantizes `data` as with [QuantizationTarget::quantize], choosing the quantization
+    /// of each block that has the lowest error weighted by `importance`, which has a value for
+    /// each element of a row.
+    ///
+    /// The candidates for each block are quantizations of the block with its values clamped
+    /// to a fraction of its largest magnitude, which trades accuracy of the outliers for
+    /// accuracy of the rest of the block. The unclamped quantization is always a candidate,
+    /// so the weighted error is never worse than without an importance matrix.
+    pub(crate) fn quantize_weighted(
+        self,
+        data: &[f32],
+        n_elements: usize,
+        row_length: usize,
+        importance: &[f32],
+    ) -> Result<ggml::QuantizationResult, QuantizeError> {
+        assert_eq!(importance.len(), row_length);
+        let block_size = ggml::blck_size(self.into());
+        let type_size = ggml::type_size(self.into());
+        let clamp = |block: &[f32], factor: f32| {
+            let max = factor * block.iter().fold(0.0f32, |max, value| max.max(value.abs()));
+            block
+                .iter()
+                .map(|value| value.clamp(-max, max))
+                .collect::<Vec<_>>()
+        };
+
+        // Find the candidate with the lowest weighted error for each block
+        let mut best_factors = vec![0; n_elements / block_size];
+        let mut best_errors = vec![f64::INFINITY; n_elements / block_size];
+        for (i, factor) in CLAMP_FACTORS.iter().enumerate() {
+            let clamped: Vec<f32> = data
+                .chunks_exact(block_size)
+                .flat_map(|block| clamp(block, *factor))
+                .collect();
+            let candidate = self.quantize(&clamped, n_elements, row_length)?;
+            let dequantized = ggml::dequantize(self.into(), &candidate.output)
+                .expect("quantization targets can always be dequantized");
+
+            for (block, (original, dequantized)) in data
+                .chunks_exact(block_size)
+                .zip(dequantized.chunks_exact(block_size))
+                .enumerate()
+            {
+                let start = block * block_size % row_length;
+                let error: f64 = original
+                    .iter()
+                    .zip(dequantized)
+                    .zip(&importance[start..start + block_size])
+                    .map(|((original, dequantized), importance)| {
+                        f64::from(*importance) * f64::from(original - dequantized).powi(2)
+                    })
+                    .sum();
+                if error < best_errors[block] {
+                    best_errors[block] = error;
+                    best_factors[block] = i;
+                }
+            }
+        }
+
+        // Quantize the blocks that use the same candidate together, so that the history
+        // is that of the blocks that are actually used
+        let mut output = vec![0; n_elements / block_size * type_size];
+        let mut history = vec![0; 16];
+        for (i, factor) in CLAMP_FACTORS.iter().enumerate() {
+            let blocks: Vec<usize> = (0..best_factors.len())
+                .filter(|block| best_factors[*block] == i)
+                .collect();
+            if blocks.is_empty() {
+                continue;
+            }
+
+            let clamped: Vec<f32> = blocks
+                .iter()
+                .flat_map(|block| clamp(&data[block * block_size..][..block_size], *factor))
+                .collect();
+            let result = self.quantize(&clamped, clamped.len(), block_size)?;
+            for (block, quantized) in blocks.iter().zip(result.output.chunks_exact(type_size)) {
+                output[block * type_size..][..type_size].copy_from_slice(quantized);
+            }
+            for (total, count) in history.iter_mut().zip(&result.history) {
+                *total += count;
+            }
+        }
+
+        Ok(ggml::QuantizationResult { output, history })
+    }
+
+    /// Quantizes `data`, which is made up of rows of `row_length` values, as with
+    /// [QuantizationTarget::quantize_weighted] if there is an `importance` for each element
+    /// of a row, or as with [QuantizationTarget::quantize] otherwise.
+    ///
+    /// The rows are split into contiguous chunks that are quantized on up to `n_threads`
+    /// threads. As each row is quantized independently, the result is identical to
+    /// quantizing all of the rows at once.
+    pub(crate) fn quantize_parallel(
+        self,
+        data: &[f32],
+        row_length: usize,
+        importance: Option<&[f32]>,
+        n_threads: usize,
+    ) -> Result<ggml::QuantizationResult, QuantizeError> {
+        let quantize_rows = |rows: &[f32]| match importance {
+            Some(importance) => self.quantize_weighted(rows, rows.len(), row_length, importance),
+            None => self.quantize(rows, rows.len(), row_length),
+        };
+
+        let n_rows = data.len() / row_length;
+        let n_threads = n_threads.clamp(1, n_rows.max(1));
+        if n_threads == 1 {
+            return quantize_rows(data);
+        }
+
+        let rows_per_thread = (n_rows + n_threads - 1) / n_threads;
+        let results = std::thread::scope(|scope| {
+            let handles: Vec<_> = data
+                .chunks(rows_per_thread * row_length)
+                .map(|rows| scope.spawn(move || quantize_rows(rows)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("quantization thread panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        let mut output = Vec::with_capacity(
+            data.len() / ggml::blck_size(self.into()) * ggml::type_size(self.into()),
+        );
+        let mut history = vec![0; 16];
+        for result in results {
+            let result = result?;
+            output.extend_from_slice(&result.output);
+            for (total, count) in history.iter_mut().zip(&result.history) {
+                *total += count;
+            }
+        }
+        Ok(ggml::QuantizationResult { output, history })
+    }
+
+    /// Returns the file type of a model whose quantized tensors have `n_elements` elements of
+    /// each target, or that of `default` if there are none.
+    fn file_type_format(
+        default: QuantizationTarget,
+        n_elements: &BTreeMap<QuantizationTarget, usize>,
+    ) -> FileTypeFormat {
+        let mostly = n_elements
+            .iter()
+            .max_by_key(|(_, n_elements)| **n_elements)
+            .map_or(default, |(target, _)| *target);
+        let mixed = n_elements.len() > 1;
+
+        match mostly {
+            QuantizationTarget::Q3_K if mixed => FileTypeFormat::MostlyQ3_K_M,
+            QuantizationTarget::Q4_K if mixed => FileTypeFormat::MostlyQ4_K_M,
+            QuantizationTarget::Q5_K if mixed => FileTypeFormat::MostlyQ5_K_M,
+            mostly => mostly.into(),
+        }
+    }
 }
 impl TryFrom<ggml::Type> for QuantizationTarget {
     type Error = ();
@@ -238,6 +900,11 @@ impl TryFrom<ggml::Type> for QuantizationTarget {
             ggml::Type::Q5_0 => Ok(QuantizationTarget::Q5_0),
             ggml::Type::Q5_1 => Ok(QuantizationTarget::Q5_1),
             ggml::Type::Q8_0 => Ok(QuantizationTarget::Q8_0),
+            ggml::Type::Q2_K => Ok(QuantizationTarget::Q2_K),
+            ggml::Type::Q3_K => Ok(QuantizationTarget::Q3_K),
+            ggml::Type::Q4_K => Ok(QuantizationTarget::Q4_K),
+            ggml::Type::Q5_K => Ok(QuantizationTarget::Q5_K),
+            ggml::Type::Q6_K => Ok(QuantizationTarget::Q6_K),
             _ => Err(()),
         }
     }
@@ -250,6 +917,11 @@ impl From<QuantizationTarget> for ggml::Type {
             QuantizationTarget::Q5_0 => ggml::Type::Q5_0,
             QuantizationTarget::Q5_1 => ggml::Type::Q5_1,
             QuantizationTarget::Q8_0 => ggml::Type::Q8_0,
+            QuantizationTarget::Q2_K => ggml::Type::Q2_K,
+            QuantizationTarget::Q3_K => ggml::Type::Q3_K,
+            QuantizationTarget::Q4_K => ggml::Type::Q4_K,
+            QuantizationTarget::Q5_K => ggml::Type::Q5_K,
+            QuantizationTarget::Q6_K => ggml::Type::Q6_K,
         }
     }
 }
@@ -261,17 +933,23 @@ impl From<QuantizationTarget> for FileTypeFormat {
             QuantizationTarget::Q5_0 => FileTypeFormat::MostlyQ5_0,
             QuantizationTarget::Q5_1 => FileTypeFormat::MostlyQ5_1,
             QuantizationTarget::Q8_0 => FileTypeFormat::MostlyQ8_0,
+            QuantizationTarget::Q2_K => FileTypeFormat::MostlyQ2_K,
+            QuantizationTarget::Q3_K => FileTypeFormat::MostlyQ3_K_S,
+            QuantizationTarget::Q4_K => FileTypeFormat::MostlyQ4_K_S,
+            QuantizationTarget::Q5_K => FileTypeFormat::MostlyQ5_K_S,
+            QuantizationTarget::Q6_K => FileTypeFormat::MostlyQ6_K,
         }
     }
 }
 
 struct QuantizeSaver<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> {
     // Input
-    quantization_target: QuantizationTarget,
+    target: SaveTarget,
     hyperparameters: &'a H,
     tensors: &'a HashMap<String, TensorLoadInfo>,
     to_quantize: &'a [Regex],
     to_skip: &'a [Regex],
+    lora_adapters: &'a mut [LoraAdapter],
     source_reader: &'a mut R,
     progress_callback: F,
 
@@ -279,34 +957,79 @@ struct QuantizeSaver<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead
     total_size_original: usize,
     total_size_new: usize,
     history_all: Vec<i64>,
+    error_all: QuantizationStatsAccumulator,
 }
 impl<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek>
     QuantizeSaver<'a, F, H, R>
 {
+    #[allow(clippy::too_many_arguments)]
     fn new(
-        quantization_target: QuantizationTarget,
+        target: SaveTarget,
         hyperparameters: &'a H,
         tensors: &'a HashMap<String, TensorLoadInfo>,
         to_quantize: &'a [Regex],
         to_skip: &'a [Regex],
+        lora_adapters: &'a mut [LoraAdapter],
         source_reader: &'a mut R,
         progress_callback: F,
     ) -> Self {
         Self {
-            quantization_target,
+            target,
             hyperparameters,
             tensors,
             to_quantize,
             to_skip,
+            lora_adapters,
             source_reader,
             progress_callback,
 
             total_size_original: 0,
             total_size_new: 0,
             history_all: vec![0; 16],
+            error_all: QuantizationStatsAccumulator::default(),
         }
     }
 }
+impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> QuantizeSaver<'_, F, H, R> {
+    /// Applies the LoRA adapters to the `data` of `tensor`, and returns the patched data.
+    fn patch(&mut self, tensor: &TensorLoadInfo, data: Vec<u8>) -> Result<Vec<u8>, QuantizeError> {
+        let context = ggml::Context::new_with_allocate(tensor.calc_absolute_size(false));
+        let mut target = match *tensor.dims() {
+            [ne0] => context.new_tensor_1d(tensor.element_type, ne0),
+            [ne0, ne1] => context.new_tensor_2d(tensor.element_type, ne0, ne1),
+            [ne0, ne1, ne2] => context.new_tensor_3d(tensor.element_type, ne0, ne1, ne2),
+            [ne0, ne1, ne2, ne3] => context.new_tensor_4d(tensor.element_type, ne0, ne1, ne2, ne3),
+            _ => {
+                return Err(QuantizeError::InvariantBroken {
+                    path: PathBuf::default(),
+                    invariant: format!(
+                        "the tensor {} should have between 1 and {} dimensions, not {}",
+                        tensor.name,
+                        ggml::MAX_DIMS,
+                        tensor.n_dims
+                    ),
+                })
+            }
+        };
+        unsafe { target.write_data(&data) };
+
+        for lora_adapter in self
+            .lora_adapters
+            .iter_mut()
+            .filter(|adapter| adapter.tensors_to_patch.contains(&tensor.name))
+        {
+            lora_adapter.patch(tensor, &mut target)?;
+            (self.progress_callback)(QuantizeProgress::TensorPatched {
+                name: &tensor.name,
+                source: &lora_adapter.path,
+            });
+        }
+
+        let mut patched = vec![0; data.len()];
+        unsafe { target.read_data(0, &mut patched) };
+        Ok(patched)
+    }
+}
 impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler<QuantizeError>
     for QuantizeSaver<'_, F, H, R>
 {
@@ -329,56 +1052,62 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
             element_type: tensor.element_type,
         });
 
-        // Quantize only 2D tensors
-        let quantize = tensor.n_dims == 2
-            && self.to_quantize.iter().any(|re| re.is_match(tensor_name))
-            && !self.to_skip.iter().any(|re| re.is_match(tensor_name));
-        let raw_data = tensor.read_data(self.source_reader)?;
-
-        if quantize && !matches!(tensor.element_type, ggml::Type::F32 | ggml::Type::F16) {
-            return Err(QuantizeError::UnsupportedElementType {
-                element_type: tensor.element_type,
-            });
+        // Quantize the tensor, unless it already is of the target type
+        let quantization_target = self
+            .target
+            .quantization_target(tensor, self.to_quantize, self.to_skip)
+            .filter(|quantization_target| tensor.element_type != (*quantization_target).into());
+        // Dequantize every tensor that is not already stored with enough precision
+        let dequantization_type = match self.target {
+            SaveTarget::Dequantize(_) if tensor.n_dims == 1 => Some(ggml::Type::F32),
+            SaveTarget::Dequantize(element_type) => Some(element_type),
+            _ => None,
         }
+        .filter(|element_type| {
+            tensor.element_type != *element_type && tensor.element_type != ggml::Type::F32
+        });
 
+        let mut raw_data = tensor.read_data(self.source_reader)?;
         self.total_size_original += raw_data.len();
 
-        let (element_type, data) = if quantize {
+        if self
+            .lora_adapters
+            .iter()
+            .any(|adapter| adapter.tensors_to_patch.contains(tensor_name))
+        {
+            raw_data = self.patch(tensor, raw_data)?;
+        }
+
+        let (element_type, data) = if let Some(quantization_target) = quantization_target {
             (self.progress_callback)(QuantizeProgress::TensorQuantizing { name: tensor_name });
 
-            let data_f32: Vec<f32> = match tensor.element_type {
-                ggml::Type::F32 => raw_data
-                    .chunks_exact(4)
-                    .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
-                    .collect(),
-                ggml::Type::F16 => raw_data
-                    .chunks_exact(2)
-                    .map(|chunk| {
-                        f16::from_bits(u16::from_le_bytes(chunk.try_into().unwrap())).to_f32()
-                    })
-                    .collect(),
-                _ => unreachable!(),
-            };
+            let data_f32 = ggml::dequantize(tensor.element_type, &raw_data).ok_or(
+                QuantizeError::UnsupportedElementType {
+                    element_type: tensor.element_type,
+                },
+            )?;
 
-            let result = match self.quantization_target {
-                QuantizationTarget::Q4_0 => {
-                    ggml::quantize_q4_0(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-                QuantizationTarget::Q4_1 => {
-                    ggml::quantize_q4_1(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-                QuantizationTarget::Q5_0 => {
-                    ggml::quantize_q5_0(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-                QuantizationTarget::Q5_1 => {
-                    ggml::quantize_q5_1(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-                QuantizationTarget::Q8_0 => {
-                    ggml::quantize_q8_0(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
+            let n_threads = match self.target {
+                SaveTarget::Quantize { n_threads, .. } => n_threads,
+                _ => 1,
             };
+            let result = quantization_target.quantize_parallel(
+                &data_f32,
+                tensor.dims[0],
+                self.target.importance(tensor)?.as_deref(),
+                n_threads,
+            )?;
             let new_data = result.output;
 
+            // Measure what the quantization cost by comparing against the dequantized result
+            let mut error = QuantizationStatsAccumulator::default();
+            error.add(
+                &data_f32,
+                &ggml::dequantize(quantization_target.into(), &new_data)
+                    .expect("quantization targets can always be dequantized"),
+            );
+            self.error_all.add_all(&error);
+
             let mut history_new = vec![];
             for (i, val) in result.history.iter().enumerate() {
                 self.history_all[i] += val;
@@ -387,14 +1116,33 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
 
             (self.progress_callback)(QuantizeProgress::TensorQuantized {
                 name: tensor_name,
+                element_type: quantization_target.into(),
                 original_size: raw_data.len(),
                 reduced_size: new_data.len(),
                 history: history_new,
+                error: error.stats(),
             });
 
             self.total_size_new += new_data.len();
 
-            (self.quantization_target.into(), new_data)
+            (quantization_target.into(), new_data)
+        } else if let Some(element_type) = dequantization_type {
+            let unsupported = || QuantizeError::UnsupportedElementType {
+                element_type: tensor.element_type,
+            };
+            let data_f32 =
+                ggml::dequantize(tensor.element_type, &raw_data).ok_or_else(unsupported)?;
+            let new_data = ggml::quantize(element_type, &data_f32).ok_or_else(unsupported)?;
+
+            (self.progress_callback)(QuantizeProgress::TensorDequantized {
+                name: tensor_name,
+                element_type,
+                original_size: raw_data.len(),
+                new_size: new_data.len(),
+            });
+            self.total_size_new += new_data.len();
+
+            (element_type, new_data)
         } else {
             (self.progress_callback)(QuantizeProgress::TensorSkipped {
                 name: tensor_name,
@@ -412,3 +1160,524 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io::BufWriter, sync::Mutex};
+
+    use ggml::format::SaveContainerType;
+
+    use super::*;
+    use crate::{test_util::*, ModelParameters, TokenId, TokenizerSource};
+
+    #[test]
+    fn test_quantization_stats() {
+        let stats = QuantizationStats::measure(&[3.0, -4.0, 0.0, 0.0], &[3.0, -3.0, 0.0, 1.0]);
+        assert_eq!(
+            stats,
+            QuantizationStats {
+                rmse: (0.5f32).sqrt(),
+                max_abs_error: 1.0,
+                relative_error: (0.08f64).sqrt() as f32,
+            }
+        );
+
+        let mut accumulator = QuantizationStatsAccumulator::default();
+        assert!(accumulator.is_empty());
+        assert_eq!(accumulator.stats(), QuantizationStats::default());
+
+        let mut other = QuantizationStatsAccumulator::default();
+        accumulator.add(&[3.0, -4.0], &[3.0, -3.0]);
+        other.add(&[0.0, 0.0], &[0.0, 1.0]);
+        accumulator.add_all(&other);
+        assert_eq!(accumulator.stats(), stats);
+    }
+
+    #[test]
+    fn test_file_type_format() {
+        use QuantizationTarget::*;
+
+        let n_elements = |n_elements: &[(QuantizationTarget, usize)]| {
+            n_elements.iter().copied().collect::<BTreeMap<_, _>>()
+        };
+        let cases = [
+            (Q4_0, n_elements(&[]), FileTypeFormat::MostlyQ4_0),
+            (Q4_0, n_elements(&[(Q8_0, 10)]), FileTypeFormat::MostlyQ8_0),
+            (
+                Q8_0,
+                n_elements(&[(Q8_0, 10), (Q4_0, 20)]),
+                FileTypeFormat::MostlyQ4_0,
+            ),
+            (
+                Q4_K,
+                n_elements(&[(Q4_K, 10)]),
+                FileTypeFormat::MostlyQ4_K_S,
+            ),
+            (
+                Q4_K,
+                n_elements(&[(Q4_K, 20), (Q6_K, 10)]),
+                FileTypeFormat::MostlyQ4_K_M,
+            ),
+            (
+                Q5_K,
+                n_elements(&[(Q5_K, 20), (Q8_0, 10)]),
+                FileTypeFormat::MostlyQ5_K_M,
+            ),
+            (
+                Q6_K,
+                n_elements(&[(Q6_K, 20), (Q4_K, 10)]),
+                FileTypeFormat::MostlyQ6_K,
+            ),
+        ];
+        for (default, n_elements, format) in cases {
+            assert_eq!(
+                QuantizationTarget::file_type_format(default, &n_elements),
+                format,
+                "{n_elements:?}"
+            );
+        }
+    }
+
+    fn open(path: &Path) -> std::io::BufReader<std::fs::File> {
+        std::io::BufReader::new(std::fs::File::open(path).unwrap())
+    }
+
+    fn create(path: &Path) -> BufWriter<std::fs::File> {
+        BufWriter::new(std::fs::File::create(path).unwrap())
+    }
+
+    #[test]
+    fn can_merge_lora_into_model() {
+        let base_path = temp_path("merge-base.bin");
+        let lora_paths = vec![temp_path("merge-lora.bin"), temp_path("merge-lora-w1.bin")];
+        write_random_model(&base_path, test_hyperparameters());
+        write_lora(
+            &lora_paths[0],
+            &model_tensors(test_hyperparameters()),
+            &["layers.0.w1", "layers.1.w2"],
+        );
+        write_lora(
+            &lora_paths[1],
+            &model_tensors(test_hyperparameters()),
+            &["layers.1.w1"],
+        );
+
+        let patched_tensors = Mutex::new(vec![]);
+        let merge = |name: &str, quantization_type: Option<ggml::Type>| {
+            let merged_path = temp_path(name);
+            let mut writer = create(&merged_path);
+            merge_lora::<TestModel, _, _>(
+                &mut open(&base_path),
+                &mut writer,
+                TokenizerSource::Embedded.retrieve(&base_path).unwrap(),
+                &lora_paths,
+                quantization_type,
+                |progress| {
+                    if let QuantizeProgress::TensorPatched { name, source } = progress {
+                        let patched = (name.to_owned(), source.to_owned());
+                        patched_tensors.lock().unwrap().push(patched);
+                    }
+                },
+            )
+            .unwrap();
+            drop(writer);
+            merged_path
+        };
+        let load = |path: &Path, lora_adapters: Option<Vec<PathBuf>>| {
+            let params = ModelParameters {
+                lora_adapters,
+                ..Default::default()
+            };
+            load_model(path, params).unwrap()
+        };
+
+        let merged_path = merge("merge-merged.bin", None);
+        // Each tensor is only reported as patched by the adapters that patch it.
+        assert_eq!(
+            std::mem::take(&mut *patched_tensors.lock().unwrap()),
+            [
+                ("layers.0.w1".to_string(), lora_paths[0].clone()),
+                ("layers.1.w1".to_string(), lora_paths[1].clone()),
+                ("layers.1.w2".to_string(), lora_paths[0].clone()),
+            ]
+        );
+        let quantized_path = merge("merge-quantized.bin", Some(ggml::Type::Q8_0));
+
+        let patched = load(&base_path, Some(lora_paths.clone()));
+        let merged = load(&merged_path, None);
+        let quantized = load(&quantized_path, None);
+        for path in [&base_path, &merged_path, &quantized_path]
+            .into_iter()
+            .chain(&lora_paths)
+        {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        let patched_logits = feed(&patched, &mut start_session(&patched), &[3, 1, 4]);
+        let merged_logits = feed(&merged, &mut start_session(&merged), &[3, 1, 4]);
+        assert_eq!(patched_logits, merged_logits);
+
+        assert_eq!(
+            quantized.hyperparameters.file_type.format,
+            FileTypeFormat::MostlyQ8_0
+        );
+        assert_eq!(quantized.layers[0].w1.get_type(), ggml::Type::Q8_0);
+    }
+
+    #[test]
+    fn can_convert_container() {
+        let path = |name: &str| temp_path(&format!("container-{name}.bin"));
+        let original_path = path("ggjt");
+        write_random_model(&original_path, test_hyperparameters());
+
+        // Go through every container, ending up back at an aligned GGJT model.
+        let mut logits = vec![];
+        let mut source_path = original_path.clone();
+        for (name, container_type) in [
+            ("ggmf", SaveContainerType::GgmfV1),
+            ("ggml", SaveContainerType::Ggml),
+            ("ggjt-realigned", SaveContainerType::GgjtV3),
+        ] {
+            let destination_path = path(name);
+            let tokenizer = TokenizerSource::Embedded.retrieve(&source_path).unwrap();
+            convert_container::<TestModel, _, _>(
+                &mut open(&source_path),
+                &mut create(&destination_path),
+                tokenizer,
+                container_type,
+                |_| {},
+            )
+            .unwrap();
+
+            assert_eq!(
+                ggml::ContainerType::read::<std::io::Error>(&mut open(&destination_path)).unwrap(),
+                container_type.into()
+            );
+
+            let model = load_model(&destination_path, ModelParameters::default()).unwrap();
+            let mut session = start_session(&model);
+            logits.push(feed(&model, &mut session, &[3, 1, 4]));
+
+            if source_path != original_path {
+                std::fs::remove_file(&source_path).unwrap();
+            }
+            source_path = destination_path;
+        }
+        std::fs::remove_file(&source_path).unwrap();
+        std::fs::remove_file(&original_path).unwrap();
+
+        let original_logits = load_random_model("container-original", ModelParameters::default())
+            .map(|model| feed(&model, &mut start_session(&model), &[3, 1, 4]))
+            .unwrap();
+        for converted_logits in logits {
+            assert_eq!(converted_logits, original_logits);
+        }
+    }
+
+    #[test]
+    fn can_dequantize_and_requantize_model() {
+        let path = |name: &str| temp_path(&format!("dequantize-{name}.bin"));
+        let load = |path: &Path| load_model(path, ModelParameters::default()).unwrap();
+
+        let original_path = path("f32");
+        let quantized_path = path("q8_0");
+        let dequantized_path = path("dequantized");
+        let requantized_path = path("q5_1");
+        write_random_model(&original_path, test_hyperparameters());
+
+        quantize::<TestModel, _, _>(
+            &mut open(&original_path),
+            &mut create(&quantized_path),
+            TokenizerSource::Embedded.retrieve(&original_path).unwrap(),
+            SaveContainerType::GgjtV3,
+            ggml::Type::Q8_0,
+            |_| {},
+        )
+        .unwrap();
+        dequantize::<TestModel, _, _>(
+            &mut open(&quantized_path),
+            &mut create(&dequantized_path),
+            TokenizerSource::Embedded.retrieve(&quantized_path).unwrap(),
+            ggml::Type::F32,
+            |_| {},
+        )
+        .unwrap();
+        // Quantizing an already-quantized model goes through its dequantized values.
+        quantize::<TestModel, _, _>(
+            &mut open(&quantized_path),
+            &mut create(&requantized_path),
+            TokenizerSource::Embedded.retrieve(&quantized_path).unwrap(),
+            SaveContainerType::GgjtV3,
+            ggml::Type::Q5_1,
+            |_| {},
+        )
+        .unwrap();
+        assert!(matches!(
+            dequantize::<TestModel, _, _>(
+                &mut open(&quantized_path),
+                &mut create(&path("invalid")),
+                TokenizerSource::Embedded.retrieve(&quantized_path).unwrap(),
+                ggml::Type::Q4_0,
+                |_| {},
+            ),
+            Err(QuantizeError::InvalidQuantizationTarget {
+                element_type: ggml::Type::Q4_0
+            })
+        ));
+
+        let quantized = load(&quantized_path);
+        let dequantized = load(&dequantized_path);
+        let requantized = load(&requantized_path);
+        assert_eq!(
+            dequantized.hyperparameters.file_type.format,
+            FileTypeFormat::F32
+        );
+        assert_eq!(dequantized.layers[0].w1.get_type(), ggml::Type::F32);
+        assert_eq!(
+            dequantized.layers[0].w1.to_vec_f32().unwrap(),
+            quantized.layers[0].w1.to_vec_f32().unwrap()
+        );
+        assert_eq!(requantized.layers[0].w1.get_type(), ggml::Type::Q5_1);
+
+        for path in [
+            original_path,
+            quantized_path,
+            dequantized_path,
+            requantized_path,
+            path("invalid"),
+        ] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn can_report_quantization_error() {
+        let path = temp_path("quantization-error.bin");
+        write_random_model(&path, test_hyperparameters());
+
+        let errors = std::cell::RefCell::new(HashMap::new());
+        let total_error = std::cell::Cell::new(None);
+        let mut destination = std::io::Cursor::new(vec![]);
+        quantize::<TestModel, _, _>(
+            &mut open(&path),
+            &mut destination,
+            TokenizerSource::Embedded.retrieve(&path).unwrap(),
+            SaveContainerType::GgjtV3,
+            ggml::Type::Q8_0,
+            |progress| match progress {
+                QuantizeProgress::TensorQuantized { name, error, .. } => {
+                    errors.borrow_mut().insert(name.to_string(), error);
+                }
+                QuantizeProgress::Finished { error, .. } => total_error.set(error),
+                _ => {}
+            },
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let errors = errors.into_inner();
+        assert!(errors.contains_key("layers.0.w1"));
+        assert!(!errors.contains_key("layers.0.norm"));
+        for error in errors.values().chain(total_error.get().as_ref()) {
+            assert!(error.rmse > 0.0);
+            assert!(error.max_abs_error >= error.rmse);
+            assert!(error.relative_error > 0.0 && error.relative_error < 0.05);
+        }
+        let total_error = total_error.get().unwrap();
+        assert_eq!(
+            total_error.max_abs_error,
+            errors
+                .values()
+                .map(|error| error.max_abs_error)
+                .fold(0.0, f32::max)
+        );
+    }
+
+    #[test]
+    fn can_quantize_with_rules() {
+        let original_path = temp_path("quantization-rules-original.bin");
+        let quantized_path = temp_path("quantization-rules-quantized.bin");
+        write_random_model(&original_path, test_hyperparameters());
+
+        let quantize = |quantization_type: ggml::Type, rules: &[QuantizationRule]| {
+            quantize_with_parameters::<TestModel, _, _>(
+                &mut open(&original_path),
+                &mut create(&quantized_path),
+                TokenizerSource::Embedded.retrieve(&original_path).unwrap(),
+                SaveContainerType::GgjtV3,
+                quantization_type,
+                &QuantizeParameters {
+                    rules: rules.to_vec(),
+                    ..Default::default()
+                },
+                |_| {},
+            )
+        };
+
+        quantize(
+            ggml::Type::Q8_0,
+            &[
+                QuantizationRule::new(r"layers\.0\.w1", ggml::Type::Q5_1).unwrap(),
+                QuantizationRule::new(r"layers\.0\..*", ggml::Type::Q5_0).unwrap(),
+                // Only matches whole names, so this does not match `tok_embeddings`.
+                QuantizationRule::new("embeddings", ggml::Type::Q4_1).unwrap(),
+            ],
+        )
+        .unwrap();
+        let model = load_model(&quantized_path, ModelParameters::default()).unwrap();
+        assert_eq!(
+            model.hyperparameters.file_type.format,
+            FileTypeFormat::MostlyQ8_0
+        );
+        assert_eq!(model.tok_embeddings.get_type(), ggml::Type::Q8_0);
+        assert_eq!(model.layers[0].w1.get_type(), ggml::Type::Q5_1);
+        assert_eq!(model.layers[0].w2.get_type(), ggml::Type::Q5_0);
+        assert_eq!(model.layers[1].w1.get_type(), ggml::Type::Q8_0);
+        assert_eq!(model.layers[0].norm.get_type(), ggml::Type::F32);
+
+        // k-quantization needs rows of 256 values, which this model does not have
+        assert!(matches!(
+            quantize(
+                ggml::Type::Q8_0,
+                &[QuantizationRule::new(r"layers\.1\..*", ggml::Type::Q6_K).unwrap()],
+            ),
+            Err(QuantizeError::UnalignedRowLength {
+                element_type: ggml::Type::Q6_K,
+                row_length: 32,
+                block_size: 256,
+            })
+        ));
+        assert!(matches!(
+            quantize(
+                ggml::Type::Q8_0,
+                &[QuantizationRule::new(".*", ggml::Type::F16).unwrap()],
+            ),
+            Err(QuantizeError::InvalidQuantizationTarget {
+                element_type: ggml::Type::F16
+            })
+        ));
+
+        std::fs::remove_file(&original_path).unwrap();
+        std::fs::remove_file(&quantized_path).unwrap();
+    }
+
+    #[test]
+    fn can_quantize_in_parallel() {
+        let original_path = temp_path("parallel-quantization.bin");
+        write_random_model(&original_path, test_hyperparameters());
+        let model = load_model(&original_path, ModelParameters::default()).unwrap();
+        let tokens: Vec<TokenId> = (0..16).map(|i| (i * 5 % N_VOCAB) as TokenId).collect();
+        let importance = start_session(&model)
+            .calibrate(&model, tokens.as_slice(), |_, _| {})
+            .unwrap();
+
+        let quantize = |importance: Option<ImportanceMatrix>, n_threads: usize| {
+            let mut output = std::io::Cursor::new(vec![]);
+            quantize_with_parameters::<TestModel, _, _>(
+                &mut open(&original_path),
+                &mut output,
+                TokenizerSource::Embedded.retrieve(&original_path).unwrap(),
+                SaveContainerType::GgjtV3,
+                ggml::Type::Q5_1,
+                &QuantizeParameters {
+                    importance,
+                    n_threads,
+                    ..Default::default()
+                },
+                |_| {},
+            )
+            .unwrap();
+            output.into_inner()
+        };
+
+        // The output does not depend on the number of threads, even with more threads than rows.
+        for importance in [None, Some(importance)] {
+            let sequential = quantize(importance.clone(), 1);
+            for n_threads in [2, 3, 8, 1000] {
+                assert!(quantize(importance.clone(), n_threads) == sequential);
+            }
+        }
+
+        std::fs::remove_file(&original_path).unwrap();
+    }
+
+    #[test]
+    fn can_quantize_with_hf_tokenizer() {
+        let original_path = temp_path("hf-tokenizer-original.bin");
+        let quantized_path = temp_path("hf-tokenizer-quantized.bin");
+        write_random_model(&original_path, test_hyperparameters());
+
+        let quantize_and_load = |tokenizer: &str| {
+            quantize::<TestModel, _, _>(
+                &mut open(&original_path),
+                &mut create(&quantized_path),
+                TokenizerSource::HuggingFaceTokenizerString(tokenizer.to_string())
+                    .retrieve(&original_path)
+                    .unwrap(),
+                SaveContainerType::GgjtV3,
+                ggml::Type::Q8_0,
+                |_| {},
+            )
+            .unwrap();
+            load_model(&quantized_path, ModelParameters::default())
+                .unwrap()
+                .tokenizer
+        };
+
+        // Byte-level tokens are saved as the bytes they stand for, and the vocabulary is
+        // padded to the size the model expects.
+        let tokenizer = quantize_and_load(
+            r#"{"model": {"type": "BPE", "merges": [], "vocab": {"<|endoftext|>": 0,
+                "h": 1, "e": 2, "l": 3, "o": 4, "Ġ": 5, "Ġh": 6, "ll": 7, "Ċ": 8, "Ã©": 9}},
+                "decoder": {"type": "ByteLevel", "add_prefix_space": true,
+                "trim_offsets": true, "use_regex": true}}"#,
+        );
+        assert_eq!(tokenizer.len(), N_VOCAB);
+        assert_eq!(tokenizer.token(0), b"<|endoftext|>");
+        assert_eq!(tokenizer.token(6), b" h");
+        assert_eq!(tokenizer.token(8), b"\n");
+        assert_eq!(tokenizer.token(9), "é".as_bytes());
+        assert_eq!(tokenizer.token(15), b"");
+        assert_eq!(tokenizer.id(b"ll"), Some(7));
+
+        // SentencePiece pieces are saved with spaces and byte fallbacks resolved.
+        let tokenizer = quantize_and_load(
+            r#"{"model": {"type": "Unigram", "unk_id": 0,
+                "vocab": [["<unk>", 0.0], ["▁hello", -1.5], ["<0x0A>", -2.0]]},
+                "decoder": {"type": "Metaspace", "replacement": "▁",
+                "add_prefix_space": true}}"#,
+        );
+        assert_eq!(tokenizer.token(1), b" hello");
+        assert_eq!(tokenizer.token(2), b"\n");
+
+        // Tokenizers with more tokens than the model are rejected rather than truncated.
+        let vocab: Vec<_> = ('a'..='q')
+            .enumerate()
+            .map(|(id, token)| format!(r#""{token}": {id}"#))
+            .collect();
+        let tokenizer = format!(
+            r#"{{"model": {{"type": "BPE", "merges": [], "vocab": {{{}}}}}}}"#,
+            vocab.join(", ")
+        );
+        let result = quantize::<TestModel, _, _>(
+            &mut open(&original_path),
+            &mut std::io::Cursor::new(vec![]),
+            TokenizerSource::HuggingFaceTokenizerString(tokenizer)
+                .retrieve(&original_path)
+                .unwrap(),
+            SaveContainerType::GgjtV3,
+            ggml::Type::Q8_0,
+            |_| {},
+        );
+        assert!(matches!(
+            result,
+            Err(QuantizeError::VocabularySizeMismatch {
+                tokenizer: 17,
+                model: N_VOCAB
+            })
+        ));
+
+        std::fs::remove_file(&original_path).unwrap();
+        std::fs::remove_file(&quantized_path).unwrap();
+    }
+}
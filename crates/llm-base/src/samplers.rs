@@ -292,7 +292,7 @@ pub fn sample_token(
     previous_tokens: &[TokenId],
     last_logits: impl IntoIterator<Item = f32>,
 ) -> Result<TokenId, SamplingError> {
-    Logits::try_from_iter(last_logits.into_iter())
+    Logits::try_from_iter(last_logits)
         .map_err(|err| SamplingError::LogitsError(err.into()))?
         .sample_token(
             &mut SamplerResources {
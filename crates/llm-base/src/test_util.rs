@@ -0,0 +1,559 @@
+//! Fixtures shared by the tests of this crate and of the model crates.
+//!
+//! This is only available to tests, or with the `test-util` feature; it is not part of
+//! the public API.
+
+use std::{
+    convert::Infallible,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use ggml::{
+    format::{self, SaveContainerType, SaveHandler, TensorSaveInfo},
+    Tensor,
+};
+use regex::Regex;
+
+use crate::{
+    model::common, model::HyperparametersWriteError, util, FileType, FileTypeFormat, GraphOutputs,
+    HfConfig, Hyperparameters, InferenceFeedback, InferenceSession, InferenceSessionConfig,
+    KnownModel, LoadError, ModelContext, ModelKVMemoryType, ModelParameters, OutputRequest,
+    TensorLoader, TokenId, Tokenizer, TokenizerSource,
+};
+
+/// The size of the vocabulary of the [TestModel] written by [write_random_model].
+pub const N_VOCAB: usize = 16;
+
+/// Returns a path in the temporary directory that is unique to this process.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("llm-{name}-{}", std::process::id()))
+}
+
+/// Starts a session with full-precision memory and a single thread, so that the results
+/// are reproducible.
+pub fn start_session(model: &impl KnownModel) -> InferenceSession {
+    model.start_session(InferenceSessionConfig {
+        memory_k_type: ModelKVMemoryType::Float32,
+        memory_v_type: ModelKVMemoryType::Float32,
+        n_threads: 1,
+        ..Default::default()
+    })
+}
+
+/// Starts a session that offloads the first layer of the `model` to an accelerator, without
+/// evaluating anything on it.
+pub fn start_offloading_session(model: &TestModel) -> InferenceSession {
+    let params = ModelParameters {
+        use_gpu: true,
+        gpu_layers: Some(1),
+        ..ModelParameters::default()
+    };
+    let hyperparameters = &model.hyperparameters;
+    InferenceSession::new(
+        Default::default(),
+        &params,
+        hyperparameters.n_layer,
+        hyperparameters.n_embd,
+        hyperparameters.n_vocab,
+    )
+}
+
+/// Feeds `tokens` to the `session`, returning the logits of the last token.
+pub fn feed(
+    model: &impl KnownModel,
+    session: &mut InferenceSession,
+    tokens: &[TokenId],
+) -> Vec<f32> {
+    let mut output = OutputRequest {
+        all_logits: Some(vec![]),
+        ..Default::default()
+    };
+    session
+        .feed_prompt(model, tokens, &mut output, |_| {
+            Ok::<_, Infallible>(InferenceFeedback::Continue)
+        })
+        .unwrap();
+    session.last_logits.clone()
+}
+
+/// Writes a GGJT model with the `hyperparameters` and F32 `tensors` of the given
+/// dimensions, filled with small deterministic pseudo-random values.
+///
+/// The vocabulary has a `<i>` token for each token ID `i`.
+pub fn write_model(
+    path: &Path,
+    hyperparameters: impl Hyperparameters,
+    tensors: Vec<(String, Vec<usize>)>,
+) {
+    struct RandomSaver<H> {
+        hyperparameters: H,
+        tensors: Vec<(String, Vec<usize>)>,
+        state: u32,
+    }
+    impl<H: Hyperparameters> SaveHandler<std::io::Error> for RandomSaver<H> {
+        fn write_hyperparameters(
+            &mut self,
+            writer: &mut dyn std::io::Write,
+        ) -> Result<(), std::io::Error> {
+            self.hyperparameters.write_ggml(writer).unwrap();
+            Ok(())
+        }
+
+        fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, std::io::Error> {
+            let (_, dims) = self.tensors.iter().find(|(n, _)| n == tensor_name).unwrap();
+            let values: Vec<f32> = (0..dims.iter().product::<usize>())
+                .map(|_| {
+                    // A small xorshift generator keeps the weights deterministic.
+                    self.state ^= self.state << 13;
+                    self.state ^= self.state >> 17;
+                    self.state ^= self.state << 5;
+                    (self.state as f32 / u32::MAX as f32 - 0.5) * 0.2
+                })
+                .collect();
+
+            Ok(TensorSaveInfo {
+                n_dims: dims.len(),
+                dims: std::array::from_fn(|i| dims.get(i).copied().unwrap_or(1)),
+                element_type: ggml::Type::F32,
+                data: bytemuck::cast_slice(&values).to_vec(),
+            })
+        }
+    }
+
+    let vocabulary: Vec<_> = (0..hyperparameters.n_vocabulary())
+        .map(|i| (format!("<{i}>").into_bytes(), 0.0))
+        .collect();
+    let tensor_names: Vec<_> = tensors.iter().map(|(n, _)| n.clone()).collect();
+    let mut writer = BufWriter::new(std::fs::File::create(path).unwrap());
+    format::save(
+        &mut writer,
+        &mut RandomSaver {
+            hyperparameters,
+            tensors,
+            state: 0x2545_f491,
+        },
+        SaveContainerType::GgjtV3,
+        &vocabulary,
+        &tensor_names,
+    )
+    .unwrap();
+}
+
+/// Writes a GGLA LoRA adapter of rank 4 that patches the `tensor_names` of a model with
+/// the given `tensors`.
+pub fn write_lora(path: &Path, tensors: &[(String, Vec<usize>)], tensor_names: &[&str]) {
+    let lora_tensors = tensor_names
+        .iter()
+        .flat_map(|name| {
+            let (_, dims) = tensors.iter().find(|(n, _)| n == name).unwrap();
+            [
+                (format!("{name}.loraA"), vec![4, dims[0]]),
+                (format!("{name}.loraB"), vec![4, dims[1]]),
+            ]
+        })
+        .collect();
+    write_model(path, crate::LoraParameters { r: 4, alpha: 8 }, lora_tensors);
+
+    // LoRA adapters use the GGLA container, which has the same layout as GGJT
+    // apart from its header.
+    let mut data = std::fs::read(path).unwrap();
+    let mut header = vec![];
+    crate::ContainerType::Ggla(1).write(&mut header).unwrap();
+    data[..header.len()].copy_from_slice(&header);
+    std::fs::write(path, data).unwrap();
+}
+
+/// Returns the hyperparameters of the [TestModel] written by [write_random_model].
+pub fn test_hyperparameters() -> TestHyperparameters {
+    TestHyperparameters {
+        n_vocab: N_VOCAB,
+        n_embd: 32,
+        n_layer: 2,
+        file_type: FileType {
+            format: FileTypeFormat::F32,
+            quantization_version: 0,
+        },
+    }
+}
+
+/// Returns the names and dimensions of the tensors of a [TestModel].
+pub fn model_tensors(hyperparameters: TestHyperparameters) -> Vec<(String, Vec<usize>)> {
+    let TestHyperparameters {
+        n_vocab,
+        n_embd,
+        n_layer,
+        ..
+    } = hyperparameters;
+
+    let mut tensors = vec![("tok_embeddings".to_string(), vec![n_embd, n_vocab])];
+    for i in 0..n_layer {
+        tensors.extend([
+            (format!("layers.{i}.norm"), vec![n_embd]),
+            (format!("layers.{i}.w1"), vec![n_embd, 4 * n_embd]),
+            (format!("layers.{i}.w2"), vec![4 * n_embd, n_embd]),
+        ]);
+    }
+    tensors.extend([
+        ("norm".to_string(), vec![n_embd]),
+        ("output".to_string(), vec![n_embd, n_vocab]),
+    ]);
+    tensors
+}
+
+/// Writes a [TestModel] with the `hyperparameters` and random weights.
+pub fn write_random_model(path: &Path, hyperparameters: TestHyperparameters) {
+    write_model(path, hyperparameters, model_tensors(hyperparameters));
+}
+
+/// Loads the [TestModel] at `path` with a context size of 32.
+pub fn load_model(path: &Path, params: ModelParameters) -> Result<TestModel, LoadError> {
+    crate::load(
+        path,
+        TokenizerSource::Embedded,
+        ModelParameters {
+            context_size: 32,
+            ..params
+        },
+        |_| {},
+    )
+}
+
+/// Writes a [TestModel] with [test_hyperparameters] and loads it.
+pub fn load_random_model(name: &str, params: ModelParameters) -> Result<TestModel, LoadError> {
+    let path = temp_path(&format!("{name}.bin"));
+    write_random_model(&path, test_hyperparameters());
+    let model = load_model(&path, params);
+    std::fs::remove_file(&path).unwrap();
+    model
+}
+
+/// The hyperparameters of a [TestModel].
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct TestHyperparameters {
+    /// Size of the model's vocabulary
+    pub n_vocab: usize,
+    /// Size of the model's embedding layer
+    pub n_embd: usize,
+    /// Number of layers in the model
+    pub n_layer: usize,
+    /// file type
+    pub file_type: FileType,
+}
+
+impl Hyperparameters for TestHyperparameters {
+    fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
+        Ok(TestHyperparameters {
+            n_vocab: util::read_i32(reader)?.try_into()?,
+            n_embd: util::read_i32(reader)?.try_into()?,
+            n_layer: util::read_i32(reader)?.try_into()?,
+            file_type: util::read_filetype(reader)?,
+        })
+    }
+
+    fn write_ggml(&self, writer: &mut dyn std::io::Write) -> Result<(), HyperparametersWriteError> {
+        util::write_i32(writer, self.n_vocab.try_into()?)?;
+        util::write_i32(writer, self.n_embd.try_into()?)?;
+        util::write_i32(writer, self.n_layer.try_into()?)?;
+        util::write_i32(writer, self.file_type.into())?;
+        Ok(())
+    }
+
+    fn n_vocabulary(&self) -> usize {
+        self.n_vocab
+    }
+
+    fn n_embd(&self) -> Option<usize> {
+        Some(self.n_embd)
+    }
+
+    fn n_layer(&self) -> Option<usize> {
+        Some(self.n_layer)
+    }
+
+    fn file_type(&self) -> Option<FileType> {
+        Some(self.file_type)
+    }
+
+    fn file_type_mut(&mut self) -> Option<&mut FileType> {
+        Some(&mut self.file_type)
+    }
+}
+
+/// A small model for testing the model-independent parts of this crate.
+///
+/// Each layer normalizes its input, which is used by a single attention head (with the
+/// normalized input as its keys and values) and by a feed-forward network in parallel.
+pub struct TestModel {
+    /// The hyperparameters of the model.
+    pub hyperparameters: TestHyperparameters,
+    params: ModelParameters,
+    /// The tokenizer of the model.
+    pub tokenizer: Tokenizer,
+    /// The token embeddings.
+    pub tok_embeddings: Tensor,
+    /// The layers of the model.
+    pub layers: Vec<TestLayer>,
+    /// The normalization gain of the output.
+    pub norm: Tensor,
+    /// The language model head.
+    pub output: Tensor,
+    context: ModelContext,
+}
+
+/// A layer of a [TestModel].
+pub struct TestLayer {
+    /// The normalization gain of the input.
+    pub norm: Tensor,
+    /// The first feed-forward weight.
+    pub w1: Tensor,
+    /// The second feed-forward weight.
+    pub w2: Tensor,
+}
+
+unsafe impl Send for TestModel {}
+unsafe impl Sync for TestModel {}
+
+impl KnownModel for TestModel {
+    type Hyperparameters = TestHyperparameters;
+
+    fn new<E: std::error::Error>(
+        hyperparameters: Self::Hyperparameters,
+        params: ModelParameters,
+        tokenizer: Tokenizer,
+        tensor_loader: impl TensorLoader<E>,
+    ) -> Result<Self, E> {
+        let mut tl = tensor_loader;
+        let TestHyperparameters {
+            n_vocab, n_embd, ..
+        } = hyperparameters;
+
+        let backend = params.backend(0);
+        let tok_embeddings = tl
+            .load("tok_embeddings", &[n_embd, n_vocab])?
+            .transfer_to(backend);
+        let mut layers = Vec::new();
+        for i in 0..hyperparameters.n_layer {
+            let backend = params.backend(i);
+            layers.push(TestLayer {
+                norm: tl
+                    .load(&format!("layers.{i}.norm"), &[n_embd])?
+                    .transfer_to(backend),
+                w1: tl
+                    .load(&format!("layers.{i}.w1"), &[n_embd, 4 * n_embd])?
+                    .transfer_to(backend),
+                w2: tl
+                    .load(&format!("layers.{i}.w2"), &[4 * n_embd, n_embd])?
+                    .transfer_to(backend),
+            });
+        }
+        let norm = tl.load("norm", &[n_embd])?.transfer_to(backend);
+        let output = tl.load("output", &[n_embd, n_vocab])?.transfer_to(backend);
+
+        let context = tl.finish();
+
+        Ok(TestModel {
+            hyperparameters,
+            params,
+            tokenizer,
+            tok_embeddings,
+            layers,
+            norm,
+            output,
+            context,
+        })
+    }
+
+    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
+        InferenceSession::new(
+            config,
+            &self.params,
+            self.hyperparameters.n_layer,
+            self.hyperparameters.n_embd,
+            self.hyperparameters.n_vocab,
+        )
+    }
+
+    fn evaluate(
+        &self,
+        session: &mut InferenceSession,
+        input_tokens: &[TokenId],
+        output_request: &mut OutputRequest,
+    ) {
+        let input_len = input_tokens.len();
+        let session_len = session.n_past;
+        let ctx_size = self.params.context_size;
+        let TestHyperparameters {
+            n_vocab, n_embd, ..
+        } = self.hyperparameters;
+
+        let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
+            let mut ctx0 = builder.ctx0.borrow_mut();
+            let (memory_k_size, memory_v_size) = (
+                builder.memory_k.element_size(),
+                builder.memory_v.element_size(),
+            );
+
+            let mut input_layer = ctx0.op_get_rows(&self.tok_embeddings, builder.embd);
+
+            let mut gf = ctx0.create_compute_graph();
+            for (il, layer) in self.layers.iter().enumerate() {
+                ctx0.set_offloading(self.params.should_offload(il));
+                ctx0.use_scratch(builder.get_scratch(0));
+                let current = ctx0.op_mul(&ctx0.op_norm(&input_layer), &layer.norm);
+
+                // store the keys and values
+                let k = ctx0.op_view_1d(
+                    builder.memory_k,
+                    input_len * n_embd,
+                    (memory_k_size * n_embd) * (il * ctx_size + session_len),
+                );
+                let v = ctx0.op_view_1d(
+                    builder.memory_v,
+                    input_len * n_embd,
+                    (memory_v_size * n_embd) * (il * ctx_size + session_len),
+                );
+                gf.build_forward_expand(&ctx0.op_cpy(&current, &k));
+                gf.build_forward_expand(&ctx0.op_cpy(&current, &v));
+
+                // self-attention
+                let k = ctx0.op_reshape_2d(
+                    &ctx0.op_view_1d(
+                        builder.memory_k,
+                        (session_len + input_len) * n_embd,
+                        il * ctx_size * memory_k_size * n_embd,
+                    ),
+                    n_embd,
+                    session_len + input_len,
+                );
+                let kq = ctx0.op_scale_inplace(
+                    &ctx0.op_mul_mat(&k, &current),
+                    &ctx0.new_f32(1f32 / f32::sqrt(n_embd as f32)),
+                );
+                let kq_masked = ctx0.op_diag_mask_inf_inplace(&kq, session_len);
+                let kq_softmax = ctx0.op_soft_max_inplace(&kq_masked);
+                let v_trans = ctx0.op_cpy(
+                    &ctx0.op_transpose(&ctx0.op_reshape_2d(
+                        &ctx0.op_view_1d(
+                            builder.memory_v,
+                            (session_len + input_len) * n_embd,
+                            il * ctx_size * memory_v_size * n_embd,
+                        ),
+                        n_embd,
+                        session_len + input_len,
+                    )),
+                    &ctx0.new_tensor_2d(
+                        builder.memory_v.get_type(),
+                        session_len + input_len,
+                        n_embd,
+                    ),
+                );
+                let attention = ctx0.op_mul_mat(&v_trans, &kq_softmax);
+
+                // feed-forward
+                ctx0.use_scratch(builder.get_scratch(1));
+                let mut feed_forward = builder.op_mul_mat_weight(&ctx0, &layer.w1, &current);
+                feed_forward = ctx0.op_gelu(&feed_forward);
+                feed_forward = builder.op_mul_mat_weight(&ctx0, &layer.w2, &feed_forward);
+
+                input_layer = ctx0.op_add(&ctx0.op_add(&input_layer, &attention), &feed_forward);
+            }
+
+            ctx0.use_scratch(builder.get_scratch(0));
+            input_layer = ctx0.op_mul(&ctx0.op_norm(&input_layer), &self.norm);
+
+            ctx0.use_scratch(None);
+            ctx0.set_offloading(false);
+
+            let embeddings_tensor: Tensor = input_layer.share();
+            input_layer = builder.op_mul_mat_weight(&ctx0, &self.output, &input_layer);
+
+            (
+                gf,
+                GraphOutputs {
+                    result: input_layer,
+                    embedding_result: embeddings_tensor,
+                },
+            )
+        });
+
+        common::read_last_token(session, &outputs.result, n_vocab, input_len);
+        common::extract_logits(output_request, &outputs.result, n_vocab, input_len);
+        common::extract_embeddings(output_request, &outputs.embedding_result, n_embd, input_len);
+    }
+
+    fn hyperparameters(&self) -> &Self::Hyperparameters {
+        &self.hyperparameters
+    }
+
+    fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    fn model_context(&self) -> Option<&ModelContext> {
+        Some(&self.context)
+    }
+
+    fn context_size(&self) -> usize {
+        self.params.context_size
+    }
+
+    fn bot_token_id(&self) -> Option<TokenId> {
+        None
+    }
+
+    fn eot_token_id(&self) -> TokenId {
+        0
+    }
+
+    fn quantize_tensors() -> Vec<Regex> {
+        ["tok_embeddings", "output", r"layers\.\d+\.w\d"]
+            .into_iter()
+            .map(|s| Regex::new(s).unwrap())
+            .collect()
+    }
+
+    fn skip_quantize_tensors() -> Vec<Regex> {
+        vec![]
+    }
+
+    fn hf_hyperparameters(
+        config: &HfConfig,
+        file_type: FileType,
+    ) -> Result<Self::Hyperparameters, LoadError> {
+        Ok(TestHyperparameters {
+            n_vocab: config.get("vocab_size")?,
+            n_embd: config.get("hidden_size")?,
+            n_layer: config.get("num_hidden_layers")?,
+            file_type,
+        })
+    }
+
+    fn hf_tensor_name(name: &str) -> Option<String> {
+        let name = match name {
+            "model.embed_tokens.weight" => "tok_embeddings",
+            "model.norm.weight" => "norm",
+            "lm_head.weight" => "output",
+            name => {
+                let (layer, name) = name.strip_prefix("model.layers.")?.split_once('.')?;
+                let name = match name {
+                    "norm.weight" => "norm",
+                    "mlp.up_proj.weight" => "w1",
+                    "mlp.down_proj.weight" => "w2",
+                    _ => return None,
+                };
+                return Some(format!("layers.{layer}.{name}"));
+            }
+        };
+        Some(name.to_string())
+    }
+
+    fn hf_transposed(name: &str) -> bool {
+        // Stored as [in, out], like the weights of `Conv1D` modules.
+        name.ends_with(".w2")
+    }
+
+    fn supports_rewind(&self) -> bool {
+        true
+    }
+}
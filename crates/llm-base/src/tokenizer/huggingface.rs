@@ -1,4 +1,8 @@
-use super::{TokenId, TokenizationError};
+use std::collections::HashMap;
+
+use tokenizers::{DecoderWrapper, ModelWrapper};
+
+use super::{Token, TokenId, TokenScore, TokenizationError};
 
 /// A Hugging Face tokenizer.
 #[derive(Debug, Clone)]
@@ -38,6 +42,45 @@ impl HuggingFaceTokenizer {
         self.tokenizer.get_vocab_size(false) == 0
     }
 
+    /// Returns the bytes and score of every token in this tokenizer, including added tokens,
+    /// so that they can be saved as the vocabulary of a GGML model.
+    ///
+    /// Byte-level tokens and SentencePiece pieces are converted back to the bytes they stand
+    /// for. Only unigram models have scores; the tokens of other models are scored `0.0`.
+    pub(crate) fn vocabulary(&self) -> Vec<(Token, TokenScore)> {
+        let byte_level = matches!(
+            self.tokenizer.get_decoder(),
+            Some(DecoderWrapper::ByteLevel(_))
+        );
+        let scores: HashMap<&str, TokenScore> = match self.tokenizer.get_model() {
+            ModelWrapper::Unigram(unigram) => unigram
+                .iter()
+                .map(|(token, score)| (token.as_str(), *score as TokenScore))
+                .collect(),
+            _ => HashMap::new(),
+        };
+        let char_bytes = byte_level_char_bytes();
+
+        (0..self.tokenizer.get_vocab_size(true))
+            .map(|id| {
+                let Some(token) = self.tokenizer.id_to_token(id as TokenId) else {
+                    return (vec![], 0.0);
+                };
+                let score = scores.get(token.as_str()).copied().unwrap_or(0.0);
+                let bytes = if byte_level {
+                    token
+                        .chars()
+                        .map(|c| char_bytes.get(&c).copied())
+                        .collect::<Option<_>>()
+                        .unwrap_or_else(|| token.into_bytes())
+                } else {
+                    piece_bytes(&token)
+                };
+                (bytes, score)
+            })
+            .collect()
+    }
+
     /// Tokenize a `text` with this tokenizer.
     ///
     /// `bos` controls whether a beginning-of-string token should be inserted.
@@ -73,3 +116,35 @@ impl HuggingFaceTokenizer {
             .to_vec()
     }
 }
+
+/// Returns the byte that each character of a byte-level (GPT-2 style) token stands for.
+///
+/// Printable bytes are represented by themselves, and the remaining bytes by the characters
+/// from U+0100 onwards, in order.
+fn byte_level_char_bytes() -> HashMap<char, u8> {
+    let mut n = 0;
+    (0..=u8::MAX)
+        .map(|byte| {
+            let c = if matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF) {
+                char::from(byte)
+            } else {
+                n += 1;
+                char::from_u32(0xFF + n).unwrap()
+            };
+            (c, byte)
+        })
+        .collect()
+}
+
+/// Returns the bytes a SentencePiece piece stands for: byte fallback pieces such as `<0x0A>`
+/// are a single byte, and `▁` is a space.
+fn piece_bytes(piece: &str) -> Token {
+    if let Some(hex) = piece.strip_prefix("<0x").and_then(|p| p.strip_suffix('>')) {
+        if hex.len() == 2 {
+            if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                return vec![byte];
+            }
+        }
+    }
+    piece.replace('\u{2581}', " ").into_bytes()
+}
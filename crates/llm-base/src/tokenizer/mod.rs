@@ -106,6 +106,7 @@ impl TokenizerSource {
 }
 
 /// Encapsulates the tokenizer for a model, and provides methods to tokenize text.
+#[allow(clippy::large_enum_variant)]
 pub enum Tokenizer {
     /// The vocabulary built-in to the model.
     Embedded(EmbeddedTokenizer),
@@ -163,6 +164,15 @@ impl Tokenizer {
         }
     }
 
+    /// Returns the bytes and score of every token in this tokenizer, in order of their IDs,
+    /// as they are saved in the vocabulary of a GGML model.
+    pub(crate) fn vocabulary(&self) -> Vec<(Token, TokenScore)> {
+        match self {
+            Tokenizer::Embedded(v) => v.iter().collect(),
+            Tokenizer::HuggingFace(v) => v.vocabulary(),
+        }
+    }
+
     /// Tokenize a `text` with this tokenizer.
     ///
     /// `bos` controls whether a beginning-of-string token should be inserted.
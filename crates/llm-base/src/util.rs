@@ -150,6 +150,59 @@ pub fn softmax(logits: &[f32]) -> Vec<f32> {
     probs
 }
 
+/// Transposes `rows` rows of `columns` values.
+pub(crate) fn transpose(data: &[f32], rows: usize, columns: usize) -> Vec<f32> {
+    let mut transposed = vec![0.0; data.len()];
+    for row in 0..rows {
+        for column in 0..columns {
+            transposed[column * rows + row] = data[row * columns + column];
+        }
+    }
+    transposed
+}
+
+/// Reorders the `rows` rows of a weight of `n_head` attention heads from the Hugging Face
+/// layout, which keeps the two halves of the rotary dimensions of a head apart, to the
+/// original layout, which interleaves them.
+pub(crate) fn unpermute_heads(data: &[f32], rows: usize, n_head: usize) -> Vec<f32> {
+    let row_len = data.len() / rows;
+    let head_rows = rows / n_head;
+    let half = head_rows / 2;
+
+    let mut output = Vec::with_capacity(data.len());
+    for head in 0..n_head {
+        for i in 0..half {
+            for j in 0..2 {
+                let row = head * head_rows + j * half + i;
+                output.extend_from_slice(&data[row * row_len..(row + 1) * row_len]);
+            }
+        }
+    }
+    output
+}
+
+/// Converts safetensors data of type `dtype` to `f32` values.
+pub(crate) fn to_f32(dtype: safetensors::Dtype, data: &[u8]) -> Option<Vec<f32>> {
+    match dtype {
+        safetensors::Dtype::F32 => Some(
+            data.chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect(),
+        ),
+        safetensors::Dtype::F16 => Some(
+            data.chunks_exact(2)
+                .map(|chunk| half::f16::from_le_bytes(chunk.try_into().unwrap()).to_f32())
+                .collect(),
+        ),
+        safetensors::Dtype::BF16 => Some(
+            data.chunks_exact(2)
+                .map(|chunk| half::bf16::from_le_bytes(chunk.try_into().unwrap()).to_f32())
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
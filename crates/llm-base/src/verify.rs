@@ -0,0 +1,538 @@
+//! Implements verification of the integrity of model files.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{ContainerType, KnownModel, LoadError, Loader, Tokenizer, TokenizerSource};
+
+/// The maximum number of bytes of a tensor's data that [verify] reads at a time.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Each of the steps of [verify].
+#[derive(Clone, Copy, Debug)]
+pub enum VerifyProgress<'a> {
+    /// The hyperparameters and tensor headers have been loaded from the model.
+    Loaded {
+        /// The number of tensors in the model.
+        tensor_count: usize,
+    },
+    /// A tensor has been verified.
+    TensorVerified {
+        /// The name of the tensor.
+        name: &'a str,
+        /// The current tensor (0-indexed).
+        current_tensor: usize,
+        /// The number of total tensors.
+        tensor_count: usize,
+    },
+    /// The SHA-256 hash of the model file has been computed.
+    HashComputed {
+        /// The hash, as lowercase hexadecimal.
+        sha256: &'a str,
+    },
+}
+
+/// A problem found in a model file by [verify].
+#[derive(Clone, Debug, PartialEq)]
+pub enum VerifyIssue {
+    /// The data of a tensor extends past the end of the file, which usually means that the file
+    /// was truncated.
+    TensorOutOfBounds {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The offset of the first byte of the tensor's data.
+        start: u64,
+        /// The offset after the last byte of the tensor's data.
+        end: u64,
+        /// The size of the file.
+        file_size: u64,
+    },
+    /// The padding between the header of a tensor in a GGJT model and its aligned data is not
+    /// zero, which means that the header and the data do not line up as the loader expects.
+    NonZeroPadding {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The offset of the first byte of the padding, right after the tensor's header.
+        start: u64,
+        /// The offset after the last byte of the padding, where the tensor's data starts.
+        end: u64,
+    },
+    /// The number of elements of a quantized tensor is not a multiple of the block size of its
+    /// type, so its data cannot be dequantized.
+    PartialBlock {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The number of elements in the tensor.
+        n_elements: usize,
+        /// The number of elements in each block of the tensor's type.
+        block_size: usize,
+    },
+    /// A tensor contains NaN or infinite values.
+    NonFiniteValues {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The number of values that are NaN or infinite.
+        count: usize,
+    },
+    /// The SHA-256 hash of the file does not match the one in the manifest.
+    ChecksumMismatch {
+        /// The hash in the manifest.
+        expected: String,
+        /// The hash of the file.
+        actual: String,
+    },
+}
+impl std::fmt::Display for VerifyIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyIssue::TensorOutOfBounds {
+                tensor_name,
+                start,
+                end,
+                file_size,
+            } => write!(
+                f,
+                "the data of tensor `{tensor_name}` ({start}..{end}) extends past the end of the file ({file_size} bytes)"
+            ),
+            VerifyIssue::NonZeroPadding {
+                tensor_name,
+                start,
+                end,
+            } => write!(
+                f,
+                "the padding before the data of tensor `{tensor_name}` ({start}..{end}) is not zero"
+            ),
+            VerifyIssue::PartialBlock {
+                tensor_name,
+                n_elements,
+                block_size,
+            } => write!(
+                f,
+                "tensor `{tensor_name}` has {n_elements} elements, which is not a multiple of its block size {block_size}"
+            ),
+            VerifyIssue::NonFiniteValues { tensor_name, count } => write!(
+                f,
+                "tensor `{tensor_name}` contains {count} NaN or infinite values"
+            ),
+            VerifyIssue::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "the SHA-256 hash of the file is {actual}, but the manifest expects {expected}"
+            ),
+        }
+    }
+}
+
+/// The result of [verify].
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifyReport {
+    /// The container type of the model.
+    pub container_type: ContainerType,
+    /// The size of the model file.
+    pub file_size: u64,
+    /// The number of tensors in the model.
+    pub tensor_count: usize,
+    /// The SHA-256 hash of the file as lowercase hexadecimal, if it was checked against a
+    /// manifest.
+    pub sha256: Option<String>,
+    /// The problems that were found, in the order of the tensors in the file.
+    pub issues: Vec<VerifyIssue>,
+}
+impl VerifyReport {
+    /// Returns whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+#[derive(Error, Debug)]
+/// Errors that prevented [verify] from checking a model.
+pub enum VerifyError {
+    #[error("could not load model")]
+    /// The model's header or the header of one of its tensors could not be loaded, e.g.
+    /// because the file was truncated within them.
+    Load(#[from] LoadError),
+    #[error("non-specific I/O error")]
+    /// A non-specific IO error.
+    Io(#[from] std::io::Error),
+    #[error("could not read manifest {path:?}")]
+    /// The manifest could not be read.
+    ManifestReadFailed {
+        /// The original error.
+        source: std::io::Error,
+        /// The path of the manifest.
+        path: PathBuf,
+    },
+    #[error("line {line} of manifest {path:?} is not of the form `<sha256>  <file name>`")]
+    /// A line of the manifest could not be parsed.
+    InvalidManifest {
+        /// The path of the manifest.
+        path: PathBuf,
+        /// The line that could not be parsed (1-indexed).
+        line: usize,
+    },
+    #[error("manifest {path:?} has no entry for {file_name:?}")]
+    /// The manifest does not list the model.
+    NotInManifest {
+        /// The path of the manifest.
+        path: PathBuf,
+        /// The file name of the model.
+        file_name: String,
+    },
+}
+
+/// Verifies the integrity of the model at `path` without loading it.
+///
+/// The data of each tensor is read and checked in chunks, so that only a small part of the
+/// model is held in memory at any time.
+///
+/// Fails if the headers of the model cannot be loaded. Otherwise, every tensor is checked for
+/// problems that would only show up later as I/O errors or garbage output:
+/// - its data must lie within the file, which catches truncated downloads;
+/// - in GGJT models, the padding between its header and its aligned data must be zero;
+/// - its number of elements must be a multiple of the block size of its type;
+/// - its values, dequantized if necessary, must not be NaN or infinite.
+///
+/// If a `manifest` is provided, the SHA-256 hash of the file is also checked against the entry
+/// for the model's file name. The manifest uses the format of `sha256sum`, i.e. lines of
+/// `<sha256>  <file name>`.
+pub fn verify<M: KnownModel>(
+    path: &Path,
+    manifest: Option<&Path>,
+    mut progress_callback: impl FnMut(VerifyProgress),
+) -> Result<VerifyReport, VerifyError> {
+    let expected_sha256 = manifest
+        .map(|manifest| manifest_sha256(manifest, path))
+        .transpose()?;
+
+    let file = File::open(path).map_err(|source| LoadError::OpenFileFailed {
+        source,
+        path: path.to_owned(),
+    })?;
+    let file_size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    // The header of the first tensor follows the vocabulary.
+    let mut header_start = {
+        let mut loader: Loader<M::Hyperparameters, _> =
+            Loader::new(Tokenizer::empty_embedded(), |_| {});
+        loader.wants_tensors = false;
+        ggml::format::load(&mut reader, &mut loader)
+            .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+        reader.stream_position()?
+    };
+    reader.seek(SeekFrom::Start(0))?;
+
+    let tokenizer = TokenizerSource::Embedded
+        .retrieve(path)
+        .expect("embedded tokenizers can always be retrieved");
+    let mut loader: Loader<M::Hyperparameters, _> = Loader::new(tokenizer, |_| {});
+    ggml::format::load(&mut reader, &mut loader)
+        .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+    let mut tensors: Vec<_> = loader.tensors.values().collect();
+    tensors.sort_by_key(|tensor| tensor.start_offset);
+    progress_callback(VerifyProgress::Loaded {
+        tensor_count: tensors.len(),
+    });
+
+    let mut issues = vec![];
+    for (current_tensor, tensor) in tensors.iter().enumerate() {
+        let start = tensor.start_offset;
+        let end = start + tensor.calc_file_size() as u64;
+        let header_end = header_start + (12 + 4 * tensor.n_dims + tensor.name.len()) as u64;
+        header_start = end;
+
+        // The padding is only read if it lies within the file; otherwise, the data of the
+        // tensor does not either, which is reported below.
+        if matches!(loader.container_type, ContainerType::Ggjt(_))
+            && header_end.max(start) <= file_size
+        {
+            let mut padding = vec![0; start.saturating_sub(header_end) as usize];
+            reader.seek(SeekFrom::Start(header_end))?;
+            reader.read_exact(&mut padding)?;
+            if padding.iter().any(|&byte| byte != 0) {
+                issues.push(VerifyIssue::NonZeroPadding {
+                    tensor_name: tensor.name.clone(),
+                    start: header_end,
+                    end: start,
+                });
+            }
+        }
+
+        let block_size = ggml::blck_size(tensor.element_type);
+        if tensor.n_elements % block_size != 0 {
+            issues.push(VerifyIssue::PartialBlock {
+                tensor_name: tensor.name.clone(),
+                n_elements: tensor.n_elements,
+                block_size,
+            });
+        } else if header_end.max(end) > file_size {
+            issues.push(VerifyIssue::TensorOutOfBounds {
+                tensor_name: tensor.name.clone(),
+                start,
+                end,
+                file_size,
+            });
+        } else {
+            let mut count = 0;
+            tensor.read_data_chunked(&mut reader, CHUNK_SIZE, |data| {
+                if let Some(values) = ggml::dequantize(tensor.element_type, data) {
+                    count += values.iter().filter(|value| !value.is_finite()).count();
+                }
+            })?;
+            if count > 0 {
+                issues.push(VerifyIssue::NonFiniteValues {
+                    tensor_name: tensor.name.clone(),
+                    count,
+                });
+            }
+        }
+
+        progress_callback(VerifyProgress::TensorVerified {
+            name: &tensor.name,
+            current_tensor,
+            tensor_count: tensors.len(),
+        });
+    }
+
+    let sha256 = match expected_sha256 {
+        Some(expected) => {
+            reader.seek(SeekFrom::Start(0))?;
+            let actual = sha256_hex(&mut reader)?;
+            progress_callback(VerifyProgress::HashComputed { sha256: &actual });
+            if actual != expected {
+                issues.push(VerifyIssue::ChecksumMismatch {
+                    expected,
+                    actual: actual.clone(),
+                });
+            }
+            Some(actual)
+        }
+        None => None,
+    };
+
+    Ok(VerifyReport {
+        container_type: loader.container_type,
+        file_size,
+        tensor_count: tensors.len(),
+        sha256,
+        issues,
+    })
+}
+
+/// Returns the lowercase SHA-256 hash that `manifest` lists for the file name of `path`.
+fn manifest_sha256(manifest: &Path, path: &Path) -> Result<String, VerifyError> {
+    let contents =
+        std::fs::read_to_string(manifest).map_err(|source| VerifyError::ManifestReadFailed {
+            source,
+            path: manifest.to_owned(),
+        })?;
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = || VerifyError::InvalidManifest {
+            path: manifest.to_owned(),
+            line: i + 1,
+        };
+        let (hash, name) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        // `sha256sum` marks files that were read in binary mode with a `*`
+        let name = name.trim_start();
+        let name = name.strip_prefix('*').unwrap_or(name);
+        if Path::new(name).file_name() == path.file_name() {
+            return Ok(hash.to_ascii_lowercase());
+        }
+    }
+
+    Err(VerifyError::NotInManifest {
+        path: manifest.to_owned(),
+        file_name,
+    })
+}
+
+/// Returns the SHA-256 hash of the rest of `reader` as lowercase hexadecimal.
+fn sha256_hex(reader: &mut impl Read) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn can_verify_model() {
+        let model_path = temp_path("verify-model.bin");
+        let manifest_path = temp_path("verify-manifest.sha256");
+        let tensors = model_tensors(test_hyperparameters());
+        let (last_tensor, _) = tensors.last().unwrap().clone();
+        write_model(&model_path, test_hyperparameters(), tensors.clone());
+        let original = std::fs::read(&model_path).unwrap();
+        let file_name = model_path.file_name().unwrap().to_str().unwrap().to_owned();
+
+        let report = verify::<TestModel>(&model_path, None, |_| {}).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.tensor_count, tensors.len());
+        assert_eq!(report.file_size, original.len() as u64);
+        assert_eq!(report.sha256, None);
+
+        // The hash is checked against the manifest entry for the model's file name.
+        std::fs::write(
+            &manifest_path,
+            format!(
+                "{}  other.bin\n{}  {file_name}\n",
+                "1".repeat(64),
+                "0".repeat(64)
+            ),
+        )
+        .unwrap();
+        let report = verify::<TestModel>(&model_path, Some(&manifest_path), |_| {}).unwrap();
+        let sha256 = report.sha256.clone().unwrap();
+        assert_eq!(
+            report.issues,
+            [VerifyIssue::ChecksumMismatch {
+                expected: "0".repeat(64),
+                actual: sha256.clone(),
+            }]
+        );
+        std::fs::write(&manifest_path, format!("{sha256} *{file_name}\n")).unwrap();
+        let report = verify::<TestModel>(&model_path, Some(&manifest_path), |_| {}).unwrap();
+        assert!(report.is_ok());
+
+        std::fs::write(&manifest_path, format!("{sha256}  other.bin\n")).unwrap();
+        assert!(matches!(
+            verify::<TestModel>(&model_path, Some(&manifest_path), |_| {}),
+            Err(VerifyError::NotInManifest { .. })
+        ));
+        std::fs::write(&manifest_path, "not a hash\n").unwrap();
+        assert!(matches!(
+            verify::<TestModel>(&model_path, Some(&manifest_path), |_| {}),
+            Err(VerifyError::InvalidManifest { line: 1, .. })
+        ));
+
+        // The last value of the file belongs to the last tensor.
+        let mut corrupted = original.clone();
+        let n = corrupted.len();
+        corrupted[n - 4..].copy_from_slice(&f32::NAN.to_le_bytes());
+        std::fs::write(&model_path, &corrupted).unwrap();
+        let report = verify::<TestModel>(&model_path, None, |_| {}).unwrap();
+        assert_eq!(
+            report.issues,
+            [VerifyIssue::NonFiniteValues {
+                tensor_name: last_tensor.clone(),
+                count: 1,
+            }]
+        );
+
+        std::fs::write(&model_path, &original[..n - 4]).unwrap();
+        let report = verify::<TestModel>(&model_path, None, |_| {}).unwrap();
+        assert!(matches!(
+            &report.issues[..],
+            [VerifyIssue::TensorOutOfBounds { tensor_name, end, file_size, .. }]
+                if *tensor_name == last_tensor && *end == n as u64 && *file_size == n as u64 - 4
+        ));
+
+        // The header of each tensor follows the data of the previous one.
+        let mut loader: Loader<TestHyperparameters, _> =
+            Loader::new(Tokenizer::empty_embedded(), |_| {});
+        ggml::format::load(&mut std::io::Cursor::new(&original), &mut loader).unwrap();
+        let mut infos: Vec<_> = loader.tensors.values().collect();
+        infos.sort_by_key(|info| info.start_offset);
+        let headers: Vec<_> = infos
+            .windows(2)
+            .map(|pair| {
+                let header_start = pair[0].start_offset + pair[0].calc_file_size() as u64;
+                let header_end =
+                    header_start + (12 + 4 * pair[1].n_dims + pair[1].name.len()) as u64;
+                (pair[1], header_start as usize, header_end)
+            })
+            .collect();
+
+        let (info, _, header_end) = headers
+            .iter()
+            .find(|(info, _, header_end)| *header_end < info.start_offset)
+            .unwrap();
+        let mut corrupted = original.clone();
+        corrupted[info.start_offset as usize - 1] = 1;
+        std::fs::write(&model_path, &corrupted).unwrap();
+        let report = verify::<TestModel>(&model_path, None, |_| {}).unwrap();
+        assert_eq!(
+            report.issues,
+            [VerifyIssue::NonZeroPadding {
+                tensor_name: info.name.clone(),
+                start: *header_end,
+                end: info.start_offset,
+            }]
+        );
+
+        // A file truncated right after the header of the last tensor is out of bounds, too.
+        let (info, _, header_end) = headers.last().unwrap();
+        std::fs::write(&model_path, &original[..*header_end as usize]).unwrap();
+        let report = verify::<TestModel>(&model_path, None, |_| {}).unwrap();
+        assert_eq!(
+            report.issues,
+            [VerifyIssue::TensorOutOfBounds {
+                tensor_name: info.name.clone(),
+                start: info.start_offset,
+                end: n as u64,
+                file_size: *header_end,
+            }]
+        );
+
+        // Turn the last tensor into a Q8_0 tensor with 1000 rows of 33 values, whose data
+        // extends past the end of the file so that no further headers are read.
+        let (info, header_start, _) = headers.last().unwrap();
+        let mut corrupted = original.clone();
+        corrupted[header_start + 8..header_start + 12]
+            .copy_from_slice(&u32::from(ggml::Type::Q8_0).to_le_bytes());
+        corrupted[header_start + 12..header_start + 16].copy_from_slice(&33i32.to_le_bytes());
+        corrupted[header_start + 16..header_start + 20].copy_from_slice(&1000i32.to_le_bytes());
+        std::fs::write(&model_path, &corrupted).unwrap();
+        let report = verify::<TestModel>(&model_path, None, |_| {}).unwrap();
+        assert_eq!(
+            report.issues,
+            [VerifyIssue::PartialBlock {
+                tensor_name: info.name.clone(),
+                n_elements: 33 * 1000,
+                block_size: 32,
+            }]
+        );
+
+        // A file truncated within its headers cannot be verified at all.
+        std::fs::write(&model_path, &original[..10]).unwrap();
+        assert!(matches!(
+            verify::<TestModel>(&model_path, None, |_| {}),
+            Err(VerifyError::Load(_))
+        ));
+
+        std::fs::remove_file(&model_path).unwrap();
+        std::fs::remove_file(&manifest_path).unwrap();
+    }
+}
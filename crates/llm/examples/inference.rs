@@ -64,6 +64,7 @@ fn main() {
             parameters: &llm::InferenceParameters::default(),
             play_back_previous_tokens: false,
             maximum_token_count: None,
+            cancellation: None,
         },
         // OutputRequest
         &mut Default::default(),
@@ -94,6 +94,7 @@ fn main() {
                             parameters: &inference_parameters,
                             play_back_previous_tokens: false,
                             maximum_token_count: None,
+                            cancellation: None,
                         },
                         &mut Default::default(),
                         conversation_inference_callback(&format!("{character_name}:"), print_token),
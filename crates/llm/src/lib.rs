@@ -45,6 +45,7 @@
 //!         parameters: &llm::InferenceParameters::default(),
 //!         play_back_previous_tokens: false,
 //!         maximum_token_count: None,
+//!         cancellation: None,
 //!     },
 //!     // llm::OutputRequest
 //!     &mut Default::default(),
@@ -77,17 +78,23 @@ use std::{
 // Try not to expose too many GGML details here.
 // This is the "user-facing" API, and GGML may not always be our backend.
 pub use llm_base::{
-    conversation_inference_callback, feed_prompt_callback,
+    conversation_inference_callback, convert_container, convert_hf, dequantize, diff, dump_weights,
+    estimate_memory, feed_prompt_callback,
     ggml::accelerator::get_accelerator as ggml_get_accelerator,
     ggml::accelerator::Accelerator as GgmlAccelerator, ggml::format as ggml_format,
-    ggml::RoPEOverrides, load, load_progress_callback_stdout, quantize, samplers, ElementType,
-    FileType, FileTypeFormat, FormatMagic, Hyperparameters, InferenceError, InferenceFeedback,
-    InferenceParameters, InferenceRequest, InferenceResponse, InferenceSession,
-    InferenceSessionConfig, InferenceSnapshot, InferenceSnapshotRef, InferenceStats,
-    InvalidTokenBias, KnownModel, LoadError, LoadProgress, Loader, Model, ModelKVMemoryType,
-    ModelParameters, OutputRequest, Prompt, QuantizeError, QuantizeProgress, RewindError,
-    SnapshotError, TokenBias, TokenId, TokenUtf8Buffer, TokenizationError, Tokenizer,
-    TokenizerSource,
+    ggml::RoPEOverrides, load, load_progress_callback_stdout, load_tokenizer, merge_lora, quantize,
+    quantize_with_parameters, samplers, save_dumped_tensors, verify, write_npy, CancellationToken,
+    ContainerType, ConvertProgress, DiffProgress, DumpError, DumpedTensor, ElementType, FileType,
+    FileTypeFormat, FormatMagic, HfConfig, Hyperparameters, ImportanceMatrix,
+    ImportanceMatrixError, InferenceError, InferenceFeedback, InferenceParameters,
+    InferenceRequest, InferenceResponse, InferenceSession, InferenceSessionConfig,
+    InferenceSnapshot, InferenceSnapshotRef, InferenceStats, InvalidTokenBias, KnownModel,
+    LoadError, LoadProgress, Loader, LoraAdapter, LoraAdapterId, LoraError, MemoryEstimate, Model,
+    ModelDiff, ModelInfo, ModelKVMemoryType, ModelParameters, OutputRequest, Prompt,
+    QuantizationRule, QuantizationStats, QuantizeError, QuantizeParameters, QuantizeProgress,
+    Regex, RewindError, SnapshotError, TensorDiff, TensorDistance, TensorDumpFormat,
+    TensorShapeMismatch, TokenBias, TokenDiff, TokenId, TokenUtf8Buffer, TokenizationError,
+    Tokenizer, TokenizerSource, VerifyError, VerifyIssue, VerifyProgress, VerifyReport,
 };
 
 use serde::Serialize;
@@ -263,6 +270,75 @@ pub fn load_dynamic(
     })
 }
 
+/// A helper function that loads only the tokenizer of the specified model from disk,
+/// using an architecture specified at runtime, along with information about the model
+/// read from its hyperparameters. None of the model's tensors are loaded.
+///
+/// To get the model's hyperparameters themselves, use [load_tokenizer] with the
+/// architecture's model type (e.g. through [ModelArchitecture::visit]).
+pub fn load_tokenizer_dynamic(
+    architecture: Option<ModelArchitecture>,
+    path: &Path,
+    tokenizer_source: TokenizerSource,
+) -> Result<(Tokenizer, ModelInfo), LoadError> {
+    let architecture = architecture.ok_or_else(|| LoadError::MissingModelArchitecture {
+        path: path.to_owned(),
+    })?;
+
+    struct LoadTokenizerVisitor<'a> {
+        path: &'a Path,
+        tokenizer_source: TokenizerSource,
+    }
+    impl<'a> ModelArchitectureVisitor<Result<(Tokenizer, ModelInfo), LoadError>>
+        for LoadTokenizerVisitor<'a>
+    {
+        fn visit<M: KnownModel + 'static>(&mut self) -> Result<(Tokenizer, ModelInfo), LoadError> {
+            let (tokenizer, hyperparameters, container_type) =
+                load_tokenizer::<M>(self.path, self.tokenizer_source.clone())?;
+            Ok((
+                tokenizer,
+                M::info_from_hyperparameters(&hyperparameters, container_type),
+            ))
+        }
+    }
+
+    architecture.visit(&mut LoadTokenizerVisitor {
+        path,
+        tokenizer_source,
+    })
+}
+
+/// A helper function that estimates the memory required to load the specified model
+/// and run an inference session with it, using an architecture specified at runtime.
+/// The model itself is not loaded.
+pub fn estimate_memory_dynamic(
+    architecture: Option<ModelArchitecture>,
+    path: &Path,
+    params: &ModelParameters,
+    session_config: &InferenceSessionConfig,
+) -> Result<MemoryEstimate, LoadError> {
+    let architecture = architecture.ok_or_else(|| LoadError::MissingModelArchitecture {
+        path: path.to_owned(),
+    })?;
+
+    struct EstimateMemoryVisitor<'a> {
+        path: &'a Path,
+        params: &'a ModelParameters,
+        session_config: &'a InferenceSessionConfig,
+    }
+    impl<'a> ModelArchitectureVisitor<Result<MemoryEstimate, LoadError>> for EstimateMemoryVisitor<'a> {
+        fn visit<M: KnownModel + 'static>(&mut self) -> Result<MemoryEstimate, LoadError> {
+            estimate_memory::<M>(self.path, self.params, self.session_config)
+        }
+    }
+
+    architecture.visit(&mut EstimateMemoryVisitor {
+        path,
+        params,
+        session_config,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
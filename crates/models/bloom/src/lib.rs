@@ -5,7 +5,7 @@
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel,
+    util, FileType, GraphOutputs, HfConfig, InferenceSession, InferenceSessionConfig, KnownModel,
     ModelContext, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
 };
 
@@ -51,36 +51,56 @@ impl KnownModel for Bloom {
         tensor_loader: impl llm_base::TensorLoader<E>,
     ) -> Result<Self, E> {
         let mut tl = tensor_loader;
+        let Hyperparameters {
+            n_vocab,
+            n_embd,
+            n_mult,
+            ..
+        } = hyperparameters;
+        let n_ff = ((4 * n_embd + n_mult - 1) / n_mult) * n_mult;
 
         // model-global weights
-        let wte = tl.load("tok_embeddings.weight")?;
-        let norm = tl.load("norm.weight")?;
-        let norm_bias = tl.load("norm.bias")?;
-        let output_norm = tl.load("output_norm.weight")?;
-        let output_norm_bias = tl.load("output_norm.bias")?;
-        let output = tl.load("output.weight")?;
+        let wte = tl.load("tok_embeddings.weight", &[n_embd, n_vocab])?;
+        let norm = tl.load("norm.weight", &[n_embd])?;
+        let norm_bias = tl.load("norm.bias", &[n_embd])?;
+        let output_norm = tl.load("output_norm.weight", &[n_embd])?;
+        let output_norm_bias = tl.load("output_norm.bias", &[n_embd])?;
+        let output = tl.load("output.weight", &[n_embd, n_vocab])?;
 
         let mut layers = Vec::new();
         for i in 0..hyperparameters.n_layer {
             let layer = Layer {
-                attention_norm: tl.load(&format!("layers.{i}.attention_norm.weight"))?,
-                attention_norm_b: tl.load(&format!("layers.{i}.attention_norm.bias"))?,
-
-                query_key_value: tl
-                    .load(&format!("layers.{i}.attention.query_key_value.weight"))?,
-                query_key_value_b: tl
-                    .load(&format!("layers.{i}.attention.query_key_value.bias"))?,
-
-                wo: tl.load(&format!("layers.{i}.attention.wo.weight"))?,
-                wo_b: tl.load(&format!("layers.{i}.attention.wo.bias"))?,
-
-                ffn_norm: tl.load(&format!("layers.{i}.ffn_norm.weight"))?,
-                ffn_norm_b: tl.load(&format!("layers.{i}.ffn_norm.bias"))?,
-
-                w1: tl.load(&format!("layers.{i}.feed_forward.w1.weight"))?,
-                w1_b: tl.load(&format!("layers.{i}.feed_forward.w1.bias"))?,
-                w2: tl.load(&format!("layers.{i}.feed_forward.w2.weight"))?,
-                w2_b: tl.load(&format!("layers.{i}.feed_forward.w2.bias"))?,
+                attention_norm: tl.load(&format!("layers.{i}.attention_norm.weight"), &[n_embd])?,
+                attention_norm_b: tl.load(&format!("layers.{i}.attention_norm.bias"), &[n_embd])?,
+
+                query_key_value: tl.load(
+                    &format!("layers.{i}.attention.query_key_value.weight"),
+                    &[n_embd, 3 * n_embd],
+                )?,
+                query_key_value_b: tl.load(
+                    &format!("layers.{i}.attention.query_key_value.bias"),
+                    &[3 * n_embd],
+                )?,
+
+                wo: tl.load(
+                    &format!("layers.{i}.attention.wo.weight"),
+                    &[n_embd, n_embd],
+                )?,
+                wo_b: tl.load(&format!("layers.{i}.attention.wo.bias"), &[n_embd])?,
+
+                ffn_norm: tl.load(&format!("layers.{i}.ffn_norm.weight"), &[n_embd])?,
+                ffn_norm_b: tl.load(&format!("layers.{i}.ffn_norm.bias"), &[n_embd])?,
+
+                w1: tl.load(
+                    &format!("layers.{i}.feed_forward.w1.weight"),
+                    &[n_embd, n_ff],
+                )?,
+                w1_b: tl.load(&format!("layers.{i}.feed_forward.w1.bias"), &[n_ff])?,
+                w2: tl.load(
+                    &format!("layers.{i}.feed_forward.w2.weight"),
+                    &[n_ff, n_embd],
+                )?,
+                w2_b: tl.load(&format!("layers.{i}.feed_forward.w2.bias"), &[n_embd])?,
             };
 
             layers.push(layer);
@@ -159,7 +179,8 @@ impl KnownModel for Bloom {
                 current = ctx0.op_add(&current, &self.layers[il].attention_norm_b);
 
                 //attention
-                current = ctx0.op_mul_mat(&self.layers[il].query_key_value, &current);
+                current =
+                    builder.op_mul_mat_weight(&ctx0, &self.layers[il].query_key_value, &current);
                 current = ctx0.op_add(&current, &self.layers[il].query_key_value_b);
 
                 // self-attention
@@ -281,7 +302,7 @@ impl KnownModel for Bloom {
                 );
 
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
+                current = builder.op_mul_mat_weight(&ctx0, &self.layers[il].wo, &current);
                 current = ctx0.op_add(&current, &self.layers[il].wo_b);
 
                 let input_feed_forward = ctx0.op_add(&current, &input_self_attention);
@@ -295,7 +316,7 @@ impl KnownModel for Bloom {
 
                 current = ctx0.op_add(&current, &self.layers[il].ffn_norm_b);
 
-                current = ctx0.op_mul_mat(&self.layers[il].w1, &current);
+                current = builder.op_mul_mat_weight(&ctx0, &self.layers[il].w1, &current);
 
                 current = ctx0.op_add(&current, &self.layers[il].w1_b);
 
@@ -303,7 +324,7 @@ impl KnownModel for Bloom {
 
                 current = ctx0.op_gelu(&current);
 
-                current = ctx0.op_mul_mat(&self.layers[il].w2, &current);
+                current = builder.op_mul_mat_weight(&ctx0, &self.layers[il].w2, &current);
 
                 current = ctx0.op_add(&current, &self.layers[il].w2_b);
 
@@ -324,7 +345,7 @@ impl KnownModel for Bloom {
             let embeddings_tensor: ggml::Tensor = input_layer.share();
 
             // lm_head
-            input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
+            input_layer = builder.op_mul_mat_weight(&ctx0, &self.output, &input_layer);
 
             (
                 gf,
@@ -349,6 +370,10 @@ impl KnownModel for Bloom {
         &self.tokenizer
     }
 
+    fn model_context(&self) -> Option<&ModelContext> {
+        Some(&self.context)
+    }
+
     fn context_size(&self) -> usize {
         self.params.context_size
     }
@@ -369,6 +394,60 @@ impl KnownModel for Bloom {
         vec![]
     }
 
+    fn hf_hyperparameters(
+        config: &HfConfig,
+        file_type: FileType,
+    ) -> Result<Self::Hyperparameters, llm_base::LoadError> {
+        let n_embd = match config.get_optional("n_embed")? {
+            Some(n_embd) => n_embd,
+            None => config.get("hidden_size")?,
+        };
+        let n_head = match config.get_optional("n_head")? {
+            Some(n_head) => n_head,
+            None => config.get("num_attention_heads")?,
+        };
+        let n_layer = match config.get_optional("n_layer")? {
+            Some(n_layer) => n_layer,
+            None => config.get("num_hidden_layers")?,
+        };
+        Ok(Hyperparameters {
+            n_vocab: config.get("vocab_size")?,
+            n_embd,
+            // Not used by BLOOM, whose feed-forward size is always 4 * n_embd
+            n_mult: 1,
+            n_head,
+            n_layer,
+            file_type,
+        })
+    }
+
+    fn hf_tensor_name(name: &str) -> Option<String> {
+        let name = match name {
+            "lm_head.weight" => return Some("output.weight".to_owned()),
+            name => name.strip_prefix("transformer.")?,
+        };
+        let (module, parameter) = name.rsplit_once('.')?;
+        let module = match module {
+            "word_embeddings" => "tok_embeddings".to_owned(),
+            "word_embeddings_layernorm" => "norm".to_owned(),
+            "ln_f" => "output_norm".to_owned(),
+            module => {
+                let (layer, module) = module.strip_prefix("h.")?.split_once('.')?;
+                let module = match module {
+                    "input_layernorm" => "attention_norm",
+                    "self_attention.query_key_value" => "attention.query_key_value",
+                    "self_attention.dense" => "attention.wo",
+                    "post_attention_layernorm" => "ffn_norm",
+                    "mlp.dense_h_to_4h" => "feed_forward.w1",
+                    "mlp.dense_4h_to_h" => "feed_forward.w2",
+                    _ => return None,
+                };
+                format!("layers.{layer}.{module}")
+            }
+        };
+        Some(format!("{module}.{parameter}"))
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
@@ -417,6 +496,18 @@ impl llm_base::Hyperparameters for Hyperparameters {
         self.n_vocab
     }
 
+    fn n_embd(&self) -> Option<usize> {
+        Some(self.n_embd)
+    }
+
+    fn n_layer(&self) -> Option<usize> {
+        Some(self.n_layer)
+    }
+
+    fn n_head(&self) -> Option<usize> {
+        Some(self.n_head)
+    }
+
     fn file_type(&self) -> Option<FileType> {
         Some(self.file_type)
     }
@@ -11,8 +11,8 @@ use ggml::Tensor;
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelContext, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
+    util, FileType, GraphOutputs, HfConfig, InferenceSession, InferenceSessionConfig, KnownModel,
+    LoadError, ModelContext, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
 };
 
 /// The Falcon model. Ref: [Technology Innovation Institute](https://huggingface.co/tiiuae)
@@ -54,18 +54,32 @@ impl KnownModel for Falcon {
     ) -> Result<Self, E> {
         let mut tl = tensor_loader;
 
+        // utilizing n_head_kv to determine the model version (parameters)
+        let Hyperparameters {
+            n_vocab,
+            n_embd,
+            n_head,
+            n_head_kv,
+            ..
+        } = hyperparameters;
+        let head_dim = n_embd / n_head;
+
         // model-gobal weights
-        let tok_embeddings = tl.load("transformer.word_embeddings.weight")?;
+        let tok_embeddings = tl.load("transformer.word_embeddings.weight", &[n_embd, n_vocab])?;
 
         let backend = params.backend(0);
 
-        let output_norm = tl.load("transformer.ln_f.weight")?.transfer_to(backend);
-        let output_norm_b = tl.load("transformer.ln_f.bias")?.transfer_to(backend);
-        let lm_head = tl.load("lm_head.weight")?.transfer_to(backend);
+        let output_norm = tl
+            .load("transformer.ln_f.weight", &[n_embd])?
+            .transfer_to(backend);
+        let output_norm_b = tl
+            .load("transformer.ln_f.bias", &[n_embd])?
+            .transfer_to(backend);
+        let lm_head = tl
+            .load("lm_head.weight", &[n_embd, n_vocab])?
+            .transfer_to(backend);
 
         let mut layers = Vec::new();
-        // utilizing n_head_kv to determine the model version (parameters)
-        let Hyperparameters { n_head_kv, .. } = hyperparameters;
         for i in 0..hyperparameters.n_layer {
             let backend = params.backend(i);
 
@@ -84,11 +98,11 @@ impl KnownModel for Falcon {
                 if let Some(norm_name) = attention_norm_name {
                     (
                         Some(
-                            tl.load(&format!("{}.weight", norm_name))?
+                            tl.load(&format!("{}.weight", norm_name), &[n_embd])?
                                 .transfer_to(backend),
                         ),
                         Some(
-                            tl.load(&format!("{}.bias", norm_name))?
+                            tl.load(&format!("{}.bias", norm_name), &[n_embd])?
                                 .transfer_to(backend),
                         ),
                     )
@@ -98,27 +112,37 @@ impl KnownModel for Falcon {
 
             let layer = Layer {
                 input_layernorm: tl
-                    .load(&format!("{}.weight", input_layernorm_name))?
+                    .load(&format!("{}.weight", input_layernorm_name), &[n_embd])?
                     .transfer_to(backend),
                 input_layernorm_b: tl
-                    .load(&format!("{}.bias", input_layernorm_name))?
+                    .load(&format!("{}.bias", input_layernorm_name), &[n_embd])?
                     .transfer_to(backend),
                 attention_norm: attention_norm_weight,
                 attention_norm_b: attention_norm_bias,
                 query_key_value: tl
-                    .load(&format!(
-                        "transformer.h.{i}.self_attention.query_key_value.weight"
-                    ))?
+                    .load(
+                        &format!("transformer.h.{i}.self_attention.query_key_value.weight"),
+                        &[n_embd, (n_head + 2 * n_head_kv) * head_dim],
+                    )?
                     .transfer_to(backend),
                 wo: tl
-                    .load(&format!("transformer.h.{i}.self_attention.dense.weight"))?
+                    .load(
+                        &format!("transformer.h.{i}.self_attention.dense.weight"),
+                        &[n_embd, n_embd],
+                    )?
                     .transfer_to(backend),
 
                 ffn_up: tl
-                    .load(&format!("transformer.h.{i}.mlp.dense_h_to_4h.weight"))?
+                    .load(
+                        &format!("transformer.h.{i}.mlp.dense_h_to_4h.weight"),
+                        &[n_embd, 4 * n_embd],
+                    )?
                     .transfer_to(backend),
                 ffn_down: tl
-                    .load(&format!("transformer.h.{i}.mlp.dense_4h_to_h.weight"))?
+                    .load(
+                        &format!("transformer.h.{i}.mlp.dense_4h_to_h.weight"),
+                        &[4 * n_embd, n_embd],
+                    )?
                     .transfer_to(backend),
             };
 
@@ -215,7 +239,8 @@ impl KnownModel for Falcon {
                 }
 
                 // compute QKV
-                current = ctx0.op_mul_mat(&self.layers[il].query_key_value, &current);
+                current =
+                    builder.op_mul_mat_weight(&ctx0, &self.layers[il].query_key_value, &current);
 
                 let fused_qkv_row_nb = head_dim * (n_head + 2 * n_head_kv) * f32_size;
 
@@ -317,7 +342,7 @@ impl KnownModel for Falcon {
                 );
 
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
+                current = builder.op_mul_mat_weight(&ctx0, &self.layers[il].wo, &current);
 
                 // feed forward uses second scratch buffer
                 ctx0.use_scratch(builder.get_scratch(1));
@@ -326,9 +351,9 @@ impl KnownModel for Falcon {
                 let attn_out =
                     ctx0.op_cpy(&current, &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, n));
 
-                current = ctx0.op_mul_mat(&self.layers[il].ffn_up, &inp_ff);
+                current = builder.op_mul_mat_weight(&ctx0, &self.layers[il].ffn_up, &inp_ff);
                 current = ctx0.op_gelu(&current);
-                current = ctx0.op_mul_mat(&self.layers[il].ffn_down, &current);
+                current = builder.op_mul_mat_weight(&ctx0, &self.layers[il].ffn_down, &current);
 
                 current = ctx0.op_add(&current, &attn_out);
                 current = ctx0.op_add(&current, &input_layer);
@@ -352,7 +377,7 @@ impl KnownModel for Falcon {
             ctx0.use_scratch(None);
 
             // lm_head
-            input_layer = ctx0.op_mul_mat(&self.lm_head, &input_layer);
+            input_layer = builder.op_mul_mat_weight(&ctx0, &self.lm_head, &input_layer);
 
             (
                 gf,
@@ -377,6 +402,10 @@ impl KnownModel for Falcon {
         &self.tokenizer
     }
 
+    fn model_context(&self) -> Option<&ModelContext> {
+        Some(&self.context)
+    }
+
     fn context_size(&self) -> usize {
         self.params.context_size
     }
@@ -396,6 +425,49 @@ impl KnownModel for Falcon {
     fn skip_quantize_tensors() -> Vec<Regex> {
         vec![]
     }
+
+    fn hf_hyperparameters(
+        config: &HfConfig,
+        file_type: FileType,
+    ) -> Result<Self::Hyperparameters, LoadError> {
+        // Falcon checkpoints use either the original or the Transformers names.
+        let n_head = match config.get_optional("n_head")? {
+            Some(n_head) => n_head,
+            None => config.get("num_attention_heads")?,
+        };
+        let n_head_kv = match config.get_optional("n_head_kv")? {
+            Some(n_head_kv) => n_head_kv,
+            None => match config.get_optional("num_kv_heads")? {
+                Some(n_head_kv) => n_head_kv,
+                None if config.get_optional("multi_query")?.unwrap_or(false) => 1,
+                None => n_head,
+            },
+        };
+        let n_layer = match config.get_optional("n_layer")? {
+            Some(n_layer) => n_layer,
+            None => config.get("num_hidden_layers")?,
+        };
+        let hyperparameters = Hyperparameters {
+            n_vocab: config.get("vocab_size")?,
+            n_embd: config.get("hidden_size")?,
+            n_head,
+            n_head_kv,
+            n_layer,
+            file_type,
+        };
+        hyperparameters.validate()?;
+
+        Ok(hyperparameters)
+    }
+
+    fn hf_tensor_name(name: &str) -> Option<String> {
+        // The tensors are named after the Hugging Face implementation.
+        Some(name.to_owned())
+    }
+
+    fn supports_rewind(&self) -> bool {
+        true
+    }
 }
 
 /// Falcon [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -415,6 +487,20 @@ pub struct Hyperparameters {
     file_type: FileType,
 }
 
+impl Hyperparameters {
+    /// Checks that the model can be built with these hyperparameters, as the size of each
+    /// attention head is derived from them.
+    fn validate(&self) -> Result<(), LoadError> {
+        if self.n_head == 0 {
+            return Err(LoadError::InvalidHyperparameter {
+                name: "n_head",
+                reason: "the model must have at least one attention head".to_owned(),
+            });
+        }
+        Ok(())
+    }
+}
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
         let hyperparameters = Hyperparameters {
@@ -425,6 +511,7 @@ impl llm_base::Hyperparameters for Hyperparameters {
             n_layer: util::read_i32(reader)?.try_into()?,
             file_type: util::read_filetype(reader)?,
         };
+        hyperparameters.validate()?;
 
         Ok(hyperparameters)
     }
@@ -443,6 +530,18 @@ impl llm_base::Hyperparameters for Hyperparameters {
         self.n_vocab
     }
 
+    fn n_embd(&self) -> Option<usize> {
+        Some(self.n_embd)
+    }
+
+    fn n_layer(&self) -> Option<usize> {
+        Some(self.n_layer)
+    }
+
+    fn n_head(&self) -> Option<usize> {
+        Some(self.n_head)
+    }
+
     fn file_type(&self) -> Option<FileType> {
         Some(self.file_type)
     }
@@ -469,3 +568,147 @@ struct Layer {
     ffn_up: Tensor,
     ffn_down: Tensor,
 }
+
+#[cfg(test)]
+mod tests {
+    use llm_base::{
+        test_util::{feed, start_session, temp_path, write_model},
+        TokenizerSource,
+    };
+
+    use super::*;
+
+    #[test]
+    fn can_rewind_7b_layout() {
+        test_rewind(1);
+    }
+
+    #[test]
+    fn can_rewind_grouped_kv_layout() {
+        // Falcon 40B groups several query heads onto each key-value head.
+        test_rewind(2);
+    }
+
+    fn test_rewind(n_head_kv: usize) {
+        let hyperparameters = Hyperparameters {
+            n_vocab: 16,
+            n_embd: 32,
+            n_head: 4,
+            n_head_kv,
+            n_layer: 2,
+            file_type: FileType {
+                format: llm_base::FileTypeFormat::F32,
+                quantization_version: 0,
+            },
+        };
+
+        let path = temp_path(&format!("falcon-rewind-{n_head_kv}.bin"));
+        write_model(&path, hyperparameters, model_tensors(hyperparameters));
+        let model = llm_base::load::<Falcon>(
+            &path,
+            TokenizerSource::Embedded,
+            ModelParameters {
+                context_size: 32,
+                ..Default::default()
+            },
+            |_| {},
+        );
+        std::fs::remove_file(&path).unwrap();
+        let model = model.unwrap();
+
+        // Falcon requires 32-bit memory tensors, which the shared session uses.
+        let mut session = start_session(&model);
+        let original_logits = feed(&model, &mut session, &[3, 1, 4, 1, 5]);
+        session.rewind(&model, 3).unwrap();
+        let redone_logits = feed(&model, &mut session, &[4, 1, 5]);
+
+        assert_eq!(original_logits, redone_logits);
+        assert_eq!(session.tokens(), &[3, 1, 4, 1, 5]);
+    }
+
+    #[test]
+    fn rejects_model_without_attention_heads() {
+        let hyperparameters = Hyperparameters {
+            n_vocab: 16,
+            n_embd: 32,
+            n_head: 4,
+            n_head_kv: 1,
+            n_layer: 1,
+            file_type: FileType {
+                format: llm_base::FileTypeFormat::F32,
+                quantization_version: 0,
+            },
+        };
+
+        let path = temp_path("falcon-no-heads.bin");
+        write_model(
+            &path,
+            Hyperparameters {
+                n_head: 0,
+                ..hyperparameters
+            },
+            model_tensors(hyperparameters),
+        );
+        let result =
+            llm_base::load::<Falcon>(&path, TokenizerSource::Embedded, Default::default(), |_| {});
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            result,
+            Err(LoadError::InvalidHyperparameter { name: "n_head", .. })
+        ));
+    }
+
+    fn model_tensors(hyperparameters: Hyperparameters) -> Vec<(String, Vec<usize>)> {
+        let Hyperparameters {
+            n_vocab,
+            n_embd,
+            n_head,
+            n_head_kv,
+            n_layer,
+            ..
+        } = hyperparameters;
+        let head_dim = n_embd / n_head;
+
+        let mut tensors = vec![
+            (
+                "transformer.word_embeddings.weight".to_string(),
+                vec![n_embd, n_vocab],
+            ),
+            ("transformer.ln_f.weight".to_string(), vec![n_embd]),
+            ("transformer.ln_f.bias".to_string(), vec![n_embd]),
+            ("lm_head.weight".to_string(), vec![n_embd, n_vocab]),
+        ];
+        for i in 0..n_layer {
+            let norms: &[&str] = if n_head_kv == 1 {
+                &["input_layernorm"]
+            } else {
+                &["ln_mlp", "ln_attn"]
+            };
+            for norm in norms {
+                tensors.push((format!("transformer.h.{i}.{norm}.weight"), vec![n_embd]));
+                tensors.push((format!("transformer.h.{i}.{norm}.bias"), vec![n_embd]));
+            }
+            tensors.extend([
+                (
+                    format!("transformer.h.{i}.self_attention.query_key_value.weight"),
+                    vec![n_embd, (n_head + 2 * n_head_kv) * head_dim],
+                ),
+                (
+                    format!("transformer.h.{i}.self_attention.dense.weight"),
+                    vec![n_embd, n_embd],
+                ),
+                (
+                    format!("transformer.h.{i}.mlp.dense_h_to_4h.weight"),
+                    vec![n_embd, 4 * n_embd],
+                ),
+                (
+                    format!("transformer.h.{i}.mlp.dense_4h_to_h.weight"),
+                    vec![4 * n_embd, n_embd],
+                ),
+            ]);
+        }
+
+        tensors
+    }
+}
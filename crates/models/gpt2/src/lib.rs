@@ -5,8 +5,8 @@ use ggml::Tensor;
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelContext, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
+    util, FileType, GraphOutputs, HfConfig, InferenceSession, InferenceSessionConfig, KnownModel,
+    LoadError, ModelContext, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
 };
 
 /// The GPT-2 model. Ref: [The Illustrated GPT-2](https://jalammar.github.io/illustrated-gpt2/)
@@ -52,57 +52,69 @@ impl KnownModel for Gpt2 {
         tensor_loader: impl llm_base::TensorLoader<E>,
     ) -> Result<Self, E> {
         let mut tl = tensor_loader;
+        let Hyperparameters {
+            n_vocab,
+            n_ctx,
+            n_embd,
+            ..
+        } = hyperparameters;
 
         // model-global weights
         let backend = params.backend(0);
 
-        let wpe = tl.load("model/wpe")?.transfer_to(backend);
-        let wte = tl.load("model/wte")?.transfer_to(backend);
+        let wpe = tl.load("model/wpe", &[n_embd, n_ctx])?.transfer_to(backend);
+        let wte = tl
+            .load("model/wte", &[n_embd, n_vocab])?
+            .transfer_to(backend);
 
-        let ln_f_g = tl.load("model/ln_f/g")?.transfer_to(backend);
-        let ln_f_b = tl.load("model/ln_f/b")?.transfer_to(backend);
+        let ln_f_g = tl.load("model/ln_f/g", &[n_embd])?.transfer_to(backend);
+        let ln_f_b = tl.load("model/ln_f/b", &[n_embd])?.transfer_to(backend);
 
         // GPT-2's language model head is optional; if it is not present,
         // the `wte` tensor is used instead.
-        let lm_head = {
-            if let Ok(tensor) = tl.load("model/lm_head") {
-                Some(tensor.transfer_to(backend))
-            } else {
-                None
-            }
-        };
+        let lm_head = tl
+            .load_optional("model/lm_head", &[n_embd, n_vocab])?
+            .map(|tensor| tensor.transfer_to(backend));
 
         let mut layers = Vec::new();
         for i in 0..hyperparameters.n_layer {
             let backend = params.backend(i);
             let layer = Layer {
-                ln_1_g: tl.load(&format!("model/h{i}/ln_1/g"))?.transfer_to(backend),
-                ln_1_b: tl.load(&format!("model/h{i}/ln_1/b"))?.transfer_to(backend),
-                ln_2_g: tl.load(&format!("model/h{i}/ln_2/g"))?.transfer_to(backend),
-                ln_2_b: tl.load(&format!("model/h{i}/ln_2/b"))?.transfer_to(backend),
+                ln_1_g: tl
+                    .load(&format!("model/h{i}/ln_1/g"), &[n_embd])?
+                    .transfer_to(backend),
+                ln_1_b: tl
+                    .load(&format!("model/h{i}/ln_1/b"), &[n_embd])?
+                    .transfer_to(backend),
+                ln_2_g: tl
+                    .load(&format!("model/h{i}/ln_2/g"), &[n_embd])?
+                    .transfer_to(backend),
+                ln_2_b: tl
+                    .load(&format!("model/h{i}/ln_2/b"), &[n_embd])?
+                    .transfer_to(backend),
                 c_attn_attn_w: tl
-                    .load(&format!("model/h{i}/attn/c_attn/w"))?
+                    .load(&format!("model/h{i}/attn/c_attn/w"), &[n_embd, 3 * n_embd])?
                     .transfer_to(backend),
                 c_attn_attn_b: tl
-                    .load(&format!("model/h{i}/attn/c_attn/b"))?
+                    .load(&format!("model/h{i}/attn/c_attn/b"), &[3 * n_embd])?
                     .transfer_to(backend),
                 c_attn_proj_w: tl
-                    .load(&format!("model/h{i}/attn/c_proj/w"))?
+                    .load(&format!("model/h{i}/attn/c_proj/w"), &[n_embd, n_embd])?
                     .transfer_to(backend),
                 c_attn_proj_b: tl
-                    .load(&format!("model/h{i}/attn/c_proj/b"))?
+                    .load(&format!("model/h{i}/attn/c_proj/b"), &[n_embd])?
                     .transfer_to(backend),
                 c_mlp_fc_w: tl
-                    .load(&format!("model/h{i}/mlp/c_fc/w"))?
+                    .load(&format!("model/h{i}/mlp/c_fc/w"), &[n_embd, 4 * n_embd])?
                     .transfer_to(backend),
                 c_mlp_fc_b: tl
-                    .load(&format!("model/h{i}/mlp/c_fc/b"))?
+                    .load(&format!("model/h{i}/mlp/c_fc/b"), &[4 * n_embd])?
                     .transfer_to(backend),
                 c_mlp_proj_w: tl
-                    .load(&format!("model/h{i}/mlp/c_proj/w"))?
+                    .load(&format!("model/h{i}/mlp/c_proj/w"), &[4 * n_embd, n_embd])?
                     .transfer_to(backend),
                 c_mlp_proj_b: tl
-                    .load(&format!("model/h{i}/mlp/c_proj/b"))?
+                    .load(&format!("model/h{i}/mlp/c_proj/b"), &[n_embd])?
                     .transfer_to(backend),
             };
 
@@ -183,7 +195,8 @@ impl KnownModel for Gpt2 {
                 );
 
                 // attn
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_attn_w, &current);
+                current =
+                    builder.op_mul_mat_weight(&ctx0, &self.layers[il].c_attn_attn_w, &current);
                 current = ctx0.op_add(&current, &self.layers[il].c_attn_attn_b);
 
                 // self-attn
@@ -272,7 +285,8 @@ impl KnownModel for Gpt2 {
                 );
 
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_proj_w, &current);
+                current =
+                    builder.op_mul_mat_weight(&ctx0, &self.layers[il].c_attn_proj_w, &current);
                 current = ctx0.op_add(&current, &self.layers[il].c_attn_proj_b);
 
                 // add input
@@ -291,14 +305,14 @@ impl KnownModel for Gpt2 {
                 );
 
                 // feed-forward fully connected
-                current = ctx0.op_mul_mat(&self.layers[il].c_mlp_fc_w, &current);
+                current = builder.op_mul_mat_weight(&ctx0, &self.layers[il].c_mlp_fc_w, &current);
                 current = ctx0.op_add(&current, &self.layers[il].c_mlp_fc_b);
 
                 // feed-forward activation
                 current = ctx0.op_gelu(&current);
 
                 // feed-forward projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_mlp_proj_w, &current);
+                current = builder.op_mul_mat_weight(&ctx0, &self.layers[il].c_mlp_proj_w, &current);
                 current = ctx0.op_add(&current, &self.layers[il].c_mlp_proj_b);
 
                 // input for next layer
@@ -317,7 +331,7 @@ impl KnownModel for Gpt2 {
             let embeddings_tensor: ggml::Tensor = input_layer.share();
 
             let head = self.lm_head.as_ref().unwrap_or(&self.wte);
-            input_layer = ctx0.op_mul_mat(head, &input_layer);
+            input_layer = builder.op_mul_mat_weight(&ctx0, head, &input_layer);
 
             (
                 gf,
@@ -342,6 +356,10 @@ impl KnownModel for Gpt2 {
         &self.tokenizer
     }
 
+    fn model_context(&self) -> Option<&ModelContext> {
+        Some(&self.context)
+    }
+
     fn context_size(&self) -> usize {
         self.params.context_size
     }
@@ -371,6 +389,55 @@ impl KnownModel for Gpt2 {
     fn skip_quantize_tensors() -> Vec<Regex> {
         vec![]
     }
+
+    fn hf_hyperparameters(
+        config: &HfConfig,
+        file_type: FileType,
+    ) -> Result<Self::Hyperparameters, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: config.get("vocab_size")?,
+            n_ctx: config.get("n_positions")?,
+            n_embd: config.get("n_embd")?,
+            n_head: config.get("n_head")?,
+            n_layer: config.get("n_layer")?,
+            file_type,
+        })
+    }
+
+    fn hf_tensor_name(name: &str) -> Option<String> {
+        let name = name.strip_prefix("transformer.").unwrap_or(name);
+        let name = match name {
+            "wte.weight" => "wte",
+            "wpe.weight" => "wpe",
+            "ln_f.weight" => "ln_f/g",
+            "ln_f.bias" => "ln_f/b",
+            "lm_head.weight" => "lm_head",
+            name => {
+                let (layer, name) = name.strip_prefix("h.")?.split_once('.')?;
+                let (module, parameter) = name.rsplit_once('.')?;
+                let parameter = match (module, parameter) {
+                    ("ln_1" | "ln_2", "weight") => "g",
+                    (_, "weight") => "w",
+                    (_, "bias") => "b",
+                    _ => return None,
+                };
+                return Some(format!(
+                    "model/h{layer}/{}/{parameter}",
+                    module.replace('.', "/")
+                ));
+            }
+        };
+        Some(format!("model/{name}"))
+    }
+
+    fn hf_transposed(name: &str) -> bool {
+        // The layers use `Conv1D` modules, which store their weights as [in, out].
+        name.starts_with("model/h") && name.ends_with("/w")
+    }
+
+    fn supports_rewind(&self) -> bool {
+        true
+    }
 }
 
 /// GPT-2 [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -431,6 +498,18 @@ impl llm_base::Hyperparameters for Hyperparameters {
         self.n_vocab
     }
 
+    fn n_embd(&self) -> Option<usize> {
+        Some(self.n_embd)
+    }
+
+    fn n_layer(&self) -> Option<usize> {
+        Some(self.n_layer)
+    }
+
+    fn n_head(&self) -> Option<usize> {
+        Some(self.n_head)
+    }
+
     fn file_type(&self) -> Option<FileType> {
         Some(self.file_type)
     }
@@ -462,3 +541,84 @@ struct Layer {
     c_mlp_proj_w: Tensor,
     c_mlp_proj_b: Tensor,
 }
+
+#[cfg(test)]
+mod tests {
+    use llm_base::test_util::{feed, start_session, temp_path, write_model};
+
+    use super::*;
+
+    #[test]
+    fn can_rewind() {
+        let hyperparameters = Hyperparameters {
+            n_vocab: 16,
+            n_ctx: 32,
+            n_embd: 32,
+            n_head: 4,
+            n_layer: 2,
+            file_type: FileType {
+                format: llm_base::FileTypeFormat::F32,
+                quantization_version: 0,
+            },
+        };
+
+        let path = temp_path("gpt2-rewind.bin");
+        write_model(&path, hyperparameters, model_tensors(hyperparameters));
+        let model = llm_base::load::<Gpt2>(
+            &path,
+            llm_base::TokenizerSource::Embedded,
+            ModelParameters {
+                context_size: 32,
+                ..Default::default()
+            },
+            |_| {},
+        );
+        std::fs::remove_file(&path).unwrap();
+        let model = model.unwrap();
+
+        let mut session = start_session(&model);
+        let original_logits = feed(&model, &mut session, &[3, 1, 4, 1, 5]);
+        session.rewind(&model, 3).unwrap();
+        let redone_logits = feed(&model, &mut session, &[4, 1, 5]);
+
+        assert_eq!(original_logits, redone_logits);
+        assert_eq!(session.tokens(), &[3, 1, 4, 1, 5]);
+    }
+
+    fn model_tensors(hyperparameters: Hyperparameters) -> Vec<(String, Vec<usize>)> {
+        let Hyperparameters {
+            n_vocab,
+            n_ctx,
+            n_embd,
+            n_layer,
+            ..
+        } = hyperparameters;
+
+        let mut tensors = vec![
+            ("model/wte".to_string(), vec![n_embd, n_vocab]),
+            ("model/wpe".to_string(), vec![n_embd, n_ctx]),
+            ("model/ln_f/g".to_string(), vec![n_embd]),
+            ("model/ln_f/b".to_string(), vec![n_embd]),
+        ];
+        for i in 0..n_layer {
+            tensors.extend([
+                (format!("model/h{i}/ln_1/g"), vec![n_embd]),
+                (format!("model/h{i}/ln_1/b"), vec![n_embd]),
+                (format!("model/h{i}/ln_2/g"), vec![n_embd]),
+                (format!("model/h{i}/ln_2/b"), vec![n_embd]),
+                (
+                    format!("model/h{i}/attn/c_attn/w"),
+                    vec![n_embd, 3 * n_embd],
+                ),
+                (format!("model/h{i}/attn/c_attn/b"), vec![3 * n_embd]),
+                (format!("model/h{i}/attn/c_proj/w"), vec![n_embd, n_embd]),
+                (format!("model/h{i}/attn/c_proj/b"), vec![n_embd]),
+                (format!("model/h{i}/mlp/c_fc/w"), vec![n_embd, 4 * n_embd]),
+                (format!("model/h{i}/mlp/c_fc/b"), vec![4 * n_embd]),
+                (format!("model/h{i}/mlp/c_proj/w"), vec![4 * n_embd, n_embd]),
+                (format!("model/h{i}/mlp/c_proj/b"), vec![n_embd]),
+            ]);
+        }
+        tensors
+    }
+}
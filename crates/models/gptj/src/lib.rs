@@ -7,8 +7,9 @@ use ggml::Tensor;
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelContext, ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    util, FileType, GraphOutputs, HfConfig, InferenceSession, InferenceSessionConfig, KnownModel,
+    LoadError, ModelContext, ModelParameters, OutputRequest, Regex, TensorLoader, TokenId,
+    Tokenizer,
 };
 
 /// The GPT-J model. Ref: [GitHub](https://github.com/kingoflolz/mesh-transformer-jax/#gpt-j-6b)
@@ -54,16 +55,25 @@ impl KnownModel for GptJ {
         Self: Sized,
     {
         let mut tl = tensor_loader;
+        let Hyperparameters {
+            n_vocab, n_embd, ..
+        } = hyperparameters;
 
         // model-global weights
-        let wte = tl.load("transformer.wte.weight")?;
+        let wte = tl.load("transformer.wte.weight", &[n_embd, n_vocab])?;
 
         let backend = params.backend(0);
 
-        let ln_f_g = tl.load("transformer.ln_f.weight")?.transfer_to(backend);
-        let ln_f_b = tl.load("transformer.ln_f.bias")?.transfer_to(backend);
-        let lmh_g = tl.load("lm_head.weight")?.transfer_to(backend);
-        let lmh_b = tl.load("lm_head.bias")?.transfer_to(backend);
+        let ln_f_g = tl
+            .load("transformer.ln_f.weight", &[n_embd])?
+            .transfer_to(backend);
+        let ln_f_b = tl
+            .load("transformer.ln_f.bias", &[n_embd])?
+            .transfer_to(backend);
+        let lmh_g = tl
+            .load("lm_head.weight", &[n_embd, n_vocab])?
+            .transfer_to(backend);
+        let lmh_b = tl.load("lm_head.bias", &[n_vocab])?.transfer_to(backend);
 
         let mut layers = Vec::new();
         for i in 0..hyperparameters.n_layer {
@@ -71,34 +81,52 @@ impl KnownModel for GptJ {
 
             let layer = Layer {
                 ln_1_g: tl
-                    .load(&format!("transformer.h.{i}.ln_1.weight"))?
+                    .load(&format!("transformer.h.{i}.ln_1.weight"), &[n_embd])?
                     .transfer_to(backend),
                 ln_1_b: tl
-                    .load(&format!("transformer.h.{i}.ln_1.bias"))?
+                    .load(&format!("transformer.h.{i}.ln_1.bias"), &[n_embd])?
                     .transfer_to(backend),
                 c_attn_q_proj_w: tl
-                    .load(&format!("transformer.h.{i}.attn.q_proj.weight"))?
+                    .load(
+                        &format!("transformer.h.{i}.attn.q_proj.weight"),
+                        &[n_embd, n_embd],
+                    )?
                     .transfer_to(backend),
                 c_attn_k_proj_w: tl
-                    .load(&format!("transformer.h.{i}.attn.k_proj.weight"))?
+                    .load(
+                        &format!("transformer.h.{i}.attn.k_proj.weight"),
+                        &[n_embd, n_embd],
+                    )?
                     .transfer_to(backend),
                 c_attn_v_proj_w: tl
-                    .load(&format!("transformer.h.{i}.attn.v_proj.weight"))?
+                    .load(
+                        &format!("transformer.h.{i}.attn.v_proj.weight"),
+                        &[n_embd, n_embd],
+                    )?
                     .transfer_to(backend),
                 c_attn_proj_w: tl
-                    .load(&format!("transformer.h.{i}.attn.out_proj.weight"))?
+                    .load(
+                        &format!("transformer.h.{i}.attn.out_proj.weight"),
+                        &[n_embd, n_embd],
+                    )?
                     .transfer_to(backend),
                 c_mlp_fc_w: tl
-                    .load(&format!("transformer.h.{i}.mlp.fc_in.weight"))?
+                    .load(
+                        &format!("transformer.h.{i}.mlp.fc_in.weight"),
+                        &[n_embd, 4 * n_embd],
+                    )?
                     .transfer_to(backend),
                 c_mlp_fc_b: tl
-                    .load(&format!("transformer.h.{i}.mlp.fc_in.bias"))?
+                    .load(&format!("transformer.h.{i}.mlp.fc_in.bias"), &[4 * n_embd])?
                     .transfer_to(backend),
                 c_mlp_proj_w: tl
-                    .load(&format!("transformer.h.{i}.mlp.fc_out.weight"))?
+                    .load(
+                        &format!("transformer.h.{i}.mlp.fc_out.weight"),
+                        &[4 * n_embd, n_embd],
+                    )?
                     .transfer_to(backend),
                 c_mlp_proj_b: tl
-                    .load(&format!("transformer.h.{i}.mlp.fc_out.bias"))?
+                    .load(&format!("transformer.h.{i}.mlp.fc_out.bias"), &[n_embd])?
                     .transfer_to(backend),
             };
 
@@ -177,7 +205,11 @@ impl KnownModel for GptJ {
                 let overrides = self.params.rope_overrides.as_ref();
                 let qcur = ctx0.op_rope_inplace(
                     &ctx0.op_reshape_3d(
-                        &ctx0.op_mul_mat(&self.layers[il].c_attn_q_proj_w, &current),
+                        &builder.op_mul_mat_weight(
+                            &ctx0,
+                            &self.layers[il].c_attn_q_proj_w,
+                            &current,
+                        ),
                         n_embd / n_head,
                         n_head,
                         input_len,
@@ -189,7 +221,11 @@ impl KnownModel for GptJ {
                 );
                 let kcur = ctx0.op_rope_inplace(
                     &ctx0.op_reshape_3d(
-                        &ctx0.op_mul_mat(&self.layers[il].c_attn_k_proj_w, &current),
+                        &builder.op_mul_mat_weight(
+                            &ctx0,
+                            &self.layers[il].c_attn_k_proj_w,
+                            &current,
+                        ),
                         n_embd / n_head,
                         n_head,
                         input_len,
@@ -201,8 +237,11 @@ impl KnownModel for GptJ {
                 );
 
                 // self-attention store key and value to memory
-                let vcur =
-                    ctx0.op_transpose(&ctx0.op_mul_mat(&self.layers[il].c_attn_v_proj_w, &current));
+                let vcur = ctx0.op_transpose(&builder.op_mul_mat_weight(
+                    &ctx0,
+                    &self.layers[il].c_attn_v_proj_w,
+                    &current,
+                ));
 
                 let k = ctx0.op_view_1d(
                     builder.memory_k,
@@ -262,18 +301,19 @@ impl KnownModel for GptJ {
                 );
 
                 // self-attention projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_proj_w, &current);
+                current =
+                    builder.op_mul_mat_weight(&ctx0, &self.layers[il].c_attn_proj_w, &current);
 
                 // feed-forward
                 let ff_in = current.share();
 
-                current = ctx0.op_mul_mat(&self.layers[il].c_mlp_fc_w, &input_sa);
+                current = builder.op_mul_mat_weight(&ctx0, &self.layers[il].c_mlp_fc_w, &input_sa);
                 current = ctx0.op_add(&current, &self.layers[il].c_mlp_fc_b);
 
                 current = ctx0.op_gelu(&current);
 
                 // feed-forward projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_mlp_proj_w, &current);
+                current = builder.op_mul_mat_weight(&ctx0, &self.layers[il].c_mlp_proj_w, &current);
                 current = ctx0.op_add(&current, &self.layers[il].c_mlp_proj_b);
 
                 current = ctx0.op_add(&current, &ff_in);
@@ -289,7 +329,7 @@ impl KnownModel for GptJ {
             let embeddings_tensor: ggml::Tensor = input_layer.share();
 
             // lm_head
-            input_layer = ctx0.op_mul_mat(&self.lmh_g, &input_layer);
+            input_layer = builder.op_mul_mat_weight(&ctx0, &self.lmh_g, &input_layer);
 
             ctx0.set_offloading(false);
 
@@ -318,6 +358,10 @@ impl KnownModel for GptJ {
         &self.tokenizer
     }
 
+    fn model_context(&self) -> Option<&ModelContext> {
+        Some(&self.context)
+    }
+
     fn context_size(&self) -> usize {
         self.params.context_size
     }
@@ -338,6 +382,26 @@ impl KnownModel for GptJ {
         vec![]
     }
 
+    fn hf_hyperparameters(
+        config: &HfConfig,
+        file_type: FileType,
+    ) -> Result<Self::Hyperparameters, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: config.get("vocab_size")?,
+            n_ctx: config.get("n_positions")?,
+            n_embd: config.get("n_embd")?,
+            n_head: config.get("n_head")?,
+            n_layer: config.get("n_layer")?,
+            n_rot: config.get("rotary_dim")?,
+            file_type,
+        })
+    }
+
+    fn hf_tensor_name(name: &str) -> Option<String> {
+        // The tensors are named after the Hugging Face implementation.
+        Some(name.to_owned())
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
@@ -404,6 +468,18 @@ impl llm_base::Hyperparameters for Hyperparameters {
         self.n_vocab
     }
 
+    fn n_embd(&self) -> Option<usize> {
+        Some(self.n_embd)
+    }
+
+    fn n_layer(&self) -> Option<usize> {
+        Some(self.n_layer)
+    }
+
+    fn n_head(&self) -> Option<usize> {
+        Some(self.n_head)
+    }
+
     fn file_type(&self) -> Option<FileType> {
         Some(self.file_type)
     }
@@ -8,8 +8,9 @@ use ggml::Tensor;
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelContext, ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    util, BuildContext, FileType, GraphOutputs, HfConfig, InferenceSession, InferenceSessionConfig,
+    KnownModel, LoadError, ModelContext, ModelParameters, OutputRequest, Regex, TensorLoader,
+    TokenId, Tokenizer,
 };
 
 /// The GPT-NeoX model. Ref: [GitHub](https://github.com/EleutherAI/gpt-neox)
@@ -54,72 +55,105 @@ impl KnownModel for GptNeoX {
         Self: Sized,
     {
         let mut tl = tensor_loader;
+        let Hyperparameters {
+            n_vocab, n_embd, ..
+        } = hyperparameters;
 
         // model-global weights
-        let wte = tl.load("gpt_neox.embed_in.weight")?;
+        let wte = tl.load("gpt_neox.embed_in.weight", &[n_embd, n_vocab])?;
 
         let backend = params.backend(0);
 
         let ln_f_g = tl
-            .load("gpt_neox.final_layer_norm.weight")?
+            .load("gpt_neox.final_layer_norm.weight", &[n_embd])?
             .transfer_to(backend);
         let ln_f_b = tl
-            .load("gpt_neox.final_layer_norm.bias")?
+            .load("gpt_neox.final_layer_norm.bias", &[n_embd])?
+            .transfer_to(backend);
+        let lmh_g = tl
+            .load("embed_out.weight", &[n_embd, n_vocab])?
             .transfer_to(backend);
-        let lmh_g = tl.load("embed_out.weight")?.transfer_to(backend);
 
         let mut layers = Vec::new();
         for i in 0..hyperparameters.n_layer {
             let backend = params.backend(i);
             let layer = Layer {
                 ln_1_g: tl
-                    .load(&format!("gpt_neox.layers.{i}.input_layernorm.weight"))?
+                    .load(
+                        &format!("gpt_neox.layers.{i}.input_layernorm.weight"),
+                        &[n_embd],
+                    )?
                     .transfer_to(backend),
                 ln_1_b: tl
-                    .load(&format!("gpt_neox.layers.{i}.input_layernorm.bias"))?
+                    .load(
+                        &format!("gpt_neox.layers.{i}.input_layernorm.bias"),
+                        &[n_embd],
+                    )?
                     .transfer_to(backend),
 
                 c_attn_attn_w: tl
-                    .load(&format!(
-                        "gpt_neox.layers.{i}.attention.query_key_value.weight"
-                    ))?
+                    .load(
+                        &format!("gpt_neox.layers.{i}.attention.query_key_value.weight"),
+                        &[n_embd, 3 * n_embd],
+                    )?
                     .transfer_to(backend),
                 c_attn_attn_b: tl
-                    .load(&format!(
-                        "gpt_neox.layers.{i}.attention.query_key_value.bias"
-                    ))?
+                    .load(
+                        &format!("gpt_neox.layers.{i}.attention.query_key_value.bias"),
+                        &[3 * n_embd],
+                    )?
                     .transfer_to(backend),
 
                 c_attn_proj_w: tl
-                    .load(&format!("gpt_neox.layers.{i}.attention.dense.weight"))?
+                    .load(
+                        &format!("gpt_neox.layers.{i}.attention.dense.weight"),
+                        &[n_embd, n_embd],
+                    )?
                     .transfer_to(backend),
                 c_attn_proj_b: tl
-                    .load(&format!("gpt_neox.layers.{i}.attention.dense.bias"))?
+                    .load(
+                        &format!("gpt_neox.layers.{i}.attention.dense.bias"),
+                        &[n_embd],
+                    )?
                     .transfer_to(backend),
 
                 ln_2_g: tl
-                    .load(&format!(
-                        "gpt_neox.layers.{i}.post_attention_layernorm.weight"
-                    ))?
+                    .load(
+                        &format!("gpt_neox.layers.{i}.post_attention_layernorm.weight"),
+                        &[n_embd],
+                    )?
                     .transfer_to(backend),
                 ln_2_b: tl
-                    .load(&format!(
-                        "gpt_neox.layers.{i}.post_attention_layernorm.bias"
-                    ))?
+                    .load(
+                        &format!("gpt_neox.layers.{i}.post_attention_layernorm.bias"),
+                        &[n_embd],
+                    )?
                     .transfer_to(backend),
 
                 c_mlp_fc_w: tl
-                    .load(&format!("gpt_neox.layers.{i}.mlp.dense_h_to_4h.weight"))?
+                    .load(
+                        &format!("gpt_neox.layers.{i}.mlp.dense_h_to_4h.weight"),
+                        &[n_embd, 4 * n_embd],
+                    )?
                     .transfer_to(backend),
                 c_mlp_fc_b: tl
-                    .load(&format!("gpt_neox.layers.{i}.mlp.dense_h_to_4h.bias"))?
+                    .load(
+                        &format!("gpt_neox.layers.{i}.mlp.dense_h_to_4h.bias"),
+                        &[4 * n_embd],
+                    )?
                     .transfer_to(backend),
 
                 c_mlp_proj_w: tl
-                    .load(&format!("gpt_neox.layers.{i}.mlp.dense_4h_to_h.weight"))?
+                    .load(
+                        &format!("gpt_neox.layers.{i}.mlp.dense_4h_to_h.weight"),
+                        &[4 * n_embd, n_embd],
+                    )?
                     .transfer_to(backend),
                 c_mlp_proj_b: tl
-                    .load(&format!("gpt_neox.layers.{i}.mlp.dense_4h_to_h.bias"))?
+                    .load(
+                        &format!("gpt_neox.layers.{i}.mlp.dense_4h_to_h.bias"),
+                        &[n_embd],
+                    )?
                     .transfer_to(backend),
             };
 
@@ -197,7 +231,8 @@ impl KnownModel for GptNeoX {
                 );
 
                 // self-attention compute QKV
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_attn_w, &current);
+                current =
+                    builder.op_mul_mat_weight(&ctx0, &self.layers[il].c_attn_attn_w, &current);
                 current = ctx0.op_add(&current, &self.layers[il].c_attn_attn_b);
 
                 let nb = current.get_nb()[1];
@@ -298,7 +333,8 @@ impl KnownModel for GptNeoX {
                 current = ctx0.op_cpy(&KQV_merged, &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, n));
 
                 // self-attention projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_proj_w, &current);
+                current =
+                    builder.op_mul_mat_weight(&ctx0, &self.layers[il].c_attn_proj_w, &current);
                 current = ctx0.op_add(&current, &self.layers[il].c_attn_proj_b);
 
                 // use the second scratch for the feed forward
@@ -307,7 +343,8 @@ impl KnownModel for GptNeoX {
                 let feedforward_input: Tensor;
                 if !use_parallel_residual {
                     feedforward_input = ctx0.op_add(&current, &input_layer);
-                    current = feed_forward_network(&ctx0, &self.layers[il], &feedforward_input);
+                    current =
+                        feed_forward_network(&builder, &ctx0, &self.layers[il], &feedforward_input);
                     // input for next layer
                     input_layer = ctx0.op_add(&current, &feedforward_input);
                 } else {
@@ -316,7 +353,7 @@ impl KnownModel for GptNeoX {
 
                     // this is independent of the self-attention result, so it could be done in parallel to the self-attention
                     // note here we pass inpL instead of cur
-                    current = feed_forward_network(&ctx0, &self.layers[il], &input_layer);
+                    current = feed_forward_network(&builder, &ctx0, &self.layers[il], &input_layer);
 
                     // layer input + FF
                     current = ctx0.op_add(&current, &feedforward_input);
@@ -340,7 +377,7 @@ impl KnownModel for GptNeoX {
             ctx0.use_scratch(None);
             ctx0.set_offloading(false);
             // apply language model head
-            input_layer = ctx0.op_mul_mat(&self.lmh_g, &input_layer);
+            input_layer = builder.op_mul_mat_weight(&ctx0, &self.lmh_g, &input_layer);
 
             (
                 gf,
@@ -365,6 +402,10 @@ impl KnownModel for GptNeoX {
         &self.tokenizer
     }
 
+    fn model_context(&self) -> Option<&ModelContext> {
+        Some(&self.context)
+    }
+
     fn context_size(&self) -> usize {
         self.params.context_size
     }
@@ -385,6 +426,32 @@ impl KnownModel for GptNeoX {
         vec![]
     }
 
+    fn hf_hyperparameters(
+        config: &HfConfig,
+        file_type: FileType,
+    ) -> Result<Self::Hyperparameters, LoadError> {
+        let n_embd = config.get("hidden_size")?;
+        let n_head = config.get("num_attention_heads")?;
+        let rotary_pct: f32 = config.get("rotary_pct")?;
+        Ok(Hyperparameters {
+            n_vocab: config.get("vocab_size")?,
+            n_ctx: config.get("max_position_embeddings")?,
+            n_embd,
+            n_head,
+            n_layer: config.get("num_hidden_layers")?,
+            n_rot: (rotary_pct * (n_embd / n_head) as f32) as usize,
+            use_parallel_residual: config
+                .get_optional("use_parallel_residual")?
+                .unwrap_or(true),
+            file_type,
+        })
+    }
+
+    fn hf_tensor_name(name: &str) -> Option<String> {
+        // The tensors are named after the Hugging Face implementation.
+        Some(name.to_owned())
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
@@ -457,6 +524,18 @@ impl llm_base::Hyperparameters for Hyperparameters {
         self.n_vocab
     }
 
+    fn n_embd(&self) -> Option<usize> {
+        Some(self.n_embd)
+    }
+
+    fn n_layer(&self) -> Option<usize> {
+        Some(self.n_layer)
+    }
+
+    fn n_head(&self) -> Option<usize> {
+        Some(self.n_head)
+    }
+
     fn file_type(&self) -> Option<FileType> {
         Some(self.file_type)
     }
@@ -490,14 +569,19 @@ struct Layer {
     c_mlp_proj_b: Tensor,
 }
 
-fn feed_forward_network(context: &ggml::Context, layer: &Layer, input: &Tensor) -> Tensor {
+fn feed_forward_network(
+    builder: &BuildContext,
+    context: &ggml::Context,
+    layer: &Layer,
+    input: &Tensor,
+) -> Tensor {
     let mut current = context.op_norm(input);
 
     //gain and bias
     current = context.op_add(&context.op_mul(&current, &layer.ln_2_g), &layer.ln_2_b);
 
     // apply weights
-    current = context.op_mul_mat(&layer.c_mlp_fc_w, &current);
+    current = builder.op_mul_mat_weight(context, &layer.c_mlp_fc_w, &current);
 
     // apply bias
     current = context.op_add(&current, &layer.c_mlp_fc_b);
@@ -507,7 +591,7 @@ fn feed_forward_network(context: &ggml::Context, layer: &Layer, input: &Tensor)
 
     // projection
     // cur = proj_w*cur + proj_b
-    current = context.op_mul_mat(&layer.c_mlp_proj_w, &current);
+    current = builder.op_mul_mat_weight(context, &layer.c_mlp_proj_w, &current);
 
     current = context.op_add(&current, &layer.c_mlp_proj_b);
 
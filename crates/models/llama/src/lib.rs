@@ -6,8 +6,9 @@ use std::error::Error;
 use llm_base::{
     ggml::{self},
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelContext, ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    util, FileType, GraphOutputs, HfConfig, InferenceSession, InferenceSessionConfig, KnownModel,
+    LoadError, ModelContext, ModelParameters, OutputRequest, Regex, TensorLoader, TokenId,
+    Tokenizer,
 };
 
 /// The LLaMA model. Ref: [Introducing LLaMA](https://ai.facebook.com/blog/large-language-model-llama-meta-ai/)
@@ -48,13 +49,56 @@ impl KnownModel for Llama {
     ) -> Result<Self, E> {
         let mut tl = tensor_loader;
 
+        // TODO: read from file
+        let mut version = match hyperparameters.n_layer {
+            26 => LlamaModelType::Model3b,
+            32 => LlamaModelType::Model7b,
+            40 => LlamaModelType::Model13b,
+            60 => LlamaModelType::Model30b,
+            80 => LlamaModelType::Model65b,
+            _ => LlamaModelType::Model7b, // anything < 32
+        };
+        // TODO: temporary fix for 70B models
+        if let Some(n_gqa) = params.n_gqa {
+            if hyperparameters.n_layer >= 80 {
+                assert_eq!(
+                    hyperparameters.n_head % n_gqa,
+                    0,
+                    "assuming 70B Llama2 model based on GQA == 8"
+                );
+                hyperparameters.n_head_kv = hyperparameters.n_head / n_gqa;
+                version = LlamaModelType::Model70b;
+            }
+        }
+
+        let Hyperparameters {
+            n_vocab,
+            n_embd,
+            n_mult,
+            n_head,
+            n_head_kv,
+            ..
+        } = hyperparameters;
+        let n_embd_gqa = n_embd / (n_head / n_head_kv);
+        let n_ff = {
+            let n_ff = (8 * n_embd) / 3;
+            // 70B models scale the feed-forward size by 1.3.
+            let n_ff = match version {
+                LlamaModelType::Model70b => n_ff * 13 / 10,
+                _ => n_ff,
+            };
+            ((n_ff + n_mult - 1) / n_mult) * n_mult
+        };
+
         // model-global weights
-        let wte = tl.load("tok_embeddings.weight")?;
+        let wte = tl.load("tok_embeddings.weight", &[n_embd, n_vocab])?;
 
         let backend = params.backend(0);
 
-        let norm = tl.load("norm.weight")?.transfer_to(backend);
-        let output = tl.load("output.weight")?.transfer_to(backend);
+        let norm = tl.load("norm.weight", &[n_embd])?.transfer_to(backend);
+        let output = tl
+            .load("output.weight", &[n_embd, n_vocab])?
+            .transfer_to(backend);
 
         let mut layers = Vec::new();
 
@@ -63,59 +107,58 @@ impl KnownModel for Llama {
 
             let layer = Layer {
                 attention_norm: tl
-                    .load(&format!("layers.{i}.attention_norm.weight"))?
+                    .load(&format!("layers.{i}.attention_norm.weight"), &[n_embd])?
                     .transfer_to(backend),
                 wq: tl
-                    .load(&format!("layers.{i}.attention.wq.weight"))?
+                    .load(
+                        &format!("layers.{i}.attention.wq.weight"),
+                        &[n_embd, n_embd],
+                    )?
                     .transfer_to(backend),
                 wk: tl
-                    .load(&format!("layers.{i}.attention.wk.weight"))?
+                    .load(
+                        &format!("layers.{i}.attention.wk.weight"),
+                        &[n_embd, n_embd_gqa],
+                    )?
                     .transfer_to(backend),
                 wv: tl
-                    .load(&format!("layers.{i}.attention.wv.weight"))?
+                    .load(
+                        &format!("layers.{i}.attention.wv.weight"),
+                        &[n_embd, n_embd_gqa],
+                    )?
                     .transfer_to(backend),
                 wo: tl
-                    .load(&format!("layers.{i}.attention.wo.weight"))?
+                    .load(
+                        &format!("layers.{i}.attention.wo.weight"),
+                        &[n_embd, n_embd],
+                    )?
                     .transfer_to(backend),
                 ffn_norm: tl
-                    .load(&format!("layers.{i}.ffn_norm.weight"))?
+                    .load(&format!("layers.{i}.ffn_norm.weight"), &[n_embd])?
                     .transfer_to(backend),
                 w1: tl
-                    .load(&format!("layers.{i}.feed_forward.w1.weight"))?
+                    .load(
+                        &format!("layers.{i}.feed_forward.w1.weight"),
+                        &[n_embd, n_ff],
+                    )?
                     .transfer_to(backend),
                 w2: tl
-                    .load(&format!("layers.{i}.feed_forward.w2.weight"))?
+                    .load(
+                        &format!("layers.{i}.feed_forward.w2.weight"),
+                        &[n_ff, n_embd],
+                    )?
                     .transfer_to(backend),
                 w3: tl
-                    .load(&format!("layers.{i}.feed_forward.w3.weight"))?
+                    .load(
+                        &format!("layers.{i}.feed_forward.w3.weight"),
+                        &[n_embd, n_ff],
+                    )?
                     .transfer_to(backend),
             };
             layers.push(layer);
         }
         let context = tl.finish();
 
-        // TODO: read from file
-        let mut version = match hyperparameters.n_layer {
-            26 => LlamaModelType::Model3b,
-            32 => LlamaModelType::Model7b,
-            40 => LlamaModelType::Model13b,
-            60 => LlamaModelType::Model30b,
-            80 => LlamaModelType::Model65b,
-            _ => LlamaModelType::Model7b, // anything < 32
-        };
-        // TODO: temporary fix for 70B models
-        if let Some(n_gqa) = params.n_gqa {
-            if hyperparameters.n_layer >= 80 {
-                assert_eq!(
-                    hyperparameters.n_head % n_gqa,
-                    0,
-                    "assuming 70B Llama2 model based on GQA == 8"
-                );
-                hyperparameters.n_head_kv = hyperparameters.n_head / n_gqa;
-                version = LlamaModelType::Model70b;
-            }
-        }
-
         Ok(Self {
             hyperparameters,
             params,
@@ -191,7 +234,7 @@ impl KnownModel for Llama {
                 let q_current = ctx0
                     .op_rope_inplace(
                         &ctx0.op_reshape_3d(
-                            &ctx0.op_mul_mat(&self.layers[il].wq, &current),
+                            &builder.op_mul_mat_weight(&ctx0, &self.layers[il].wq, &current),
                             n_embd / n_head,
                             n_head,
                             input_len,
@@ -205,7 +248,7 @@ impl KnownModel for Llama {
                 let k_current = ctx0
                     .op_rope_inplace(
                         &ctx0.op_reshape_3d(
-                            &ctx0.op_mul_mat(&self.layers[il].wk, &current),
+                            &builder.op_mul_mat_weight(&ctx0, &self.layers[il].wk, &current),
                             n_embd / n_head,
                             n_head_kv,
                             input_len,
@@ -220,7 +263,7 @@ impl KnownModel for Llama {
                 // store key and value to memory
                 // compute the transposed [N, n_embd] V matrix
                 let v_current = ctx0.op_transpose(&ctx0.op_reshape_2d(
-                    &ctx0.op_mul_mat(&self.layers[il].wv, &current),
+                    &builder.op_mul_mat_weight(&ctx0, &self.layers[il].wv, &current),
                     n_embd_gqa,
                     input_len,
                 ));
@@ -307,7 +350,7 @@ impl KnownModel for Llama {
                     .set_name("KQV_merged_contiguous");
 
                 // projection (no bias)
-                current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
+                current = builder.op_mul_mat_weight(&ctx0, &self.layers[il].wo, &current);
 
                 ctx0.use_scratch(builder.get_scratch(1));
 
@@ -320,16 +363,16 @@ impl KnownModel for Llama {
                 // cur = cur*ffn_norm(broadcasted)
                 current = ctx0.op_mul(&current, &self.layers[il].ffn_norm);
 
-                let tmp = ctx0.op_mul_mat(&self.layers[il].w3, &current);
+                let tmp = builder.op_mul_mat_weight(&ctx0, &self.layers[il].w3, &current);
 
-                current = ctx0.op_mul_mat(&self.layers[il].w1, &current);
+                current = builder.op_mul_mat_weight(&ctx0, &self.layers[il].w1, &current);
 
                 // SILU activation
                 current = ctx0.op_silu(&current);
 
                 current = ctx0.op_mul(&current, &tmp);
 
-                current = ctx0.op_mul_mat(&self.layers[il].w2, &current);
+                current = builder.op_mul_mat_weight(&ctx0, &self.layers[il].w2, &current);
 
                 current = ctx0.op_add(&current, &input_feed_forward);
 
@@ -349,7 +392,7 @@ impl KnownModel for Llama {
 
             ctx0.set_offloading(false);
             // lm_head
-            input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
+            input_layer = builder.op_mul_mat_weight(&ctx0, &self.output, &input_layer);
 
             ctx0.use_scratch(None);
             (
@@ -375,6 +418,10 @@ impl KnownModel for Llama {
         &self.tokenizer
     }
 
+    fn model_context(&self) -> Option<&ModelContext> {
+        Some(&self.context)
+    }
+
     fn context_size(&self) -> usize {
         self.params.context_size
     }
@@ -395,6 +442,66 @@ impl KnownModel for Llama {
         vec![]
     }
 
+    fn hf_hyperparameters(
+        config: &HfConfig,
+        file_type: FileType,
+    ) -> Result<Self::Hyperparameters, LoadError> {
+        let n_embd = config.get("hidden_size")?;
+        let n_head = config.get("num_attention_heads")?;
+        let hyperparameters = Hyperparameters {
+            n_vocab: config.get("vocab_size")?,
+            n_embd,
+            n_mult: find_n_mult(config.get("intermediate_size")?, n_embd),
+            n_head,
+            n_head_kv: config
+                .get_optional("num_key_value_heads")?
+                .unwrap_or(n_head),
+            n_layer: config.get("num_hidden_layers")?,
+            n_rot: 0,
+            file_type,
+        };
+        hyperparameters.validate()?;
+
+        Ok(Hyperparameters {
+            n_rot: n_embd / n_head,
+            ..hyperparameters
+        })
+    }
+
+    fn hf_tensor_name(name: &str) -> Option<String> {
+        let name = match name {
+            "model.embed_tokens.weight" => return Some("tok_embeddings.weight".to_owned()),
+            "model.norm.weight" => return Some("norm.weight".to_owned()),
+            "lm_head.weight" => return Some("output.weight".to_owned()),
+            name => name,
+        };
+
+        let (layer, name) = name.strip_prefix("model.layers.")?.split_once('.')?;
+        let name = match name {
+            "input_layernorm.weight" => "attention_norm.weight",
+            "self_attn.q_proj.weight" => "attention.wq.weight",
+            "self_attn.k_proj.weight" => "attention.wk.weight",
+            "self_attn.v_proj.weight" => "attention.wv.weight",
+            "self_attn.o_proj.weight" => "attention.wo.weight",
+            "post_attention_layernorm.weight" => "ffn_norm.weight",
+            "mlp.gate_proj.weight" => "feed_forward.w1.weight",
+            "mlp.down_proj.weight" => "feed_forward.w2.weight",
+            "mlp.up_proj.weight" => "feed_forward.w3.weight",
+            _ => return None,
+        };
+        Some(format!("layers.{layer}.{name}"))
+    }
+
+    fn hf_permuted_heads(hyperparameters: &Self::Hyperparameters, name: &str) -> Option<usize> {
+        if name.ends_with(".attention.wq.weight") {
+            Some(hyperparameters.n_head)
+        } else if name.ends_with(".attention.wk.weight") {
+            Some(hyperparameters.n_head_kv)
+        } else {
+            None
+        }
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
@@ -421,6 +528,37 @@ pub struct Hyperparameters {
     pub file_type: FileType,
 }
 
+impl Hyperparameters {
+    /// Checks that the model can be built with these hyperparameters, as the size of each
+    /// attention head, of each group of query heads and of the feed-forward layers is
+    /// derived from them.
+    fn validate(&self) -> Result<(), LoadError> {
+        if self.n_mult == 0 {
+            return Err(LoadError::InvalidHyperparameter {
+                name: "n_mult",
+                reason: "the feed-forward size must be rounded to a multiple of at least 1"
+                    .to_owned(),
+            });
+        }
+        if self.n_head == 0 {
+            return Err(LoadError::InvalidHyperparameter {
+                name: "n_head",
+                reason: "the model must have at least one attention head".to_owned(),
+            });
+        }
+        if self.n_head_kv == 0 || self.n_head % self.n_head_kv != 0 {
+            return Err(LoadError::InvalidHyperparameter {
+                name: "n_head_kv",
+                reason: format!(
+                    "the {} attention heads must be split evenly between the key-value heads",
+                    self.n_head
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
         let n_vocab = util::read_i32(reader)?.try_into()?;
@@ -434,7 +572,7 @@ impl llm_base::Hyperparameters for Hyperparameters {
         // Defaults to multi-head attention where n_head_kv == n_heads
         let n_head_kv = n_head;
 
-        Ok(Hyperparameters {
+        let hyperparameters = Hyperparameters {
             n_head,
             n_head_kv,
             n_vocab,
@@ -443,7 +581,10 @@ impl llm_base::Hyperparameters for Hyperparameters {
             n_layer,
             n_rot,
             file_type,
-        })
+        };
+        hyperparameters.validate()?;
+
+        Ok(hyperparameters)
     }
 
     fn write_ggml(&self, writer: &mut dyn std::io::Write) -> Result<(), HyperparametersWriteError> {
@@ -461,6 +602,18 @@ impl llm_base::Hyperparameters for Hyperparameters {
         self.n_vocab
     }
 
+    fn n_embd(&self) -> Option<usize> {
+        Some(self.n_embd)
+    }
+
+    fn n_layer(&self) -> Option<usize> {
+        Some(self.n_layer)
+    }
+
+    fn n_head(&self) -> Option<usize> {
+        Some(self.n_head)
+    }
+
     fn file_type(&self) -> Option<FileType> {
         Some(self.file_type)
     }
@@ -470,6 +623,17 @@ impl llm_base::Hyperparameters for Hyperparameters {
     }
 }
 
+/// Finds the `n_mult` that the original implementation rounds the feed-forward size
+/// of `n_ff` up to a multiple of, so that the feed-forward size can be derived from the
+/// hyperparameters when loading.
+fn find_n_mult(n_ff: usize, n_embd: usize) -> usize {
+    let n_ff_unrounded = (8 * n_embd) / 3;
+    (1..=8192)
+        .rev()
+        .find(|n_mult| n_ff_unrounded + (n_mult - n_ff_unrounded % n_mult) % n_mult == n_ff)
+        .unwrap_or(256)
+}
+
 struct Layer {
     attention_norm: ggml::Tensor,
 
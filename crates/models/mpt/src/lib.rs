@@ -5,8 +5,8 @@ use ggml::Tensor;
 use llm_base::{
     ggml::{self},
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelContext, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
+    util, FileType, GraphOutputs, HfConfig, InferenceSession, InferenceSessionConfig, KnownModel,
+    LoadError, ModelContext, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
 };
 
 /// The MosaicML Pretrained Transformer (MPT) model. Ref: [Mosaic ML](https://www.mosaicml.com/blog/mpt-7b)
@@ -45,23 +45,39 @@ impl KnownModel for Mpt {
         tensor_loader: impl llm_base::TensorLoader<E>,
     ) -> Result<Self, E> {
         let mut tl = tensor_loader;
+        let Hyperparameters {
+            n_vocab, n_embd, ..
+        } = hyperparameters;
 
         // model-gobal weights
-        let wte = tl.load("transformer.wte.weight")?;
-        let norm = tl.load("transformer.norm_f.weight")?;
+        let wte = tl.load("transformer.wte.weight", &[n_embd, n_vocab])?;
+        let norm = tl.load("transformer.norm_f.weight", &[n_embd])?;
 
         let mut layers = Vec::new();
         for i in 0..hyperparameters.n_layer {
             let layer = Layer {
-                norm_1_weight: tl.load(&format!("transformer.blocks.{i}.norm_1.weight"))?,
-                c_attn_wqkv_weight: tl.load(&format!("transformer.blocks.{i}.attn.Wqkv.weight"))?,
-
-                c_attn_out_proj_weight: tl
-                    .load(&format!("transformer.blocks.{i}.attn.out_proj.weight"))?,
-                norm_2_weight: tl.load(&format!("transformer.blocks.{i}.norm_2.weight"))?,
-
-                ffn_up_proj: tl.load(&format!("transformer.blocks.{i}.ffn.up_proj.weight"))?,
-                ffn_down_proj: tl.load(&format!("transformer.blocks.{i}.ffn.down_proj.weight"))?,
+                norm_1_weight: tl
+                    .load(&format!("transformer.blocks.{i}.norm_1.weight"), &[n_embd])?,
+                c_attn_wqkv_weight: tl.load(
+                    &format!("transformer.blocks.{i}.attn.Wqkv.weight"),
+                    &[n_embd, 3 * n_embd],
+                )?,
+
+                c_attn_out_proj_weight: tl.load(
+                    &format!("transformer.blocks.{i}.attn.out_proj.weight"),
+                    &[n_embd, n_embd],
+                )?,
+                norm_2_weight: tl
+                    .load(&format!("transformer.blocks.{i}.norm_2.weight"), &[n_embd])?,
+
+                ffn_up_proj: tl.load(
+                    &format!("transformer.blocks.{i}.ffn.up_proj.weight"),
+                    &[n_embd, 4 * n_embd],
+                )?,
+                ffn_down_proj: tl.load(
+                    &format!("transformer.blocks.{i}.ffn.down_proj.weight"),
+                    &[4 * n_embd, n_embd],
+                )?,
             };
 
             layers.push(layer);
@@ -129,7 +145,8 @@ impl KnownModel for Mpt {
                 let mut current = ctx0.op_norm(&input_layer);
                 current = ctx0.op_mul(&current, &self.layers[il].norm_1_weight);
 
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_wqkv_weight, &current);
+                current =
+                    builder.op_mul_mat_weight(&ctx0, &self.layers[il].c_attn_wqkv_weight, &current);
 
                 let nb = current.get_nb()[1];
                 let qcur = ctx0.op_view_2d(&current, (n_embd, n), nb, 0);
@@ -209,7 +226,11 @@ impl KnownModel for Mpt {
 
                 current = ctx0.op_cpy(&kqv_merged, &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, n));
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_out_proj_weight, &current);
+                current = builder.op_mul_mat_weight(
+                    &ctx0,
+                    &self.layers[il].c_attn_out_proj_weight,
+                    &current,
+                );
 
                 input_layer = ctx0.op_add(&input_layer, &current);
 
@@ -219,12 +240,13 @@ impl KnownModel for Mpt {
                 current = ctx0.op_norm(&input_layer);
                 current = ctx0.op_mul(&current, &self.layers[il].norm_2_weight);
 
-                current = ctx0.op_mul_mat(&self.layers[il].ffn_up_proj, &current);
+                current = builder.op_mul_mat_weight(&ctx0, &self.layers[il].ffn_up_proj, &current);
 
                 current = ctx0.op_gelu(&current);
 
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].ffn_down_proj, &current);
+                current =
+                    builder.op_mul_mat_weight(&ctx0, &self.layers[il].ffn_down_proj, &current);
 
                 input_layer = ctx0.op_add(&input_layer, &current);
             }
@@ -241,7 +263,7 @@ impl KnownModel for Mpt {
             // disable scratch buffer for last layer
             ctx0.use_scratch(None);
             // output embedding weight tied to input embedding
-            input_layer = ctx0.op_mul_mat(&self.wte, &input_layer);
+            input_layer = builder.op_mul_mat_weight(&ctx0, &self.wte, &input_layer);
 
             (
                 gf,
@@ -266,6 +288,10 @@ impl KnownModel for Mpt {
         &self.tokenizer
     }
 
+    fn model_context(&self) -> Option<&ModelContext> {
+        Some(&self.context)
+    }
+
     fn context_size(&self) -> usize {
         self.params.context_size
     }
@@ -286,6 +312,29 @@ impl KnownModel for Mpt {
         vec![]
     }
 
+    fn hf_hyperparameters(
+        config: &HfConfig,
+        file_type: FileType,
+    ) -> Result<Self::Hyperparameters, LoadError> {
+        Ok(Hyperparameters {
+            n_embd: config.get("d_model")?,
+            max_seq_len: config.get("max_seq_len")?,
+            n_head: config.get("n_heads")?,
+            n_layer: config.get("n_layers")?,
+            n_vocab: config.get("vocab_size")?,
+            alibi_bias_max: config
+                .get_optional("attn_config.alibi_bias_max")?
+                .unwrap_or(8.0),
+            clip_kqv: config.get_optional("attn_config.clip_qkv")?.unwrap_or(0.0),
+            file_type,
+        })
+    }
+
+    fn hf_tensor_name(name: &str) -> Option<String> {
+        // The tensors are named after the Hugging Face implementation.
+        Some(name.to_owned())
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
@@ -345,6 +394,18 @@ impl llm_base::Hyperparameters for Hyperparameters {
         self.n_vocab
     }
 
+    fn n_embd(&self) -> Option<usize> {
+        Some(self.n_embd)
+    }
+
+    fn n_layer(&self) -> Option<usize> {
+        Some(self.n_layer)
+    }
+
+    fn n_head(&self) -> Option<usize> {
+        Some(self.n_head)
+    }
+
     fn file_type(&self) -> Option<FileType> {
         Some(self.file_type)
     }